@@ -0,0 +1,230 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use direlera_rs::cache_system::CacheSystem;
+use direlera_rs::ids::*;
+use direlera_rs::protocol::*;
+use direlera_rs::quality::GradeDisplay;
+use direlera_rs::room::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// counts allocations so the game data path can be checked for per-frame garbage
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ATOMIC_INPUT_SIZE: u8 = 2;
+
+fn addr(i: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 27999))
+}
+
+fn make_room(players: usize) -> Rc<RefCell<Room>> {
+    let mut room = Room::new();
+    for i in 0..players {
+        room.players.push(PlayerAddr::Playing(addr(i)));
+    }
+    Rc::new(RefCell::new(room))
+}
+
+fn make_user(players: usize, conn_type: u8) -> Rc<RefCell<User>> {
+    let mut user = User::new(addr(0));
+    user.connect_type = conn_type;
+    user.atomic_input_size = ATOMIC_INPUT_SIZE;
+    user.players_input.resize(players, Vec::new());
+    Rc::new(RefCell::new(user))
+}
+
+// merge one full frame of every player's input, as input_process does per GAME_DATA.
+fn bench_gen_input(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gen_input");
+    for players in [2usize, 4, 8] {
+        for conn_type in [1u8, 3, 6] {
+            let room = make_room(players);
+            let user = make_user(players, conn_type);
+            let input = vec![0x55u8; (conn_type * ATOMIC_INPUT_SIZE) as usize];
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}p", players), format!("conn{}", conn_type)),
+                &input,
+                |b, input| {
+                    b.iter(|| {
+                        for i in 0..players {
+                            user.borrow_mut().players_input[i].extend_from_slice(input);
+                        }
+                        black_box(UserRoom::gen_input(user.clone(), room.clone()).unwrap());
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+// one frame of an 8 player game as input_process handles it: merge, cache
+// lookup, message body and datagram for every player.
+fn bench_frame(c: &mut Criterion) {
+    const PLAYERS: usize = 8;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut socket = rt
+        .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+        .unwrap();
+    let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let room = make_room(PLAYERS);
+    let users: Vec<_> = (0..PLAYERS)
+        .map(|_| {
+            let user = make_user(PLAYERS, 1);
+            user.borrow_mut().ip_addr = sink.local_addr().unwrap();
+            user
+        })
+        .collect();
+    let mut frame_no = 0u16;
+    let mut frame = || {
+        // distinct input every frame so the cache never hits
+        frame_no = frame_no.wrapping_add(1);
+        let input = frame_no.to_le_bytes();
+        for user in &users {
+            for i in 0..PLAYERS {
+                user.borrow_mut().players_input[i].extend_from_slice(&input);
+            }
+            let merged = UserRoom::gen_input(user.clone(), room.clone()).unwrap();
+            let p = {
+                let mut u = user.borrow_mut();
+                let mut data = u.pool.take();
+                match u.put_cache.position(&merged) {
+                    Some(cache_position) => {
+                        GameCache2Client::new(cache_position).write(&mut data);
+                        u.pool.give(merged);
+                        Protocol::new(GAME_CACHE, data)
+                    }
+                    None => {
+                        GameData2Client::write(&merged, &mut data);
+                        u.put_cache.put_data(merged);
+                        Protocol::new(GAME_DATA, data)
+                    }
+                }
+            };
+            rt.block_on(User::make_send_packet(user, &mut socket, p))
+                .unwrap();
+        }
+    };
+    // warm the pools and fill the caches, then report the steady state
+    for _ in 0..1024 {
+        frame();
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..1000 {
+        frame();
+    }
+    eprintln!(
+        "frame/8p: {:.1} allocations per frame",
+        (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / 1000.0
+    );
+    c.bench_function("frame/8p", |b| b.iter(&mut frame));
+}
+
+fn bench_cache(c: &mut Criterion) {
+    let mut cs = CacheSystem::new();
+    for i in 0..256u32 {
+        cs.put_data(i.to_le_bytes().to_vec());
+    }
+    let mut group = c.benchmark_group("cache");
+    group.bench_function("find_first", |b| {
+        b.iter(|| cs.position(black_box(&0u32.to_le_bytes())))
+    });
+    group.bench_function("find_last", |b| {
+        b.iter(|| cs.position(black_box(&255u32.to_le_bytes())))
+    });
+    group.bench_function("find_miss", |b| {
+        b.iter(|| cs.position(black_box(&1000u32.to_le_bytes())))
+    });
+    group.bench_function("put_rollover", |b| {
+        let mut n = 256u32;
+        b.iter(|| {
+            n = n.wrapping_add(1);
+            cs.put_data(black_box(n.to_le_bytes().to_vec()))
+        })
+    });
+    group.finish();
+}
+
+fn bench_packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packets");
+    group.bench_function("game_data", |b| {
+        let game_data = vec![0x55u8; 24];
+        b.iter(|| {
+            let data = GameData2Client::new(game_data.len() as u16, game_data.clone())
+                .packetize()
+                .unwrap();
+            black_box(Protocol::new(GAME_DATA, data).make_packet().unwrap())
+        })
+    });
+    group.bench_function("game_cache", |b| {
+        b.iter(|| {
+            let data = GameCache2Client::new(black_box(17)).packetize().unwrap();
+            black_box(Protocol::new(GAME_CACHE, data).make_packet().unwrap())
+        })
+    });
+    for users in [10usize, 100] {
+        let mut user_room = UserRoom::new();
+        for i in 0..users {
+            let mut u = User::new(addr(i));
+            u.name = format!("user{}", i).into_bytes();
+            u.user_id = UserId(i as u16);
+            user_room.users.insert(addr(i), Rc::new(RefCell::new(u)));
+        }
+        for i in 0..users as u32 / 4 {
+            let mut r = Room::new();
+            r.game_id = GameId(i);
+            r.game_name = format!("game {}", i);
+            r.players.push(PlayerAddr::Idle(addr(i as usize)));
+            user_room
+                .add_room(GameId(i), Rc::new(RefCell::new(r)))
+                .unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::new("server_status", users),
+            &user_room,
+            |b, user_room| {
+                b.iter(|| {
+                    black_box(
+                        user_room
+                            .make_server_status(addr(0), GradeDisplay::Off)
+                            .unwrap(),
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_gen_input,
+    bench_frame,
+    bench_cache,
+    bench_packets
+);
+criterion_main!(benches);
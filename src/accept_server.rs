@@ -1,15 +1,44 @@
-use log::{info};
+use crate::acl::Acl;
+use crate::encryption::{self, hello_public_key};
+use crate::federation::PEER_MAGIC;
+use crate::load::shared_level;
+use crate::protocol::{
+    hello_has_tag, CHALLENGE_TAG, FAST_INPUT_TAG, INFO_TAG, KEEPALIVE_TAG, PAUSE_TAG,
+};
+use crate::query_limit::QueryLimiter;
+use crate::reachability::probe_token;
+use crate::server_info::{HelloTagsCache, ServerInfo};
+use crate::service_server::Event;
+use crate::settings;
+use log::info;
 use std::collections::HashMap;
 
+use std::io;
 use std::net::SocketAddr;
-use std::{io};
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Semaphore;
+
+// tcp_fallback connections served at once, past this new ones are closed
+const MAX_TCP_CONNECTIONS: usize = 64;
+// a connection sending nothing for this long is closed
+const TCP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+// and any connection after this long, however it trickles bytes in
+const TCP_CONNECTION_TIME: Duration = Duration::from_secs(30);
 
-use tokio::net::UdpSocket;
 pub struct AcceptServer {
     pub socket: UdpSocket,
     pub buf: Vec<u8>,
     pub to_send: Option<(usize, SocketAddr)>,
     pub config_obj: HashMap<String, String>,
+    pub tx: Sender<Event>,
+    // the service server's load level, see load.rs
+    pub load: Arc<AtomicU8>,
 }
 
 impl AcceptServer {
@@ -20,27 +49,145 @@ impl AcceptServer {
             mut buf,
             mut to_send,
             config_obj,
+            tx,
+            load,
         } = self;
+        // already validated when the service server was built
+        let acl = Acl::from_config(&config_obj).unwrap_or_default();
+        let mut queries = QueryLimiter::from_config(&config_obj);
+        let mut info = HelloTagsCache::new(ServerInfo::from_config(&config_obj));
+        let hello = format!("HELLOD00D{}\x00", config_obj.get("sub_port").unwrap()).into_bytes();
+        let encrypt = settings::get_bool(&config_obj, "encryption", false);
+        let login_challenge = config_obj
+            .get("login_challenge")
+            .map_or("off", |x| x.as_str());
 
         loop {
             // First we check to see if there's a message we need to echo back.
             // If so then we try to send it back to the original source, waiting
             // until it's writable and we're able to do so.
-            if let Some((size, peer)) = to_send {
+            if let Some((size, peer)) = to_send.filter(|(_, peer)| acl.allows(peer.ip())) {
                 info!("size: {}", size);
-                if size == 5 {
-                    let ping = b"PING\x00";
-                    if ping == &buf[..size] {
-                        let _amt = socket.send_to("PONG\x00".as_bytes(), &peer).await?;
-                    }
+                let query =
+                    buf[..size].starts_with(b"PING\x00") || buf[..size].starts_with(b"HELLO");
+                if query && !queries.allows(peer.ip(), Instant::now(), &buf[..size]) {
+                    // over control_queries_per_minute
+                } else if &buf[..size] == b"PING\x00" {
+                    let _amt = socket.send_to("PONG\x00".as_bytes(), &peer).await?;
                 } else if size > 5 && &buf[..5] == "HELLO".as_bytes() {
-                    let sub_port = config_obj.get("sub_port").unwrap();
-                    let _amt = socket
-                        .send_to(format!("HELLOD00D{}\x00", sub_port).as_bytes(), &peer)
-                        .await?;
+                    let mut reply = hello.clone();
+                    let client_public = hello_public_key(&buf[..size]).filter(|_| encrypt);
+                    if let Some((tag, key)) = client_public.and_then(encryption::agree) {
+                        reply.extend(tag);
+                        let _ = tx.send(Event::Encrypt(peer, key)).await;
+                    }
+                    if hello_has_tag(&buf[..size], PAUSE_TAG) {
+                        reply.extend_from_slice(PAUSE_TAG);
+                        reply.push(0);
+                        let _ = tx.send(Event::PauseCapable(peer)).await;
+                    }
+                    if hello_has_tag(&buf[..size], FAST_INPUT_TAG) {
+                        reply.extend_from_slice(FAST_INPUT_TAG);
+                        reply.push(0);
+                        let _ = tx.send(Event::FastInputCapable(peer)).await;
+                    }
+                    if hello_has_tag(&buf[..size], KEEPALIVE_TAG) {
+                        let interval = settings::keepalive_interval(&config_obj).as_secs();
+                        reply.extend_from_slice(format!("KEEPALIVE={}\x00", interval).as_bytes());
+                    }
+                    if login_challenge != "off" && hello_has_tag(&buf[..size], CHALLENGE_TAG) {
+                        reply.extend_from_slice(CHALLENGE_TAG);
+                        reply.push(0);
+                        let _ = tx.send(Event::ChallengeCapable(peer)).await;
+                    }
+                    if hello_has_tag(&buf[..size], INFO_TAG) {
+                        reply.extend_from_slice(info.tags(shared_level(&load)));
+                    }
+                    let _amt = socket.send_to(&reply, &peer).await?;
+                } else if size > 5 && &buf[..5] == PEER_MAGIC {
+                    // status of a federated server, handled by the service server
+                    let _ = tx.send(Event::PeerStatus(peer, buf[..size].to_vec())).await;
+                } else if let Some(token) = probe_token(&buf[..size]) {
+                    let port = socket.local_addr()?.port();
+                    let _ = tx.send(Event::ProbeReceived(port, token.to_vec())).await;
                 }
             }
             to_send = Some(socket.recv_from(&mut buf).await?);
         }
     }
 }
+
+// optional tcp listener on the control port (tcp_fallback) for networks that
+// block udp: the same PING and HELLO exchange, so server browsers and health
+// checks still get an answer. messages are NUL terminated. encryption is
+// negotiated for the client's udp address, so it is not offered here.
+// connections are capped in number and in time, they are only for queries.
+pub async fn run_tcp(
+    listener: TcpListener,
+    config_obj: HashMap<String, String>,
+    load: Arc<AtomicU8>,
+) -> Result<(), io::Error> {
+    info!("Accept Run (tcp) on {}", listener.local_addr()?);
+    let acl = Acl::from_config(&config_obj).unwrap_or_default();
+    let sub_port = config_obj.get("sub_port").cloned().unwrap_or_default();
+    let mut info = HelloTagsCache::new(ServerInfo::from_config(&config_obj));
+    let slots = Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if !acl.allows(peer.ip()) {
+            continue;
+        }
+        let permit = match slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                info!("tcp control {}: too many connections", peer);
+                continue;
+            }
+        };
+        let (sub_port, info) = (sub_port.clone(), info.tags(shared_level(&load)).to_vec());
+        tokio::spawn(async move {
+            let serve = serve_tcp(stream, &sub_port, &info);
+            match tokio::time::timeout(TCP_CONNECTION_TIME, serve).await {
+                Ok(Err(e)) => info!("tcp control {}: {}", peer, e),
+                Err(_) => info!(
+                    "tcp control {}: closed after {:?}",
+                    peer, TCP_CONNECTION_TIME
+                ),
+                Ok(Ok(())) => {}
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn serve_tcp(mut stream: TcpStream, sub_port: &str, info: &[u8]) -> Result<(), io::Error> {
+    let mut buf = vec![0; 1024];
+    let mut pending = Vec::new();
+    loop {
+        // idle connections are closed
+        let read = stream.read(&mut buf);
+        let size = match tokio::time::timeout(TCP_READ_TIMEOUT, read).await {
+            Ok(size) => size?,
+            Err(_) => return Ok(()),
+        };
+        if size == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buf[..size]);
+        while let Some(end) = pending.iter().position(|x| *x == 0) {
+            let message: Vec<u8> = pending.drain(..=end).collect();
+            if message == b"PING\x00" {
+                stream.write_all(b"PONG\x00").await?;
+            } else if message.starts_with(b"HELLO") {
+                let mut reply = format!("HELLOD00D{}\x00", sub_port).into_bytes();
+                if hello_has_tag(&message, INFO_TAG) {
+                    reply.extend_from_slice(info);
+                }
+                stream.write_all(&reply).await?;
+            }
+        }
+        if pending.len() > buf.len() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::settings;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .with_context(|| format!("bad cidr {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse()
+                .with_context(|| format!("bad cidr {}", s))?,
+            None => max,
+        };
+        if prefix > max {
+            anyhow::bail!("bad cidr {}", s);
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// allow/deny rules by cidr. the most specific matching rule wins (deny on a tie),
+// addresses matching no rule are allowed.
+#[derive(Debug, Default)]
+pub struct Acl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn from_config(config: &HashMap<String, String>) -> anyhow::Result<Acl> {
+        let parse = |key| -> anyhow::Result<Vec<Cidr>> {
+            settings::get_list(config, key)
+                .iter()
+                .map(|x| x.parse())
+                .collect()
+        };
+        Ok(Acl {
+            allow: parse("acl_allow")?,
+            deny: parse("acl_deny")?,
+        })
+    }
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let best = |rules: &Vec<Cidr>| {
+            rules
+                .iter()
+                .filter(|x| x.contains(ip))
+                .map(|x| x.prefix as i32)
+                .max()
+                .unwrap_or(-1)
+        };
+        let (allow, deny) = (best(&self.allow), best(&self.deny));
+        deny < 0 || allow > deny
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lan_only() {
+        let mut config = HashMap::new();
+        config.insert("acl_deny".to_string(), "0.0.0.0/0, 10.9.0.0/16".to_string());
+        config.insert("acl_allow".to_string(), "10.0.0.0/8,::1".to_string());
+        let acl = Acl::from_config(&config).unwrap();
+        assert!(acl.allows("10.1.2.3".parse().unwrap()));
+        assert!(!acl.allows("10.9.2.3".parse().unwrap()));
+        assert!(!acl.allows("8.8.8.8".parse().unwrap()));
+        assert!(acl.allows("::1".parse().unwrap()));
+        assert!(acl.allows("::2".parse().unwrap()));
+
+        assert!(Acl::default().allows("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+// http api for matchmaking bots (bot_api = "127.0.0.1:27890", bot_api_key):
+// a discord bot can pair two players, create a room for one and tell the other
+// where to join. every request needs "Authorization: Bearer <bot_api_key>".
+//
+// GET  /status                                  the public status, as status_export
+// GET  /protocol                                the protocol reference in markdown
+// POST /rooms   {"owner": "kim", "name": "KOF98"}  room owned by kim, who must be in the lobby
+// POST /invite  {"user": "lee", "game_id": 7}   tell lee in the lobby to join game 7
+// POST /message {"user": "lee", "text": "hi"}   a server message to lee
+//
+// requests are answered by the service server through Event::Bot, so they see
+// and change the same state players do.
+use std::time::Duration;
+
+use log::info;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::ids::GameId;
+use crate::service_server::Event;
+
+const MAX_REQUEST: usize = 16 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CreateRoom {
+    pub owner: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Invite {
+    pub user: String,
+    pub game_id: GameId,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Message {
+    pub user: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BotRequest {
+    Status,
+    Protocol,
+    CreateRoom(CreateRoom),
+    Invite(Invite),
+    Message(Message),
+}
+
+// http status and body, json unless content_type says otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotReply {
+    pub status: u16,
+    pub body: String,
+    pub content_type: &'static str,
+}
+
+impl BotReply {
+    pub fn ok(body: String) -> BotReply {
+        BotReply {
+            status: 200,
+            body,
+            content_type: "application/json",
+        }
+    }
+    pub fn markdown(body: String) -> BotReply {
+        BotReply {
+            status: 200,
+            body,
+            content_type: "text/markdown; charset=utf-8",
+        }
+    }
+    pub fn error(status: u16, message: &str) -> BotReply {
+        BotReply {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+            content_type: "application/json",
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        _ => "Error",
+    }
+}
+
+// the request line and headers up to the blank line, and the body
+pub fn parse_request(head: &str, body: &[u8], key: &str) -> Result<BotRequest, BotReply> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, path) = (
+        request_line.next().unwrap_or(""),
+        request_line.next().unwrap_or(""),
+    );
+    let authorized = lines.any(|line| match line.split_once(':') {
+        Some((name, value)) if name.eq_ignore_ascii_case("authorization") => {
+            !key.is_empty() && value.trim().strip_prefix("Bearer ") == Some(key)
+        }
+        _ => false,
+    });
+    if !authorized {
+        return Err(BotReply::error(401, "bad or missing api key"));
+    }
+    let json = |x: serde_json::Result<BotRequest>| {
+        x.map_err(|e| BotReply::error(400, &format!("bad body: {}", e)))
+    };
+    match (method, path) {
+        ("GET", "/status") => Ok(BotRequest::Status),
+        ("GET", "/protocol") => Ok(BotRequest::Protocol),
+        ("POST", "/rooms") => json(serde_json::from_slice(body).map(BotRequest::CreateRoom)),
+        ("POST", "/invite") => json(serde_json::from_slice(body).map(BotRequest::Invite)),
+        ("POST", "/message") => json(serde_json::from_slice(body).map(BotRequest::Message)),
+        _ => Err(BotReply::error(404, "no such endpoint")),
+    }
+}
+
+// head and body of one request, None when the client went away
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        if let Some(end) = data.windows(4).position(|x| x == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&data[..end]).to_string();
+            let length = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map_or(Ok(0), |(_, value)| value.trim().parse::<usize>())?;
+            if end + 4 + length > MAX_REQUEST {
+                anyhow::bail!("request too large");
+            }
+            if data.len() >= end + 4 + length {
+                return Ok(Some((head, data[end + 4..end + 4 + length].to_vec())));
+            }
+        } else if data.len() > MAX_REQUEST {
+            anyhow::bail!("request too large");
+        }
+        let size = stream.read(&mut buf).await?;
+        if size == 0 {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buf[..size]);
+    }
+}
+
+async fn serve(mut stream: TcpStream, key: &str, tx: Sender<Event>) -> anyhow::Result<()> {
+    let (head, body) = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => match request? {
+            Some(request) => request,
+            None => return Ok(()),
+        },
+        Err(_) => return Ok(()),
+    };
+    let reply = match parse_request(&head, &body, key) {
+        Ok(request) => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            tx.send(Event::Bot(request, reply_tx)).await?;
+            match timeout(REQUEST_TIMEOUT, reply_rx).await {
+                Ok(Ok(reply)) => reply,
+                _ => BotReply::error(500, "no answer from the server"),
+            }
+        }
+        Err(reply) => reply,
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.status,
+        reason(reply.status),
+        reply.content_type,
+        reply.body.len(),
+        reply.body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn run(listener: TcpListener, key: String, tx: Sender<Event>) -> anyhow::Result<()> {
+    info!("bot api on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (key, tx) = (key.clone(), tx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &key, tx).await {
+                info!("bot api {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        let head = "POST /rooms HTTP/1.1\r\nHost: x\r\nauthorization: Bearer s3cret";
+        assert_eq!(
+            parse_request(head, br#"{"owner": "kim", "name": "KOF98"}"#, "s3cret"),
+            Ok(BotRequest::CreateRoom(CreateRoom {
+                owner: "kim".to_string(),
+                name: "KOF98".to_string()
+            }))
+        );
+        assert_eq!(
+            parse_request(head, b"{}", "s3cret").unwrap_err().status,
+            400
+        );
+        assert_eq!(parse_request(head, b"", "other").unwrap_err().status, 401);
+        let head = "GET /status HTTP/1.1\r\nAuthorization: Bearer s3cret";
+        assert_eq!(parse_request(head, b"", "s3cret"), Ok(BotRequest::Status));
+        let head = "GET /protocol HTTP/1.1\r\nAuthorization: Bearer s3cret";
+        assert_eq!(parse_request(head, b"", "s3cret"), Ok(BotRequest::Protocol));
+        let head = "GET /users HTTP/1.1\r\nAuthorization: Bearer s3cret";
+        assert_eq!(parse_request(head, b"", "s3cret").unwrap_err().status, 404);
+    }
+}
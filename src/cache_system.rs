@@ -1,49 +1,70 @@
+// the 256 entry input cache kaillera keeps on both ends of a connection, one
+// per direction: cache_system for what a client sent (GAME_CACHE from it names
+// a position in it) and put_cache for what the server sent to it (a repeated
+// input goes out as GAME_CACHE). an input not cached yet is appended; once 256
+// are cached the oldest is dropped and every position moves down by one, the
+// same as the client does, so both ends always agree on positions.
+//
+// looking up an input is expected to miss (it is then sent as GAME_DATA) and
+// gives an Option; looking up a position that was never filled is the client's
+// fault and gives an error.
 use crate::room::*;
+use std::collections::{HashMap, VecDeque};
 
+pub const CACHE_SIZE: usize = 256;
 
 #[derive(Debug)]
 pub struct CacheSystem {
-    pub incoming_data_vec: Vec<Vec<u8>>, // position, data
+    incoming_data_vec: VecDeque<Vec<u8>>, // position, data
+    // data -> absolute position (position + evicted)
+    index: HashMap<Vec<u8>, usize>,
+    evicted: usize,
 }
 
 impl CacheSystem {
     pub fn new() -> CacheSystem {
         CacheSystem {
-            incoming_data_vec: Vec::new(), // vec![Vec::new(); 256],
+            incoming_data_vec: VecDeque::new(),
+            index: HashMap::new(),
+            evicted: 0,
         }
     }
     pub fn reset(&mut self) {
-        self.incoming_data_vec.clear(); // vec![Vec::new(); 256];
+        self.incoming_data_vec.clear();
+        self.index.clear();
+        self.evicted = 0;
     }
-    pub fn get_cache_position(&self, b: Vec<u8>) -> Result<u8, KailleraError> {
-        let p = self.incoming_data_vec.iter().position(|x| x == &b);
-        match p {
-            Some(s) => Ok(s as u8),
-            None => Err(KailleraError::NotFound),
-        }
-        // match self.incoming_hit_cache.get(&b) {
-        //     Some(s) => Ok(*s),
-        //     None => {
-        //         return Err(KailleraError::NotFound);
-        //     }
-        // }
+    pub fn len(&self) -> usize {
+        self.incoming_data_vec.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.incoming_data_vec.is_empty()
+    }
+    // where b is cached, None when it is not
+    pub fn position(&self, b: &[u8]) -> Option<u8> {
+        self.index.get(b).map(|s| (s - self.evicted) as u8)
     }
+    // the position of b, cached first if it was not
     pub fn put_data(&mut self, b: Vec<u8>) -> u8 {
-        let p = self.get_cache_position(b.clone());
+        let p = self.position(&b);
         match p {
-            Ok(s) => s,
-            Err(_e) => {
-                if self.incoming_data_vec.len() < 256 {
-                    self.incoming_data_vec.push(b);
-                    (self.incoming_data_vec.len() - 1) as u8
-                } else {
-                    self.incoming_data_vec.remove(0);
-                    self.incoming_data_vec.push(b);
-                    (self.incoming_data_vec.len() - 1) as u8
+            Some(s) => s,
+            None => {
+                // when full, drop the oldest entry so every position shifts down by one.
+                if self.incoming_data_vec.len() >= CACHE_SIZE {
+                    if let Some(old) = self.incoming_data_vec.pop_front() {
+                        self.index.remove(&old);
+                    }
+                    self.evicted += 1;
                 }
+                self.index
+                    .insert(b.clone(), self.evicted + self.incoming_data_vec.len());
+                self.incoming_data_vec.push_back(b);
+                (self.incoming_data_vec.len() - 1) as u8
             }
         }
     }
+    // the input cached at pos, an error when pos was never filled
     pub fn get_data(&self, pos: u8) -> Result<Vec<u8>, KailleraError> {
         match self.incoming_data_vec.get(pos as usize) {
             Some(s) => Ok(s.clone()),
@@ -60,16 +81,60 @@ mod tests {
     fn this_test_will_pass() {
         let mut cs = CacheSystem::new();
         cs.put_data(vec![1, 2, 3, 4]);
-        let cp = cs.get_cache_position(vec![1, 2, 3, 4]);
-        match cp {
-            Ok(s) => assert_eq!(s, 0),
-            Err(_e) => assert_eq!(1, 0),
-        }
+        assert_eq!(cs.position(&[1, 2, 3, 4]), Some(0));
         cs.put_data(vec![1, 2, 3, 4]);
-        let cp = cs.get_cache_position(vec![1, 2, 3, 4]);
-        match cp {
-            Ok(s) => assert_eq!(s, 0),
-            Err(_e) => assert_eq!(1, 0),
+        assert_eq!(cs.position(&[1, 2, 3, 4]), Some(0));
+        assert_eq!(cs.len(), 1);
+        assert_eq!(cs.position(&[5]), None);
+        assert!(cs.get_data(1).is_err());
+    }
+    #[test]
+    fn rollover() {
+        let mut cs = CacheSystem::new();
+        for i in 0..256u16 {
+            assert_eq!(cs.put_data(i.to_le_bytes().to_vec()), i as u8);
+        }
+        assert_eq!(cs.put_data(256u16.to_le_bytes().to_vec()), 255);
+        assert!(cs.position(&0u16.to_le_bytes()).is_none());
+        assert_eq!(cs.position(&1u16.to_le_bytes()).unwrap(), 0);
+        assert_eq!(cs.position(&255u16.to_le_bytes()).unwrap(), 254);
+        assert_eq!(cs.get_data(0).unwrap(), 1u16.to_le_bytes().to_vec());
+        assert_eq!(cs.get_data(255).unwrap(), 256u16.to_le_bytes().to_vec());
+
+        cs.reset();
+        assert_eq!(cs.put_data(vec![9]), 0);
+        assert_eq!(cs.position(&[9]).unwrap(), 0);
+    }
+    #[test]
+    fn positions_match_the_client() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        // the client's cache: append, drop the front when full
+        let mut client: Vec<Vec<u8>> = Vec::new();
+        let mut cs = CacheSystem::new();
+        let mut rng = StdRng::seed_from_u64(4954);
+        for _ in 0..2000 {
+            // few enough distinct inputs that many repeat, enough to roll over
+            let input = vec![rng.gen_range(0..400u16) as u8, rng.gen_range(0..2)];
+            match cs.position(&input) {
+                Some(pos) => {
+                    assert_eq!(client[pos as usize], input);
+                    // a repeat moves nothing
+                    assert_eq!(cs.put_data(input.clone()), pos);
+                }
+                None => {
+                    assert!(!client.contains(&input));
+                    if client.len() == CACHE_SIZE {
+                        client.remove(0);
+                    }
+                    client.push(input.clone());
+                    assert_eq!(cs.put_data(input) as usize, client.len() - 1);
+                }
+            }
+            assert_eq!(cs.len(), client.len());
+        }
+        for (pos, input) in client.iter().enumerate() {
+            assert_eq!(&cs.get_data(pos as u8).unwrap(), input);
         }
     }
 }
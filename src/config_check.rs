@@ -0,0 +1,374 @@
+// strict checks of the flat server config: unknown keys, values that do not
+// parse, and port conflicts. errors point at the line and column in the
+// config file; keys coming from APP_* environment variables have no location.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::acl::Acl;
+use crate::settings;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Port,
+    Num,
+    Bool,
+    Text,
+    Range(u64, u64),
+    OneOf(&'static [&'static str]),
+    // comma separated cidrs, parsed by Acl
+    Cidrs,
+    // comma separated name:bytes
+    SizeMap,
+    // comma separated name:players, the name may contain ':'
+    CountMap,
+    // comma separated connection type (1-6):seconds
+    TypeMap,
+}
+
+use Kind::*;
+
+pub const KEYS: &[(&str, Kind)] = &[
+    ("main_port", Port),
+    ("sub_port", Port),
+    ("port_fallback", Range(0, 100)),
+    ("bind_retries", Range(0, 100)),
+    ("bind_retry_ms", Range(1, 60000)),
+    ("tcp_fallback", Bool),
+    ("control_queries_per_minute", Num),
+    ("control_query_token", Text),
+    ("debug", Bool),
+    ("debug_random_ping", Bool),
+    ("debug_random_ping_max", Range(1, u32::MAX as u64)),
+    ("debug_ping_jitter", Num),
+    // legacy, see settings::UNUSED
+    ("priority", Num),
+    ("key", Text),
+    ("log_format", OneOf(&["text", "json"])),
+    ("log_file", Text),
+    ("log_queue", Range(1, u32::MAX as u64)),
+    ("duplicate_login", OneOf(&["keep", "replace", "reject"])),
+    ("language", OneOf(&["en", "ko"])),
+    ("refusal_feedback", OneOf(&["off", "short", "verbose"])),
+    ("max_users", Num),
+    ("server_name", Text),
+    ("server_description", Text),
+    ("listed_max_users", Num),
+    ("server_name_load", Bool),
+    ("load_full_packets_per_sec", Range(1, u32::MAX as u64)),
+    ("load_full_games", Range(1, u32::MAX as u64)),
+    ("reserved_slots", Num),
+    ("vips", Text),
+    ("full_server_policy", OneOf(&["reject", "bump"])),
+    ("max_pending_sessions", Range(1, u32::MAX as u64)),
+    ("handshake_timeout_secs", Range(1, u32::MAX as u64)),
+    ("login_challenge", OneOf(&["off", "offer", "require"])),
+    ("suggest_connection_type", Bool),
+    ("keepalive_interval_secs", Range(1, 3600)),
+    ("keepalive_timeout_secs", Range(1, u32::MAX as u64)),
+    ("keepalive_timeout_by_type", TypeMap),
+    ("bans", Text),
+    ("bans_file", Text),
+    ("acl_deny", Cidrs),
+    ("acl_allow", Cidrs),
+    ("encryption", Bool),
+    ("admins", Text),
+    ("admins_file", Text),
+    ("resync_after", Num),
+    ("loss_advise_percent", Range(0, 100)),
+    ("friends_file", Text),
+    ("find_privacy", OneOf(&["open", "friends", "admins"])),
+    ("templates_file", Text),
+    ("persistent_rooms_file", Text),
+    ("state_file", Text),
+    ("state_save_secs", Range(1, 86400)),
+    ("script_file", Text),
+    ("id_state_file", Text),
+    ("dump_dir", Text),
+    ("audit_log", Text),
+    ("chat_filter", Text),
+    ("chat_timestamps", Bool),
+    ("chat_timezone", Text),
+    ("filter_hits_to_mute", Num),
+    ("mute_minutes", Num),
+    ("mutes_to_kick", Num),
+    ("kicks_to_ban", Num),
+    ("ban_minutes", Num),
+    ("max_ping", Num),
+    ("max_ping_action", OneOf(&["refuse", "warn"])),
+    ("netsync_timeout", Num),
+    ("netsync_notice_secs", Num),
+    ("relay_per_minute", Num),
+    ("advertise_minutes", Num),
+    ("pacing_fps", Range(1, 1000)),
+    ("pacing_warn_percent", Range(0, 100)),
+    ("send_pacing_ms", Range(0, 1000)),
+    ("room_max_players", Range(2, 8)),
+    ("min_players_by_game", CountMap),
+    ("duplicate_room_name", OneOf(&["allow", "suffix", "reject"])),
+    ("game_names_file", Text),
+    ("allowed_games", Text),
+    ("allowed_games_file", Text),
+    ("ping_order", Bool),
+    ("connection_grade", OneOf(&["off", "type", "suffix"])),
+    ("input_sizes", SizeMap),
+    ("input_max_packets_per_sec", Num),
+    ("input_max_bytes_per_sec", Num),
+    ("emulators_file", Text),
+    ("input_record_dir", Text),
+    ("input_record_format", OneOf(&["csv", "json"])),
+    ("emulinker_conf_dir", Text),
+    ("peers", Text),
+    ("peer_key", Text),
+    ("status_export_file", Text),
+    ("status_export_url", Text),
+    ("status_export_interval", Num),
+    ("reachability_url", Text),
+    ("reachability_timeout_secs", Range(1, 300)),
+    ("bot_api", Text),
+    ("bot_api_key", Text),
+    ("control_socket", Text),
+    ("timeline_size", Num),
+    ("notice", Text),
+    ("motd", Text),
+    ("motd_file", Text),
+    ("rules", Text),
+    ("rules_agree_secs", Range(1, u32::MAX as u64)),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    // 1 based, None when the key is not in the file
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: ", line, column)?,
+            _ => write!(f, "environment: ")?,
+        }
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+// line and column of the value of `key = value` in the config file
+fn locate(source: &str, key: &str) -> (Option<usize>, Option<usize>) {
+    for (i, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let rest = match trimmed.strip_prefix(key) {
+            Some(rest) if rest.trim_start().starts_with('=') => rest,
+            _ => continue,
+        };
+        let after_eq = rest.trim_start()[1..].trim_start();
+        let column = line.len() - after_eq.len() + 1;
+        return (Some(i + 1), Some(column));
+    }
+    (None, None)
+}
+
+fn check_value(kind: Kind, value: &str) -> Result<(), String> {
+    let value = value.trim();
+    match kind {
+        Port => match value.parse::<u16>() {
+            Ok(0) | Err(_) => Err(format!("\"{}\" is not a port (1-65535)", value)),
+            Ok(_) => Ok(()),
+        },
+        Num => value
+            .parse::<u64>()
+            .map(|_| ())
+            .map_err(|_| format!("\"{}\" is not a number", value)),
+        Bool => value
+            .parse::<bool>()
+            .map(|_| ())
+            .map_err(|_| format!("\"{}\" is not true or false", value)),
+        Text => Ok(()),
+        Range(min, max) => match value.parse::<u64>() {
+            Ok(x) if (min..=max).contains(&x) => Ok(()),
+            _ => Err(format!(
+                "\"{}\" is not a number in {}..={}",
+                value, min, max
+            )),
+        },
+        OneOf(names) => {
+            if names.contains(&value) {
+                Ok(())
+            } else {
+                Err(format!("\"{}\" is not one of {}", value, names.join(", ")))
+            }
+        }
+        SizeMap | CountMap => {
+            for item in value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                let (pair, count) = match kind {
+                    SizeMap => (item.split_once(':'), "emulator:bytes"),
+                    _ => (item.rsplit_once(':'), "game:players"),
+                };
+                match pair.map(|(_, size)| size.trim().parse::<u8>()) {
+                    Some(Ok(size)) if size > 0 => {}
+                    _ => return Err(format!("\"{}\" is not {}", item, count)),
+                }
+            }
+            Ok(())
+        }
+        TypeMap => {
+            for item in value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                match item
+                    .split_once(':')
+                    .map(|(t, secs)| (t.trim().parse::<u8>(), secs.trim().parse::<u32>()))
+                {
+                    Some((Ok(1..=6), Ok(secs))) if secs > 0 => {}
+                    _ => return Err(format!("\"{}\" is not connection_type:seconds", item)),
+                }
+            }
+            Ok(())
+        }
+        Cidrs => {
+            let config = HashMap::from([("acl_allow".to_string(), value.to_string())]);
+            Acl::from_config(&config)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// every problem found, in file order
+pub fn validate(config: &HashMap<String, String>, source: &str) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut push = |key: &str, message: String| {
+        let (line, column) = locate(source, key);
+        errors.push(ConfigError {
+            key: key.to_string(),
+            line,
+            column,
+            message,
+        });
+    };
+    let mut keys: Vec<_> = config.keys().collect();
+    keys.sort();
+    for key in keys {
+        match KEYS.iter().find(|(name, _)| name == key) {
+            Some((_, kind)) => {
+                if let Err(message) = check_value(*kind, &config[key]) {
+                    push(key, message);
+                }
+            }
+            None => push(key, "unknown key".to_string()),
+        }
+    }
+    for key in ["main_port", "sub_port"] {
+        if !config.contains_key(key) {
+            push(key, "missing".to_string());
+        }
+    }
+    if let (Some(main), Some(sub)) = (config.get("main_port"), config.get("sub_port")) {
+        if main.trim() == sub.trim() {
+            push(
+                "sub_port",
+                format!("same port as main_port ({})", main.trim()),
+            );
+        }
+    }
+    let interval = settings::keepalive_interval(config);
+    if config.contains_key("keepalive_timeout_secs")
+        && settings::get_num(config, "keepalive_timeout_secs", 0) < interval.as_secs() * 2
+    {
+        push(
+            "keepalive_timeout_secs",
+            format!(
+                "shorter than two keepalive intervals ({}s)",
+                interval.as_secs() * 2
+            ),
+        );
+    }
+    if config.contains_key("bot_api") && !config.contains_key("bot_api_key") {
+        push("bot_api", "needs bot_api_key".to_string());
+    }
+    // a HELLO over tcp cannot ask for CHALLENGE, so its client would never get in
+    if settings::get_bool(config, "tcp_fallback", false)
+        && config.get("login_challenge").map(|x| x.as_str()) == Some("require")
+    {
+        push(
+            "tcp_fallback",
+            "does not offer CHALLENGE, so it cannot be on with login_challenge = \"require\""
+                .to_string(),
+        );
+    }
+    errors.sort_by_key(|e| (e.line.unwrap_or(usize::MAX), e.column));
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_location() {
+        let source = "main_port = 27888\nsub_port = 27888\n# comment\n  langauge = \"en\"\nmax_ping_action=\"kick\"\n";
+        let config = HashMap::from([
+            ("main_port".to_string(), "27888".to_string()),
+            ("sub_port".to_string(), "27888".to_string()),
+            ("langauge".to_string(), "en".to_string()),
+            ("max_ping_action".to_string(), "kick".to_string()),
+            ("acl_deny".to_string(), "10.0.0.0/33".to_string()),
+            ("input_sizes".to_string(), "mame:2,snes9x".to_string()),
+        ]);
+        let errors: Vec<_> = validate(&config, source)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(errors.len(), 5);
+        assert_eq!(errors[0], "2:12: sub_port: same port as main_port (27888)");
+        assert_eq!(errors[1], "4:14: langauge: unknown key");
+        assert_eq!(
+            errors[2],
+            "5:17: max_ping_action: \"kick\" is not one of refuse, warn"
+        );
+        assert!(errors[3].starts_with("environment: acl_deny: "));
+        assert_eq!(
+            errors[4],
+            "environment: input_sizes: \"snes9x\" is not emulator:bytes"
+        );
+
+        let source = "main_port = 27888\nsub_port = 27999\n";
+        let config = HashMap::from([
+            ("main_port".to_string(), "27888".to_string()),
+            ("sub_port".to_string(), "27999".to_string()),
+        ]);
+        assert!(validate(&config, source).is_empty());
+
+        let mut config = config;
+        config.insert("tcp_fallback".to_string(), "true".to_string());
+        config.insert("login_challenge".to_string(), "offer".to_string());
+        assert!(validate(&config, source).is_empty());
+        config.insert("login_challenge".to_string(), "require".to_string());
+        let errors = validate(&config, source);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "tcp_fallback");
+    }
+
+    #[test]
+    fn checks_keepalive() {
+        assert!(check_value(TypeMap, "6:600, 5:400").is_ok());
+        assert!(check_value(TypeMap, "7:600").is_err());
+        assert!(check_value(TypeMap, "6:0").is_err());
+        assert!(check_value(CountMap, "D&D: Mystara:2, Gauntlet:3").is_ok());
+        assert_eq!(
+            check_value(CountMap, "Gauntlet"),
+            Err("\"Gauntlet\" is not game:players".to_string())
+        );
+        let config = HashMap::from([
+            ("main_port".to_string(), "27888".to_string()),
+            ("sub_port".to_string(), "27999".to_string()),
+            ("keepalive_interval_secs".to_string(), "60".to_string()),
+            ("keepalive_timeout_secs".to_string(), "90".to_string()),
+        ]);
+        let errors = validate(&config, "");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "shorter than two keepalive intervals (120s)"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// administration over a local unix socket (control_socket = "/run/direlera.sock"),
+// for an ssh session on the host without an http port. one command per line,
+// answered with text lines and an empty line:
+//
+// users                  who is online, one line each
+// kick <name>            disconnect a user
+// ban <name> [minutes]   disconnect a user and refuse their ip, 60 minutes by default
+// announce <text>        a server message to everyone, in the lobby and in rooms
+// close <game id>        send everyone out of a room, which closes it
+// dump                   the server snapshot, one line of json
+// timeline [hh:mm]       the latest server events, or those around hh:mm
+//
+// `direlera-rs ctl <socket> <command>` sends one command and prints the answer.
+// the socket is only accessible to the server's user, whoever can open it is an admin.
+use std::time::Duration;
+
+use chrono::NaiveTime;
+
+use crate::ids::GameId;
+
+pub const DEFAULT_BAN_MINUTES: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlRequest {
+    Users,
+    Kick(String),
+    Ban(String, Duration),
+    Announce(String),
+    Close(GameId),
+    Dump,
+    // None: the latest events
+    Timeline(Option<NaiveTime>),
+}
+
+pub fn parse_command(line: &str) -> Result<ControlRequest, String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    let needs = |what: &str| format!("{} needs {}", command, what);
+    match command {
+        "users" => Ok(ControlRequest::Users),
+        "dump" => Ok(ControlRequest::Dump),
+        "kick" if !rest.is_empty() => Ok(ControlRequest::Kick(rest.to_string())),
+        "kick" => Err(needs("a user name")),
+        "ban" if !rest.is_empty() => {
+            // the last word is the minutes when it is a number, names may have spaces
+            let (name, minutes) = match rest.rsplit_once(' ') {
+                Some((name, minutes)) if minutes.parse::<u64>().is_ok() => {
+                    (name.trim(), minutes.parse().unwrap_or(DEFAULT_BAN_MINUTES))
+                }
+                _ => (rest, DEFAULT_BAN_MINUTES),
+            };
+            Ok(ControlRequest::Ban(
+                name.to_string(),
+                Duration::from_secs(minutes * 60),
+            ))
+        }
+        "ban" => Err(needs("a user name")),
+        "announce" if !rest.is_empty() => Ok(ControlRequest::Announce(rest.to_string())),
+        "announce" => Err(needs("a message")),
+        "close" => rest
+            .parse()
+            .map(|x| ControlRequest::Close(GameId(x)))
+            .map_err(|_| needs("a game id")),
+        "timeline" if rest.is_empty() => Ok(ControlRequest::Timeline(None)),
+        "timeline" => NaiveTime::parse_from_str(rest, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(rest, "%H:%M:%S"))
+            .map(|x| ControlRequest::Timeline(Some(x)))
+            .map_err(|_| needs("a time like 21:05")),
+        "" => Err("empty command".to_string()),
+        _ => Err(format!(
+            "unknown command {}, try users, kick, ban, announce, close, dump or timeline",
+            command
+        )),
+    }
+}
+
+#[cfg(unix)]
+mod socket {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::Path;
+
+    use log::info;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::sync::mpsc::Sender;
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::service_server::Event;
+
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // the socket is made in a 0700 directory next to path, so nobody connects
+    // before it is 0600, then moved to path. only a socket there is replaced,
+    // one left by a server that did not shut down; anything else is an error.
+    pub fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if !meta.file_type().is_socket() => {
+                anyhow::bail!("{} exists and is not a socket", path.display())
+            }
+            _ => {}
+        }
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+        let dir = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let made = dir.join("socket");
+        let bound = (|| {
+            let listener = UnixListener::bind(&made)?;
+            fs::set_permissions(&made, Permissions::from_mode(0o600))?;
+            fs::rename(&made, path)?;
+            anyhow::Ok(listener)
+        })();
+        let _ = fs::remove_file(&made);
+        fs::remove_dir(&dir)?;
+        let listener = bound?;
+        info!("control socket on {}", path.display());
+        Ok(listener)
+    }
+
+    async fn serve(stream: UnixStream, tx: Sender<Event>) -> anyhow::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let reply = match parse_command(&line) {
+                Ok(request) => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    tx.send(Event::Control(request, reply_tx)).await?;
+                    match timeout(REPLY_TIMEOUT, reply_rx).await {
+                        Ok(Ok(reply)) => reply,
+                        _ => "error: no answer from the server".to_string(),
+                    }
+                }
+                Err(e) => format!("error: {}", e),
+            };
+            let mut reply = reply.trim_end().to_string();
+            reply.push_str("\n\n");
+            write.write_all(reply.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run(listener: UnixListener, tx: Sender<Event>) -> anyhow::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, tx).await {
+                    info!("control socket: {}", e);
+                }
+            });
+        }
+    }
+
+    // the answer to one command, without the empty line ending it
+    pub async fn send_command(path: &Path, command: &str) -> anyhow::Result<String> {
+        let stream = UnixStream::connect(path).await?;
+        let (read, mut write) = stream.into_split();
+        write
+            .write_all(format!("{}\n", command.trim()).as_bytes())
+            .await?;
+        let mut lines = BufReader::new(read).lines();
+        let mut reply = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            if line.is_empty() {
+                break;
+            }
+            reply.push(line);
+        }
+        Ok(reply.join("\n"))
+    }
+}
+
+#[cfg(unix)]
+pub use socket::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("users\n"), Ok(ControlRequest::Users));
+        assert_eq!(
+            parse_command("kick  kim"),
+            Ok(ControlRequest::Kick("kim".to_string()))
+        );
+        assert_eq!(
+            parse_command("ban lee jun 30"),
+            Ok(ControlRequest::Ban(
+                "lee jun".to_string(),
+                Duration::from_secs(1800)
+            ))
+        );
+        assert_eq!(
+            parse_command("ban kim"),
+            Ok(ControlRequest::Ban(
+                "kim".to_string(),
+                Duration::from_secs(3600)
+            ))
+        );
+        assert_eq!(
+            parse_command("close 7"),
+            Ok(ControlRequest::Close(GameId(7)))
+        );
+        assert_eq!(
+            parse_command("close seven"),
+            Err("close needs a game id".to_string())
+        );
+        assert_eq!(
+            parse_command("timeline 21:05"),
+            Ok(ControlRequest::Timeline(NaiveTime::from_hms_opt(21, 5, 0)))
+        );
+        assert_eq!(
+            parse_command("timeline"),
+            Ok(ControlRequest::Timeline(None))
+        );
+        assert!(parse_command("timeline 25:00").is_err());
+        assert!(parse_command("announce").is_err());
+        assert!(parse_command("reboot").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_over_sockets_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("direlera-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("direlera.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+
+        std::fs::remove_file(&path).unwrap();
+        drop(bind(&path).unwrap());
+        // the socket of a server that is gone
+        let listener = bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        drop(listener);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+// /desync: a player who sees the emulators drift apart reports it from game
+// chat. the report is one json line in audit_log with what the server knew
+// about every seated player at that moment, so reports can be lined up with
+// the sync engine's state afterwards.
+use serde::Serialize;
+
+use crate::ids::{GameId, UserId};
+use crate::room::{display_name, User};
+
+#[derive(Debug, Serialize)]
+pub struct PlayerSync {
+    pub user_id: UserId,
+    pub name: String,
+    pub player_index: u8,
+    pub connect_type: u8,
+    pub ping: u32,
+    // frames received from the player this game
+    pub frames: u64,
+    pub fps: Option<f64>,
+    // inputs the player has merged but not yet sent out, per seat
+    pub pending_inputs: Vec<usize>,
+    // entries in the input cache the player sends from, and the one the
+    // server sends to it from
+    pub cache_size: usize,
+    pub put_cache_size: usize,
+}
+
+impl PlayerSync {
+    pub fn new(user: &User) -> PlayerSync {
+        PlayerSync {
+            user_id: user.user_id,
+            name: display_name(&user.name),
+            player_index: user.player_index,
+            connect_type: user.connect_type,
+            ping: user.ping,
+            frames: user.pacing.frames,
+            fps: user.pacing.fps(),
+            pending_inputs: user.players_input.iter().map(|x| x.len()).collect(),
+            cache_size: user.cache_system.len(),
+            put_cache_size: user.put_cache.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DesyncReport {
+    pub time: String,
+    pub event: &'static str,
+    pub game_id: GameId,
+    pub game_name: String,
+    pub reporter: String,
+    pub players: Vec<PlayerSync>,
+}
+
+impl DesyncReport {
+    // one line, newline included
+    pub fn line(&self) -> anyhow::Result<Vec<u8>> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_one_line() {
+        let report = DesyncReport {
+            time: "2024-01-01T00:00:00Z".to_string(),
+            event: "desync",
+            game_id: GameId(3),
+            game_name: "kof98".to_string(),
+            reporter: "p1".to_string(),
+            players: vec![PlayerSync {
+                user_id: UserId(1),
+                name: "p1".to_string(),
+                player_index: 0,
+                connect_type: 1,
+                ping: 20,
+                frames: 600,
+                fps: Some(59.9),
+                pending_inputs: vec![0, 2],
+                cache_size: 12,
+                put_cache_size: 30,
+            }],
+        };
+        let line = report.line().unwrap();
+        assert_eq!(line.iter().filter(|&&x| x == b'\n').count(), 1);
+        let value: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(value["game_id"], 3);
+        assert_eq!(value["players"][0]["frames"], 600);
+        assert_eq!(value["players"][0]["pending_inputs"][1], 2);
+    }
+}
@@ -0,0 +1,164 @@
+// wireshark lua dissector generated from schema::MESSAGES.
+// `direlera-rs dissector > kaillera.lua`, then load it from wireshark's plugin directory.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::schema::*;
+
+fn proto_field(abbrev: &str, name: &str, ty: FieldType) -> String {
+    match ty {
+        FieldType::U8 => format!("ProtoField.uint8(\"{}\", \"{}\")", abbrev, name),
+        FieldType::U16 => format!("ProtoField.uint16(\"{}\", \"{}\")", abbrev, name),
+        FieldType::U32 => format!("ProtoField.uint32(\"{}\", \"{}\")", abbrev, name),
+        FieldType::Str => format!("ProtoField.stringz(\"{}\", \"{}\")", abbrev, name),
+        FieldType::Bytes => format!("ProtoField.bytes(\"{}\", \"{}\")", abbrev, name),
+    }
+}
+
+fn kind(ty: FieldType) -> &'static str {
+    match ty {
+        FieldType::U8 => "u8",
+        FieldType::U16 => "u16",
+        FieldType::U32 => "u32",
+        FieldType::Str => "str",
+        FieldType::Bytes => "bytes",
+    }
+}
+
+fn layouts(
+    out: &mut String,
+    table: &str,
+    fields_of: impl Fn(&MessageSchema) -> Option<&'static [Field]>,
+) {
+    writeln!(out, "local {} = {{", table).unwrap();
+    for m in MESSAGES {
+        if let Some(fields) = fields_of(m) {
+            let items: Vec<_> = fields
+                .iter()
+                .map(|x| format!("{{ \"{}\", fields[\"{}\"] }}", kind(x.ty), abbrev(m, x)))
+                .collect();
+            writeln!(
+                out,
+                "    [0x{:02x}] = {{ {} }},",
+                m.message_type,
+                items.join(", ")
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn abbrev(m: &MessageSchema, field: &Field) -> String {
+    format!("kaillera.{}.{}", m.name.to_lowercase(), field.name)
+}
+
+pub fn lua_dissector(port: u16) -> String {
+    let mut out = String::new();
+    out += "-- generated by `direlera-rs dissector`, do not edit.\n";
+    out += "local kaillera = Proto(\"kaillera\", \"Kaillera\")\n";
+    out += &format!(
+        "kaillera.prefs.port = Pref.uint(\"Server port\", {}, \"UDP port of the game server\")\n\n",
+        port
+    );
+
+    out += "local types = {\n";
+    for m in MESSAGES {
+        writeln!(out, "    [0x{:02x}] = \"{}\",", m.message_type, m.name).unwrap();
+    }
+    out += "}\n\n";
+
+    // one ProtoField per message field, shared by both directions
+    let mut fields = BTreeMap::new();
+    for m in MESSAGES {
+        for x in m.to_server.into_iter().chain(m.to_client).flatten() {
+            fields.insert(abbrev(m, x), proto_field(&abbrev(m, x), x.name, x.ty));
+        }
+    }
+    out += "local fields = {\n";
+    out += "    count = ProtoField.uint8(\"kaillera.count\", \"Messages\"),\n";
+    out += "    seq = ProtoField.uint16(\"kaillera.seq\", \"Sequence\"),\n";
+    out += "    length = ProtoField.uint16(\"kaillera.length\", \"Length\"),\n";
+    out += "    type = ProtoField.uint8(\"kaillera.type\", \"Type\", base.HEX, types),\n";
+    for (abbrev, field) in &fields {
+        writeln!(out, "    [\"{}\"] = {},", abbrev, field).unwrap();
+    }
+    out += "}\n";
+    out += "local field_list = {}\n";
+    out += "for _, v in pairs(fields) do table.insert(field_list, v) end\n";
+    out += "kaillera.fields = field_list\n\n";
+
+    layouts(&mut out, "to_server", |m| m.to_server);
+    layouts(&mut out, "to_client", |m| m.to_client);
+
+    out += r#"
+local function dissect_body(layout, buf, tree)
+    local pos = 0
+    for _, item in ipairs(layout) do
+        local kind, field = item[1], item[2]
+        if pos >= buf:len() then return end
+        if kind == "u8" then
+            tree:add_le(field, buf(pos, 1)); pos = pos + 1
+        elseif kind == "u16" then
+            tree:add_le(field, buf(pos, 2)); pos = pos + 2
+        elseif kind == "u32" then
+            tree:add_le(field, buf(pos, 4)); pos = pos + 4
+        elseif kind == "str" then
+            local len = buf(pos):stringz():len() + 1
+            tree:add(field, buf(pos, math.min(len, buf:len() - pos))); pos = pos + len
+        else
+            tree:add(field, buf(pos)); pos = buf:len()
+        end
+    end
+end
+
+function kaillera.dissector(buf, pinfo, tree)
+    if buf:len() < 1 then return end
+    pinfo.cols.protocol = "KAILLERA"
+    local layouts = to_client
+    if pinfo.dst_port == kaillera.prefs.port then layouts = to_server end
+    local root = tree:add(kaillera, buf())
+    local count = buf(0, 1):uint()
+    root:add(fields.count, buf(0, 1))
+    local pos = 1
+    local names = {}
+    for i = 1, count do
+        if pos + 5 > buf:len() then break end
+        local length = buf(pos + 2, 2):le_uint()
+        local message_type = buf(pos + 4, 1):uint()
+        local name = types[message_type] or string.format("0x%02x", message_type)
+        local sub = root:add(kaillera, buf(pos, 4 + length), name)
+        sub:add_le(fields.seq, buf(pos, 2))
+        sub:add_le(fields.length, buf(pos + 2, 2))
+        sub:add(fields.type, buf(pos + 4, 1))
+        if length > 1 and layouts[message_type] then
+            dissect_body(layouts[message_type], buf(pos + 5, length - 1):tvb(), sub)
+        end
+        table.insert(names, name)
+        pos = pos + 4 + length
+    end
+    pinfo.cols.info = table.concat(names, ", ")
+end
+
+local udp_port = DissectorTable.get("udp.port")
+udp_port:add(kaillera.prefs.port, kaillera)
+"#;
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_message_in_dissector() {
+        let lua = lua_dissector(27999);
+        for m in MESSAGES {
+            assert!(lua.contains(&format!("\"{}\"", m.name)));
+        }
+        assert!(lua.contains(
+            "[\"kaillera.update_game_status.max_players\"] = ProtoField.uint8(\"kaillera.update_game_status.max_players\", \"max_players\")"
+        ));
+        assert!(lua.contains("Pref.uint(\"Server port\", 27999"));
+    }
+}
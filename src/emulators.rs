@@ -0,0 +1,105 @@
+// what the server knows about the emulators kaillera clients are built into,
+// found by the start of the emulator name sent at login. new rooms take their
+// defaults from the creator's emulator: how many players its netplay handles,
+// whether it needs /samedelay, and the input bytes per frame per player
+// (input_sizes in the config still wins). emulators_file (json) adds entries
+// and replaces built-in ones with the same prefix:
+// [{"prefix": "MAME32k", "max_players": 8}, {"prefix": "Snes9x", "input_size": 4}]
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Capabilities {
+    pub prefix: String,
+    // None: room_max_players
+    #[serde(default)]
+    pub max_players: Option<u8>,
+    #[serde(default)]
+    pub same_delay: bool,
+    // None: taken from the input itself
+    #[serde(default)]
+    pub input_size: Option<u8>,
+}
+
+// controller ports of the consoles, so rooms do not offer seats the game never has
+fn builtin() -> Vec<Capabilities> {
+    let ports = |prefix: &str, max_players| Capabilities {
+        prefix: prefix.to_string(),
+        max_players: Some(max_players),
+        same_delay: false,
+        input_size: None,
+    };
+    vec![ports("Project64k", 4), ports("Snes9x", 5)]
+}
+
+#[derive(Debug)]
+pub struct Emulators {
+    entries: Vec<Capabilities>,
+}
+
+impl Default for Emulators {
+    fn default() -> Emulators {
+        Emulators { entries: builtin() }
+    }
+}
+
+impl Emulators {
+    // without a path only the built-in entries are known
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Emulators> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Emulators::default()),
+        };
+        let file: Vec<Capabilities> = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        Ok(Emulators::new(file))
+    }
+    pub fn new(entries: Vec<Capabilities>) -> Emulators {
+        let mut emulators = Emulators::default();
+        for x in entries {
+            emulators
+                .entries
+                .retain(|y| !y.prefix.eq_ignore_ascii_case(&x.prefix));
+            emulators.entries.push(x);
+        }
+        emulators
+    }
+    // the entry with the longest prefix the name starts with, ignoring case
+    pub fn find(&self, emulator: &str) -> Option<&Capabilities> {
+        let emulator = emulator.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|x| emulator.starts_with(&x.prefix.to_lowercase()))
+            .max_by_key(|x| x.prefix.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let emulators: Vec<Capabilities> = serde_json::from_str(
+            r#"[{"prefix": "mame", "max_players": 8},
+                {"prefix": "MAME32k", "same_delay": true, "input_size": 2},
+                {"prefix": "project64k", "max_players": 2}]"#,
+        )
+        .unwrap();
+        let emulators = Emulators::new(emulators);
+        let mame32k = emulators.find("MAME32k 0.64 (Feb 2003)").unwrap();
+        assert_eq!(
+            (mame32k.max_players, mame32k.same_delay, mame32k.input_size),
+            (None, true, Some(2))
+        );
+        assert_eq!(emulators.find("MAMEPlus").unwrap().max_players, Some(8));
+        assert_eq!(
+            emulators.find("Project64k 0.13").unwrap().max_players,
+            Some(2)
+        );
+        assert_eq!(emulators.find("Snes9x 1.60").unwrap().max_players, Some(5));
+        assert!(emulators.find("Nestopia").is_none());
+    }
+}
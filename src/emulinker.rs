@@ -0,0 +1,158 @@
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Import of an EmuLinker-SF conf directory (emulinker.cfg, access.cfg, language.properties)
+// into direlera config keys. Unsupported settings are ignored.
+
+// java properties: key=value (or key: value), '#'/'!' comments, \uXXXX escapes.
+pub fn parse_properties(text: &str) -> HashMap<String, String> {
+    let mut props = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+        let pos = match line.find(['=', ':']) {
+            Some(pos) => pos,
+            None => continue,
+        };
+        let key = line[..pos].trim().to_string();
+        let value = unescape(line[pos + 1..].trim());
+        props.insert(key, value);
+    }
+    props
+}
+
+fn unescape(s: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('t') => ret.push('\t'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    ret.push(c);
+                }
+            }
+            Some(c) => ret.push(c),
+            None => {}
+        }
+    }
+    ret
+}
+
+pub fn map_emulinker_config(props: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    let keys = [
+        ("controllers.connect.port", "main_port"),
+        ("server.maxUsers", "max_users"),
+    ];
+    for (from, to) in keys {
+        if let Some(v) = props.get(from) {
+            config.insert(to.to_string(), v.clone());
+        }
+    }
+    config
+}
+
+// KailleraServerImpl.LoginMessage.1, .2, ... become the notice lines.
+pub fn map_language(props: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    let mut lines = Vec::new();
+    for i in 1.. {
+        match props.get(&format!("KailleraServerImpl.LoginMessage.{}", i)) {
+            Some(line) => lines.push(line.clone()),
+            None => break,
+        }
+    }
+    if !lines.is_empty() {
+        config.insert("notice".to_string(), lines.join("\n"));
+    }
+    config
+}
+
+// access.cfg lines:
+//   user,<normal|elevated|moderator|admin>,<names>,<addresses>[,message]
+//   ipaddress,<allow|deny>,<addresses>
+// addresses are '|' separated with '*' wildcards. admin/moderator users become admins,
+// denied addresses become bans.
+pub fn map_access_list(text: &str) -> HashMap<String, String> {
+    let mut admins = Vec::new();
+    let mut bans = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let f: Vec<_> = line.split(',').map(|x| x.trim()).collect();
+        match (f.first().copied(), f.get(1).copied()) {
+            (Some("user"), Some("admin" | "moderator")) => {
+                if let Some(addrs) = f.get(3) {
+                    admins.extend(addrs.split('|').filter(|x| *x != "*" && !x.is_empty()));
+                }
+            }
+            (Some("ipaddress"), Some("deny")) => {
+                if let Some(addrs) = f.get(2) {
+                    bans.extend(addrs.split('|').filter(|x| !x.is_empty()));
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut config = HashMap::new();
+    if !admins.is_empty() {
+        config.insert("admins".to_string(), admins.join(","));
+    }
+    if !bans.is_empty() {
+        config.insert("bans".to_string(), bans.join(","));
+    }
+    config
+}
+
+pub fn load_emulinker_dir(dir: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let cfg = dir.join("emulinker.cfg");
+    let text = fs::read_to_string(&cfg).with_context(|| format!("read {}", cfg.display()))?;
+    let mut config = map_emulinker_config(&parse_properties(&text));
+    if let Ok(text) = fs::read_to_string(dir.join("language.properties")) {
+        config.extend(map_language(&parse_properties(&text)));
+    }
+    if let Ok(text) = fs::read_to_string(dir.join("access.cfg")) {
+        config.extend(map_access_list(&text));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import() {
+        let props = parse_properties(
+            "# comment\ncontrollers.connect.port=27888\nserver.maxUsers = 50\nserver.maxPing=1000\n",
+        );
+        let config = map_emulinker_config(&props);
+        assert_eq!(config["main_port"], "27888");
+        assert_eq!(config["max_users"], "50");
+        assert_eq!(config.len(), 2);
+
+        let props = parse_properties(
+            "KailleraServerImpl.LoginMessage.1=Welcome\nKailleraServerImpl.LoginMessage.2=\\uD55C\\uAE00\n",
+        );
+        assert_eq!(map_language(&props)["notice"], "Welcome\n한글");
+
+        let config = map_access_list(
+            "user,admin,*,127.0.0.1|10.0.0.2,Hi admin\nuser,normal,*,*\nipaddress,deny,1.2.3.*|5.6.7.8\nipaddress,allow,*\n",
+        );
+        assert_eq!(config["admins"], "127.0.0.1,10.0.0.2");
+        assert_eq!(config["bans"], "1.2.3.*,5.6.7.8");
+    }
+}
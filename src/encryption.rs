@@ -0,0 +1,188 @@
+// Opt-in encryption extension for tournament servers on hostile networks. With
+// `encryption = true`, a client that adds "AEAD=<hex x25519 public key>" to its HELLO
+// gets "AEAD=<hex public key>" of a fresh server key pair back. Both ends derive the
+// session key from the x25519 shared secret and the two public keys, and from then on
+// every datagram of that session is an 8 byte counter followed by the AES-256-GCM
+// ciphertext of the plain datagram; the nonce is the direction and that counter.
+// Someone sniffing the link reads no inputs or chat, and altered or forged datagrams
+// fail the tag and are dropped. The server is not authenticated: someone who can
+// rewrite the HELLO exchange in flight can sit in the middle. Legacy clients never
+// send the tag and are unaffected.
+use aes_gcm::aead::{Aead, Nonce};
+use aes_gcm::{Aes256Gcm, Key, KeyInit};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::protocol::hello_tags;
+
+pub const ENCRYPTION_TAG: &[u8] = b"AEAD=";
+
+const KEY_LABEL: &[u8] = b"direlera aead v1";
+const CLIENT_TO_SERVER: [u8; 4] = *b"C2S\0";
+const SERVER_TO_CLIENT: [u8; 4] = *b"S2C\0";
+const COUNTER_LEN: usize = 8;
+
+// the client's public key in its HELLO
+pub fn hello_public_key(hello: &[u8]) -> Option<[u8; 32]> {
+    let hex = hello_tags(hello).find_map(|x| x.strip_prefix(ENCRYPTION_TAG))?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).ok()?;
+        key[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(key)
+}
+
+// the tag for the HELLO reply and the session key, None for a client key that
+// gives no shared secret (a low order point)
+pub fn agree(client_public: [u8; 32]) -> Option<(Vec<u8>, [u8; 32])> {
+    let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let server_public = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(client_public));
+    if !shared.was_contributory() {
+        return None;
+    }
+    let key = session_key(shared.as_bytes(), &client_public, server_public.as_bytes());
+    let mut tag = ENCRYPTION_TAG.to_vec();
+    tag.extend(
+        server_public
+            .as_bytes()
+            .iter()
+            .flat_map(|x| format!("{:02x}", x).into_bytes()),
+    );
+    tag.push(0);
+    Some((tag, key))
+}
+
+fn session_key(shared: &[u8; 32], client_public: &[u8; 32], server_public: &[u8; 32]) -> [u8; 32] {
+    let mut info = KEY_LABEL.to_vec();
+    info.extend_from_slice(client_public);
+    info.extend_from_slice(server_public);
+    hmac_sha256::HMAC::mac(info, shared)
+}
+
+#[derive(Clone)]
+pub struct SessionCipher {
+    cipher: Aes256Gcm,
+    // datagrams sealed so far, the counter of the next nonce
+    sent: u64,
+}
+
+impl std::fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
+
+impl SessionCipher {
+    pub fn new(key: [u8; 32]) -> SessionCipher {
+        SessionCipher {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            sent: 0,
+        }
+    }
+    // a datagram to the client
+    pub fn seal(&mut self, datagram: &[u8]) -> Vec<u8> {
+        let counter = self.sent.to_le_bytes();
+        self.sent += 1;
+        let mut sealed = counter.to_vec();
+        let body = self
+            .cipher
+            .encrypt(&nonce(SERVER_TO_CLIENT, counter), datagram)
+            .unwrap_or_default();
+        sealed.extend(body);
+        sealed
+    }
+    // a datagram from the client, None when it was not sealed with this session's key
+    pub fn open(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < COUNTER_LEN {
+            return None;
+        }
+        let (counter, body) = datagram.split_at(COUNTER_LEN);
+        let counter: [u8; COUNTER_LEN] = counter.try_into().ok()?;
+        self.cipher
+            .decrypt(&nonce(CLIENT_TO_SERVER, counter), body)
+            .ok()
+    }
+}
+
+// what a client sends, for handler tests
+#[cfg(test)]
+pub fn seal_as_client(key: [u8; 32], counter: u64, datagram: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let counter = counter.to_le_bytes();
+    let mut sealed = counter.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(&nonce(CLIENT_TO_SERVER, counter), datagram)
+            .unwrap(),
+    );
+    sealed
+}
+
+fn nonce(direction: [u8; 4], counter: [u8; COUNTER_LEN]) -> Nonce<Aes256Gcm> {
+    let mut nonce = Nonce::<Aes256Gcm>::default();
+    nonce[..4].copy_from_slice(&direction);
+    nonce[4..].copy_from_slice(&counter);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_and_server_agree() {
+        // the client's half of the exchange
+        let client_secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let client_public = PublicKey::from(&client_secret);
+        let mut hello = b"HELLO0.83\x00".to_vec();
+        hello.extend_from_slice(ENCRYPTION_TAG);
+        hello.extend(
+            client_public
+                .as_bytes()
+                .iter()
+                .flat_map(|x| format!("{:02x}", x).into_bytes()),
+        );
+        hello.push(0);
+        assert_eq!(hello_public_key(&hello), Some(*client_public.as_bytes()));
+        assert_eq!(hello_public_key(b"HELLO0.83\x00AEAD=zz\x00"), None);
+        assert_eq!(hello_public_key(b"HELLO0.83\x00"), None);
+
+        let (tag, key) = agree(*client_public.as_bytes()).unwrap();
+        let server_public =
+            hello_public_key(&[b"HELLOD00D27999\x00".as_slice(), &tag].concat()).unwrap();
+        let shared = client_secret.diffie_hellman(&PublicKey::from(server_public));
+        assert_eq!(
+            session_key(shared.as_bytes(), client_public.as_bytes(), &server_public),
+            key
+        );
+        // the all zero key is a low order point
+        assert!(agree([0; 32]).is_none());
+
+        let mut server = SessionCipher::new(key);
+        let client = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let datagram = b"\x01\x00\x00\x05\x00\x12\x00\x02\x00\x01\x02";
+        let sealed = server.seal(datagram);
+        let counter: [u8; 8] = sealed[..8].try_into().unwrap();
+        let plain = client
+            .decrypt(&nonce(SERVER_TO_CLIENT, counter), &sealed[8..])
+            .unwrap();
+        assert_eq!(plain, datagram);
+        // nonces are not reused
+        assert_ne!(server.seal(datagram)[..8], sealed[..8]);
+
+        let from_client = seal_as_client(key, 7, datagram);
+        assert_eq!(server.open(&from_client).unwrap(), datagram);
+        // altered, or sealed in the other direction
+        let mut altered = from_client.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(server.open(&altered).is_none());
+        assert!(server.open(&sealed).is_none());
+        assert!(server.open(datagram).is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::protocol::*;
+use crate::room::*;
+
+// datagram sent to a peer's main port: PEER_MAGIC + text body. the shared
+// peer_key is never sent: the body starts with an HMAC-SHA256 of the rest made
+// with it, and the rest starts with the time it was sent, so a captured
+// datagram cannot be altered and stops being accepted after MAX_AGE_SECS.
+pub const PEER_MAGIC: &[u8] = b"PEER\x00";
+// keep the datagram inside the accept server's receive buffer
+const MAX_BODY_LEN: usize = 1000;
+// peers report every few seconds, allow for clocks a little apart
+pub const MAX_AGE_SECS: u64 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerRoom {
+    pub game_name: String,
+    pub emul_name: String,
+    pub game_status: GameStatus,
+    pub players: usize,
+    pub max_players: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub main_port: u16,
+    pub users: usize,
+    pub rooms: Vec<PeerRoom>,
+}
+
+fn clean(s: &str) -> String {
+    s.replace(['\t', '\n'], " ")
+}
+
+fn mac_hex(key: &str, signed: &[u8]) -> String {
+    hmac_sha256::HMAC::mac(signed, key.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+// body layout (one field per line): the mac of what follows, the unix time
+// it was sent, main_port, users, then one
+// "game_name\temul_name\tstatus\tplayers\tmax_players" line per room.
+pub fn encode_status(key: &str, now: u64, status: &PeerStatus) -> Vec<u8> {
+    let mut signed = format!("{}\n{}\n{}\n", now, status.main_port, status.users);
+    // the mac, its newline and the magic come on top
+    let room_for_rooms = MAX_BODY_LEN - PEER_MAGIC.len() - 65;
+    for r in &status.rooms {
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            clean(&r.game_name),
+            clean(&r.emul_name),
+            r.game_status,
+            r.players,
+            r.max_players
+        );
+        if signed.len() + line.len() > room_for_rooms {
+            break;
+        }
+        signed += &line;
+    }
+    let mut v = PEER_MAGIC.to_vec();
+    v.extend_from_slice(mac_hex(key, signed.as_bytes()).as_bytes());
+    v.push(b'\n');
+    v.extend_from_slice(signed.as_bytes());
+    v
+}
+
+pub fn decode_status(key: &str, now: u64, data: &[u8]) -> Result<PeerStatus, KailleraError> {
+    let invalid = |pos: usize| KailleraError::InvalidInput {
+        message: "bad peer status".to_string(),
+        pos,
+    };
+    let body = data.strip_prefix(PEER_MAGIC).ok_or_else(|| invalid(0))?;
+    let split = body
+        .iter()
+        .position(|x| *x == b'\n')
+        .ok_or_else(|| invalid(0))?;
+    let (mac, signed) = (&body[..split], &body[split + 1..]);
+    let expected = mac_hex(key, signed);
+    // compare every byte, so the time taken says nothing about the mac
+    if mac.len() != expected.len()
+        || mac
+            .iter()
+            .zip(expected.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            != 0
+    {
+        return Err(KailleraError::TokenError);
+    }
+    let signed = String::from_utf8_lossy(signed);
+    let mut lines = signed.lines();
+    let sent: u64 = lines
+        .next()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid(1))?;
+    if sent.abs_diff(now) > MAX_AGE_SECS {
+        return Err(KailleraError::InvalidInput {
+            message: format!("peer status {} seconds old", now as i64 - sent as i64),
+            pos: 1,
+        });
+    }
+    let main_port = lines
+        .next()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid(2))?;
+    let users = lines
+        .next()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid(3))?;
+    let mut rooms = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let f: Vec<_> = line.split('\t').collect();
+        if f.len() != 5 {
+            return Err(invalid(4 + i));
+        }
+        rooms.push(PeerRoom {
+            game_name: f[0].to_string(),
+            emul_name: f[1].to_string(),
+            game_status: f[2].parse().map_err(|_| invalid(4 + i))?,
+            players: f[3].parse().map_err(|_| invalid(4 + i))?,
+            max_players: f[4].parse().map_err(|_| invalid(4 + i))?,
+        });
+    }
+    Ok(PeerStatus {
+        main_port,
+        users,
+        rooms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_round_trip() {
+        let status = PeerStatus {
+            main_port: 27888,
+            users: 3,
+            rooms: vec![PeerRoom {
+                game_name: "Street Fighter\tII".to_string(),
+                emul_name: "MAME".to_string(),
+                game_status: GAME_STATUS_PLAYING,
+                players: 2,
+                max_players: 8,
+            }],
+        };
+        let data = encode_status("secret", 1000, &status);
+        // the key itself is not in the datagram
+        assert!(!data.windows(6).any(|x| x == b"secret"));
+        let de = decode_status("secret", 1010, &data).unwrap();
+        assert_eq!(de.main_port, 27888);
+        assert_eq!(de.users, 3);
+        assert_eq!(de.rooms[0].game_name, "Street Fighter II");
+        assert_eq!((de.rooms[0].players, de.rooms[0].max_players), (2, 8));
+        assert!(matches!(
+            decode_status("other", 1000, &data),
+            Err(KailleraError::TokenError)
+        ));
+
+        // altered after signing
+        let mut forged = data.clone();
+        let users = forged.len() - 1 - "Street Fighter II\tMAME\t1\t2\t8".len() - 2;
+        forged[users] = b'9';
+        assert!(matches!(
+            decode_status("secret", 1000, &forged),
+            Err(KailleraError::TokenError)
+        ));
+        // replayed later
+        assert!(decode_status("secret", 1000 + MAX_AGE_SECS + 1, &data).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// friend lists by user name, kept in friends_file as "name<TAB>friend,friend" lines.
+// names are not authenticated, anyone can log in as anyone, so the lists are capped
+// per name and in all to keep the file from growing without end.
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const MAX_FRIENDS: usize = 50;
+pub const MAX_LISTS: usize = 10000;
+// longer than any name a client sends
+pub const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct Friends {
+    pub path: Option<PathBuf>,
+    pub lists: HashMap<String, BTreeSet<String>>,
+}
+
+impl Friends {
+    // without a path the lists only live until restart
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Friends> {
+        let mut friends = Friends {
+            path: path.map(|x| x.to_path_buf()),
+            lists: HashMap::new(),
+        };
+        let text = match path.map(fs::read_to_string) {
+            Some(Ok(text)) => text,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(friends),
+        };
+        for line in text.lines() {
+            if let Some((name, list)) = line.split_once('\t') {
+                let list = list
+                    .split(',')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string());
+                friends.lists.insert(name.to_string(), list.collect());
+            }
+        }
+        Ok(friends)
+    }
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, self.to_text())?;
+        }
+        Ok(())
+    }
+    pub fn to_text(&self) -> String {
+        let mut names: Vec<_> = self.lists.keys().collect();
+        names.sort();
+        let mut text = String::new();
+        for name in names {
+            let list: Vec<_> = self.lists[name].iter().map(|x| x.as_str()).collect();
+            text += &format!("{}\t{}\n", name, list.join(","));
+        }
+        text
+    }
+    // Ok(false) when friend already is on the list, Err says why it cannot be
+    pub fn add(&mut self, name: &str, friend: &str) -> Result<bool, String> {
+        if friend.chars().count() > MAX_NAME_LEN {
+            return Err(format!("names are at most {} characters", MAX_NAME_LEN));
+        }
+        if !self.lists.contains_key(name) && self.lists.len() >= MAX_LISTS {
+            return Err("the server keeps no more friend lists".to_string());
+        }
+        let list = self.lists.entry(name.to_string()).or_default();
+        if !list.contains(friend) && list.len() >= MAX_FRIENDS {
+            return Err(format!("at most {} friends", MAX_FRIENDS));
+        }
+        Ok(list.insert(friend.to_string()))
+    }
+    pub fn remove(&mut self, name: &str, friend: &str) -> bool {
+        let list = match self.lists.get_mut(name) {
+            Some(list) => list,
+            None => return false,
+        };
+        let removed = list.remove(friend);
+        if list.is_empty() {
+            self.lists.remove(name);
+        }
+        removed
+    }
+    pub fn list(&self, name: &str) -> Vec<String> {
+        self.lists
+            .get(name)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    // users who have name in their list
+    pub fn followers(&self, name: &str) -> Vec<String> {
+        self.lists
+            .iter()
+            .filter(|(_, list)| list.contains(name))
+            .map(|(user, _)| user.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_remove_save() {
+        let path = std::env::temp_dir().join(format!("direlera-friends-{}", std::process::id()));
+        let mut friends = Friends::load(Some(&path)).unwrap();
+        assert_eq!(friends.add("alice", "bob"), Ok(true));
+        assert_eq!(friends.add("alice", "bob"), Ok(false));
+        assert_eq!(friends.add("carol", "bob"), Ok(true));
+        assert_eq!(friends.add("carol", "alice"), Ok(true));
+        let mut followers = friends.followers("bob");
+        followers.sort();
+        assert_eq!(followers, vec!["alice", "carol"]);
+        friends.save().unwrap();
+
+        let mut friends = Friends::load(Some(&path)).unwrap();
+        assert_eq!(friends.list("carol"), vec!["alice", "bob"]);
+        assert!(friends.remove("alice", "bob"));
+        assert!(!friends.remove("alice", "bob"));
+        assert!(friends.followers("bob") == vec!["carol"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn capped() {
+        let mut friends = Friends::default();
+        for i in 0..MAX_FRIENDS {
+            assert_eq!(friends.add("alice", &format!("f{}", i)), Ok(true));
+        }
+        assert!(friends.add("alice", "one more").is_err());
+        assert_eq!(friends.add("alice", "f0"), Ok(false));
+        assert!(friends.add("bob", &"x".repeat(MAX_NAME_LEN + 1)).is_err());
+        for i in 1..MAX_LISTS {
+            friends.lists.insert(format!("u{}", i), BTreeSet::new());
+        }
+        assert!(friends.add("newcomer", "alice").is_err());
+        assert_eq!(friends.add("alice", "f0"), Ok(false));
+    }
+}
@@ -0,0 +1,97 @@
+// one name per game. emulators send the rom's full name, so "Street Fighter II
+// (USA)" and "Street Fighter II (Japan, Rev 1)" look like different games.
+// game_names_file (json) removes region and revision suffixes with regexes,
+// then maps whole names to one alias:
+// {"strip": ["\\s*\\((USA|Japan|Europe|World|Rev \\w+)[^)]*\\)"],
+//  "aliases": {"Street Fighter II - The World Warrior": "Street Fighter II"}}
+// applied to allowed_games and the games in status_export. clients are always
+// sent the raw name, which is what their emulators look the rom up by.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+struct GameNamesFile {
+    #[serde(default)]
+    strip: Vec<String>,
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct GameNames {
+    strip: Vec<Regex>,
+    // normalized name -> listed name
+    aliases: HashMap<String, String>,
+}
+
+impl GameNames {
+    // without a path every name is kept as sent
+    pub fn load(path: Option<&Path>) -> anyhow::Result<GameNames> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(GameNames::default()),
+        };
+        let file: GameNamesFile = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        GameNames::new(&file.strip, file.aliases)
+    }
+    pub fn new(strip: &[String], aliases: HashMap<String, String>) -> anyhow::Result<GameNames> {
+        let strip = strip
+            .iter()
+            .map(|x| Regex::new(x).map_err(|e| anyhow::anyhow!("{}: {}", x, e)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(GameNames { strip, aliases })
+    }
+    pub fn normalize(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for re in &self.strip {
+            name = re.replace_all(&name, "").to_string();
+        }
+        let name = name.trim();
+        self.aliases
+            .get(name)
+            .map_or(name, |x| x.as_str())
+            .to_string()
+    }
+    // allowed_games: comma separated, compared after normalizing both sides.
+    // empty allows everything
+    pub fn allowed(&self, allowed_games: &str, name: &str) -> bool {
+        let name = self.normalize(name);
+        let mut games = allowed_games
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .peekable();
+        games.peek().is_none() || games.any(|x| self.normalize(x).eq_ignore_ascii_case(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_names() {
+        let names = GameNames::new(
+            &[r"\s*\((USA|Japan|Europe|World|Rev \w+)[^)]*\)".to_string()],
+            HashMap::from([(
+                "Street Fighter II - The World Warrior".to_string(),
+                "Street Fighter II".to_string(),
+            )]),
+        )
+        .unwrap();
+        assert_eq!(
+            names.normalize("Street Fighter II - The World Warrior (Japan, Rev 1)"),
+            "Street Fighter II"
+        );
+        assert_eq!(names.normalize("KOF98 (USA) (Rev A)"), "KOF98");
+        assert!(names.allowed("", "anything"));
+        assert!(names.allowed("kof98, SF3", "KOF98 (Europe)"));
+        assert!(!names.allowed("SF3", "KOF98"));
+        assert!(GameNames::new(&["(".to_string()], HashMap::new()).is_err());
+    }
+}
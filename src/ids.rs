@@ -0,0 +1,135 @@
+// typed ids, so a user id cannot end up where a game id belongs, and the last
+// allocated user and game ids, kept in a small text file so ids stay unique
+// across restarts (see id_state_file).
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+// a newtype over the id's wire integer. serializes as the bare integer, so
+// packets and json keep their layout
+macro_rules! id_type {
+    ($name:ident, $inner:ty) => {
+        #[derive(
+            Debug,
+            Default,
+            Clone,
+            Copy,
+            PartialEq,
+            Eq,
+            Hash,
+            PartialOrd,
+            Ord,
+            Serialize,
+            Deserialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub $inner);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+        impl From<$inner> for $name {
+            fn from(x: $inner) -> $name {
+                $name(x)
+            }
+        }
+        impl From<$name> for $inner {
+            fn from(x: $name) -> $inner {
+                x.0
+            }
+        }
+    };
+}
+
+// the u16 in USER_JOIN, PLAYER_INFO, USER_QUIT, ...
+id_type!(UserId, u16);
+// the u32 in CREATE_GAME, JOIN_GAME, ...
+id_type!(GameId, u32);
+// a game played in a room, numbered from 1: Room::history.len() once it began
+id_type!(SessionId, usize);
+
+impl UserId {
+    // the next id, wrapping at u16::MAX
+    pub fn next(self) -> UserId {
+        UserId(self.0.wrapping_add(1))
+    }
+    pub fn to_le_bytes(self) -> [u8; 2] {
+        self.0.to_le_bytes()
+    }
+}
+
+impl GameId {
+    pub fn next(self) -> GameId {
+        GameId(self.0.wrapping_add(1))
+    }
+    pub fn to_le_bytes(self) -> [u8; 4] {
+        self.0.to_le_bytes()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdState {
+    pub user_id: UserId,
+    pub game_id: GameId,
+}
+
+impl IdState {
+    // a missing file starts from zero
+    pub fn load(path: &Path) -> anyhow::Result<IdState> {
+        let mut state = IdState::default();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(e.into()),
+        };
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("user_id", v)) => state.user_id = UserId(v.trim().parse()?),
+                Some(("game_id", v)) => state.game_id = GameId(v.trim().parse()?),
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+    pub fn to_text(&self) -> String {
+        format!("user_id={}\ngame_id={}\n", self.user_id, self.game_id)
+    }
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        // write then rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_text())?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_load() {
+        let path = std::env::temp_dir().join(format!("direlera-ids-{}", std::process::id()));
+        assert_eq!(IdState::load(&path).unwrap(), IdState::default());
+        let state = IdState {
+            user_id: UserId(65535),
+            game_id: GameId(1234),
+        };
+        state.save(&path).unwrap();
+        assert_eq!(IdState::load(&path).unwrap(), state);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ids_keep_wire_layout() {
+        assert_eq!(bincode::serialize(&GameId(7)).unwrap(), 7u32.to_le_bytes());
+        assert_eq!(bincode::serialize(&UserId(7)).unwrap(), 7u16.to_le_bytes());
+        assert_eq!(serde_json::to_string(&GameId(7)).unwrap(), "7");
+        assert_eq!(UserId(u16::MAX).next(), UserId(0));
+        assert_eq!(u32::from(GameId(3).next()), 4);
+    }
+}
@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ids::GameId;
+
+// per-player raw input of one game, exported as csv or json when the game ends.
+#[derive(Debug, Serialize)]
+pub struct PlayerInputs {
+    pub player: usize,
+    pub name: String,
+    pub input_size: usize,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+impl PlayerInputs {
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> {
+        self.data.chunks(self.input_size.max(1))
+    }
+}
+
+#[derive(Debug)]
+pub struct InputRecorder {
+    pub players: Vec<PlayerInputs>,
+}
+
+#[derive(Serialize)]
+struct JsonPlayer<'a> {
+    #[serde(flatten)]
+    player: &'a PlayerInputs,
+    frames: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    game_id: GameId,
+    game_name: &'a str,
+    players: Vec<JsonPlayer<'a>>,
+}
+
+pub(crate) fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+impl InputRecorder {
+    pub fn new(names: Vec<String>) -> InputRecorder {
+        InputRecorder {
+            players: names
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| PlayerInputs {
+                    player: i + 1,
+                    name,
+                    input_size: 0,
+                    data: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+    pub fn push(&mut self, player_index: usize, input_size: u8, data: &[u8]) {
+        if let Some(p) = self.players.get_mut(player_index) {
+            p.input_size = input_size as usize;
+            p.data.extend_from_slice(data);
+        }
+    }
+    // frame,player,name,input
+    pub fn to_csv(&self) -> String {
+        let mut ret = "frame,player,name,input\n".to_string();
+        for p in &self.players {
+            let name = p.name.replace([',', '"', '\n'], " ");
+            for (frame, input) in p.frames().enumerate() {
+                ret += &format!("{},{},{},{}\n", frame, p.player, name, hex(input));
+            }
+        }
+        ret
+    }
+    pub fn to_json(&self, game_id: GameId, game_name: &str) -> anyhow::Result<String> {
+        let record = JsonRecord {
+            game_id,
+            game_name,
+            players: self
+                .players
+                .iter()
+                .map(|p| JsonPlayer {
+                    player: p,
+                    frames: p.frames().map(hex).collect(),
+                })
+                .collect(),
+        };
+        Ok(serde_json::to_string(&record)?)
+    }
+    pub fn export(
+        &self,
+        dir: &Path,
+        format: &str,
+        game_id: GameId,
+        game_name: &str,
+    ) -> anyhow::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let (path, body) = self.render(dir, format, game_id, game_name)?;
+        fs::write(&path, body)?;
+        Ok(path)
+    }
+    // file path and contents export would write
+    pub fn render(
+        &self,
+        dir: &Path,
+        format: &str,
+        game_id: GameId,
+        game_name: &str,
+    ) -> anyhow::Result<(PathBuf, String)> {
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        let (body, ext) = match format {
+            "json" => (self.to_json(game_id, game_name)?, "json"),
+            _ => (self.to_csv(), "csv"),
+        };
+        Ok((dir.join(format!("{}-game{}.{}", stamp, game_id, ext)), body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_and_json() {
+        let mut rec = InputRecorder::new(vec!["a".to_string(), "b,c".to_string()]);
+        rec.push(0, 2, &[1, 0, 2, 0]);
+        rec.push(1, 2, &[0xff, 0x10]);
+        rec.push(5, 2, &[0, 0]);
+        assert_eq!(
+            rec.to_csv(),
+            "frame,player,name,input\n0,1,a,0100\n1,1,a,0200\n0,2,b c,ff10\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&rec.to_json(GameId(7), "kof").unwrap()).unwrap();
+        assert_eq!(json["game_id"], 7);
+        assert_eq!(json["players"][0]["frames"][1], "0200");
+        assert_eq!(json["players"][1]["input_size"], 2);
+    }
+}
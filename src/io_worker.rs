@@ -0,0 +1,332 @@
+// a dedicated thread for log output and state files (ids, friends, dumps,
+// input records, the audit log) so a slow disk never stalls the udp
+// dispatcher. log records wait in a bounded queue that drops the oldest under
+// pressure; file writes are never dropped.
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::ids::GameId;
+
+#[derive(Debug, Serialize)]
+pub struct LogRecord {
+    pub time: String,
+    pub level: String,
+    pub file: String,
+    pub line: u32,
+    pub message: String,
+    // the room whose message was being handled, see in_room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub game_id: Option<GameId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+tokio::task_local! {
+    static ROOM: Option<(GameId, String)>;
+}
+
+// log lines from f carry the game id and room name of room, so
+// `select(.game_id == 7)` on the json log or grepping "game=7" in the text log
+// finds everything one room did. the service server handles each message from
+// a user in a room inside one; task local, so it follows the handler across awaits.
+pub async fn in_room<F: Future>(room: Option<(GameId, String)>, f: F) -> F::Output {
+    ROOM.scope(room, f).await
+}
+
+pub fn current_room() -> Option<(GameId, String)> {
+    ROOM.try_with(|x| x.clone()).ok().flatten()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<LogFormat> {
+        match name {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    logs: VecDeque<LogRecord>,
+    // path, data, and whether it is appended instead of replacing the file
+    writes: VecDeque<(PathBuf, Vec<u8>, bool)>,
+    // log records dropped since the worker last looked
+    dropped: u64,
+    busy: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    cv: Condvar,
+    max_logs: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct IoWorker {
+    shared: Arc<Shared>,
+}
+
+impl IoWorker {
+    // queue only, nothing drains it until start
+    fn new(max_logs: usize) -> IoWorker {
+        IoWorker {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                cv: Condvar::new(),
+                max_logs: max_logs.max(1),
+            }),
+        }
+    }
+    // log lines go to log_file, or stderr without one
+    pub fn start(max_logs: usize, format: LogFormat, log_file: Option<PathBuf>) -> IoWorker {
+        let worker = IoWorker::new(max_logs);
+        let shared = worker.shared.clone();
+        std::thread::Builder::new()
+            .name("direlera-io".to_string())
+            .spawn(move || run(shared, format, log_file))
+            .expect("spawn io worker");
+        worker
+    }
+    pub fn log(&self, record: LogRecord) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.logs.len() >= self.shared.max_logs {
+            queue.logs.pop_front();
+            queue.dropped += 1;
+        }
+        queue.logs.push_back(record);
+        self.shared.cv.notify_all();
+    }
+    // replaces path with data, through a temporary file so a crash never leaves it truncated
+    pub fn write_file(&self, path: PathBuf, data: Vec<u8>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.writes.push_back((path, data, false));
+        self.shared.cv.notify_all();
+    }
+    // adds data to the end of path, for line based logs like audit_log
+    pub fn append_file(&self, path: PathBuf, data: Vec<u8>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.writes.push_back((path, data, true));
+        self.shared.cv.notify_all();
+    }
+    // wait until everything queued so far is written
+    pub fn flush(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.busy || !queue.logs.is_empty() || !queue.writes.is_empty() {
+            queue = self.shared.cv.wait(queue).unwrap();
+        }
+    }
+}
+
+pub fn format_record(format: LogFormat, record: &LogRecord) -> String {
+    match format {
+        LogFormat::Text => match (&record.game_id, &record.room) {
+            (Some(game_id), Some(room)) => format!(
+                "{}:{} {} [{}] game={} room={:?} - {}\n",
+                record.file, record.line, record.time, record.level, game_id, room, record.message
+            ),
+            _ => format!(
+                "{}:{} {} [{}] - {}\n",
+                record.file, record.line, record.time, record.level, record.message
+            ),
+        },
+        LogFormat::Json => match serde_json::to_string(record) {
+            Ok(line) => line + "\n",
+            Err(e) => format!("{{\"error\":\"{}\"}}\n", e),
+        },
+    }
+}
+
+fn write_atomic(path: &PathBuf, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+fn append(path: &PathBuf, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(data)
+}
+
+fn run(shared: Arc<Shared>, format: LogFormat, log_file: Option<PathBuf>) {
+    let mut out: Box<dyn Write> = match log_file
+        .as_ref()
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+    {
+        Some(Ok(file)) => Box::new(file),
+        Some(Err(e)) => {
+            eprintln!("cannot open log file {:?}: {}", log_file, e);
+            Box::new(std::io::stderr())
+        }
+        None => Box::new(std::io::stderr()),
+    };
+    loop {
+        let (logs, writes, dropped) = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.logs.is_empty() && queue.writes.is_empty() {
+                queue = shared.cv.wait(queue).unwrap();
+            }
+            queue.busy = true;
+            let dropped = std::mem::take(&mut queue.dropped);
+            (
+                std::mem::take(&mut queue.logs),
+                std::mem::take(&mut queue.writes),
+                dropped,
+            )
+        };
+        let mut text = String::new();
+        if dropped > 0 {
+            text += &format!("io worker: {} log records dropped\n", dropped);
+        }
+        for record in &logs {
+            text += &format_record(format, record);
+        }
+        for (path, data, appended) in &writes {
+            let result = if *appended {
+                append(path, data)
+            } else {
+                write_atomic(path, data)
+            };
+            if let Err(e) = result {
+                text += &format!("io worker: writing {} failed: {}\n", path.display(), e);
+            }
+        }
+        let _ = out.write_all(text.as_bytes());
+        let _ = out.flush();
+        shared.queue.lock().unwrap().busy = false;
+        shared.cv.notify_all();
+    }
+}
+
+// log::Log front end: records are captured on the calling thread and
+// formatted and written by the io worker.
+pub struct QueuedLogger {
+    worker: IoWorker,
+    level: LevelFilter,
+}
+
+impl QueuedLogger {
+    pub fn init(worker: IoWorker, level: LevelFilter) -> anyhow::Result<()> {
+        log::set_boxed_logger(Box::new(QueuedLogger { worker, level }))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for QueuedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (game_id, room) = current_room().unzip();
+        self.worker.log(LogRecord {
+            time: chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+            level: record.level().to_string(),
+            file: record.file().unwrap_or("unknown").to_string(),
+            line: record.line().unwrap_or(0),
+            message: record.args().to_string(),
+            game_id,
+            room,
+        });
+    }
+    fn flush(&self) {
+        self.worker.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            time: "2024-01-01T00:00:00".to_string(),
+            level: "INFO".to_string(),
+            file: "src/main.rs".to_string(),
+            line: 7,
+            message: message.to_string(),
+            game_id: None,
+            room: None,
+        }
+    }
+
+    #[test]
+    fn drops_oldest_log_records() {
+        let worker = IoWorker::new(2);
+        worker.log(record("a"));
+        worker.log(record("b"));
+        worker.log(record("c"));
+        worker.write_file(PathBuf::from("ids"), vec![1]);
+        let queue = worker.shared.queue.lock().unwrap();
+        let kept: Vec<_> = queue.logs.iter().map(|x| x.message.as_str()).collect();
+        assert_eq!(kept, vec!["b", "c"]);
+        assert_eq!(queue.dropped, 1);
+        assert_eq!(queue.writes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn formats() {
+        assert_eq!(
+            format_record(LogFormat::Text, &record("hi")),
+            "src/main.rs:7 2024-01-01T00:00:00 [INFO] - hi\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_record(LogFormat::Json, &record("hi"))).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["message"], "hi");
+        assert!(json.get("game_id").is_none());
+
+        let mut in_kof = record("hi");
+        let room = Some((GameId(7), "kof98".to_string()));
+        (in_kof.game_id, in_kof.room) = in_room(room, async {
+            tokio::task::yield_now().await;
+            current_room()
+        })
+        .await
+        .unzip();
+        assert!(current_room().is_none());
+        assert_eq!(
+            format_record(LogFormat::Text, &in_kof),
+            "src/main.rs:7 2024-01-01T00:00:00 [INFO] game=7 room=\"kof98\" - hi\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_record(LogFormat::Json, &in_kof)).unwrap();
+        assert_eq!(json["game_id"], 7);
+
+        let path = std::env::temp_dir().join(format!("direlera-io-{}", std::process::id()));
+        let worker = IoWorker::start(8, LogFormat::Text, None);
+        worker.write_file(path.clone(), b"done".to_vec());
+        worker.append_file(path.clone(), b", more".to_vec());
+        worker.flush();
+        assert_eq!(fs::read(&path).unwrap(), b"done, more");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,7 +1,52 @@
 pub mod accept_server;
+pub mod acl;
+pub mod bot_api;
 pub mod cache_system;
+pub mod config_check;
+pub mod control;
+pub mod desync;
+pub mod dissector;
+pub mod emulators;
+pub mod emulinker;
+pub mod encryption;
+pub mod federation;
 pub mod foo;
+pub mod friends;
+pub mod game_names;
+pub mod ids;
+pub mod input_record;
+pub mod io_worker;
+pub mod list_files;
+pub mod load;
 pub mod misc;
+pub mod motd;
+pub mod pacing;
+pub mod packet_util;
+pub mod pending;
+pub mod persistent_rooms;
+pub mod pool;
+pub mod port_bind;
 pub mod protocol;
+pub mod punishment;
+pub mod quality;
+pub mod query_limit;
+pub mod reachability;
 pub mod room;
+pub mod saved_state;
+pub mod schema;
+pub mod scripting;
+pub mod selftest;
+pub mod send_pacing;
+pub mod server_info;
 pub mod service_server;
+pub mod settings;
+pub mod simulate;
+pub mod snapshot;
+pub mod stats;
+pub mod status_export;
+pub mod stress;
+pub mod suspicion;
+pub mod templates;
+#[cfg(test)]
+pub mod test_util;
+pub mod timeline;
@@ -0,0 +1,142 @@
+// bans, admins and allowed_games kept in their own files (bans_file,
+// admins_file, allowed_games_file), one entry per line, so external tools or
+// other servers can manage them. the files are checked every CHECK_EVERY and a
+// changed one replaces the file's part of the list right away; entries written
+// in the config itself stay. a file that cannot be read keeps what was last
+// read from it. motd_file is watched the same way, its text replaces motd.
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+// lists that can come from a file, the file is <key>_file
+pub const KEYS: &[&str] = &["bans", "admins", "allowed_games"];
+// texts that can come from a file, taken as they are
+pub const TEXT_KEYS: &[&str] = &["motd"];
+pub const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct ListFile {
+    key: &'static str,
+    path: PathBuf,
+    // the list as written in the config, None for a text
+    base: Option<Vec<String>>,
+    modified: Option<SystemTime>,
+    // warned that it cannot be read, until it can again
+    missing: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ListFiles {
+    files: Vec<ListFile>,
+}
+
+// "# ..." lines and blank lines are skipped, entries may also be comma separated
+pub fn parse_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|x| x.split('#').next().unwrap_or(""))
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+impl ListFiles {
+    pub fn from_config(config: &HashMap<String, String>) -> ListFiles {
+        let files = KEYS
+            .iter()
+            .chain(TEXT_KEYS)
+            .filter_map(|key| {
+                let path = config.get(&format!("{}_file", key))?;
+                Some(ListFile {
+                    key,
+                    path: PathBuf::from(path),
+                    base: KEYS
+                        .contains(key)
+                        .then(|| crate::settings::get_list(config, key)),
+                    modified: None,
+                    missing: false,
+                })
+            })
+            .collect();
+        ListFiles { files }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+    // (key, the whole comma separated list or the text) for each file that changed
+    // since the last call, every file on the first
+    pub fn changed(&mut self) -> Vec<(&'static str, String)> {
+        let mut changed = Vec::new();
+        for file in &mut self.files {
+            let modified = match fs::metadata(&file.path).and_then(|x| x.modified()) {
+                Ok(t) => t,
+                Err(e) => {
+                    if !file.missing {
+                        warn!("{}: {}, keeping the last list", file.path.display(), e);
+                    }
+                    file.missing = true;
+                    file.modified = None;
+                    continue;
+                }
+            };
+            if file.modified == Some(modified) {
+                continue;
+            }
+            let text = match fs::read_to_string(&file.path) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("{}: {}, keeping the last list", file.path.display(), e);
+                    continue;
+                }
+            };
+            file.modified = Some(modified);
+            file.missing = false;
+            match &file.base {
+                Some(base) => {
+                    let mut list = base.clone();
+                    list.extend(parse_list(&text));
+                    changed.push((file.key, list.join(",")));
+                }
+                None => changed.push((file.key, text)),
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_changed_files() {
+        assert_eq!(
+            parse_list("# bans\n1.2.3.4\n\n5.6.*, 7.7.7.7 # spam\n"),
+            ["1.2.3.4", "5.6.*", "7.7.7.7"]
+        );
+        let path = std::env::temp_dir().join(format!("direlera-bans-{}.txt", std::process::id()));
+        fs::write(&path, "1.2.3.4\n").unwrap();
+        let config: HashMap<String, String> =
+            [("bans", "9.9.9.9"), ("bans_file", path.to_str().unwrap())]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        let mut files = ListFiles::from_config(&config);
+        assert_eq!(files.changed(), [("bans", "9.9.9.9,1.2.3.4".to_string())]);
+        assert!(files.changed().is_empty());
+
+        // a missing file keeps the last list until it is back
+        fs::remove_file(&path).unwrap();
+        assert!(files.changed().is_empty());
+        fs::write(&path, "5.5.5.5\n").unwrap();
+        assert_eq!(files.changed(), [("bans", "9.9.9.9,5.5.5.5".to_string())]);
+        let config = HashMap::from([("motd_file".to_string(), path.to_str().unwrap().to_string())]);
+        let mut files = ListFiles::from_config(&config);
+        assert_eq!(files.changed(), [("motd", "5.5.5.5\n".to_string())]);
+        fs::remove_file(&path).unwrap();
+        assert!(ListFiles::from_config(&HashMap::new()).is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+// how busy the server is, so players can pick a quieter one: datagrams a
+// second, games running and the share of the time the dispatcher spends
+// handling datagrams (it is one task, so one core). sampled on the keepalive
+// tick; /stats, the snapshot and status_export show it, and with
+// server_name_load the name server browsers get ends in "[load: low]".
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::settings;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadLevel {
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl LoadLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LoadLevel::Low => "low",
+            LoadLevel::Medium => "medium",
+            LoadLevel::High => "high",
+        }
+    }
+    fn from_u8(x: u8) -> LoadLevel {
+        match x {
+            2 => LoadLevel::High,
+            1 => LoadLevel::Medium,
+            _ => LoadLevel::Low,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Load {
+    pub packets_per_sec: u64,
+    pub active_games: usize,
+    pub cpu_percent: u32,
+    pub level: LoadLevel,
+}
+
+// what counts as a full server; past 80% of any of them the load is high
+#[derive(Debug, Clone, Copy)]
+pub struct LoadLimits {
+    pub packets_per_sec: u64,
+    pub games: usize,
+}
+
+impl LoadLimits {
+    pub fn from_config(config: &HashMap<String, String>) -> LoadLimits {
+        LoadLimits {
+            packets_per_sec: settings::get_num(config, "load_full_packets_per_sec", 20000).max(1),
+            games: settings::get_num(config, "load_full_games", 100).max(1),
+        }
+    }
+}
+
+impl Load {
+    // low under 40% of the limits and cpu, medium under 80%
+    pub fn level(&self, limits: LoadLimits) -> LoadLevel {
+        let percent = [
+            self.packets_per_sec * 100 / limits.packets_per_sec,
+            (self.active_games * 100 / limits.games) as u64,
+            self.cpu_percent as u64,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+        match percent {
+            0..=39 => LoadLevel::Low,
+            40..=79 => LoadLevel::Medium,
+            _ => LoadLevel::High,
+        }
+    }
+    // "low (120 packets/s, 3 games, 12% cpu)"
+    pub fn line(&self) -> String {
+        format!(
+            "{} ({} packets/s, {} games, {}% cpu)",
+            self.level.name(),
+            self.packets_per_sec,
+            self.active_games,
+            self.cpu_percent
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadMeter {
+    // since the last sample
+    datagrams: u64,
+    busy: Duration,
+    since: Instant,
+    pub current: Load,
+    // the level for the accept server's HELLO replies
+    pub shared: Arc<AtomicU8>,
+}
+
+impl Default for LoadMeter {
+    fn default() -> Self {
+        LoadMeter {
+            datagrams: 0,
+            busy: Duration::ZERO,
+            since: Instant::now(),
+            current: Load::default(),
+            shared: Arc::new(AtomicU8::new(0)),
+        }
+    }
+}
+
+impl LoadMeter {
+    // a datagram and the time it took to handle
+    pub fn note_datagram(&mut self, busy: Duration) {
+        self.datagrams += 1;
+        self.busy += busy;
+    }
+    pub fn sample(&mut self, now: Instant, active_games: usize, limits: LoadLimits) -> Load {
+        let elapsed = now.duration_since(self.since).max(Duration::from_millis(1));
+        let mut load = Load {
+            packets_per_sec: self.datagrams * 1000 / elapsed.as_millis() as u64,
+            active_games,
+            cpu_percent: (self.busy.as_micros() * 100 / elapsed.as_micros()).min(100) as u32,
+            level: LoadLevel::Low,
+        };
+        load.level = load.level(limits);
+        self.shared.store(load.level as u8, Ordering::Relaxed);
+        self.current = load;
+        self.datagrams = 0;
+        self.busy = Duration::ZERO;
+        self.since = now;
+        load
+    }
+}
+
+pub fn shared_level(shared: &AtomicU8) -> LoadLevel {
+    LoadLevel::from_u8(shared.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_load() {
+        let limits = LoadLimits::from_config(&HashMap::new());
+        let mut meter = LoadMeter::default();
+        let start = meter.since;
+        for _ in 0..3000 {
+            meter.note_datagram(Duration::from_micros(100));
+        }
+        // 300 datagrams a second, busy 3% of the time
+        let load = meter.sample(start + Duration::from_secs(10), 2, limits);
+        assert_eq!((load.packets_per_sec, load.cpu_percent), (300, 3));
+        assert_eq!(load.level, LoadLevel::Low);
+        assert_eq!(load.line(), "low (300 packets/s, 2 games, 3% cpu)");
+
+        let load = meter.sample(start + Duration::from_secs(20), 60, limits);
+        assert_eq!((load.packets_per_sec, load.level), (0, LoadLevel::Medium));
+        assert_eq!(shared_level(&meter.shared), LoadLevel::Medium);
+        for _ in 0..10 {
+            meter.note_datagram(Duration::from_secs(1));
+        }
+        let load = meter.sample(start + Duration::from_secs(30), 0, limits);
+        assert_eq!((load.cpu_percent, load.level), (100, LoadLevel::High));
+    }
+}
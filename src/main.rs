@@ -1,19 +1,82 @@
 use config::Config;
-use direlera_rs::accept_server::AcceptServer;
+use direlera_rs::accept_server::{self, AcceptServer};
+use direlera_rs::acl::Acl;
+use direlera_rs::bot_api;
+use direlera_rs::config_check;
+#[cfg(unix)]
+use direlera_rs::control;
+use direlera_rs::dissector;
+use direlera_rs::emulators::Emulators;
+use direlera_rs::emulinker;
+use direlera_rs::friends::Friends;
+use direlera_rs::game_names::GameNames;
+use direlera_rs::ids::IdState;
+use direlera_rs::io_worker::{IoWorker, LogFormat, QueuedLogger};
+use direlera_rs::pending::PendingSessions;
+use direlera_rs::persistent_rooms::PersistentRooms;
+use direlera_rs::port_bind::{self, BindPolicy};
+use direlera_rs::punishment::Punishments;
 use direlera_rs::room::*;
+use direlera_rs::schema;
+use direlera_rs::scripting::ScriptHooks;
+use direlera_rs::selftest;
 use direlera_rs::service_server::*;
+use direlera_rs::settings;
+use direlera_rs::simulate;
+use direlera_rs::stats::ServerStats;
+use direlera_rs::stress;
+use direlera_rs::templates::Templates;
+use direlera_rs::timeline::{self, Timeline};
 use log::{error, info, log_enabled, Level, LevelFilter};
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::io::Write;
-use tokio::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env::set_var("RUST_LOG", "info");
     env::set_var("RUST_BACKTRACE", "1");
+    let args: Vec<String> = env::args().collect();
+    if args.len() >= 2 && args[1] == "dissector" {
+        let port = args.get(2).and_then(|x| x.parse().ok()).unwrap_or(27999);
+        print!("{}", dissector::lua_dissector(port));
+        return Ok(());
+    }
+    if args.len() == 2 && args[1] == "protocol-doc" {
+        print!("{}", schema::markdown());
+        return Ok(());
+    }
+    if args.len() == 3 && args[1] == "--selftest" {
+        env_logger::init();
+        if let Err(e) = selftest::run_selftest(&args[2]).await {
+            error!("selftest failed: {:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.len() == 3 && args[1] == "simulate" {
+        print!("{}", simulate::run_script(&args[2])?);
+        return Ok(());
+    }
+    #[cfg(unix)]
+    if args.len() >= 4 && args[1] == "ctl" {
+        let command = args[3..].join(" ");
+        println!(
+            "{}",
+            control::send_command(Path::new(&args[2]), &command).await?
+        );
+        return Ok(());
+    }
+    if args.len() >= 3 && args[1] == "stress" {
+        env_logger::init();
+        let options = stress::StressOptions::parse(&args[3..])?;
+        print!("{}", stress::run_stress(&args[2], &options).await?);
+        return Ok(());
+    }
+    let check_only = args.len() == 2 && args[1] == "--check-config";
     let settings = Config::builder()
         // Add in `./Settings.toml`
         .add_source(config::File::with_name("./direlera"))
@@ -21,52 +84,155 @@ async fn main() -> Result<(), Box<dyn Error>> {
         // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
         .add_source(config::Environment::with_prefix("APP"))
         .build()
-        .unwrap();
+        .and_then(|x| x.try_deserialize::<HashMap<String, String>>());
 
     // Print out our settings (as a HashMap)
-    let config_obj = settings.try_deserialize::<HashMap<String, String>>()?;
-    println!("{:?}", config_obj);
-    env_logger::Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{}:{} {} [{}] - {}",
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.level(),
-                record.args()
-            )
-        })
-        .filter_level(LevelFilter::Info)
-        .init();
+    let mut config_obj = match settings {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("direlera.toml: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for note in settings::migrate_legacy(&mut config_obj) {
+        eprintln!("direlera.toml: {}", note);
+    }
+    let source = std::fs::read_to_string("direlera.toml").unwrap_or_default();
+    let errors = config_check::validate(&config_obj, &source);
+    for e in &errors {
+        match e.line {
+            Some(_) => eprintln!("direlera.toml:{}", e),
+            None => eprintln!("{}", e),
+        }
+    }
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+    if check_only {
+        println!("config ok");
+        return Ok(());
+    }
+    // settings imported from an EmuLinker-SF conf directory take precedence
+    let mut imported_from = None;
+    if let Some(dir) = config_obj.get("emulinker_conf_dir").cloned() {
+        let imported = emulinker::load_emulinker_dir(Path::new(&dir))?;
+        let mut keys: Vec<_> = imported.keys().cloned().collect();
+        keys.sort();
+        imported_from = Some((dir, keys));
+        config_obj.extend(imported);
+    }
+    let log_format = config_obj.get("log_format").map_or("text", |x| x.as_str());
+    let io = IoWorker::start(
+        settings::get_num(&config_obj, "log_queue", 4096),
+        LogFormat::from_name(log_format).unwrap_or(LogFormat::Text),
+        config_obj.get("log_file").map(PathBuf::from),
+    );
+    QueuedLogger::init(io.clone(), LevelFilter::Info)?;
+    if let Some((dir, keys)) = imported_from {
+        info!("imported from {}: {:?}", dir, keys);
+    }
+    info!("config: {:?}", settings::redacted(&config_obj));
     // env_logger::init();
     if log_enabled!(Level::Info) {
         let x = 3 * 4; // expensive computation
         info!("the answer was: {}", x);
     }
-    let main_port = config_obj.get("main_port").unwrap();
-    let socket = UdpSocket::bind(&format!("0.0.0.0:{}", main_port)).await?;
-    error!("Listening on: {}", socket.local_addr()?);
+    let policy = BindPolicy::from_config(&config_obj);
+    let bind = |port: u16| UdpSocket::bind(format!("0.0.0.0:{}", port));
+    let main_port = settings::get_num(&config_obj, "main_port", 27888u16);
+    let (socket, main_port) = port_bind::bind_port(main_port, policy, &[], bind).await?;
+    let sub_port = settings::get_num(&config_obj, "sub_port", 27999u16);
+    let (service_sock, sub_port) =
+        port_bind::bind_port(sub_port, policy, &[main_port], bind).await?;
+    config_obj.insert("main_port".to_string(), main_port.to_string());
+    config_obj.insert("sub_port".to_string(), sub_port.to_string());
+    error!(
+        "Listening on: {}, sub port {}",
+        socket.local_addr()?,
+        sub_port
+    );
 
+    let tcp_listener = if settings::get_bool(&config_obj, "tcp_fallback", false) {
+        Some(TcpListener::bind(&format!("0.0.0.0:{}", main_port)).await?)
+    } else {
+        None
+    };
+    let tcp_config = config_obj.clone();
+    let bot_listener = match config_obj.get("bot_api") {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let bot_api_key = config_obj.get("bot_api_key").cloned().unwrap_or_default();
+    #[cfg(unix)]
+    let control_listener = match config_obj.get("control_socket") {
+        Some(path) => Some(control::bind(Path::new(path))?),
+        None => None,
+    };
+
+    let (tx, rx) = mpsc::channel(32);
+    let bot_tx = tx.clone();
+    #[cfg(unix)]
+    let control_tx = tx.clone();
+    let stats = ServerStats::new();
     let server = AcceptServer {
         socket,
         buf: vec![0; 1024],
         to_send: None,
         config_obj: config_obj.clone(),
+        tx: tx.clone(),
+        load: stats.load.shared.clone(),
     };
+    let tcp_load = stats.load.shared.clone();
 
-    let session_manager = UserRoom::new();
-    let sub_port = config_obj.get("sub_port").unwrap();
-    let service_sock = UdpSocket::bind(&format!("0.0.0.0:{}", sub_port)).await?;
-    let (tx, rx) = mpsc::channel(32);
+    let mut session_manager = UserRoom::new();
+    let ids = match config_obj.get("id_state_file") {
+        Some(path) => IdState::load(Path::new(path))?,
+        None => IdState::default(),
+    };
+    session_manager.next_user_id = ids.user_id;
+    let acl = Acl::from_config(&config_obj)?;
+    let friends = Friends::load(config_obj.get("friends_file").map(Path::new))?;
+    let templates = Templates::load(config_obj.get("templates_file").map(Path::new))?;
+    let persistent_rooms =
+        PersistentRooms::load(config_obj.get("persistent_rooms_file").map(Path::new))?;
+    let game_names = GameNames::load(config_obj.get("game_names_file").map(Path::new))?;
+    let emulators = Emulators::load(config_obj.get("emulators_file").map(Path::new))?;
+    let scripts = ScriptHooks::load(config_obj.get("script_file").map(Path::new))?;
+    let pending = PendingSessions::new(settings::get_num(&config_obj, "max_pending_sessions", 256));
+    let timeline = Timeline::new(settings::get_num(
+        &config_obj,
+        "timeline_size",
+        timeline::DEFAULT_SIZE,
+    ));
     let mut service_server = ServiceServer {
         config: config_obj,
         socket: service_sock,
         buf: vec![0; 1024],
         to_send: None,
         session_manager,
-        game_id: 0,
+        game_id: ids.game_id,
+        acl,
+        stats,
+        punishments: Punishments::new(),
+        friends,
+        templates,
+        persistent_rooms,
+        redirect: None,
+        game_names,
+        emulators,
+        scripts,
+        peers: HashMap::new(),
+        encryption_pending: HashMap::new(),
+        pause_pending: HashMap::new(),
+        fast_input_pending: HashMap::new(),
+        challenge_pending: HashMap::new(),
+        probe: None,
+        pending,
+        replacing: HashMap::new(),
+        io,
+        status_exported: None,
+        state_saved: None,
+        timeline,
         rx,
         tx,
     };
@@ -76,6 +242,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     tokio::join!(
         server.run(),
+        async {
+            if let Some(listener) = tcp_listener {
+                if let Err(e) = accept_server::run_tcp(listener, tcp_config, tcp_load).await {
+                    error!("tcp fallback stopped: {}", e);
+                }
+            }
+        },
+        async {
+            if let Some(listener) = bot_listener {
+                if let Err(e) = bot_api::run(listener, bot_api_key, bot_tx).await {
+                    error!("bot api stopped: {}", e);
+                }
+            }
+        },
+        async {
+            #[cfg(unix)]
+            if let Some(listener) = control_listener {
+                if let Err(e) = control::run(listener, control_tx).await {
+                    error!("control socket stopped: {}", e);
+                }
+            }
+        },
         service_server.run(), /*service_server.keepalive_timer() */
     );
 
@@ -0,0 +1,75 @@
+// the lobby board: motd (or motd_file, reloaded when it changes) holds pages of
+// rules, schedules and links, separated by a line of "---". users read them
+// with /motd [page].
+
+// separates two pages
+const PAGE_BREAK: &str = "---";
+
+// the pages of text, without empty ones
+pub fn pages(text: &str) -> Vec<Vec<&str>> {
+    let mut pages = vec![Vec::new()];
+    for line in text.lines() {
+        if line.trim() == PAGE_BREAK {
+            pages.push(Vec::new());
+        } else if let Some(page) = pages.last_mut() {
+            page.push(line.trim_end());
+        }
+    }
+    for page in &mut pages {
+        while page.first() == Some(&"") {
+            page.remove(0);
+        }
+        while page.last() == Some(&"") {
+            page.pop();
+        }
+    }
+    pages.retain(|x| !x.is_empty());
+    pages
+}
+
+// what /motd page (from 1) shows
+pub fn page_lines(text: &str, page: usize) -> Vec<String> {
+    let pages = pages(text);
+    if pages.is_empty() {
+        return vec!["There is no message of the day.".to_string()];
+    }
+    let page = match page {
+        n if (1..=pages.len()).contains(&n) => n,
+        _ => return vec![format!("/motd 1 to {}", pages.len())],
+    };
+    let mut lines = vec![format!("== page {}/{} ==", page, pages.len())];
+    lines.extend(pages[page - 1].iter().map(|x| x.to_string()));
+    if page < pages.len() {
+        lines.push(format!("/motd {} for the next page", page + 1));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motd_pages() {
+        let text = "rules:\nbe nice\n---\n\nschedule:\nkof98 on friday\n---\n---\n";
+        assert_eq!(
+            pages(text),
+            [
+                vec!["rules:", "be nice"],
+                vec!["schedule:", "kof98 on friday"]
+            ]
+        );
+        assert_eq!(
+            page_lines(text, 1),
+            [
+                "== page 1/2 ==",
+                "rules:",
+                "be nice",
+                "/motd 2 for the next page"
+            ]
+        );
+        assert_eq!(page_lines(text, 2).len(), 3);
+        assert_eq!(page_lines(text, 3), ["/motd 1 to 2"]);
+        assert_eq!(page_lines("", 1), ["There is no message of the day."]);
+    }
+}
@@ -0,0 +1,95 @@
+// how evenly a player's input arrives. every GAME_DATA/GAME_CACHE carries
+// connection type frames, so frames over time is the emulator's frame rate;
+// a client running fast or slow stalls everyone else even on a clean network.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// inputs the estimate is made from
+const WINDOW: usize = 240;
+
+#[derive(Debug, Default)]
+pub struct FramePacing {
+    // arrival time and frames of recent inputs, oldest first
+    arrivals: VecDeque<(Instant, u32)>,
+    // warned about this game already
+    pub warned: bool,
+    // frames received this game
+    pub frames: u64,
+}
+
+impl FramePacing {
+    pub fn reset(&mut self) {
+        self.arrivals.clear();
+        self.warned = false;
+        self.frames = 0;
+    }
+    pub fn note(&mut self, now: Instant, frames: u32) {
+        if self.arrivals.len() >= WINDOW {
+            self.arrivals.pop_front();
+        }
+        self.arrivals.push_back((now, frames));
+        self.frames += frames as u64;
+    }
+    // a full window has been seen
+    pub fn settled(&self) -> bool {
+        self.arrivals.len() >= WINDOW
+    }
+    // frames per second over the window; the first input only marks the start
+    pub fn fps(&self) -> Option<f64> {
+        let (first, _) = self.arrivals.front()?;
+        let (last, _) = self.arrivals.back()?;
+        let span = last.duration_since(*first).as_secs_f64();
+        if self.arrivals.len() < 2 || span <= 0.0 {
+            return None;
+        }
+        let frames: u32 = self.arrivals.iter().skip(1).map(|(_, f)| f).sum();
+        Some(frames as f64 / span)
+    }
+    // mean absolute deviation of the gaps between inputs, in ms
+    pub fn jitter_ms(&self) -> Option<f64> {
+        let gaps: Vec<Duration> = self
+            .arrivals
+            .iter()
+            .zip(self.arrivals.iter().skip(1))
+            .map(|((a, _), (b, _))| b.duration_since(*a))
+            .collect();
+        if gaps.is_empty() {
+            return None;
+        }
+        let mean = gaps.iter().map(|x| x.as_secs_f64()).sum::<f64>() / gaps.len() as f64;
+        let deviation = gaps
+            .iter()
+            .map(|x| (x.as_secs_f64() - mean).abs())
+            .sum::<f64>()
+            / gaps.len() as f64;
+        Some(deviation * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_frame_rate() {
+        let start = Instant::now();
+        let mut pacing = FramePacing::default();
+        assert_eq!(pacing.fps(), None);
+        // 3 frames per input every 50ms: 60 fps, perfectly even
+        for i in 0..WINDOW as u64 {
+            pacing.note(start + Duration::from_millis(50 * i), 3);
+        }
+        assert!(pacing.settled());
+        assert_eq!(pacing.frames, 3 * WINDOW as u64);
+        assert!((pacing.fps().unwrap() - 60.0).abs() < 0.01);
+        assert!(pacing.jitter_ms().unwrap() < 0.01);
+
+        pacing.reset();
+        for t in [0u64, 10, 30, 40, 60] {
+            pacing.note(start + Duration::from_millis(t), 1);
+        }
+        // gaps 10, 20, 10, 20: mean 15, off by 5 each
+        assert!((pacing.jitter_ms().unwrap() - 5.0).abs() < 0.01);
+        assert!(!pacing.settled());
+    }
+}
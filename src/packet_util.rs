@@ -0,0 +1,58 @@
+// checked reads from what clients send. reading past the end of a short or
+// malformed body is an InvalidInput error the dispatcher logs with the sender,
+// not a panic of the service task that would take every session with it.
+use crate::room::KailleraError;
+
+fn short(buf: &[u8], pos: usize, len: usize) -> KailleraError {
+    KailleraError::InvalidInput {
+        message: format!("{} bytes wanted, the body has {}", len, buf.len()),
+        pos,
+    }
+}
+
+pub fn try_get_bytes(buf: &[u8], pos: usize, len: usize) -> Result<&[u8], KailleraError> {
+    pos.checked_add(len)
+        .and_then(|end| buf.get(pos..end))
+        .ok_or_else(|| short(buf, pos, len))
+}
+
+// everything from pos on, empty at the end of the body
+pub fn try_get_rest(buf: &[u8], pos: usize) -> Result<&[u8], KailleraError> {
+    buf.get(pos..).ok_or_else(|| short(buf, pos, 0))
+}
+
+pub fn try_get_u8(buf: &[u8], pos: usize) -> Result<u8, KailleraError> {
+    Ok(try_get_bytes(buf, pos, 1)?[0])
+}
+
+pub fn try_get_u16_le(buf: &[u8], pos: usize) -> Result<u16, KailleraError> {
+    let b = try_get_bytes(buf, pos, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn try_get_u32_le(buf: &[u8], pos: usize) -> Result<u32, KailleraError> {
+    let b = try_get_bytes(buf, pos, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_reads() {
+        let buf = [0u8, 0x34, 0x12, 0x78, 0x56];
+        assert_eq!(try_get_u8(&buf, 0).unwrap(), 0);
+        assert_eq!(try_get_u16_le(&buf, 1).unwrap(), 0x1234);
+        assert_eq!(try_get_u32_le(&buf, 1).unwrap(), 0x56781234);
+        assert_eq!(try_get_rest(&buf, 3).unwrap(), &[0x78, 0x56]);
+        assert!(try_get_rest(&buf, 5).unwrap().is_empty());
+        assert!(try_get_rest(&buf, 6).is_err());
+        assert!(try_get_u32_le(&buf, 2).is_err());
+        assert!(try_get_bytes(&buf, usize::MAX, 2).is_err());
+        match try_get_u16_le(&buf, 4) {
+            Err(KailleraError::InvalidInput { pos, .. }) => assert_eq!(pos, 4),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
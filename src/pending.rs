@@ -0,0 +1,65 @@
+// addresses that started a login but have not finished the ack exchange yet.
+// port scanners and half open clients end up here; the oldest one is evicted
+// once the limit is hit so they cannot grow the session map without bound.
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub struct PendingSessions {
+    // least recently seen first
+    order: VecDeque<SocketAddr>,
+    max: usize,
+}
+
+impl PendingSessions {
+    pub fn new(max: usize) -> PendingSessions {
+        PendingSessions {
+            order: VecDeque::new(),
+            max: max.max(1),
+        }
+    }
+    // track addr as most recently seen; returns the address evicted to make room
+    pub fn touch(&mut self, addr: SocketAddr) -> Option<SocketAddr> {
+        if let Some(i) = self.order.iter().position(|x| *x == addr) {
+            self.order.remove(i);
+            self.order.push_back(addr);
+            return None;
+        }
+        self.order.push_back(addr);
+        if self.order.len() > self.max {
+            return self.order.pop_front();
+        }
+        None
+    }
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.order.contains(addr)
+    }
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.order.retain(|x| x != addr);
+    }
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let addr = |i: u8| SocketAddr::from(([10, 0, 0, i], 27999));
+        let mut pending = PendingSessions::new(2);
+        assert_eq!(pending.touch(addr(1)), None);
+        assert_eq!(pending.touch(addr(2)), None);
+        // 1 is seen again, so 2 is now the oldest
+        assert_eq!(pending.touch(addr(1)), None);
+        assert_eq!(pending.touch(addr(3)), Some(addr(2)));
+        assert!(pending.contains(&addr(1)) && pending.contains(&addr(3)));
+        pending.remove(&addr(1));
+        assert_eq!(pending.len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+// standing rooms an admin made with /persist: owned by the server, open when
+// empty and opened again at startup from persistent_rooms_file (json). the
+// game name is the rom name, which is what emulators look the game up by.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::room::Room;
+use crate::templates::RoomTemplate;
+
+// owner name of persistent rooms
+pub const SERVER_OWNER: &str = "SERVER";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistentRoom {
+    pub emul_name: String,
+    // room settings, including the game name
+    pub settings: RoomTemplate,
+}
+
+impl PersistentRoom {
+    pub fn from_room(room: &Room) -> PersistentRoom {
+        PersistentRoom {
+            emul_name: room.emul_name.clone(),
+            settings: RoomTemplate::from_room(room),
+        }
+    }
+    // an empty room, the game id is left to the caller
+    pub fn open(&self) -> Room {
+        let mut room = Room::new();
+        room.game_name = self.settings.game_name.clone();
+        room.emul_name = self.emul_name.clone();
+        room.creator_id = SERVER_OWNER.to_string();
+        room.persistent = true;
+        self.settings.apply(&mut room);
+        room
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PersistentRooms {
+    pub path: Option<PathBuf>,
+    pub rooms: Vec<PersistentRoom>,
+}
+
+impl PersistentRooms {
+    // without a path the rooms are gone after a restart
+    pub fn load(path: Option<&Path>) -> anyhow::Result<PersistentRooms> {
+        let mut rooms = PersistentRooms {
+            path: path.map(|x| x.to_path_buf()),
+            rooms: Vec::new(),
+        };
+        match path.map(fs::read_to_string) {
+            Some(Ok(text)) => rooms.rooms = serde_json::from_str(&text)?,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(rooms)
+    }
+    pub fn to_text(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&self.rooms)?)
+    }
+    // replaces a room with the same game name
+    pub fn add(&mut self, room: PersistentRoom) {
+        self.remove(&room.settings.game_name);
+        self.rooms.push(room);
+    }
+    pub fn remove(&mut self, game_name: &str) -> bool {
+        let len = self.rooms.len();
+        self.rooms.retain(|x| x.settings.game_name != game_name);
+        self.rooms.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_reopen() {
+        let mut room = Room::new();
+        room.game_name = "KOF98 Ranked".to_string();
+        room.emul_name = "MAME32k".to_string();
+        room.max_players = 2;
+        room.max_ping = 150;
+        let mut rooms = PersistentRooms::default();
+        rooms.add(PersistentRoom::from_room(&room));
+        rooms.add(PersistentRoom::from_room(&room));
+        assert_eq!(rooms.rooms.len(), 1);
+
+        let path = std::env::temp_dir().join(format!("direlera-persistent-{}", std::process::id()));
+        fs::write(&path, rooms.to_text().unwrap()).unwrap();
+        let mut loaded = PersistentRooms::load(Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let reopened = loaded.rooms[0].open();
+        assert!(reopened.persistent);
+        assert_eq!(reopened.creator_id, SERVER_OWNER);
+        assert_eq!(reopened.emul_name, "MAME32k");
+        assert_eq!((reopened.max_players, reopened.max_ping), (2, 150));
+        assert!(loaded.remove("KOF98 Ranked"));
+        assert!(!loaded.remove("KOF98 Ranked"));
+    }
+}
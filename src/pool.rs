@@ -0,0 +1,56 @@
+// reusable byte buffers for the game data path. every frame of every player
+// needs a message body and a datagram; recycling them keeps the allocator out
+// of the per-frame work once a game is running.
+#[derive(Debug, Default)]
+pub struct BufPool {
+    free: Vec<Vec<u8>>,
+    // buffers beyond this are dropped instead of kept
+    max: usize,
+}
+
+impl BufPool {
+    pub fn new(max: usize) -> BufPool {
+        BufPool {
+            free: Vec::with_capacity(max),
+            max,
+        }
+    }
+    // an empty buffer, with the capacity of a previous one when there is one
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
+    }
+    pub fn give(&mut self, mut buf: Vec<u8>) {
+        if self.free.len() < self.max && buf.capacity() > 0 {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_reuses_given_buffers() {
+        let mut pool = BufPool::new(2);
+        assert_eq!(pool.take().capacity(), 0);
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(b"frame");
+        let ptr = buf.as_ptr();
+        pool.give(buf);
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        for _ in 0..3 {
+            pool.give(vec![0u8; 8]);
+        }
+        assert_eq!(pool.len(), 2);
+    }
+}
@@ -0,0 +1,114 @@
+// binding the main and sub ports on shared hosts, where a configured port may
+// be taken. port_fallback tries that many following ports, bind_retries waits
+// and tries the whole range again (bind_retry_ms, doubling each round up to a
+// minute). the ports actually bound replace main_port and sub_port in the
+// config, so HELLO replies name the right sub port.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use log::info;
+
+use crate::settings;
+
+// the doubling retry delay stops growing here
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct BindPolicy {
+    pub fallback: u16,
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl BindPolicy {
+    pub fn from_config(config: &HashMap<String, String>) -> BindPolicy {
+        BindPolicy {
+            fallback: settings::get_num(config, "port_fallback", 0),
+            retries: settings::get_num(config, "bind_retries", 0),
+            retry_delay: Duration::from_millis(settings::get_num(config, "bind_retry_ms", 1000)),
+        }
+    }
+    // port and the ones after it, skipping ports already taken by this server
+    fn candidates(&self, port: u16, skip: &[u16]) -> Vec<u16> {
+        (0..=self.fallback)
+            .filter_map(|i| port.checked_add(i))
+            .filter(|x| !skip.contains(x))
+            .collect()
+    }
+}
+
+// the first port bind succeeds on, with what it returned. the error is the
+// one of the configured port, which is the one worth reporting.
+pub async fn bind_port<T, F, Fut>(
+    port: u16,
+    policy: BindPolicy,
+    skip: &[u16],
+    bind: F,
+) -> io::Result<(T, u16)>
+where
+    F: Fn(u16) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = policy.retry_delay;
+    let mut first_error = None;
+    for round in 0..=policy.retries {
+        if round > 0 {
+            info!("port {} busy, retrying in {:?}", port, delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_DELAY);
+        }
+        for candidate in policy.candidates(port, skip) {
+            match bind(candidate).await {
+                Ok(x) => {
+                    if candidate != port {
+                        info!("port {} busy, bound {} instead", port, candidate);
+                    }
+                    return Ok((x, candidate));
+                }
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+    }
+    Err(first_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("no port to try for {}", port),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn falls_back_to_next_port() {
+        let policy = BindPolicy {
+            fallback: 3,
+            retries: 1,
+            retry_delay: Duration::from_millis(1),
+        };
+        let busy = [27888, 27889];
+        let bind = |port: u16| async move {
+            if busy.contains(&port) {
+                Err(io::Error::new(io::ErrorKind::AddrInUse, "busy"))
+            } else {
+                Ok(port)
+            }
+        };
+        let (_, port) = bind_port(27888, policy, &[27890], bind).await.unwrap();
+        assert_eq!(port, 27891);
+        let strict = BindPolicy {
+            fallback: 0,
+            ..policy
+        };
+        let e = bind_port(27888, strict, &[], bind).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    }
+}
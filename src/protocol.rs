@@ -1,7 +1,17 @@
-use std::collections::HashMap;
+// message types, the datagram framing and the builders of server to client
+// bodies. every builder has FIELDS, its layout in schema.rs, and parse, which
+// reads back a body packetize produced and is None when the body does not fit
+// FIELDS, so tests and tools check what the server sends against the layout.
+use std::collections::{HashMap, VecDeque};
 
 use serde::{Deserialize, Serialize};
 
+use crate::ids::*;
+use crate::packet_util::try_get_bytes;
+use crate::room::KailleraError;
+use crate::schema::FieldType::*;
+use crate::schema::{f, parse_fields, parse_prefix, Field};
+
 type MessageT = u8;
 use log::info;
 pub const USER_QUIT: MessageT = 1;
@@ -27,19 +37,46 @@ pub const DROP_GAME: MessageT = 0x14;
 pub const READY_TO_PLAY_SIGNAL: MessageT = 0x15;
 pub const CONNECTION_REJECT: MessageT = 0x16;
 pub const SERVER_INFO: MessageT = 0x17;
-// GameStatusWaiting = 0,
-// GameStatusPlaying = 1,
-// GameStatusNetSync = 2,
+// direlera extension: one player's input forwarded as soon as it arrives, tagged with its frame.
+// only clients that add FAST_INPUT_TAG to their HELLO get it, stock clients do not know it
+pub const FAST_INPUT: MessageT = 0x20;
+pub const FAST_INPUT_TAG: &[u8] = b"FASTINPUT";
+// direlera extension: the owner paused or resumed the game. clients ask for it
+// with PAUSE_TAG after their HELLO, like the encryption tag
+pub const GAME_PAUSE: MessageT = 0x21;
+pub const PAUSE_TAG: &[u8] = b"PAUSE";
+// direlera extension: a client that adds KEEPALIVE_TAG to its HELLO gets
+// "KEEPALIVE=<seconds>" back, how often the server wants a KEEPALIVE from it
+pub const KEEPALIVE_TAG: &[u8] = b"KEEPALIVE";
+// direlera extension: INFO_TAG after the HELLO asks for the server name,
+// description and max users, see server_info
+pub const INFO_TAG: &[u8] = b"INFO";
+// direlera extension: a client that adds CHALLENGE_TAG to its HELLO gets random
+// values in the S2C_ACKs of its login and must echo them in its C2S_ACKs, so a
+// login from a spoofed address cannot finish (login_challenge)
+pub const CHALLENGE_TAG: &[u8] = b"CHALLENGE";
 
-// PlayerStatusPlaying = 0,
-// PlayerStatusIdle = 1,
-// ProtocolPacketsSize = 1,
-// ProtocolBodySize = 5,
+// extension tags follow the HELLO, each NUL terminated
+pub fn hello_tags(hello: &[u8]) -> impl Iterator<Item = &[u8]> {
+    hello.split(|x| *x == 0).skip(1)
+}
 
+pub fn hello_has_tag(hello: &[u8], tag: &[u8]) -> bool {
+    hello_tags(hello).any(|x| x == tag)
+}
 pub type GameStatus = u8;
 pub const GAME_STATUS_WAITING: GameStatus = 0;
 pub const GAME_STATUS_PLAYING: GameStatus = 1;
 pub const GAME_STATUS_NET_SYNC: GameStatus = 2;
+
+pub fn game_status_name(status: GameStatus) -> &'static str {
+    match status {
+        GAME_STATUS_WAITING => "waiting",
+        GAME_STATUS_PLAYING => "playing",
+        GAME_STATUS_NET_SYNC => "netsync",
+        _ => "unknown",
+    }
+}
 // #[repr(C, packed)]
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -71,6 +108,19 @@ impl AckProtocol {
             dummy4: 3,
         }
     }
+    // an ack the client has to echo, see CHALLENGE_TAG
+    pub fn challenge(nonce: [u32; 4]) -> AckProtocol {
+        AckProtocol {
+            dummy0: 0,
+            dummy1: nonce[0],
+            dummy2: nonce[1],
+            dummy3: nonce[2],
+            dummy4: nonce[3],
+        }
+    }
+    pub fn nonce(&self) -> [u32; 4] {
+        [self.dummy1, self.dummy2, self.dummy3, self.dummy4]
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -103,7 +153,12 @@ impl Protocol {
     }
     pub fn make_packet(&self) -> anyhow::Result<Vec<u8>> {
         // }, Box<dyn Error>> {
-        let mut v = Vec::new();
+        let mut v = Vec::with_capacity(5 + self.data.len());
+        self.write_packet(&mut v)?;
+        Ok(v)
+    }
+    // appends header and body to out, for building a datagram in place
+    pub fn write_packet(&self, out: &mut Vec<u8>) -> anyhow::Result<()> {
         let prob = ProtocolSeqHeader {
             seq: self.header.seq,
             header: ProtocolPureHeader {
@@ -111,10 +166,9 @@ impl Protocol {
                 message_type: self.header.header.message_type,
             },
         };
-        let mut s = bincode::serialize::<ProtocolSeqHeader>(&prob)?;
-        v.append(&mut s);
-        v.append(&mut self.data.clone());
-        Ok(v)
+        bincode::serialize_into(&mut *out, &prob)?;
+        out.extend_from_slice(&self.data);
+        Ok(())
     }
 }
 
@@ -125,9 +179,16 @@ pub fn get_protocol_from_bytes(data: &Vec<u8>) -> anyhow::Result<Vec<Protocol>>
     while cur_pos + 5 <= data.len() {
         // info!("{} <= {}", cur_pos + 5, data.len());
         // info!("protocol body: {:?}", &data[cur_pos..cur_pos + 5]);
-        let protocol = bincode::deserialize::<ProtocolSeqHeader>(&data[cur_pos..cur_pos + 5])?;
-        let d = &data[cur_pos + 5..cur_pos + 5 + protocol.header.length as usize - 1];
-        cur_pos += (5 + protocol.header.length - 1) as usize;
+        let protocol = bincode::deserialize::<ProtocolSeqHeader>(try_get_bytes(data, cur_pos, 5)?)?;
+        // the length counts the message type byte, which is in the header
+        let length = (protocol.header.length as usize).checked_sub(1).ok_or(
+            KailleraError::InvalidInput {
+                message: "message length 0".to_string(),
+                pos: cur_pos + 2,
+            },
+        )?;
+        let d = try_get_bytes(data, cur_pos + 5, length)?;
+        cur_pos += 5 + length;
         v.push(Protocol {
             header: protocol,
             data: d.to_vec(),
@@ -136,18 +197,134 @@ pub fn get_protocol_from_bytes(data: &Vec<u8>) -> anyhow::Result<Vec<Protocol>>
     Ok(v)
 }
 
-// Sequence to Protocol Store
+// how many recent sequence numbers are remembered for duplicate suppression
+const SEEN_WINDOW: usize = 64;
+
+// datagram loss of one session, estimated from the newest seq of each datagram:
+// a jump past the previous newest means the datagrams in between never arrived.
+#[derive(Debug, Default, Clone)]
+pub struct LinkStats {
+    pub datagrams: u64,
+    pub lost: u64,
+    // duplicated or reordered datagrams, not newer than the previous one
+    pub stale: u64,
+    newest: Option<u16>,
+    // since the last advice check
+    pub window_datagrams: u64,
+    pub window_lost: u64,
+    pub advised: bool,
+}
+
+impl LinkStats {
+    pub fn note_datagram(&mut self, newest: u16) {
+        self.datagrams += 1;
+        self.window_datagrams += 1;
+        let prev = match self.newest {
+            Some(prev) => prev,
+            None => {
+                self.newest = Some(newest);
+                return;
+            }
+        };
+        let step = newest.wrapping_sub(prev);
+        if step == 0 || step > 0x8000 {
+            self.stale += 1;
+            return;
+        }
+        self.lost += (step - 1) as u64;
+        self.window_lost += (step - 1) as u64;
+        self.newest = Some(newest);
+    }
+    fn percent(lost: u64, received: u64) -> u64 {
+        match lost + received {
+            0 => 0,
+            total => lost * 100 / total,
+        }
+    }
+    pub fn loss_percent(&self) -> u64 {
+        Self::percent(self.lost, self.datagrams)
+    }
+    // loss percent of the window once it has `window` datagrams, starting a new one
+    pub fn take_window(&mut self, window: u64) -> Option<u64> {
+        if self.window_datagrams < window {
+            return None;
+        }
+        let loss = Self::percent(self.window_lost, self.window_datagrams);
+        self.window_datagrams = 0;
+        self.window_lost = 0;
+        Some(loss)
+    }
+    // after a resync the next seq is not comparable to the old one
+    pub fn forget_newest(&mut self) {
+        self.newest = None;
+    }
+}
+
+// Sequence to Protocol Store, one per session
 pub struct ProtocolPackets {
+    // next seq the session expects from the client
+    pub wanted_seq: u16,
     matched_seq: Option<u16>,
     pub packets: HashMap<u16, Protocol>,
+    seen: VecDeque<u16>,
+    // consecutive datagrams that did not contain the wanted seq
+    pub misses: u32,
+    // take the lowest seq of the next datagram as the wanted seq
+    pub resync_pending: bool,
+    pub link: LinkStats,
 }
 
 impl ProtocolPackets {
     pub fn new() -> ProtocolPackets {
         ProtocolPackets {
+            wanted_seq: 0,
             packets: HashMap::new(),
             matched_seq: None,
+            seen: VecDeque::with_capacity(SEEN_WINDOW),
+            misses: 0,
+            resync_pending: false,
+            link: LinkStats::default(),
+        }
+    }
+    // expect seq next, forgetting everything about the old sequence.
+    pub fn resync_to(&mut self, seq: u16) {
+        self.reset();
+        self.wanted_seq = seq;
+    }
+    // forget everything about the old sequence so the next datagram is accepted as is.
+    pub fn reset(&mut self) {
+        self.matched_seq = None;
+        self.packets.clear();
+        self.seen.clear();
+        self.misses = 0;
+        self.resync_pending = false;
+        self.link.forget_newest();
+    }
+    pub fn state(&self) -> String {
+        let mut pending: Vec<_> = self.packets.keys().collect();
+        pending.sort();
+        format!(
+            "wanted: {}, matched: {:?}, pending: {:?}, misses: {}, datagrams: {}, lost: {} ({}%), stale: {}",
+            self.wanted_seq,
+            self.matched_seq,
+            pending,
+            self.misses,
+            self.link.datagrams,
+            self.link.lost,
+            self.link.loss_percent(),
+            self.link.stale
+        )
+    }
+    // remember seq, returns false when it was already seen recently (retransmission).
+    pub fn mark_seen(&mut self, seq: u16) -> bool {
+        if self.seen.contains(&seq) {
+            return false;
         }
+        if self.seen.len() == SEEN_WINDOW {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(seq);
+        true
     }
     pub fn add(&mut self, protocol: Protocol) {
         // don't add old packet
@@ -169,6 +346,14 @@ impl ProtocolPackets {
         }
         t
     }
+    // fetch the wanted seq and advance to the next one
+    pub fn fetch_next(&mut self) -> Option<Protocol> {
+        let t = self.fetch_protocol(self.wanted_seq);
+        if t.is_some() {
+            self.wanted_seq = self.wanted_seq.wrapping_add(1);
+        }
+        t
+    }
     pub fn len(&self) -> usize {
         self.packets.len()
     }
@@ -180,18 +365,24 @@ impl ProtocolPackets {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct UserJoinPacket2Client {
     pub user_name: Vec<u8>,
-    pub user_id: u16,
+    pub user_id: UserId,
     pub ping: u32,
     pub connection_type: u8,
 }
 
 impl UserJoinPacket2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("user_name", Str),
+        f("user_id", U16),
+        f("ping", U32),
+        f("connection_type", U8),
+    ];
     pub fn new(
         user_name: Vec<u8>,
-        user_id: u16,
+        user_id: UserId,
         ping: u32,
         connection_type: u8,
     ) -> UserJoinPacket2Client {
@@ -202,6 +393,15 @@ impl UserJoinPacket2Client {
             connection_type,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<UserJoinPacket2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(UserJoinPacket2Client {
+            user_name: v[0].bytes(),
+            user_id: UserId(v[1].num() as u16),
+            ping: v[2].num(),
+            connection_type: v[3].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -213,21 +413,31 @@ impl UserJoinPacket2Client {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct UserQuitPacket2Client {
     pub user_name: Vec<u8>,
-    pub user_id: u16,
+    pub user_id: UserId,
     pub message: Vec<u8>,
 }
 
 impl UserQuitPacket2Client {
-    pub fn new(user_name: Vec<u8>, user_id: u16, message: Vec<u8>) -> UserQuitPacket2Client {
+    pub const FIELDS: &'static [Field] =
+        &[f("user_name", Str), f("user_id", U16), f("message", Str)];
+    pub fn new(user_name: Vec<u8>, user_id: UserId, message: Vec<u8>) -> UserQuitPacket2Client {
         UserQuitPacket2Client {
             user_name,
             user_id,
             message,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<UserQuitPacket2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(UserQuitPacket2Client {
+            user_name: v[0].bytes(),
+            user_id: UserId(v[1].num() as u16),
+            message: v[2].bytes(),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -239,6 +449,16 @@ impl UserQuitPacket2Client {
     }
 }
 
+// the message of a client's USER_QUIT (unused name, unused id, message), without its NUL
+pub fn quit_reason(data: &[u8]) -> Vec<u8> {
+    let rest = match data.iter().position(|x| *x == 0) {
+        Some(end) => data.get(end + 3..).unwrap_or(&[]),
+        None => &[],
+    };
+    rest.split(|x| *x == 0).next().unwrap_or(&[]).to_vec()
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct AckPacket2Client {
     pub n: u8,
     pub p0: u32,
@@ -248,9 +468,26 @@ pub struct AckPacket2Client {
 }
 
 impl AckPacket2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("p0", U32),
+        f("p1", U32),
+        f("p2", U32),
+        f("p3", U32),
+    ];
     pub fn new(n: u8, p0: u32, p1: u32, p2: u32, p3: u32) -> AckPacket2Client {
         AckPacket2Client { n, p0, p1, p2, p3 }
     }
+    pub fn parse(data: &[u8]) -> Option<AckPacket2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(AckPacket2Client {
+            n: v[0].num() as u8,
+            p0: v[1].num(),
+            p1: v[2].num(),
+            p2: v[3].num(),
+            p3: v[4].num(),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.n)?);
@@ -262,15 +499,81 @@ impl AckPacket2Client {
     }
 }
 
+// longest chat text sent in one message; clients cut anything past it
+pub const MAX_CHAT_TEXT: usize = 120;
+
+// chat text in pieces of at most max bytes, stopping at the first NUL. a piece
+// ends after the last space that fits when there is one, and never inside an
+// EUC-KR double byte character.
+pub fn split_chat(text: &[u8], max: usize) -> Vec<Vec<u8>> {
+    let text = text.split(|x| *x == 0).next().unwrap_or(&[]);
+    let max = max.max(2);
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while text.len() - start > max {
+        let mut end = start;
+        let mut space = None;
+        while end < text.len() {
+            let width = if text[end] >= 0x81 && end + 1 < text.len() {
+                2
+            } else {
+                1
+            };
+            if end + width - start > max {
+                break;
+            }
+            if text[end] == b' ' || text[end] == b'\n' {
+                space = Some(end + 1);
+            }
+            end += width;
+        }
+        let end = space.unwrap_or(end);
+        pieces.push(text[start..end].to_vec());
+        start = end;
+    }
+    if start < text.len() || pieces.is_empty() {
+        pieces.push(text[start..].to_vec());
+    }
+    pieces
+}
+
+// name\0text\0 bodies for GLOBAL_CHAT, GAME_CHAT and SERVER_INFO, one per piece of text
+pub fn chat_bodies(user_name: &[u8], text: &[u8]) -> Vec<Vec<u8>> {
+    split_chat(text, MAX_CHAT_TEXT)
+        .into_iter()
+        .map(|piece| {
+            let mut v = Vec::with_capacity(user_name.len() + piece.len() + 2);
+            v.extend_from_slice(user_name);
+            v.push(0u8);
+            v.extend(piece);
+            v.push(0u8);
+            v
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct GlobalChat2Client {
     pub user_name: Vec<u8>,
     pub message: Vec<u8>,
 }
 
 impl GlobalChat2Client {
+    pub const FIELDS: &'static [Field] = &[f("user_name", Str), f("message", Str)];
     pub fn new(user_name: Vec<u8>, message: Vec<u8>) -> GlobalChat2Client {
         GlobalChat2Client { user_name, message }
     }
+    // long messages split, see split_chat
+    pub fn packetize_split(&self) -> Vec<Vec<u8>> {
+        chat_bodies(&self.user_name, &self.message)
+    }
+    pub fn parse(data: &[u8]) -> Option<GlobalChat2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(GlobalChat2Client {
+            user_name: v[0].bytes(),
+            message: v[1].bytes(),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -281,15 +584,28 @@ impl GlobalChat2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct GameChat2Client {
     pub user_name: Vec<u8>,
     pub message: Vec<u8>,
 }
 
 impl GameChat2Client {
+    pub const FIELDS: &'static [Field] = &[f("user_name", Str), f("message", Str)];
     pub fn new(user_name: Vec<u8>, message: Vec<u8>) -> GameChat2Client {
         GameChat2Client { user_name, message }
     }
+    // long messages split, see split_chat
+    pub fn packetize_split(&self) -> Vec<Vec<u8>> {
+        chat_bodies(&self.user_name, &self.message)
+    }
+    pub fn parse(data: &[u8]) -> Option<GameChat2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(GameChat2Client {
+            user_name: v[0].bytes(),
+            message: v[1].bytes(),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -300,19 +616,26 @@ impl GameChat2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateGame2Client {
     pub user_name: Vec<u8>,
     pub game_name: Vec<u8>,
     pub emul_name: Vec<u8>,
-    pub game_id: u32,
+    pub game_id: GameId,
 }
 
 impl CreateGame2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("user_name", Str),
+        f("game_name", Str),
+        f("emulator", Str),
+        f("game_id", U32),
+    ];
     pub fn new(
         user_name: Vec<u8>,
         game_name: Vec<u8>,
         emul_name: Vec<u8>,
-        game_id: u32,
+        game_id: GameId,
     ) -> CreateGame2Client {
         CreateGame2Client {
             user_name,
@@ -321,6 +644,15 @@ impl CreateGame2Client {
             game_id,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<CreateGame2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(CreateGame2Client {
+            user_name: v[0].bytes(),
+            game_name: v[1].bytes(),
+            emul_name: v[2].bytes(),
+            game_id: GameId(v[3].num()),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -334,39 +666,57 @@ impl CreateGame2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct QuitGame2Client {
     pub user_name: Vec<u8>,
-    pub game_id: u16,
+    pub user_id: UserId,
 }
 
 impl QuitGame2Client {
-    pub fn new(user_name: Vec<u8>, game_id: u16) -> QuitGame2Client {
-        QuitGame2Client { user_name, game_id }
+    pub const FIELDS: &'static [Field] = &[f("user_name", Str), f("user_id", U16)];
+    pub fn new(user_name: Vec<u8>, user_id: UserId) -> QuitGame2Client {
+        QuitGame2Client { user_name, user_id }
+    }
+    pub fn parse(data: &[u8]) -> Option<QuitGame2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(QuitGame2Client {
+            user_name: v[0].bytes(),
+            user_id: UserId(v[1].num() as u16),
+        })
     }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
         v.push(0u8);
-        v.append(&mut bincode::serialize(&self.game_id)?);
+        v.append(&mut bincode::serialize(&self.user_id)?);
         Ok(v)
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct JoinGame2Client {
     pub n: u8,
-    pub game_id: u32,
+    pub game_id: GameId,
     pub user_name: Vec<u8>,
     pub ping: u32,
-    pub user_id: u16,
+    pub user_id: UserId,
     pub connection_type: u8,
 }
 
 impl JoinGame2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("game_id", U32),
+        f("user_name", Str),
+        f("ping", U32),
+        f("user_id", U16),
+        f("connection_type", U8),
+    ];
     pub fn new(
-        game_id: u32,
+        game_id: GameId,
         user_name: Vec<u8>,
         ping: u32,
-        user_id: u16,
+        user_id: UserId,
         connection_type: u8,
     ) -> JoinGame2Client {
         JoinGame2Client {
@@ -378,6 +728,17 @@ impl JoinGame2Client {
             connection_type,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<JoinGame2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(JoinGame2Client {
+            n: v[0].num() as u8,
+            game_id: GameId(v[1].num()),
+            user_name: v[2].bytes(),
+            ping: v[3].num(),
+            user_id: UserId(v[4].num() as u16),
+            connection_type: v[5].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.n)?);
@@ -391,17 +752,25 @@ impl JoinGame2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct UpdateGameStatus2Client {
     pub n: u8,
-    pub game_id: u32,
+    pub game_id: GameId,
     pub game_status: u8, // 0: Waiting, 1:Playing, 2:Netsync
     pub num_of_players: u8,
     pub max_players: u8,
 }
 
 impl UpdateGameStatus2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("game_id", U32),
+        f("game_status", U8),
+        f("num_players", U8),
+        f("max_players", U8),
+    ];
     pub fn new(
-        game_id: u32,
+        game_id: GameId,
         game_status: u8,
         num_of_players: u8,
         max_players: u8,
@@ -414,6 +783,16 @@ impl UpdateGameStatus2Client {
             max_players,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<UpdateGameStatus2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(UpdateGameStatus2Client {
+            n: v[0].num() as u8,
+            game_id: GameId(v[1].num()),
+            game_status: v[2].num() as u8,
+            num_of_players: v[3].num() as u8,
+            max_players: v[4].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.n)?);
@@ -425,6 +804,7 @@ impl UpdateGameStatus2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct StartGame2Client {
     pub unused: u8,
     pub frame_delay: u16,
@@ -433,6 +813,12 @@ pub struct StartGame2Client {
 }
 
 impl StartGame2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("frame_delay", U16),
+        f("player_number", U8),
+        f("total_players", U8),
+    ];
     pub fn new(frame_delay: u16, player_num: u8, total_num: u8) -> StartGame2Client {
         StartGame2Client {
             unused: 0,
@@ -441,6 +827,15 @@ impl StartGame2Client {
             total_num,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<StartGame2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(StartGame2Client {
+            unused: v[0].num() as u8,
+            frame_delay: v[1].num() as u16,
+            player_num: v[2].num() as u8,
+            total_num: v[3].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.unused)?);
@@ -451,6 +846,7 @@ impl StartGame2Client {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct GameData2Client {
     pub unused: u8,
     pub len: u16,
@@ -458,6 +854,7 @@ pub struct GameData2Client {
 }
 
 impl GameData2Client {
+    pub const FIELDS: &'static [Field] = &[f("unused", U8), f("length", U16), f("data", Bytes)];
     pub fn new(len: u16, game_data: Vec<u8>) -> GameData2Client {
         GameData2Client {
             unused: 0,
@@ -465,47 +862,167 @@ impl GameData2Client {
             game_data,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<GameData2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        let game_data = v[2].bytes();
+        if v[1].num() as usize != game_data.len() {
+            return None;
+        }
+        Some(GameData2Client {
+            unused: v[0].num() as u8,
+            len: v[1].num() as u16,
+            game_data,
+        })
+    }
+    pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut v = Vec::new();
+        v.append(&mut bincode::serialize(&self.unused)?);
+        v.append(&mut bincode::serialize(&self.len)?);
+        v.append(&mut self.game_data.clone());
+        Ok(v)
+    }
+    // same body as packetize, written into a recycled buffer
+    pub fn write(game_data: &[u8], out: &mut Vec<u8>) {
+        out.push(0);
+        out.extend_from_slice(&(game_data.len() as u16).to_le_bytes());
+        out.extend_from_slice(game_data);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastInput2Client {
+    pub unused: u8,
+    pub player_number: u8,
+    pub frame: u32,
+    pub len: u16,
+    pub game_data: Vec<u8>,
+}
+
+impl FastInput2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("player_number", U8),
+        f("frame", U32),
+        f("length", U16),
+        f("data", Bytes),
+    ];
+    pub fn new(player_number: u8, frame: u32, game_data: Vec<u8>) -> FastInput2Client {
+        FastInput2Client {
+            unused: 0,
+            player_number,
+            frame,
+            len: game_data.len() as u16,
+            game_data,
+        }
+    }
+    pub fn parse(data: &[u8]) -> Option<FastInput2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(FastInput2Client {
+            unused: v[0].num() as u8,
+            player_number: v[1].num() as u8,
+            frame: v[2].num(),
+            len: v[3].num() as u16,
+            game_data: v[4].bytes(),
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.unused)?);
+        v.append(&mut bincode::serialize(&self.player_number)?);
+        v.append(&mut bincode::serialize(&self.frame)?);
         v.append(&mut bincode::serialize(&self.len)?);
         v.append(&mut self.game_data.clone());
         Ok(v)
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct GamePause2Client {
+    pub unused: u8,
+    // 1 paused, 0 resumed
+    pub paused: u8,
+    pub user_name: Vec<u8>,
+}
+
+impl GamePause2Client {
+    pub const FIELDS: &'static [Field] = &[f("unused", U8), f("paused", U8), f("user_name", Str)];
+    pub fn new(paused: bool, user_name: Vec<u8>) -> GamePause2Client {
+        GamePause2Client {
+            unused: 0,
+            paused: paused as u8,
+            user_name,
+        }
+    }
+    pub fn parse(data: &[u8]) -> Option<GamePause2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(GamePause2Client {
+            unused: v[0].num() as u8,
+            paused: v[1].num() as u8,
+            user_name: v[2].bytes(),
+        })
+    }
+    pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut v = vec![self.unused, self.paused];
+        v.extend_from_slice(&self.user_name);
+        v.push(0u8);
+        Ok(v)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct GameCache2Client {
     pub unused: u8,
     pub cache_position: u8,
 }
 
 impl GameCache2Client {
+    pub const FIELDS: &'static [Field] = &[f("unused", U8), f("cache_position", U8)];
     pub fn new(cache_position: u8) -> GameCache2Client {
         GameCache2Client {
             unused: 0,
             cache_position,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<GameCache2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(GameCache2Client {
+            unused: v[0].num() as u8,
+            cache_position: v[1].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut bincode::serialize(&self.unused)?);
         v.append(&mut bincode::serialize(&self.cache_position)?);
         Ok(v)
     }
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.unused);
+        out.push(self.cache_position);
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct GameDrop2Client {
     pub user_name: Vec<u8>,
     pub player_number: u8,
 }
 
 impl GameDrop2Client {
+    pub const FIELDS: &'static [Field] = &[f("user_name", Str), f("player_number", U8)];
     pub fn new(user_name: Vec<u8>, player_number: u8) -> GameDrop2Client {
         GameDrop2Client {
             user_name,
             player_number,
         }
     }
+    pub fn parse(data: &[u8]) -> Option<GameDrop2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(GameDrop2Client {
+            user_name: v[0].bytes(),
+            player_number: v[1].num() as u8,
+        })
+    }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
@@ -515,20 +1032,332 @@ impl GameDrop2Client {
     }
 }
 
+// one user in USER_SERVER_STATUS
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusUser {
+    pub name: Vec<u8>,
+    pub ping: u32,
+    pub status: u8,
+    pub user_id: UserId,
+    pub connection_type: u8,
+}
+
+impl StatusUser {
+    pub const FIELDS: &'static [Field] = &[
+        f("name", Str),
+        f("ping", U32),
+        f("status", U8),
+        f("user_id", U16),
+        f("connection_type", U8),
+    ];
+}
+
+// one game in USER_SERVER_STATUS; players is "seated/max"
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusGame {
+    pub name: Vec<u8>,
+    pub game_id: GameId,
+    pub emulator: Vec<u8>,
+    pub owner: Vec<u8>,
+    pub players: Vec<u8>,
+    pub status: u8,
+}
+
+impl StatusGame {
+    pub const FIELDS: &'static [Field] = &[
+        f("name", Str),
+        f("game_id", U32),
+        f("emulator", Str),
+        f("owner", Str),
+        f("players", Str),
+        f("status", U8),
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStatus2Client {
+    pub users: Vec<StatusUser>,
+    pub games: Vec<StatusGame>,
+}
+
+impl ServerStatus2Client {
+    pub const FIELDS: &'static [Field] = &[
+        f("unused", U8),
+        f("num_users", U32),
+        f("num_games", U32),
+        f("users_and_games", Bytes),
+    ];
+    pub fn new(users: Vec<StatusUser>, games: Vec<StatusGame>) -> ServerStatus2Client {
+        ServerStatus2Client { users, games }
+    }
+    pub fn parse(data: &[u8]) -> Option<ServerStatus2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        let listed = v[3].bytes();
+        let mut rest = listed.as_slice();
+        let mut users = Vec::new();
+        for _ in 0..v[1].num() {
+            let (u, r) = parse_prefix(StatusUser::FIELDS, rest)?;
+            rest = r;
+            users.push(StatusUser {
+                name: u[0].bytes(),
+                ping: u[1].num(),
+                status: u[2].num() as u8,
+                user_id: UserId(u[3].num() as u16),
+                connection_type: u[4].num() as u8,
+            });
+        }
+        let mut games = Vec::new();
+        for _ in 0..v[2].num() {
+            let (g, r) = parse_prefix(StatusGame::FIELDS, rest)?;
+            rest = r;
+            games.push(StatusGame {
+                name: g[0].bytes(),
+                game_id: GameId(g[1].num()),
+                emulator: g[2].bytes(),
+                owner: g[3].bytes(),
+                players: g[4].bytes(),
+                status: g[5].num() as u8,
+            });
+        }
+        rest.is_empty()
+            .then_some(ServerStatus2Client { users, games })
+    }
+    pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut v = vec![0u8];
+        v.extend_from_slice(&(self.users.len() as u32).to_le_bytes());
+        v.extend_from_slice(&(self.games.len() as u32).to_le_bytes());
+        for u in &self.users {
+            v.extend_from_slice(&u.name);
+            v.push(0u8);
+            v.extend_from_slice(&u.ping.to_le_bytes());
+            v.push(u.status);
+            v.extend_from_slice(&u.user_id.to_le_bytes());
+            v.push(u.connection_type);
+        }
+        for g in &self.games {
+            v.extend_from_slice(&g.name);
+            v.push(0u8);
+            v.append(&mut bincode::serialize(&g.game_id)?);
+            for text in [&g.emulator, &g.owner, &g.players] {
+                v.extend_from_slice(text);
+                v.push(0u8);
+            }
+            v.push(g.status);
+        }
+        Ok(v)
+    }
+}
+
+// one player already in the room, in PLAYER_INFO
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomPlayer {
+    pub name: Vec<u8>,
+    pub ping: u32,
+    pub user_id: UserId,
+    pub connection_type: u8,
+}
+
+impl RoomPlayer {
+    pub const FIELDS: &'static [Field] = &[
+        f("name", Str),
+        f("ping", U32),
+        f("user_id", U16),
+        f("connection_type", U8),
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerInfo2Client {
+    pub players: Vec<RoomPlayer>,
+}
+
+impl PlayerInfo2Client {
+    pub const FIELDS: &'static [Field] =
+        &[f("unused", U8), f("num_players", U32), f("players", Bytes)];
+    pub fn new(players: Vec<RoomPlayer>) -> PlayerInfo2Client {
+        PlayerInfo2Client { players }
+    }
+    pub fn parse(data: &[u8]) -> Option<PlayerInfo2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        let listed = v[2].bytes();
+        let mut rest = listed.as_slice();
+        let mut players = Vec::new();
+        for _ in 0..v[1].num() {
+            let (p, r) = parse_prefix(RoomPlayer::FIELDS, rest)?;
+            rest = r;
+            players.push(RoomPlayer {
+                name: p[0].bytes(),
+                ping: p[1].num(),
+                user_id: UserId(p[2].num() as u16),
+                connection_type: p[3].num() as u8,
+            });
+        }
+        rest.is_empty().then_some(PlayerInfo2Client { players })
+    }
+    pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut v = vec![0u8];
+        v.extend_from_slice(&(self.players.len() as u32).to_le_bytes());
+        for p in &self.players {
+            v.extend_from_slice(&p.name);
+            v.push(0u8);
+            v.extend_from_slice(&p.ping.to_le_bytes());
+            v.extend_from_slice(&p.user_id.to_le_bytes());
+            v.push(p.connection_type);
+        }
+        Ok(v)
+    }
+}
+
+// why a login was refused. the code leads the reject message as "E<code>" so
+// client side tooling and logs can tell the causes apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    ServerFull = 1,
+    Banned = 2,
+    BadVersion = 3,
+    DuplicateName = 4,
+    RateLimited = 5,
+    HandshakeTimeout = 6,
+    Policy = 7,
+    Moved = 8,
+    Challenge = 9,
+}
+
+impl RejectReason {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+    // "ko" gives korean text, anything else english
+    pub fn text(self, language: &str) -> &'static str {
+        match (self, language) {
+            (RejectReason::ServerFull, "ko") => "서버가 가득 찼습니다.",
+            (RejectReason::Banned, "ko") => "이 서버에서 차단되었습니다.",
+            (RejectReason::BadVersion, "ko") => "지원하지 않는 클라이언트 버전입니다.",
+            (RejectReason::DuplicateName, "ko") => "이미 사용 중인 이름입니다.",
+            (RejectReason::RateLimited, "ko") => "너무 자주 접속했습니다. 잠시 후 다시 시도하세요.",
+            (RejectReason::HandshakeTimeout, "ko") => "로그인 시간이 초과되었습니다.",
+            (RejectReason::Policy, "ko") => "서버 정책에 따라 거부되었습니다.",
+            (RejectReason::Moved, "ko") => "서버가 이전되었습니다. 새 주소로 접속하세요.",
+            (RejectReason::Challenge, "ko") => "로그인 확인에 실패했습니다.",
+            (RejectReason::ServerFull, _) => "Server is full.",
+            (RejectReason::Banned, _) => "You are banned from this server.",
+            (RejectReason::BadVersion, _) => "Unsupported client version.",
+            (RejectReason::DuplicateName, _) => "This name is already in use.",
+            (RejectReason::RateLimited, _) => "Too many connections, try again later.",
+            (RejectReason::HandshakeTimeout, _) => "Login timed out.",
+            (RejectReason::Policy, _) => "Refused by server policy.",
+            (RejectReason::Moved, _) => "This server has moved, connect to the new address.",
+            (RejectReason::Challenge, _) => "Login challenge failed.",
+        }
+    }
+    // "E02 You are banned from this server. (59 minutes)", EUC-KR encoded
+    pub fn message(self, language: &str, detail: Option<&str>) -> Vec<u8> {
+        let mut message = format!("E{:02} {}", self.code(), self.text(language));
+        if let Some(detail) = detail {
+            message += &format!(" ({})", detail);
+        }
+        encoding_rs::EUC_KR.encode(&message).0.to_vec()
+    }
+}
+
+// why a request of a logged in user was ignored. with refusal_feedback the
+// user is told in chat: "short" gives the reason, "verbose" also what to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    AlreadyInRoom,
+    NoSuchGame,
+    GameStarted,
+    RoomFull,
+    NotOwner,
+    NoSuchPlayer,
+    GameNameTaken,
+    GameNotAllowed,
+}
+
+impl Refusal {
+    // "ko" gives korean text, anything else english
+    pub fn text(self, language: &str) -> &'static str {
+        match (self, language) {
+            (Refusal::AlreadyInRoom, "ko") => "이미 방에 들어가 있습니다.",
+            (Refusal::NoSuchGame, "ko") => "그 방은 더 이상 없습니다.",
+            (Refusal::GameStarted, "ko") => "게임이 이미 시작되었습니다.",
+            (Refusal::RoomFull, "ko") => "방이 가득 찼습니다.",
+            (Refusal::NotOwner, "ko") => "방장만 할 수 있습니다.",
+            (Refusal::NoSuchPlayer, "ko") => "그 플레이어는 이 방에 없습니다.",
+            (Refusal::GameNameTaken, "ko") => "같은 이름의 방이 이미 있습니다.",
+            (Refusal::GameNotAllowed, "ko") => "이 서버에서는 그 게임을 할 수 없습니다.",
+            (Refusal::AlreadyInRoom, _) => "You are already in a room.",
+            (Refusal::NoSuchGame, _) => "That game no longer exists.",
+            (Refusal::GameStarted, _) => "The game has already started.",
+            (Refusal::RoomFull, _) => "The room is full.",
+            (Refusal::NotOwner, _) => "Only the room owner can do that.",
+            (Refusal::NoSuchPlayer, _) => "That player is not in this room.",
+            (Refusal::GameNameTaken, _) => "A room with that name already exists.",
+            (Refusal::GameNotAllowed, _) => "That game is not played on this server.",
+        }
+    }
+    pub fn hint(self, language: &str) -> &'static str {
+        match (self, language) {
+            (Refusal::AlreadyInRoom, "ko") => "먼저 방을 나가세요.",
+            (Refusal::NoSuchGame, "ko") => "방 목록을 새로 고치세요.",
+            (Refusal::GameStarted, "ko") => "게임이 끝날 때까지 기다리거나 다른 방을 고르세요.",
+            (Refusal::RoomFull, "ko") => "다른 방을 고르거나 새로 만드세요.",
+            (Refusal::NotOwner, "ko") => "방장에게 부탁하세요.",
+            (Refusal::NoSuchPlayer, "ko") => "플레이어 목록이 오래되었을 수 있습니다.",
+            (Refusal::GameNameTaken, "ko") => "그 방에 들어가거나 다른 이름을 쓰세요.",
+            (Refusal::GameNotAllowed, "ko") => "공지에서 게임 목록을 확인하세요.",
+            (Refusal::AlreadyInRoom, _) => "Leave it first.",
+            (Refusal::NoSuchGame, _) => "Refresh the game list.",
+            (Refusal::GameStarted, _) => "Wait for it to end or pick another room.",
+            (Refusal::RoomFull, _) => "Pick another room or create one.",
+            (Refusal::NotOwner, _) => "Ask the owner.",
+            (Refusal::NoSuchPlayer, _) => "The player list may be out of date.",
+            (Refusal::GameNameTaken, _) => "Join it or pick another name.",
+            (Refusal::GameNotAllowed, _) => "See the notice for the game list.",
+        }
+    }
+    // EUC-KR encoded
+    pub fn message(self, language: &str, verbose: bool) -> Vec<u8> {
+        let mut message = self.text(language).to_string();
+        if verbose {
+            message = format!("{} {}", message, self.hint(language));
+        }
+        encoding_rs::EUC_KR.encode(&message).0.to_vec()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConnectionReject2Client {
     pub user_name: Vec<u8>,
-    pub user_id: u16,
+    pub user_id: UserId,
+    pub message: Vec<u8>,
 }
 
 impl ConnectionReject2Client {
-    pub fn new(user_name: Vec<u8>, user_id: u16) -> ConnectionReject2Client {
-        ConnectionReject2Client { user_name, user_id }
+    pub const FIELDS: &'static [Field] =
+        &[f("user_name", Str), f("user_id", U16), f("message", Str)];
+    pub fn new(user_name: Vec<u8>, user_id: UserId, message: Vec<u8>) -> ConnectionReject2Client {
+        ConnectionReject2Client {
+            user_name,
+            user_id,
+            message,
+        }
+    }
+    pub fn parse(data: &[u8]) -> Option<ConnectionReject2Client> {
+        let v = parse_fields(Self::FIELDS, data)?;
+        Some(ConnectionReject2Client {
+            user_name: v[0].bytes(),
+            user_id: UserId(v[1].num() as u16),
+            message: v[2].bytes(),
+        })
     }
     pub fn packetize(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
         v.append(&mut self.user_name.clone());
         v.push(0u8);
         v.append(&mut bincode::serialize(&self.user_id)?);
+        v.append(&mut self.message.clone());
+        v.push(0u8);
         Ok(v)
     }
 }
@@ -536,7 +1365,217 @@ impl ConnectionReject2Client {
 #[cfg(test)]
 mod tests {
     use crate::protocol::*;
+    #[test]
+    fn hello_tags() {
+        assert!(hello_has_tag(
+            b"HELLO0.83\x00AEAD=00\x00PAUSE\x00",
+            PAUSE_TAG
+        ));
+        assert!(!hello_has_tag(b"HELLO0.83\x00", PAUSE_TAG));
+        // the version is not a tag
+        assert!(!hello_has_tag(b"PAUSE\x00", PAUSE_TAG));
+    }
+    #[test]
+    fn split_chat_pieces() {
+        assert_eq!(split_chat(b"hello\x00", 10), vec![b"hello".to_vec()]);
+        assert_eq!(split_chat(b"", 10), vec![Vec::<u8>::new()]);
+        assert_eq!(
+            split_chat(b"aaa bbb ccc ddd", 8),
+            vec![b"aaa bbb ".to_vec(), b"ccc ddd".to_vec()]
+        );
+        assert_eq!(
+            split_chat(b"abcdefghij", 4),
+            vec![b"abcd".to_vec(), b"efgh".to_vec(), b"ij".to_vec()]
+        );
+        // a double byte character is not cut in half
+        let korean = encoding_rs::EUC_KR.encode("가나다").0.to_vec();
+        let mut text = b"a".to_vec();
+        text.extend(&korean);
+        let pieces = split_chat(&text, 4);
+        assert_eq!(pieces, vec![text[..3].to_vec(), text[3..].to_vec()]);
+        assert_eq!(
+            chat_bodies(b"Server", b"hi\x00"),
+            vec![b"Server\x00hi\x00".to_vec()]
+        );
+    }
+    #[test]
+    fn reject_reason_message() {
+        let m = RejectReason::Banned.message("en", Some("59 minutes"));
+        assert_eq!(m, b"E02 You are banned from this server. (59 minutes)");
+        let m = RejectReason::ServerFull.message("ko", None);
+        assert!(m.starts_with(b"E01 "));
+        assert_eq!(
+            encoding_rs::EUC_KR.decode(&m).0,
+            "E01 서버가 가득 찼습니다."
+        );
+    }
+    #[test]
+    fn quit_reason_text() {
+        assert_eq!(quit_reason(b"\x00\xff\xffbye for now\x00"), b"bye for now");
+        assert_eq!(quit_reason(b"\x00\xff\xff\x00"), b"");
+        assert_eq!(quit_reason(b"\x00\xff"), b"");
+        assert_eq!(quit_reason(b""), b"");
+    }
+    #[test]
+    fn refusal_message() {
+        assert_eq!(Refusal::RoomFull.message("en", false), b"The room is full.");
+        assert_eq!(
+            Refusal::NotOwner.message("en", true),
+            b"Only the room owner can do that. Ask the owner."
+        );
+        let m = Refusal::GameStarted.message("ko", false);
+        assert_eq!(
+            encoding_rs::EUC_KR.decode(&m).0,
+            "게임이 이미 시작되었습니다."
+        );
+    }
 
+    #[test]
+    fn link_stats_loss() {
+        let mut link = LinkStats::default();
+        for seq in [0u16, 1, 2, 5, 5, 4, 6] {
+            link.note_datagram(seq);
+        }
+        assert_eq!(link.datagrams, 7);
+        assert_eq!(link.lost, 2);
+        assert_eq!(link.stale, 2);
+        assert_eq!(link.take_window(10), None);
+        assert_eq!(link.take_window(7), Some(22));
+        assert_eq!(link.window_datagrams, 0);
+        // wraps around
+        link.note_datagram(0xffff);
+        link.note_datagram(1);
+        assert_eq!(link.stale, 4);
+        link.forget_newest();
+        link.note_datagram(0xffff);
+        link.note_datagram(1);
+        assert_eq!(link.lost, 3);
+    }
+    // random builders of every kind: each body parses back to the same
+    // builder, and a datagram of them back to the same messages
+    #[test]
+    fn builders_round_trip() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        // builds Type::new(args), checks Type::parse gives it back
+        macro_rules! round_trip {
+            ($message_type:expr, $ty:ident($($arg:expr),*)) => {{
+                let built = $ty::new($($arg),*);
+                let body = built.packetize().unwrap();
+                assert_eq!($ty::parse(&body), Some(built), "{:02x?}", body);
+                Protocol::new($message_type, body)
+            }};
+        }
+        // strings never hold a NUL, it ends them
+        fn text(rng: &mut StdRng) -> Vec<u8> {
+            let len = rng.gen_range(0..40);
+            (0..len).map(|_| rng.gen_range(1..=255u8)).collect()
+        }
+        fn bytes(rng: &mut StdRng) -> Vec<u8> {
+            let len = rng.gen_range(0..64);
+            (0..len).map(|_| rng.gen()).collect()
+        }
+        let mut rng = StdRng::seed_from_u64(4931);
+        for _ in 0..200 {
+            let r = &mut rng;
+            let mut messages = vec![
+                round_trip!(
+                    USER_JOIN,
+                    UserJoinPacket2Client(text(r), UserId(r.gen()), r.gen(), r.gen())
+                ),
+                round_trip!(
+                    USER_QUIT,
+                    UserQuitPacket2Client(text(r), UserId(r.gen()), text(r))
+                ),
+                round_trip!(
+                    S2C_ACK,
+                    AckPacket2Client(r.gen(), r.gen(), r.gen(), r.gen(), r.gen())
+                ),
+                round_trip!(GLOBAL_CHAT, GlobalChat2Client(text(r), text(r))),
+                round_trip!(GAME_CHAT, GameChat2Client(text(r), text(r))),
+                round_trip!(
+                    CREATE_GAME,
+                    CreateGame2Client(text(r), text(r), text(r), GameId(r.gen()))
+                ),
+                round_trip!(QUIT_GAME, QuitGame2Client(text(r), UserId(r.gen()))),
+                round_trip!(
+                    JOIN_GAME,
+                    JoinGame2Client(GameId(r.gen()), text(r), r.gen(), UserId(r.gen()), r.gen())
+                ),
+                round_trip!(
+                    UPDATE_GAME_STATUS,
+                    UpdateGameStatus2Client(GameId(r.gen()), r.gen(), r.gen(), r.gen())
+                ),
+                round_trip!(START_GAME, StartGame2Client(r.gen(), r.gen(), r.gen())),
+                {
+                    let data = bytes(r);
+                    round_trip!(GAME_DATA, GameData2Client(data.len() as u16, data))
+                },
+                round_trip!(FAST_INPUT, FastInput2Client(r.gen(), r.gen(), bytes(r))),
+                round_trip!(GAME_PAUSE, GamePause2Client(r.gen(), text(r))),
+                round_trip!(GAME_CACHE, GameCache2Client(r.gen())),
+                round_trip!(DROP_GAME, GameDrop2Client(text(r), r.gen())),
+                round_trip!(
+                    CONNECTION_REJECT,
+                    ConnectionReject2Client(text(r), UserId(r.gen()), text(r))
+                ),
+                {
+                    let users = (0..r.gen_range(0..4))
+                        .map(|_| StatusUser {
+                            name: text(r),
+                            ping: r.gen(),
+                            status: r.gen(),
+                            user_id: UserId(r.gen()),
+                            connection_type: r.gen(),
+                        })
+                        .collect();
+                    let games = (0..r.gen_range(0..4))
+                        .map(|_| StatusGame {
+                            name: text(r),
+                            game_id: GameId(r.gen()),
+                            emulator: text(r),
+                            owner: text(r),
+                            players: text(r),
+                            status: r.gen(),
+                        })
+                        .collect();
+                    round_trip!(USER_SERVER_STATUS, ServerStatus2Client(users, games))
+                },
+                {
+                    let players = (0..r.gen_range(0..4))
+                        .map(|_| RoomPlayer {
+                            name: text(r),
+                            ping: r.gen(),
+                            user_id: UserId(r.gen()),
+                            connection_type: r.gen(),
+                        })
+                        .collect();
+                    round_trip!(PLAYER_INFO, PlayerInfo2Client(players))
+                },
+            ];
+            for (seq, p) in messages.iter_mut().enumerate() {
+                p.header.seq = seq as u16;
+            }
+            let mut datagram = vec![messages.len() as u8];
+            for p in &messages {
+                p.write_packet(&mut datagram).unwrap();
+            }
+            let parsed = get_protocol_from_bytes(&datagram).unwrap();
+            assert_eq!(parsed.len(), messages.len());
+            for (a, b) in parsed.iter().zip(&messages) {
+                assert_eq!(a.header.seq, b.header.seq);
+                assert_eq!(a.header.header.message_type, b.header.header.message_type);
+                assert_eq!(a.data, b.data);
+            }
+        }
+    }
+    #[test]
+    fn game_data_length_must_match() {
+        let body = GameData2Client::new(2, vec![1, 2]).packetize().unwrap();
+        assert!(GameData2Client::parse(&body).is_some());
+        let body = GameData2Client::new(3, vec![1, 2]).packetize().unwrap();
+        assert_eq!(GameData2Client::parse(&body), None);
+    }
     #[test]
     fn pack_test() {
         let prob = ProtocolSeqHeader {
@@ -592,6 +1631,10 @@ mod tests {
             let pppp = p.header.seq;
             println!("protocol: {}, type: {}", pppp, pp,);
         }
+
+        // a length past the end of the datagram, and a length of 0
+        assert!(get_protocol_from_bytes(&vec![1, 0, 0, 18, 0, 6, 0, 0]).is_err());
+        assert!(get_protocol_from_bytes(&vec![1, 0, 0, 0, 0, 6]).is_err());
     }
     #[test]
     fn fetch_protocol() {
@@ -627,4 +1670,33 @@ mod tests {
         let r = store.fetch_protocol(5);
         assert!(r.is_some());
     }
+    #[test]
+    fn mark_seen() {
+        let mut store = ProtocolPackets::new();
+        assert!(store.mark_seen(0));
+        assert!(store.mark_seen(1));
+        assert!(!store.mark_seen(0));
+        for seq in 2..(SEEN_WINDOW as u16 + 1) {
+            assert!(store.mark_seen(seq));
+        }
+        // 0 fell out of the window
+        assert!(store.mark_seen(0));
+        assert!(!store.mark_seen(SEEN_WINDOW as u16));
+    }
+    #[test]
+    fn reset_accepts_old_seq() {
+        let mut store = ProtocolPackets::new();
+        let mut p = Protocol::new(1, vec![1]);
+        p.header.seq = 7;
+        store.add(p.clone());
+        assert!(store.fetch_protocol(7).is_some());
+        p.header.seq = 3;
+        store.add(p.clone());
+        assert_eq!(store.len(), 0);
+        store.resync_to(3);
+        store.add(p);
+        assert!(store.fetch_next().is_some());
+        assert_eq!(store.wanted_seq, 4);
+        assert!(store.fetch_next().is_none());
+    }
 }
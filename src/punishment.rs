@@ -0,0 +1,174 @@
+use crate::settings;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// chat filter hits escalate: hits -> mute, mutes -> kick, kicks -> temporary ban.
+#[derive(Debug, Clone)]
+pub struct EscalationPolicy {
+    pub hits_to_mute: u32,
+    pub mute_time: Duration,
+    pub mutes_to_kick: u32,
+    pub kicks_to_ban: u32,
+    pub ban_time: Duration,
+}
+
+impl EscalationPolicy {
+    pub fn from_config(config: &HashMap<String, String>) -> EscalationPolicy {
+        EscalationPolicy {
+            hits_to_mute: settings::get_num(config, "filter_hits_to_mute", 3),
+            mute_time: Duration::from_secs(60 * settings::get_num(config, "mute_minutes", 10)),
+            mutes_to_kick: settings::get_num(config, "mutes_to_kick", 3),
+            kicks_to_ban: settings::get_num(config, "kicks_to_ban", 2),
+            ban_time: Duration::from_secs(60 * settings::get_num(config, "ban_minutes", 60)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Warn,
+    Mute(Duration),
+    Kick,
+    Ban(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub filter_hits: u32,
+    pub mutes: u32,
+    pub kicks: u32,
+    pub muted_until: Option<Instant>,
+    pub banned_until: Option<Instant>,
+    pub last_update: Instant,
+}
+
+impl Record {
+    fn new(now: Instant) -> Record {
+        Record {
+            filter_hits: 0,
+            mutes: 0,
+            kicks: 0,
+            muted_until: None,
+            banned_until: None,
+            last_update: now,
+        }
+    }
+}
+
+fn remaining(until: Option<Instant>, now: Instant) -> Option<Duration> {
+    until.filter(|t| *t > now).map(|t| t - now)
+}
+
+// punishment records by ip address
+#[derive(Debug, Default)]
+pub struct Punishments {
+    pub records: HashMap<IpAddr, Record>,
+}
+
+impl Punishments {
+    pub fn new() -> Punishments {
+        Punishments {
+            records: HashMap::new(),
+        }
+    }
+    pub fn record_filter_hit(
+        &mut self,
+        ip: IpAddr,
+        now: Instant,
+        policy: &EscalationPolicy,
+    ) -> Action {
+        let r = self.records.entry(ip).or_insert_with(|| Record::new(now));
+        r.last_update = now;
+        r.filter_hits += 1;
+        if r.filter_hits < policy.hits_to_mute {
+            return Action::Warn;
+        }
+        r.filter_hits = 0;
+        r.mutes += 1;
+        if r.mutes < policy.mutes_to_kick {
+            r.muted_until = Some(now + policy.mute_time);
+            return Action::Mute(policy.mute_time);
+        }
+        r.mutes = 0;
+        r.kicks += 1;
+        if r.kicks < policy.kicks_to_ban {
+            return Action::Kick;
+        }
+        r.kicks = 0;
+        r.banned_until = Some(now + policy.ban_time);
+        Action::Ban(policy.ban_time)
+    }
+    // an admin ban, longer ones already running stay
+    pub fn ban(&mut self, ip: IpAddr, now: Instant, duration: Duration) {
+        let r = self.records.entry(ip).or_insert_with(|| Record::new(now));
+        r.last_update = now;
+        r.banned_until = r.banned_until.max(Some(now + duration));
+    }
+    // a ban and mute read back from state_file, zero durations are not running
+    pub fn restore(&mut self, ip: IpAddr, now: Instant, banned: Duration, muted: Duration) {
+        let r = self.records.entry(ip).or_insert_with(|| Record::new(now));
+        r.last_update = now;
+        r.banned_until = (!banned.is_zero()).then(|| now + banned);
+        r.muted_until = (!muted.is_zero()).then(|| now + muted);
+    }
+    pub fn muted_for(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        remaining(self.records.get(&ip)?.muted_until, now)
+    }
+    pub fn banned_for(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        remaining(self.records.get(&ip)?.banned_until, now)
+    }
+    // forget records with nothing active and no activity for an hour.
+    pub fn expire(&mut self, now: Instant) {
+        self.records.retain(|_, r| {
+            remaining(r.muted_until, now).is_some()
+                || remaining(r.banned_until, now).is_some()
+                || now.duration_since(r.last_update) < Duration::from_secs(3600)
+        });
+    }
+    pub fn describe(&self, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (ip, r) in &self.records {
+            let mut line = format!(
+                "{}: hits {}, mutes {}, kicks {}",
+                ip, r.filter_hits, r.mutes, r.kicks
+            );
+            if let Some(d) = remaining(r.muted_until, now) {
+                line += &format!(", muted {}s", d.as_secs());
+            }
+            if let Some(d) = remaining(r.banned_until, now) {
+                line += &format!(", banned {}s", d.as_secs());
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalation() {
+        let policy = EscalationPolicy::from_config(&HashMap::new());
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+        let mut p = Punishments::new();
+        let mut actions = Vec::new();
+        for _ in 0..18 {
+            actions.push(p.record_filter_hit(ip, now, &policy));
+        }
+        let mute = Action::Mute(policy.mute_time);
+        assert_eq!(actions[..3], [Action::Warn, Action::Warn, mute]);
+        assert_eq!(actions[5], mute);
+        assert_eq!(actions[8], Action::Kick);
+        assert_eq!(actions[17], Action::Ban(policy.ban_time));
+        assert!(p.muted_for(ip, now).is_some());
+        assert!(p.banned_for(ip, now).is_some());
+        assert!(p.banned_for(ip, now + policy.ban_time).is_none());
+
+        p.expire(now + Duration::from_secs(7200));
+        assert!(p.records.is_empty());
+    }
+}
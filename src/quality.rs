@@ -0,0 +1,81 @@
+// connection grades for the user list. clients pick their connection type
+// themselves, so "Excellent" says little about a player; with connection_grade
+// the server grades everyone from what it measured (ping, the jitter of the
+// login acks, datagram loss) and lists that instead, as the connection type
+// byte clients show as Excellent..Bad ("type") or after the name, "kim [Good]"
+// ("suffix"). the connection type the client chose still sets frame delay and
+// input size. LAN is never given, the server cannot tell.
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradeDisplay {
+    Off,
+    ConnType,
+    Suffix,
+}
+
+impl GradeDisplay {
+    pub fn from_config(config: &HashMap<String, String>) -> GradeDisplay {
+        match config.get("connection_grade").map(|x| x.trim()) {
+            Some("type") => GradeDisplay::ConnType,
+            Some("suffix") => GradeDisplay::Suffix,
+            _ => GradeDisplay::Off,
+        }
+    }
+}
+
+// average change between consecutive ping samples, ms
+pub fn jitter(pings: &[i32]) -> u32 {
+    if pings.len() < 2 {
+        return 0;
+    }
+    let total: u64 = pings
+        .windows(2)
+        .map(|x| (x[1] as i64 - x[0] as i64).unsigned_abs())
+        .sum();
+    (total / (pings.len() as u64 - 1)) as u32
+}
+
+// in ms of ping: jitter and loss hurt netplay more than a steady ping
+pub fn score(ping: u32, jitter: u32, loss_percent: u64) -> u64 {
+    ping as u64 + 2 * jitter as u64 + 20 * loss_percent
+}
+
+// kaillera connection type: 2 Excellent, 3 Good, 4 Average, 5 Low, 6 Bad
+pub fn grade(score: u64) -> u8 {
+    match score {
+        0..=30 => 2,
+        31..=60 => 3,
+        61..=120 => 4,
+        121..=200 => 5,
+        _ => 6,
+    }
+}
+
+pub fn grade_name(grade: u8) -> &'static str {
+    match grade {
+        1 => "LAN",
+        2 => "Excellent",
+        3 => "Good",
+        4 => "Average",
+        5 => "Low",
+        _ => "Bad",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grades_connections() {
+        assert_eq!(jitter(&[20, 30, 20, 30]), 10);
+        assert_eq!(jitter(&[20]), 0);
+        // steady 40ms is better than 20ms that jumps around
+        assert_eq!(grade(score(40, 2, 0)), 3);
+        assert_eq!(grade(score(20, 25, 0)), 4);
+        assert_eq!(grade(score(10, 0, 0)), 2);
+        assert_eq!(grade(score(30, 0, 10)), 6);
+        assert_eq!(grade_name(grade(score(150, 5, 1))), "Low");
+    }
+}
@@ -0,0 +1,95 @@
+// PING and HELLO on main_port answer anyone, which bots scraping server lists
+// use over and over. with control_queries_per_minute an ip gets that many
+// answers a minute, later queries are ignored until the minute is over. server
+// lists the operator trusts add TOKEN=<control_query_token> after their HELLO,
+// like the other HELLO tags, and are never limited. the answers themselves are
+// cached, see server_info::HelloTagsCache.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::settings;
+
+// ips remembered before the ones whose minute is over are forgotten
+const MAX_TRACKED: usize = 4096;
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct QueryLimiter {
+    // 0 is no limit
+    per_minute: u32,
+    token: Option<Vec<u8>>,
+    // ip -> start of its minute, queries in it
+    seen: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl QueryLimiter {
+    pub fn from_config(config: &HashMap<String, String>) -> QueryLimiter {
+        QueryLimiter {
+            per_minute: settings::get_num(config, "control_queries_per_minute", 0),
+            token: config
+                .get("control_query_token")
+                .map(|x| format!("TOKEN={}", x).into_bytes()),
+            seen: HashMap::new(),
+        }
+    }
+    // whether to answer query from ip
+    pub fn allows(&mut self, ip: IpAddr, now: Instant, query: &[u8]) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        if let Some(token) = &self.token {
+            if query
+                .split(|x| *x == 0)
+                .skip(1)
+                .any(|x| x == token.as_slice())
+            {
+                return true;
+            }
+        }
+        if self.seen.len() >= MAX_TRACKED {
+            self.seen
+                .retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        }
+        let (start, count) = self.seen.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count == self.per_minute + 1 {
+            info!("{} over {} queries a minute, ignored", ip, self.per_minute);
+        }
+        *count <= self.per_minute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_queries_per_ip() {
+        let config: HashMap<String, String> = [
+            ("control_queries_per_minute", "2"),
+            ("control_query_token", "list"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let mut limiter = QueryLimiter::from_config(&config);
+        let (scraper, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        assert!(limiter.allows(scraper, start, b"PING\x00"));
+        assert!(limiter.allows(scraper, start, b"HELLO0.83\x00"));
+        assert!(!limiter.allows(scraper, start, b"PING\x00"));
+        assert!(limiter.allows(scraper, start, b"PING\x00TOKEN=list\x00"));
+        assert!(!limiter.allows(scraper, start, b"PING\x00TOKEN=guess\x00"));
+        assert!(limiter.allows(other, start, b"PING\x00"));
+        assert!(limiter.allows(scraper, start + WINDOW, b"PING\x00"));
+
+        assert!(QueryLimiter::default().allows(scraper, start, b"PING\x00"));
+    }
+}
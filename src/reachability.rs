@@ -0,0 +1,107 @@
+// are main_port and sub_port reachable from the internet? the server asks an
+// outside echo service (reachability_url) to send a probe datagram to each
+// port: an http POST of "ports=27888,27999&token=<token>", answered by
+// PROBE_MAGIC + token sent over udp to the address the request came from.
+// probes that have not arrived after reachability_timeout_secs mean the port
+// is not forwarded or firewalled, the usual reason nobody can join a new server.
+use std::net::SocketAddr;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+pub const PROBE_MAGIC: &[u8] = b"PROBE\x00";
+
+// the token of a probe datagram
+pub fn probe_token(data: &[u8]) -> Option<&[u8]> {
+    data.strip_prefix(PROBE_MAGIC)
+        .map(|x| x.strip_suffix(b"\x00").unwrap_or(x))
+}
+
+#[derive(Debug)]
+pub struct Probe {
+    pub token: String,
+    // main_port and sub_port, and the ones probes arrived on
+    pub ports: Vec<u16>,
+    pub seen: Vec<u16>,
+    // the admin who asked for it, None at startup
+    pub requested_by: Option<SocketAddr>,
+}
+
+impl Probe {
+    pub fn new(ports: Vec<u16>) -> Probe {
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        Probe {
+            token,
+            ports,
+            seen: Vec::new(),
+            requested_by: None,
+        }
+    }
+    pub fn body(&self) -> String {
+        let ports: Vec<_> = self.ports.iter().map(|x| x.to_string()).collect();
+        format!("ports={}&token={}", ports.join(","), self.token)
+    }
+    // a probe arrived on port; false if it is not ours
+    pub fn note(&mut self, port: u16, token: &[u8]) -> bool {
+        if token != self.token.as_bytes() {
+            return false;
+        }
+        if !self.seen.contains(&port) {
+            self.seen.push(port);
+        }
+        true
+    }
+    // reachable and a line per port, what to do about the unreachable ones
+    pub fn report(&self) -> Vec<(bool, String)> {
+        self.ports
+            .iter()
+            .map(|port| {
+                let reachable = self.seen.contains(port);
+                let line = if reachable {
+                    format!("udp port {} is reachable from outside", port)
+                } else {
+                    format!(
+                        "udp port {} is NOT reachable from outside: forward it to this machine \
+                         on your router and allow it in the firewall",
+                        port
+                    )
+                };
+                (reachable, line)
+            })
+            .collect()
+    }
+    pub fn all_seen(&self) -> bool {
+        self.ports.iter().all(|x| self.seen.contains(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_ports() {
+        let mut probe = Probe::new(vec![27888, 27999]);
+        assert_eq!(probe.token.len(), 16);
+        assert_eq!(
+            probe.body(),
+            format!("ports=27888,27999&token={}", probe.token)
+        );
+        let datagram = [PROBE_MAGIC, probe.token.as_bytes(), b"\x00"].concat();
+        let token = probe_token(&datagram).unwrap().to_vec();
+        assert!(!probe.note(27888, b"other"));
+        assert!(probe.note(27888, &token));
+        assert!(!probe.all_seen());
+        let report = probe.report();
+        assert_eq!(
+            report[0],
+            (true, "udp port 27888 is reachable from outside".to_string())
+        );
+        assert!(!report[1].0 && report[1].1.starts_with("udp port 27999 is NOT reachable"));
+        assert!(probe_token(b"PING\x00").is_none());
+    }
+}
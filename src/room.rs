@@ -1,16 +1,26 @@
 use std::fmt;
 
+use chrono::{DateTime, FixedOffset, Utc};
 use log::{info, trace};
 use std::sync::atomic;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::cache_system::*;
+use crate::encryption::SessionCipher;
+use crate::ids::*;
+use crate::input_record::InputRecorder;
+use crate::pacing::FramePacing;
+use crate::pool::BufPool;
 use crate::protocol::*;
+use crate::quality::{self, GradeDisplay};
+use crate::send_pacing::SendPacer;
+use crate::suspicion::MessageStats;
 use log::error;
 use serde::__private::from_utf8_lossy;
 use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
 use std::rc::Rc;
-use std::{cmp, collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr};
 use thiserror::Error;
 use tokio::net::UdpSocket;
 
@@ -18,23 +28,35 @@ type PlayerStatus = u8;
 pub const Playing: PlayerStatus = 0;
 pub const Idle: PlayerStatus = 1;
 type PlayerInput = Vec<u8>;
+const RESEND_COUNT: usize = 3;
+// recycled buffers kept per user, enough for a few frames of 8 players
+const POOL_SIZE: usize = 16;
 pub struct User {
     // pub packets: ProtocolPackets,
     pub ip_addr: SocketAddr,
-    pub user_id: u16,
+    pub user_id: UserId,
     pub name: Vec<u8>,
     pub emul_name: String,
     pub ping: u32,
+    // the ping other clients see and frame delays are computed from. equal to
+    // ping unless debug_random_ping or debug_ping_jitter is set
+    pub shown_ping: u32,
     pub connect_type: u8,
     pub atomic_input_size: u8,
     pub player_status: PlayerStatus,
     pub ack_count: u32,
     pub send_count: u16,
-    pub cur_seq: u16,
-    pub game_room_id: Option<u32>,
+    pub game_room_id: Option<GameId>,
     pub room_order: u8,
-    pub out_packets: Vec<Protocol>,
+    // the last RESEND_COUNT messages, each datagram repeats them
+    pub out_packets: VecDeque<Protocol>,
+    // message bodies of dropped out_packets, and the datagram being built
+    pub pool: BufPool,
+    send_buf: Vec<u8>,
     pub in_packets: ProtocolPackets,
+    // seq of the last GAME_DATA or GAME_CACHE taken this game. after a resync
+    // the seen window is empty and repeated older inputs would count twice
+    pub last_input_seq: Option<u16>,
     pub player_index: u8,
     pub players_input: Vec<Vec<u8>>,
     pub cache_system: CacheSystem,
@@ -42,26 +64,82 @@ pub struct User {
     pub s2c_ack_time: Instant,
     pub pings: Vec<i32>,
     pub keepalive_time: Instant,
+    // negotiated encryption extension
+    pub cipher: Option<SessionCipher>,
+    // false until the user answers the rules prompt with /agree
+    pub rules_accepted: bool,
+    // zone of the [HH:MM] prefix on chat relayed to this user, None for no prefix
+    pub chat_clock: Option<FixedOffset>,
+    // asked for the GAME_PAUSE extension in its HELLO
+    pub pause_capable: bool,
+    // asked for FAST_INPUT_TAG in its HELLO
+    pub fast_input_capable: bool,
+    // asked for CHALLENGE_TAG in its HELLO: the login acks carry ack_nonce
+    pub challenged: bool,
+    // what the last S2C_ACK asked to be echoed
+    pub ack_nonce: Option<[u32; 4]>,
+    // arrival of GAME_DATA/GAME_CACHE during the current game
+    pub pacing: FramePacing,
+    pub input_rate: InputRate,
+    // lobby messages waiting for send_pacing_ms
+    pub send_pacer: SendPacer,
+    // message types received this session and anomalies among them
+    pub messages: MessageStats,
+}
+
+// "+9", "-3:30" or "+09:00" as an offset from utc
+pub fn parse_utc_offset(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+    let (sign, rest) = match text.as_bytes().first() {
+        Some(b'+') => (1, &text[1..]),
+        Some(b'-') => (-1, &text[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (hours, minutes) = (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?);
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+// chat text with an [HH:MM] prefix, the text stops at the first NUL
+pub fn timestamp_chat(now: DateTime<Utc>, zone: FixedOffset, message: &[u8]) -> Vec<u8> {
+    let mut text = now
+        .with_timezone(&zone)
+        .format("[%H:%M] ")
+        .to_string()
+        .into_bytes();
+    text.extend(message.split(|x| *x == 0).next().unwrap_or(&[]));
+    text
+}
+
+// user names are EUC-KR on the wire
+pub fn display_name(name: &[u8]) -> String {
+    encoding_rs::EUC_KR.decode(name).0.to_string()
 }
 
 impl User {
     pub fn new(ip_addr: SocketAddr) -> User {
         User {
-            user_id: 0,
+            user_id: UserId(0),
             name: vec![0u8],
             emul_name: "".to_string(),
             ping: 0,
+            shown_ping: 0,
             connect_type: 0,
             atomic_input_size: 0,
             player_status: Idle,
             ack_count: 0,
             send_count: 0,
-            cur_seq: 0,
             game_room_id: Option::None,
             room_order: 0,
             ip_addr,
-            out_packets: Vec::new(),
+            out_packets: VecDeque::with_capacity(RESEND_COUNT + 1),
+            pool: BufPool::new(POOL_SIZE),
+            send_buf: Vec::new(),
             in_packets: ProtocolPackets::new(),
+            last_input_seq: None,
             player_index: 0,
             players_input: Vec::new(),
             cache_system: CacheSystem::new(),
@@ -69,6 +147,17 @@ impl User {
             pings: Vec::new(),
             s2c_ack_time: Instant::now(),
             keepalive_time: Instant::now(),
+            cipher: None,
+            rules_accepted: true,
+            chat_clock: None,
+            pause_capable: false,
+            fast_input_capable: false,
+            challenged: false,
+            ack_nonce: None,
+            pacing: FramePacing::default(),
+            input_rate: InputRate::default(),
+            send_pacer: SendPacer::default(),
+            messages: MessageStats::default(),
         }
     }
     pub fn reset_outcoming(&mut self) {
@@ -76,42 +165,132 @@ impl User {
         self.put_cache.reset();
         self.players_input.clear();
         self.players_input.resize(32, Vec::new());
+        self.pacing.reset();
+        self.last_input_seq = None;
+    }
+    // whether the input message with seq comes after the last one taken this
+    // game, noting it when it does
+    pub fn fresh_input(&mut self, seq: u16) -> bool {
+        if let Some(last) = self.last_input_seq {
+            if seq.wrapping_sub(last) as i16 <= 0 {
+                return false;
+            }
+        }
+        self.last_input_seq = Some(seq);
+        true
     }
 
+    // goes through send_pacer, which may hold lobby messages back. the user is
+    // not borrowed while a datagram goes out.
     pub async fn make_send_packet(
-        &mut self,
+        user: &Rc<RefCell<User>>,
+        server_socket: &mut UdpSocket,
+        p: Protocol,
+    ) -> anyhow::Result<()> {
+        let admitted = user.borrow_mut().send_pacer.admit(p, Instant::now());
+        for p in admitted {
+            let datagram = user.borrow_mut().next_datagram(p)?;
+            Self::send_datagram(user, server_socket, datagram).await?;
+        }
+        Ok(())
+    }
+    // the next paced message, if its time has come
+    pub async fn send_paced(
+        user: &Rc<RefCell<User>>,
+        server_socket: &mut UdpSocket,
+    ) -> anyhow::Result<()> {
+        let due = user.borrow_mut().send_pacer.due(Instant::now());
+        if let Some(p) = due {
+            let datagram = user.borrow_mut().next_datagram(p)?;
+            Self::send_datagram(user, server_socket, datagram).await?;
+        }
+        Ok(())
+    }
+    async fn send_datagram(
+        user: &Rc<RefCell<User>>,
         server_socket: &mut UdpSocket,
-        mut p: Protocol,
+        datagram: Vec<u8>,
     ) -> anyhow::Result<()> {
-        // } Result<(), Box<dyn Error>> {
-        // self.server_socket.send_to(b"hihi", self.ip_addr).await?;
-        let ip_addr = self.ip_addr;
+        let ip_addr = user.borrow().ip_addr;
+        server_socket.send_to(&datagram, ip_addr).await?;
+        // the buffer is reused for the next datagram
+        user.borrow_mut().send_buf = datagram;
+        Ok(())
+    }
+    // p with the next seq, after the last few messages sent, newest first. sealed
+    // when the session is encrypted.
+    fn next_datagram(&mut self, mut p: Protocol) -> anyhow::Result<Vec<u8>> {
         p.header.seq = self.send_count;
-        self.out_packets.push(p);
-        let extra_packets = cmp::min(3, self.out_packets.len());
-        let mut packet = Vec::new();
-        packet.push(extra_packets as u8);
-        let packetLen = self.out_packets.len();
-        for i in 0..extra_packets {
-            let prev_procotol = self
-                .out_packets
-                .get_mut(packetLen - 1 - i)
-                .ok_or(KailleraError::NotFound)?;
-            let mut prev_packet = prev_procotol.make_packet()?;
-            packet.append(&mut prev_packet);
-        }
-        server_socket.send_to(&packet, ip_addr).await?;
+        self.out_packets.push_back(p);
+        while self.out_packets.len() > RESEND_COUNT {
+            if let Some(old) = self.out_packets.pop_front() {
+                self.pool.give(old.data);
+            }
+        }
+        let mut packet = std::mem::take(&mut self.send_buf);
+        packet.clear();
+        packet.push(self.out_packets.len() as u8);
+        for prev_protocol in self.out_packets.iter().rev() {
+            prev_protocol.write_packet(&mut packet)?;
+        }
         self.send_count = self.send_count.wrapping_add(1);
-        Ok(())
+        match &mut self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.seal(&packet);
+                self.send_buf = packet;
+                Ok(sealed)
+            }
+            None => Ok(packet),
+        }
+    }
+    // graded connection type, see quality.rs
+    pub fn connection_grade(&self) -> u8 {
+        quality::grade(quality::score(
+            self.ping,
+            quality::jitter(&self.pings),
+            self.in_packets.link.loss_percent(),
+        ))
+    }
+    // name and connection type as the user list shows them
+    pub fn listed_as(&self, display: GradeDisplay) -> (Vec<u8>, u8) {
+        match display {
+            GradeDisplay::Off => (self.name.clone(), self.connect_type),
+            GradeDisplay::ConnType => (self.name.clone(), self.connection_grade()),
+            GradeDisplay::Suffix => {
+                let mut name = self.name.clone();
+                name.extend_from_slice(
+                    format!(" [{}]", quality::grade_name(self.connection_grade())).as_bytes(),
+                );
+                (name, self.connect_type)
+            }
+        }
     }
     pub async fn send_message(
-        &mut self,
+        user: &Rc<RefCell<User>>,
+        server_socket: &mut UdpSocket,
+        message: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        for data in GlobalChat2Client::new(b"Server".to_vec(), message).packetize_split() {
+            Self::make_send_packet(user, server_socket, Protocol::new(GLOBAL_CHAT, data)).await?;
+        }
+        Ok(())
+    }
+    // chat from another user the way this user wants to see it
+    pub fn chat_text(&self, now: DateTime<Utc>, message: &[u8]) -> Vec<u8> {
+        match self.chat_clock {
+            Some(zone) => timestamp_chat(now, zone, message),
+            None => message.to_vec(),
+        }
+    }
+    // game chat line only this user sees
+    pub async fn send_game_message(
+        user: &Rc<RefCell<User>>,
         server_socket: &mut UdpSocket,
         message: Vec<u8>,
     ) -> anyhow::Result<()> {
-        let data = GlobalChat2Client::new(b"Server".to_vec(), message).packetize()?;
-        let p = Protocol::new(GLOBAL_CHAT, data);
-        self.make_send_packet(server_socket, p).await?;
+        for data in GameChat2Client::new(b"Server".to_vec(), message).packetize_split() {
+            Self::make_send_packet(user, server_socket, Protocol::new(GAME_CHAT, data)).await?;
+        }
         Ok(())
     }
 }
@@ -137,29 +316,146 @@ impl PlayerAddr {
         }
     }
 }
+#[derive(Debug)]
+pub struct GameSession {
+    pub started_at: chrono::DateTime<chrono::Local>,
+    pub started: Instant,
+    // player name, time since start
+    pub drops: Vec<(String, Duration)>,
+    // set when no player is playing anymore
+    pub duration: Option<Duration>,
+}
+
+pub const DEFAULT_MAX_PLAYERS: u8 = 4;
+// the player number is a byte, but emulators support at most 8 players
+pub const MAX_PLAYERS_LIMIT: u8 = 8;
+
+// game messages one player sent in the current second, against
+// input_max_packets_per_sec and input_max_bytes_per_sec (0 is no cap). a
+// client over a cap floods the server; it is dropped from the game, the
+// others keep playing.
+#[derive(Debug, Default)]
+pub struct InputRate {
+    window: Option<Instant>,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl InputRate {
+    // counts a message; true while the player is over a cap this second
+    pub fn note(&mut self, now: Instant, bytes: usize, max_packets: u64, max_bytes: u64) -> bool {
+        match self.window {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window = Some(now);
+                self.packets = 0;
+                self.bytes = 0;
+            }
+        }
+        self.packets += 1;
+        self.bytes += bytes as u64;
+        (max_packets > 0 && self.packets > max_packets) || (max_bytes > 0 && self.bytes > max_bytes)
+    }
+}
+
 #[derive(Debug)]
 pub struct Room {
     pub game_name: String,
-    pub game_id: u32,
+    pub game_id: GameId,
     pub emul_name: String,
     pub creator_id: String,
+    // made with /persist: owned by the server and kept open when empty
+    pub persistent: bool,
     // quitting user in game is None
     pub players: Vec<PlayerAddr>,
     pub game_status: GameStatus,
     pub same_delay: bool,
+    // START_GAME runs a /ready check and a countdown first
+    pub ready_check: bool,
+    pub ready_check_running: bool,
+    // the 3-2-1 before START_GAME is on
+    pub counting_down: bool,
+    pub ready_players: HashSet<SocketAddr>,
+    pub history: Vec<GameSession>,
+    // set while a game runs with input recording enabled
+    pub input_recorder: Option<InputRecorder>,
+    // forward each input immediately as FAST_INPUT instead of merging frames
+    pub fast_input: bool,
+    // next frame number by player index, fast input mode
+    pub input_frames: Vec<u32>,
+    // /pause: inputs are held and replayed on /resume. the sender of each, and
+    // the input itself in fast input mode (otherwise it waits in players_input)
+    pub paused: bool,
+    pub held_inputs: Vec<(SocketAddr, Option<Vec<u8>>)>,
+    // lowest ping becomes P1 at game start instead of join order
+    pub ping_order: bool,
+    pub max_players: u8,
+    // START_GAME needs this many seated players, 0 is no minimum
+    pub min_players: u8,
+    // START_GAME checks seated players against it, 0 is no limit
+    pub max_ping: u32,
+    // mirror game chat to the lobby
+    pub relay: bool,
+    // when recent lines were relayed, for flood limiting
+    pub relayed: VecDeque<Instant>,
+    // team label by player, set by the owner with /team
+    pub teams: Vec<(SocketAddr, String)>,
+    // tell the lobby every advertise_minutes while seats are free
+    pub advertise: bool,
+    // creation or the last advertisement
+    pub advertised: Instant,
+    // last /desync report, reports closer than DESYNC_REPORT_GAP are ignored
+    pub desync_reported: Option<Instant>,
+    // /handoff on: while the game runs, a new player may join into a seat left
+    // by someone who quit and plays from the next START_GAME (the owner frees a
+    // dropped player's seat with a kick)
+    pub handoff: bool,
 }
 
 impl Room {
     pub fn new() -> Room {
         Room {
             game_name: "".to_string(),
-            game_id: 0,
+            game_id: GameId(0),
             emul_name: "".to_string(),
             creator_id: "".to_string(),
+            persistent: false,
             players: Vec::new(),
             game_status: 0,
             same_delay: false,
+            ready_check: false,
+            ready_check_running: false,
+            counting_down: false,
+            ready_players: HashSet::new(),
+            history: Vec::new(),
+            input_recorder: None,
+            fast_input: false,
+            input_frames: Vec::new(),
+            paused: false,
+            held_inputs: Vec::new(),
+            ping_order: false,
+            max_players: DEFAULT_MAX_PLAYERS,
+            min_players: 0,
+            max_ping: 0,
+            relay: false,
+            relayed: VecDeque::new(),
+            teams: Vec::new(),
+            advertise: true,
+            advertised: Instant::now(),
+            desync_reported: None,
+            handoff: false,
+        }
+    }
+    // the creator; in a persistent room, whoever has been seated longest
+    pub fn is_owner(&self, user: &User) -> bool {
+        if !self.persistent {
+            return self.creator_id == from_utf8_lossy(&user.name);
         }
+        let first = self.players.iter().find_map(|p| match p {
+            PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) => Some(*addr),
+            PlayerAddr::None => None,
+        });
+        first == Some(user.ip_addr)
     }
     pub fn player_some_count(&self) -> usize {
         self.players
@@ -173,6 +469,184 @@ impl Room {
             })
             .count()
     }
+    // the seat a player joining the running game holds until the next game, the
+    // first one left empty
+    pub fn handoff_seat(&self) -> Option<usize> {
+        if !self.handoff || self.game_status != GAME_STATUS_PLAYING {
+            return None;
+        }
+        self.players.iter().position(|p| p.is_none())
+    }
+    // players still in the running game, seated players are player_some_count
+    pub fn active_count(&self) -> usize {
+        self.players.iter().filter(|p| p.is_playing()).count()
+    }
+    pub fn begin_session(&mut self) {
+        self.input_frames = vec![0; self.players.len()];
+        self.paused = false;
+        self.held_inputs.clear();
+        self.history.push(GameSession {
+            started_at: chrono::Local::now(),
+            started: Instant::now(),
+            drops: Vec::new(),
+            duration: None,
+        });
+    }
+    // call after the player's slot is updated. returns true when this ended the session.
+    pub fn record_drop(&mut self, name: String) -> bool {
+        let playing = self.players.iter().any(|p| p.is_playing());
+        if let Some(session) = self.history.last_mut() {
+            if session.duration.is_some() {
+                return false;
+            }
+            let elapsed = session.started.elapsed();
+            session.drops.push((name, elapsed));
+            if !playing {
+                session.duration = Some(elapsed);
+                return true;
+            }
+        }
+        false
+    }
+    pub fn history_lines(&self) -> Vec<String> {
+        let fmt = |d: &Duration| format!("{}m{:02}s", d.as_secs() / 60, d.as_secs() % 60);
+        let mut lines = Vec::new();
+        for (i, session) in self.history.iter().enumerate() {
+            let mut line = format!(
+                "#{} {} {}",
+                i + 1,
+                session.started_at.format("%H:%M"),
+                match &session.duration {
+                    Some(d) => fmt(d),
+                    None => "playing".to_string(),
+                }
+            );
+            if !session.drops.is_empty() {
+                let drops: Vec<_> = session
+                    .drops
+                    .iter()
+                    .map(|(name, t)| format!("{} ({})", name, fmt(t)))
+                    .collect();
+                line += &format!(", dropped: {}", drops.join(", "));
+            }
+            lines.push(line);
+        }
+        lines
+    }
+    // back to WAITING with every player idle. returns true when this ended a running session.
+    pub fn force_end(&mut self) -> bool {
+        for p in self.players.iter_mut() {
+            if let PlayerAddr::Playing(addr) = p {
+                *p = PlayerAddr::Idle(*addr);
+            }
+        }
+        self.game_status = GAME_STATUS_WAITING;
+        self.ready_check_running = false;
+        self.counting_down = false;
+        self.ready_players.clear();
+        self.paused = false;
+        self.held_inputs.clear();
+        match self.history.last_mut() {
+            Some(session) if session.duration.is_none() => {
+                session.duration = Some(session.started.elapsed());
+                true
+            }
+            _ => false,
+        }
+    }
+    // stable sort of the players by ping, empty slots last.
+    pub fn order_players_by_ping(&mut self, ping: impl Fn(SocketAddr) -> u32) {
+        self.players.sort_by_key(|p| match p {
+            PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) => ping(*addr),
+            PlayerAddr::None => u32::MAX,
+        });
+    }
+    // exchange two seats by player number (1 based), only before the game starts
+    pub fn swap_players(&mut self, a: usize, b: usize) -> Result<(), String> {
+        if self.game_status != GAME_STATUS_WAITING {
+            return Err("players can only be swapped before the game starts".to_string());
+        }
+        let len = self.players.len();
+        if a == 0 || b == 0 || a > len || b > len {
+            return Err(format!("player numbers are 1 to {}", len));
+        }
+        self.players.swap(a - 1, b - 1);
+        Ok(())
+    }
+    // None takes the player off their team
+    pub fn set_team(&mut self, addr: SocketAddr, label: Option<String>) {
+        self.teams.retain(|(a, _)| *a != addr);
+        if let Some(label) = label {
+            self.teams.push((addr, label));
+        }
+    }
+    // "team A: P1 kim, P3 lee" per label in label order, seated players by seat
+    pub fn team_lines(&self, describe: impl Fn(SocketAddr) -> String) -> Vec<String> {
+        let mut labels: Vec<&String> = self.teams.iter().map(|(_, l)| l).collect();
+        labels.sort();
+        labels.dedup();
+        labels
+            .into_iter()
+            .map(|label| {
+                let members: Vec<String> = self
+                    .players
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, p)| match p {
+                        PlayerAddr::Playing(a) | PlayerAddr::Idle(a)
+                            if self.teams.contains(&(*a, label.clone())) =>
+                        {
+                            Some(format!("P{} {}", i + 1, describe(*a)))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                format!("team {}: {}", label, members.join(", "))
+            })
+            .collect()
+    }
+    // at most per_minute relayed lines in any minute
+    pub fn relay_allowed(&mut self, now: Instant, per_minute: usize) -> bool {
+        while let Some(t) = self.relayed.front() {
+            if now.duration_since(*t) < Duration::from_secs(60) {
+                break;
+            }
+            self.relayed.pop_front();
+        }
+        if self.relayed.len() >= per_minute {
+            return false;
+        }
+        self.relayed.push_back(now);
+        true
+    }
+    // lobby line for a waiting room with free seats, at most once per every
+    pub fn advertisement(&mut self, now: Instant, every: Duration) -> Option<String> {
+        let seated = self.player_some_count();
+        if !self.advertise
+            || self.game_status != GAME_STATUS_WAITING
+            || seated == 0
+            || seated >= self.max_players as usize
+            || now.duration_since(self.advertised) < every
+        {
+            return None;
+        }
+        self.advertised = now;
+        let missing = self.max_players as usize - seated;
+        Some(format!(
+            "Room #{} ({}) needs {} more player{} for {}",
+            self.game_id,
+            self.creator_id,
+            missing,
+            if missing == 1 { "" } else { "s" },
+            self.game_name
+        ))
+    }
+    pub fn all_ready(&self) -> bool {
+        self.players.iter().all(|p| match p {
+            PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) => self.ready_players.contains(addr),
+            PlayerAddr::None => true,
+        })
+    }
 }
 
 #[derive(Error, Debug)]
@@ -193,10 +667,13 @@ pub enum KailleraError {
     NotFoundUser { message: String },
 }
 
+// every session and room, owned by the single service task. each room sits
+// behind its own RefCell; handlers borrow only the room they work on and
+// release it before awaiting a send (see seated_users).
 pub struct UserRoom {
     pub users: HashMap<SocketAddr, Rc<RefCell<User>>>,
-    pub rooms: HashMap<u32, Rc<RefCell<Room>>>,
-    pub next_user_id: u16,
+    pub rooms: HashMap<GameId, Rc<RefCell<Room>>>,
+    pub next_user_id: UserId,
 }
 impl fmt::Display for UserRoom {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -239,7 +716,7 @@ impl UserRoom {
         UserRoom {
             users: HashMap::new(),
             rooms: HashMap::new(),
-            next_user_id: 0,
+            next_user_id: UserId(0),
         }
     }
     //
@@ -250,6 +727,9 @@ impl UserRoom {
         let conntype = user.borrow().connect_type;
         let players_num = room.borrow().players.len();
         let atomic_length = user.borrow().atomic_input_size;
+        if user.borrow().players_input.len() < players_num {
+            anyhow::bail!("no input queues, the game was not started");
+        }
         let mut all_input = true;
         for i in 0..players_num {
             trace!(
@@ -259,22 +739,22 @@ impl UserRoom {
                 !room.borrow().players[i].is_none()
             );
         }
+        // a frame of every player, for the frames one message carries
+        let require_bytes = conntype as usize * atomic_length as usize;
         for i in 0..players_num {
             let is_exist_user = room.borrow().players[i].is_playing();
             if !is_exist_user {
                 info!("because user is not exist, make game data");
                 let t = &mut user.borrow_mut().players_input[i];
-                let require_bytes = conntype * atomic_length as u8;
-                if t.len() < require_bytes as usize {
-                    t.append(&mut vec![0; require_bytes as usize - t.len()]);
+                if t.len() < require_bytes {
+                    t.resize(require_bytes, 0);
                 }
-                assert!(t.len() >= require_bytes as usize);
             }
             let l = match user.borrow().players_input.get(i) {
-                Some(i) => i.len() as u8,
+                Some(i) => i.len(),
                 None => break,
             };
-            if l < conntype * atomic_length {
+            if l < require_bytes {
                 all_input = false;
                 break;
             }
@@ -283,32 +763,77 @@ impl UserRoom {
             anyhow::bail!("yet");
         }
 
-        let mut ret = Vec::new();
-        for _ in 0..conntype {
+        let mut user = user.borrow_mut();
+        let mut ret = user.pool.take();
+        ret.reserve(conntype as usize * atomic_length as usize * players_num);
+        for f in 0..conntype as usize {
             for i in 0..players_num {
-                {
-                    let mut t =
-                        user.borrow().players_input[i].clone()[..atomic_length as usize].to_vec();
-                    ret.append(&mut t);
-                }
-                {
-                    let t = &mut user.borrow_mut().players_input[i];
-                    *t = t[atomic_length as usize..].to_vec();
-                }
+                let start = f * atomic_length as usize;
+                ret.extend_from_slice(
+                    &user.players_input[i][start..start + atomic_length as usize],
+                );
             }
         }
+        // drop the consumed frames in one move instead of once per frame
+        for i in 0..players_num {
+            user.players_input[i].drain(..conntype as usize * atomic_length as usize);
+        }
         Ok(ret)
     }
     pub fn test_func(&mut self) {}
-    pub fn get_room(&mut self, game_id: u32) -> Result<Rc<RefCell<Room>>, KailleraError> {
+    pub fn get_room(&mut self, game_id: GameId) -> Result<Rc<RefCell<Room>>, KailleraError> {
         let r = self.rooms.get(&game_id).ok_or(KailleraError::NotFound)?;
         Ok(r.clone())
     }
+    pub fn find_user_by_name(&self, name: &[u8]) -> Option<Rc<RefCell<User>>> {
+        self.users
+            .values()
+            .find(|u| u.borrow().name == name)
+            .cloned()
+    }
+    // whether a room other than except is called name
+    pub fn game_name_taken(&self, name: &[u8], except: Option<GameId>) -> bool {
+        let name = String::from_utf8_lossy(name);
+        self.rooms
+            .iter()
+            .any(|(id, r)| Some(*id) != except && r.borrow().game_name == name)
+    }
+    // name, or name with the lowest " #n" from 2 up that no room but except uses
+    pub fn free_game_name(&self, name: &[u8], except: Option<GameId>) -> Vec<u8> {
+        if !self.game_name_taken(name, except) {
+            return name.to_vec();
+        }
+        (2..)
+            .map(|n| [name, format!(" #{}", n).as_bytes()].concat())
+            .find(|x| !self.game_name_taken(x, except))
+            .unwrap_or_default()
+    }
     pub fn get_user(&mut self, ip_addr: SocketAddr) -> Result<Rc<RefCell<User>>, KailleraError> {
         let user = self.users.get(&ip_addr).ok_or(KailleraError::NotFound)?;
         Ok(user.clone())
     }
-    pub fn add_room(&mut self, ch: u32, r: Rc<RefCell<Room>>) -> Result<(), KailleraError> {
+    // users in the room's slots, in slot order. collecting them first lets the
+    // caller send to each without keeping the room borrowed across awaits.
+    pub fn seated_users(
+        &self,
+        room: &Rc<RefCell<Room>>,
+    ) -> Result<Vec<Rc<RefCell<User>>>, KailleraError> {
+        room.borrow()
+            .players
+            .iter()
+            .filter_map(|p| match p {
+                PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) => Some(*addr),
+                PlayerAddr::None => None,
+            })
+            .map(|addr| {
+                self.users
+                    .get(&addr)
+                    .cloned()
+                    .ok_or(KailleraError::NotFound)
+            })
+            .collect()
+    }
+    pub fn add_room(&mut self, ch: GameId, r: Rc<RefCell<Room>>) -> Result<(), KailleraError> {
         match self.rooms.get(&ch) {
             Some(_s) => {
                 return Err(KailleraError::AlreadyError {
@@ -321,7 +846,7 @@ impl UserRoom {
         }
         Ok(())
     }
-    pub fn delete_room(&mut self, ch: u32) -> Result<(), KailleraError> {
+    pub fn delete_room(&mut self, ch: GameId) -> Result<(), KailleraError> {
         match self.rooms.remove(&ch) {
             Some(_s) => {}
             None => {
@@ -330,47 +855,69 @@ impl UserRoom {
         }
         Ok(())
     }
-    pub fn make_server_status(&self, exclude: SocketAddr) -> anyhow::Result<Protocol> {
-        let mut data = Vec::new();
-        data.push(0u8);
-        data.append(&mut bincode::serialize::<u32>(
-            &(self.users.len() as u32 - 1),
-        )?);
-        data.append(&mut bincode::serialize::<u32>(&(self.rooms.len() as u32))?);
-
-        for i in &self.users {
-            let u = i.1.borrow();
-            let ip_addr = u.ip_addr;
-            if ip_addr != exclude {
-                data.append(&mut u.name.clone());
-                data.push(0u8);
-                data.append(&mut bincode::serialize::<u32>(&u.ping)?);
-                data.push(
-                    num::ToPrimitive::to_u8(&u.player_status).ok_or(KailleraError::NotFound)?,
-                );
-                data.append(&mut bincode::serialize::<u16>(&u.user_id)?);
-                data.push(u.connect_type);
+    pub fn make_server_status(
+        &self,
+        exclude: SocketAddr,
+        grades: GradeDisplay,
+    ) -> anyhow::Result<Protocol> {
+        let mut users = Vec::new();
+        for u in self.users.values() {
+            let u = u.borrow();
+            if u.ip_addr != exclude {
+                let (name, connection_type) = u.listed_as(grades);
+                users.push(StatusUser {
+                    name,
+                    ping: u.shown_ping,
+                    status: num::ToPrimitive::to_u8(&u.player_status)
+                        .ok_or(KailleraError::NotFound)?,
+                    user_id: u.user_id,
+                    connection_type,
+                });
             }
         }
-        for i in &self.rooms {
-            data.append(&mut i.1.borrow().game_name.clone().into_bytes());
-            data.push(0u8);
-            data.append(&mut bincode::serialize::<u32>(&i.1.borrow().game_id)?);
-            data.append(&mut i.1.borrow().emul_name.clone().into_bytes());
-            data.push(0u8);
-            data.append(&mut i.1.borrow().creator_id.clone().into_bytes());
-            data.push(0u8);
-            data.append(
-                &mut format!("{}/{}\x00", i.1.borrow().player_some_count(), 4)
-                    .as_bytes()
-                    .to_vec(),
-            );
-            data.push(i.1.borrow().game_status);
-        }
-        let p = Protocol::new(USER_SERVER_STATUS, data);
-        Ok(p)
+        let games = self
+            .rooms
+            .values()
+            .map(|r| {
+                let r = r.borrow();
+                StatusGame {
+                    name: r.game_name.clone().into_bytes(),
+                    game_id: r.game_id,
+                    emulator: r.emul_name.clone().into_bytes(),
+                    owner: r.creator_id.clone().into_bytes(),
+                    players: format!("{}/{}", r.player_some_count(), r.max_players).into_bytes(),
+                    status: r.game_status,
+                }
+            })
+            .collect();
+        let data = ServerStatus2Client::new(users, games).packetize()?;
+        Ok(Protocol::new(USER_SERVER_STATUS, data))
     }
 
+    // PLAYER_INFO body listing the room's players other than exclude, in seat order
+    pub fn player_info(
+        &self,
+        room: &Rc<RefCell<Room>>,
+        exclude: SocketAddr,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut players = Vec::new();
+        for i in &room.borrow().players {
+            if let PlayerAddr::Idle(addr) | PlayerAddr::Playing(addr) = *i {
+                if addr == exclude {
+                    continue;
+                }
+                let u = self.users.get(&addr).ok_or(KailleraError::NotFound)?;
+                let u = u.borrow();
+                players.push(RoomPlayer {
+                    name: u.name.clone(),
+                    ping: u.shown_ping,
+                    user_id: u.user_id,
+                    connection_type: u.connect_type,
+                });
+            }
+        }
+        PlayerInfo2Client::new(players).packetize()
+    }
     // send GAME_CHAT to players of room
     pub async fn send_game_chat_to_players(
         &mut self,
@@ -380,21 +927,284 @@ impl UserRoom {
         message: Vec<u8>,
     ) -> anyhow::Result<()> {
         // send GAME_CHAT to players of room
-        for i in &room.borrow().players {
-            let mut data = Vec::new();
-            data.append(&mut who.clone().into_bytes());
-            data.push(0u8);
-            data.append(&mut message.clone());
-            match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => {
-                    let u = self.get_user(*i)?;
-                    u.borrow_mut()
-                        .make_send_packet(server_socket, Protocol::new(GAME_CHAT, data))
-                        .await?;
-                }
-                PlayerAddr::None => {}
+        let bodies = chat_bodies(who.as_bytes(), &message);
+        for u in self.seated_users(&room)? {
+            for data in &bodies {
+                User::make_send_packet(&u, server_socket, Protocol::new(GAME_CHAT, data.clone()))
+                    .await?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_status_and_player_info_parse() {
+        let mut users = UserRoom::new();
+        let mut room = Room::new();
+        for i in 1..=3u8 {
+            let mut user = User::new(addr(i as usize));
+            user.name = format!("p{}", i).into_bytes();
+            user.user_id = UserId(i as u16);
+            user.shown_ping = 10 * i as u32;
+            user.connect_type = i;
+            users
+                .users
+                .insert(user.ip_addr, Rc::new(RefCell::new(user)));
+            room.players.push(PlayerAddr::Idle(addr(i as usize)));
+        }
+        room.game_name = "kof98".to_string();
+        room.game_id = GameId(7);
+        room.max_players = 4;
+        let room = Rc::new(RefCell::new(room));
+        users.add_room(GameId(7), room.clone()).unwrap();
+
+        let status = users
+            .make_server_status(addr(1), GradeDisplay::Off)
+            .unwrap();
+        let status = ServerStatus2Client::parse(&status.data).unwrap();
+        let mut names: Vec<_> = status.users.iter().map(|u| u.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec![b"p2".to_vec(), b"p3".to_vec()]);
+        assert_eq!(status.games.len(), 1);
+        assert_eq!(status.games[0].game_id, GameId(7));
+        assert_eq!(status.games[0].players, b"3/4");
+
+        let info = users.player_info(&room, addr(2)).unwrap();
+        let info = PlayerInfo2Client::parse(&info).unwrap();
+        let listed: Vec<_> = info
+            .players
+            .iter()
+            .map(|p| (p.name.clone(), p.ping, p.user_id, p.connection_type))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (b"p1".to_vec(), 10, UserId(1), 1),
+                (b"p3".to_vec(), 30, UserId(3), 3)
+            ]
+        );
+        assert_eq!(
+            PlayerInfo2Client::new(info.players.clone())
+                .packetize()
+                .unwrap(),
+            users.player_info(&room, addr(2)).unwrap()
+        );
+    }
+
+    #[test]
+    fn swap_players() {
+        let mut room = Room::new();
+        room.players = vec![PlayerAddr::Idle(addr(1)), PlayerAddr::Idle(addr(2))];
+        room.swap_players(2, 1).unwrap();
+        assert!(matches!(room.players[0], PlayerAddr::Idle(a) if a == addr(2)));
+        assert!(room.swap_players(1, 3).is_err());
+        room.game_status = GAME_STATUS_PLAYING;
+        assert!(room.swap_players(1, 2).is_err());
+    }
+
+    #[test]
+    fn repeated_inputs() {
+        let mut user = User::new(addr(1));
+        assert!(user.fresh_input(10));
+        assert!(user.fresh_input(11));
+        assert!(!user.fresh_input(11));
+        assert!(!user.fresh_input(9));
+        // wraps around
+        user.last_input_seq = Some(0xffff);
+        assert!(user.fresh_input(0));
+        user.reset_outcoming();
+        assert!(user.fresh_input(5));
+    }
+
+    #[test]
+    fn rate_caps() {
+        let start = Instant::now();
+        let mut rate = InputRate::default();
+        assert!(!rate.note(start, 100, 2, 0));
+        assert!(!rate.note(start, 100, 2, 0));
+        assert!(rate.note(start, 100, 2, 0));
+        let next = start + Duration::from_secs(1);
+        assert!(!rate.note(next, 200, 0, 250));
+        assert!(rate.note(next, 100, 0, 250));
+    }
+
+    #[test]
+    fn team_lines() {
+        let mut room = Room::new();
+        room.players = (1..=4).map(|i| PlayerAddr::Idle(addr(i))).collect();
+        room.set_team(addr(3), Some("B".to_string()));
+        room.set_team(addr(1), Some("A".to_string()));
+        room.set_team(addr(4), Some("A".to_string()));
+        room.set_team(addr(2), Some("B".to_string()));
+        room.set_team(addr(3), Some("A".to_string()));
+        let lines = room.team_lines(|a| format!("u{}", a.port() % 10));
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("team A: P1 u"));
+        assert_eq!(lines[0].matches(", ").count(), 2);
+        room.set_team(addr(2), None);
+        assert_eq!(room.team_lines(|_| String::new()).len(), 1);
+    }
+
+    #[test]
+    fn advertises_waiting_rooms() {
+        let start = Instant::now();
+        let every = Duration::from_secs(300);
+        let mut room = Room::new();
+        room.game_id = GameId(3);
+        room.creator_id = "kim".to_string();
+        room.game_name = "KOF98".to_string();
+        room.max_players = 2;
+        room.advertised = start;
+        room.players.push(PlayerAddr::Idle(addr(1)));
+        assert_eq!(
+            room.advertisement(start + Duration::from_secs(10), every),
+            None
+        );
+        let later = start + every;
+        assert_eq!(
+            room.advertisement(later, every).unwrap(),
+            "Room #3 (kim) needs 1 more player for KOF98"
+        );
+        // rate limited
+        assert_eq!(
+            room.advertisement(later + Duration::from_secs(1), every),
+            None
+        );
+        room.players.push(PlayerAddr::Idle(addr(2)));
+        assert_eq!(room.advertisement(later + every, every), None);
+    }
+
+    #[test]
+    fn chat_timestamps() {
+        let zone = parse_utc_offset("+9").unwrap();
+        assert_eq!(zone.local_minus_utc(), 9 * 3600);
+        assert_eq!(
+            parse_utc_offset("-3:30").unwrap().local_minus_utc(),
+            -(3 * 3600 + 30 * 60)
+        );
+        assert!(parse_utc_offset("9").is_none());
+        assert!(parse_utc_offset("+25").is_none());
+        let now = DateTime::parse_from_rfc3339("2024-01-01T23:05:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(timestamp_chat(now, zone, b"hi\x00"), b"[08:05] hi".to_vec());
+        let mut user = User::new(addr(1));
+        assert_eq!(user.chat_text(now, b"hi"), b"hi".to_vec());
+        user.chat_clock = Some(zone);
+        assert_eq!(user.chat_text(now, b"hi"), b"[08:05] hi".to_vec());
+    }
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i as u8], 27999))
+    }
+
+    #[test]
+    fn relay_flood_limit() {
+        let mut room = Room::new();
+        let start = Instant::now();
+        assert!(room.relay_allowed(start, 2));
+        assert!(room.relay_allowed(start + Duration::from_secs(10), 2));
+        assert!(!room.relay_allowed(start + Duration::from_secs(20), 2));
+        assert!(room.relay_allowed(start + Duration::from_secs(61), 2));
+    }
+
+    #[test]
+    fn gen_input_8_players() {
+        let players = MAX_PLAYERS_LIMIT as usize;
+        let mut room = Room::new();
+        for i in 0..players {
+            room.players.push(PlayerAddr::Playing(addr(i)));
+        }
+        let room = Rc::new(RefCell::new(room));
+        let mut user = User::new(addr(0));
+        user.connect_type = 2;
+        user.atomic_input_size = 2;
+        user.players_input.resize(players, Vec::new());
+        let user = Rc::new(RefCell::new(user));
+        for i in 0..players - 1 {
+            let p = i as u8;
+            user.borrow_mut().players_input[i] = vec![p, p, p + 0x10, p + 0x10];
+        }
+        assert!(UserRoom::gen_input(user.clone(), room.clone()).is_err());
+        user.borrow_mut().players_input[players - 1] = vec![7, 7, 0x17, 0x17];
+        let merged = UserRoom::gen_input(user.clone(), room).unwrap();
+        let mut expected = Vec::new();
+        for frame in [0u8, 0x10] {
+            for p in 0..players as u8 {
+                expected.extend_from_slice(&[p + frame, p + frame]);
+            }
+        }
+        assert_eq!(merged, expected);
+        assert!(user.borrow().players_input.iter().all(|x| x.is_empty()));
+    }
+
+    #[test]
+    fn free_game_names() {
+        let mut user_room = UserRoom::new();
+        for (id, name) in [(1, "KOF98"), (2, "KOF98 #2")] {
+            let mut room = Room::new();
+            room.game_name = name.to_string();
+            user_room
+                .rooms
+                .insert(GameId(id), Rc::new(RefCell::new(room)));
+        }
+        assert!(user_room.game_name_taken(b"KOF98", None));
+        assert!(!user_room.game_name_taken(b"KOF98", Some(GameId(1))));
+        assert_eq!(user_room.free_game_name(b"KOF98", None), b"KOF98 #3");
+        assert_eq!(
+            user_room.free_game_name(b"KOF98 #2", Some(GameId(2))),
+            b"KOF98 #2"
+        );
+        assert_eq!(user_room.free_game_name(b"SF2", None), b"SF2");
+    }
+
+    #[test]
+    fn seated_users_in_slot_order() {
+        let mut user_room = UserRoom::new();
+        for i in 0..3 {
+            let user = Rc::new(RefCell::new(User::new(addr(i))));
+            user_room.users.insert(addr(i), user);
+        }
+        let mut room = Room::new();
+        room.players.push(PlayerAddr::Idle(addr(2)));
+        room.players.push(PlayerAddr::None);
+        room.players.push(PlayerAddr::Playing(addr(0)));
+        let room = Rc::new(RefCell::new(room));
+        let seated: Vec<_> = user_room
+            .seated_users(&room)
+            .unwrap()
+            .iter()
+            .map(|u| u.borrow().ip_addr)
+            .collect();
+        assert_eq!(seated, vec![addr(2), addr(0)]);
+        // the room is free again for the caller
+        room.borrow_mut().players.push(PlayerAddr::Idle(addr(1)));
+        assert_eq!(user_room.seated_users(&room).unwrap().len(), 3);
+        room.borrow_mut().players.push(PlayerAddr::Idle(addr(9)));
+        assert!(user_room.seated_users(&room).is_err());
+    }
+
+    #[test]
+    fn gen_input_fills_dropped_player() {
+        let mut room = Room::new();
+        for i in 0..MAX_PLAYERS_LIMIT as usize {
+            room.players.push(PlayerAddr::Playing(addr(i)));
+        }
+        room.players[5] = PlayerAddr::Idle(addr(5));
+        let room = Rc::new(RefCell::new(room));
+        let mut user = User::new(addr(0));
+        user.connect_type = 1;
+        user.atomic_input_size = 2;
+        user.players_input.resize(8, vec![1, 1]);
+        user.players_input[5].clear();
+        let user = Rc::new(RefCell::new(user));
+        let merged = UserRoom::gen_input(user, room).unwrap();
+        assert_eq!(merged.len(), 16);
+        assert_eq!(&merged[10..12], &[0, 0]);
+    }
+}
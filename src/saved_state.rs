@@ -0,0 +1,121 @@
+// what operators set up at runtime, written to state_file every state_save_secs
+// and read back at startup so a crash or a deploy does not lose it: bans and
+// mutes still running, the /redirect target, and the settings persistent rooms
+// have now (persistent_rooms_file has them as of /persist). users and their
+// rooms are not kept, clients log in again on their own.
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::persistent_rooms::PersistentRoom;
+use crate::punishment::Punishments;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedPunishment {
+    pub ip: IpAddr,
+    // left when saved
+    #[serde(default)]
+    pub banned_secs: u64,
+    #[serde(default)]
+    pub muted_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    // rfc 3339
+    pub saved_at: String,
+    #[serde(default)]
+    pub punishments: Vec<SavedPunishment>,
+    #[serde(default)]
+    pub redirect: Option<String>,
+    #[serde(default)]
+    pub persistent_rooms: Vec<PersistentRoom>,
+}
+
+impl SavedState {
+    pub fn new(punishments: &Punishments, now: Instant) -> SavedState {
+        let mut saved: Vec<_> = punishments
+            .records
+            .iter()
+            .filter_map(|(ip, r)| {
+                let left = |until: Option<Instant>| {
+                    until.map_or(0, |t| t.saturating_duration_since(now).as_secs())
+                };
+                let (banned_secs, muted_secs) = (left(r.banned_until), left(r.muted_until));
+                (banned_secs > 0 || muted_secs > 0).then_some(SavedPunishment {
+                    ip: *ip,
+                    banned_secs,
+                    muted_secs,
+                })
+            })
+            .collect();
+        saved.sort_by_key(|x| x.ip);
+        SavedState {
+            saved_at: Utc::now().to_rfc3339(),
+            punishments: saved,
+            redirect: None,
+            persistent_rooms: Vec::new(),
+        }
+    }
+    // None when there is no state file yet
+    pub fn load(path: &Path) -> anyhow::Result<Option<SavedState>> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                Ok(Some(serde_json::from_str(&text).map_err(|e| {
+                    anyhow::anyhow!("{}: {}", path.display(), e)
+                })?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    pub fn to_text(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    // puts the bans and mutes back, less the time the server was down
+    pub fn restore_punishments(&self, punishments: &mut Punishments, now: Instant) {
+        let down = DateTime::parse_from_rfc3339(&self.saved_at).map_or(0, |t| {
+            (Utc::now() - t.with_timezone(&Utc)).num_seconds().max(0) as u64
+        });
+        for x in &self.punishments {
+            let left = |secs: u64| Duration::from_secs(secs.saturating_sub(down));
+            punishments.restore(x.ip, now, left(x.banned_secs), left(x.muted_secs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_running_punishments() {
+        let now = Instant::now();
+        let mut punishments = Punishments::new();
+        let (banned, muted, over) = (
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            "10.0.0.3".parse().unwrap(),
+        );
+        punishments.restore(banned, now, Duration::from_secs(600), Duration::ZERO);
+        punishments.restore(muted, now, Duration::ZERO, Duration::from_secs(60));
+        punishments.restore(over, now, Duration::ZERO, Duration::ZERO);
+        let mut state = SavedState::new(&punishments, now);
+        assert_eq!(state.punishments.len(), 2);
+        assert_eq!(state.punishments[0].banned_secs, 600);
+
+        // saved two minutes ago
+        state.saved_at = (Utc::now() - chrono::Duration::seconds(120)).to_rfc3339();
+        let state: SavedState = serde_json::from_str(&state.to_text().unwrap()).unwrap();
+        let mut restored = Punishments::new();
+        state.restore_punishments(&mut restored, now);
+        let left = restored.banned_for(banned, now).unwrap().as_secs();
+        assert!((470..=480).contains(&left));
+        assert!(restored.muted_for(muted, now).is_none());
+        assert!(restored.banned_for(over, now).is_none());
+    }
+}
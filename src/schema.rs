@@ -0,0 +1,606 @@
+// field layout of every message body, per direction. server to client layouts
+// come from the FIELDS of the packet builders in protocol.rs; the wireshark
+// dissector and the protocol reference (docs/protocol.md, GET /protocol on the bot
+// api) are generated from here.
+use std::fmt::Write;
+
+use crate::protocol::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    // nul terminated
+    Str,
+    // everything up to the end of the message
+    Bytes,
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: FieldType,
+}
+
+#[derive(Debug)]
+pub struct MessageSchema {
+    pub message_type: u8,
+    pub name: &'static str,
+    pub doc: &'static str,
+    // None: never sent in that direction
+    pub to_server: Option<&'static [Field]>,
+    pub to_client: Option<&'static [Field]>,
+}
+
+pub const fn f(name: &'static str, ty: FieldType) -> Field {
+    Field { name, ty }
+}
+
+use FieldType::*;
+
+const ACK: &[Field] = AckPacket2Client::FIELDS;
+const CHAT_TO_SERVER: &[Field] = &[f("unused", Str), f("message", Str)];
+const GAME_DATA_BODY: &[Field] = GameData2Client::FIELDS;
+const GAME_CACHE_BODY: &[Field] = GameCache2Client::FIELDS;
+const READY_BODY: &[Field] = &[f("unused", U8)];
+
+pub const MESSAGES: &[MessageSchema] = &[
+    MessageSchema {
+        message_type: USER_QUIT,
+        name: "USER_QUIT",
+        doc: "leave the server. the server relays it to everyone with the user's name and id.",
+        to_server: Some(&[f("unused", Str), f("unused_id", U16), f("message", Str)]),
+        to_client: Some(UserQuitPacket2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: USER_JOIN,
+        name: "USER_JOIN",
+        doc: "a user logged in.",
+        to_server: None,
+        to_client: Some(UserJoinPacket2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: USER_LOGIN_INFO,
+        name: "USER_LOGIN_INFO",
+        doc: "first message of a session, sent with seq 0.",
+        to_server: Some(&[
+            f("user_name", Str),
+            f("emulator", Str),
+            f("connection_type", U8),
+        ]),
+        to_client: None,
+    },
+    MessageSchema {
+        message_type: USER_SERVER_STATUS,
+        name: "USER_SERVER_STATUS",
+        doc: "user and game list sent after login. users: name, ping u32, status u8, user_id u16, connection_type u8. games: name, game_id u32, emulator, owner, \"players/max\", status u8.",
+        to_server: None,
+        to_client: Some(ServerStatus2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: S2C_ACK,
+        name: "S2C_ACK",
+        doc: "ping measurement, the client answers with C2S_ACK.",
+        to_server: None,
+        to_client: Some(ACK),
+    },
+    MessageSchema {
+        message_type: C2S_ACK,
+        name: "C2S_ACK",
+        doc: "answer to S2C_ACK.",
+        to_server: Some(ACK),
+        to_client: None,
+    },
+    MessageSchema {
+        message_type: GLOBAL_CHAT,
+        name: "GLOBAL_CHAT",
+        doc: "lobby chat. lines starting with / are server commands.",
+        to_server: Some(CHAT_TO_SERVER),
+        to_client: Some(GlobalChat2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: GAME_CHAT,
+        name: "GAME_CHAT",
+        doc: "chat inside a game room. lines starting with / are room commands.",
+        to_server: Some(CHAT_TO_SERVER),
+        to_client: Some(GameChat2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: KEEPALIVE,
+        name: "KEEPALIVE",
+        doc: "keeps an idle session alive.",
+        to_server: Some(&[]),
+        to_client: None,
+    },
+    MessageSchema {
+        message_type: CREATE_GAME,
+        name: "CREATE_GAME",
+        doc: "create a room. the server announces it to everyone.",
+        to_server: Some(&[
+            f("unused", Str),
+            f("game_name", Str),
+            f("unused_emulator", Str),
+            f("unused_id", U32),
+        ]),
+        to_client: Some(CreateGame2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: QUIT_GAME,
+        name: "QUIT_GAME",
+        doc: "leave the room.",
+        to_server: Some(&[f("unused", Str), f("unused_id", U16)]),
+        to_client: Some(QuitGame2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: JOIN_GAME,
+        name: "JOIN_GAME",
+        doc: "join a room. the server answers with PLAYER_INFO and relays JOIN_GAME to the room.",
+        to_server: Some(&[
+            f("unused", U8),
+            f("game_id", U32),
+            f("unused_name", Str),
+            f("unused_ping", U32),
+            f("unused_id", U16),
+            f("connection_type", U8),
+        ]),
+        to_client: Some(JoinGame2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: PLAYER_INFO,
+        name: "PLAYER_INFO",
+        doc: "players already in the room: name, ping u32, user_id u16, connection_type u8 each.",
+        to_server: None,
+        to_client: Some(PlayerInfo2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: UPDATE_GAME_STATUS,
+        name: "UPDATE_GAME_STATUS",
+        doc: "room status changed. status 0 waiting, 1 playing, 2 netsync.",
+        to_server: None,
+        to_client: Some(UpdateGameStatus2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: KICK_USER_FROM_GAME,
+        name: "KICK_USER_FROM_GAME",
+        doc: "room owner removes a player.",
+        to_server: Some(&[f("unused", U8), f("user_id", U16)]),
+        to_client: None,
+    },
+    MessageSchema {
+        message_type: CLOSE_GAME,
+        name: "CLOSE_GAME",
+        doc: "the room was closed.",
+        to_server: None,
+        to_client: Some(&[f("unused", U8), f("game_id", U32)]),
+    },
+    MessageSchema {
+        message_type: START_GAME,
+        name: "START_GAME",
+        doc: "owner starts the game; the server answers each player with its player number and frame delay.",
+        to_server: Some(&[
+            f("unused", U8),
+            f("unused_delay", U16),
+            f("unused_player", U8),
+            f("unused_total", U8),
+        ]),
+        to_client: Some(StartGame2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: GAME_DATA,
+        name: "GAME_DATA",
+        doc: "input frames. the server merges every player's input and sends it back.",
+        to_server: Some(GAME_DATA_BODY),
+        to_client: Some(GAME_DATA_BODY),
+    },
+    MessageSchema {
+        message_type: GAME_CACHE,
+        name: "GAME_CACHE",
+        doc: "repeat of an input already sent, by its position in the 256 entry cache.",
+        to_server: Some(GAME_CACHE_BODY),
+        to_client: Some(GAME_CACHE_BODY),
+    },
+    MessageSchema {
+        message_type: DROP_GAME,
+        name: "DROP_GAME",
+        doc: "a player left the running game.",
+        to_server: Some(&[f("unused", Str), f("unused_player", U8)]),
+        to_client: Some(GameDrop2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: READY_TO_PLAY_SIGNAL,
+        name: "READY_TO_PLAY_SIGNAL",
+        doc: "netsync done; once every player sent it the game runs.",
+        to_server: Some(READY_BODY),
+        to_client: Some(READY_BODY),
+    },
+    MessageSchema {
+        message_type: CONNECTION_REJECT,
+        name: "CONNECTION_REJECT",
+        doc: "login refused. the message starts with E<code>, see RejectReason.",
+        to_server: None,
+        to_client: Some(ConnectionReject2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: SERVER_INFO,
+        name: "SERVER_INFO",
+        doc: "login message of the server.",
+        to_server: None,
+        to_client: Some(&[f("server", Str), f("message", Str)]),
+    },
+    MessageSchema {
+        message_type: FAST_INPUT,
+        name: "FAST_INPUT",
+        doc: "direlera extension: one player's input forwarded immediately (/fastinput true).",
+        to_server: None,
+        to_client: Some(FastInput2Client::FIELDS),
+    },
+    MessageSchema {
+        message_type: GAME_PAUSE,
+        name: "GAME_PAUSE",
+        doc: "direlera extension: the owner paused (1) or resumed (0) the game, merged input is held meanwhile. only sent to clients that added PAUSE to their HELLO.",
+        to_server: None,
+        to_client: Some(GamePause2Client::FIELDS),
+    },
+];
+
+pub fn find_message(message_type: u8) -> Option<&'static MessageSchema> {
+    MESSAGES.iter().find(|m| m.message_type == message_type)
+}
+
+// whether a message from a client has every field of its layout, trailing bytes
+// are allowed. None when clients never send that type.
+pub fn fits_to_server(message_type: u8, data: &[u8]) -> Option<bool> {
+    let fields = find_message(message_type)?.to_server?;
+    let mut rest = data;
+    for x in fields {
+        let size = match x.ty {
+            U8 => 1,
+            U16 => 2,
+            U32 => 4,
+            Str => match rest.iter().position(|x| *x == 0) {
+                Some(end) => end + 1,
+                None => return Some(false),
+            },
+            Bytes => rest.len(),
+        };
+        if rest.len() < size {
+            return Some(false);
+        }
+        rest = &rest[size..];
+    }
+    Some(true)
+}
+
+// a field read back from a message body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Num(u32),
+    // Str without its NUL, or Bytes
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn num(&self) -> u32 {
+        match self {
+            Value::Num(x) => *x,
+            Value::Bytes(_) => 0,
+        }
+    }
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            Value::Num(_) => Vec::new(),
+            Value::Bytes(x) => x.clone(),
+        }
+    }
+}
+
+// the fields of a body, in order. unlike fits_to_server the body has to end
+// with the last field, None when it is short or has bytes left over.
+pub fn parse_fields(fields: &[Field], data: &[u8]) -> Option<Vec<Value>> {
+    match parse_prefix(fields, data)? {
+        (values, []) => Some(values),
+        _ => None,
+    }
+}
+
+// the fields at the start of data and what follows them, for the repeated
+// entries of list messages
+pub fn parse_prefix<'a>(fields: &[Field], data: &'a [u8]) -> Option<(Vec<Value>, &'a [u8])> {
+    let mut values = Vec::with_capacity(fields.len());
+    let mut rest = data;
+    for x in fields {
+        let value = match x.ty {
+            U8 => {
+                let (n, r) = rest.split_first()?;
+                rest = r;
+                Value::Num(*n as u32)
+            }
+            U16 => {
+                let n = rest.get(..2)?;
+                rest = &rest[2..];
+                Value::Num(u16::from_le_bytes([n[0], n[1]]) as u32)
+            }
+            U32 => {
+                let n = rest.get(..4)?;
+                rest = &rest[4..];
+                Value::Num(u32::from_le_bytes([n[0], n[1], n[2], n[3]]))
+            }
+            Str => {
+                let end = rest.iter().position(|x| *x == 0)?;
+                let text = rest[..end].to_vec();
+                rest = &rest[end + 1..];
+                Value::Bytes(text)
+            }
+            Bytes => {
+                let bytes = rest.to_vec();
+                rest = &[];
+                Value::Bytes(bytes)
+            }
+        };
+        values.push(value);
+    }
+    Some((values, rest))
+}
+
+fn type_name(ty: FieldType) -> (&'static str, &'static str) {
+    match ty {
+        U8 => ("u8", "1"),
+        U16 => ("u16 le", "2"),
+        U32 => ("u32 le", "4"),
+        Str => ("string", "nul terminated"),
+        Bytes => ("bytes", "rest of message"),
+    }
+}
+
+fn field_table(out: &mut String, title: &str, fields: &[Field]) {
+    writeln!(out, "{}:\n", title).unwrap();
+    if fields.is_empty() {
+        out.push_str("empty body\n\n");
+        return;
+    }
+    out.push_str("| field | type | size |\n|---|---|---|\n");
+    for x in fields {
+        let (ty, size) = type_name(x.ty);
+        writeln!(out, "| {} | {} | {} |", x.name, ty, size).unwrap();
+    }
+    out.push('\n');
+}
+
+// protocol reference in markdown, `direlera-rs protocol-doc > docs/protocol.md`
+pub fn markdown() -> String {
+    let mut out = String::new();
+    out.push_str("<!-- generated by `direlera-rs protocol-doc`, do not edit -->\n");
+    out.push_str("# Kaillera protocol\n\n");
+    out.push_str(
+        "Every datagram starts with the number of messages it carries (u8). \
+The newest message comes first, older ones are repeated so a lost datagram does not lose them.\n\n",
+    );
+    out.push_str("Each message:\n\n");
+    field_table(
+        &mut out,
+        "header",
+        &[f("seq", U16), f("length", U16), f("message_type", U8)],
+    );
+    out.push_str("`length` counts the message_type byte and the body.\n\n");
+    for m in MESSAGES {
+        writeln!(out, "## 0x{:02x} {}\n\n{}\n", m.message_type, m.name, m.doc).unwrap();
+        if let Some(fields) = m.to_server {
+            field_table(&mut out, "client to server", fields);
+        }
+        if let Some(fields) = m.to_client {
+            field_table(&mut out, "server to client", fields);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::GameId;
+
+    #[test]
+    fn schema_matches_builders() {
+        // fixed size bodies must add up to what the builders produce
+        let size = |fields: &[Field]| -> usize {
+            fields
+                .iter()
+                .map(|x| match x.ty {
+                    U8 => 1,
+                    U16 => 2,
+                    U32 => 4,
+                    Str | Bytes => 0,
+                })
+                .sum()
+        };
+        let update = find_message(UPDATE_GAME_STATUS).unwrap();
+        let data = UpdateGameStatus2Client::new(GameId(1), 0, 2, 4)
+            .packetize()
+            .unwrap();
+        assert_eq!(size(update.to_client.unwrap()), data.len());
+        let start = find_message(START_GAME).unwrap();
+        let data = StartGame2Client::new(1, 1, 2).packetize().unwrap();
+        assert_eq!(size(start.to_client.unwrap()), data.len());
+        let cache = find_message(GAME_CACHE).unwrap();
+        let data = GameCache2Client::new(3).packetize().unwrap();
+        assert_eq!(size(cache.to_client.unwrap()), data.len());
+        let data = GamePause2Client::new(true, b"kim".to_vec())
+            .packetize()
+            .unwrap();
+        assert_eq!(data, b"\x00\x01kim\x00");
+        for (i, m) in MESSAGES.iter().enumerate() {
+            assert!(MESSAGES[i + 1..]
+                .iter()
+                .all(|x| x.message_type != m.message_type));
+        }
+    }
+
+    // every server to client layout against a body the server code builds, so a
+    // builder that changes without its table fails here
+    #[test]
+    fn layouts_fit_what_is_sent() {
+        use crate::ids::UserId;
+        use crate::quality::GradeDisplay;
+        use crate::room::*;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut users = UserRoom::new();
+        let mut room = Room::new();
+        for i in 1..=2u8 {
+            let addr = std::net::SocketAddr::from(([10, 0, 0, i], 27999));
+            let mut user = User::new(addr);
+            user.name = format!("p{}", i).into_bytes();
+            users.users.insert(addr, Rc::new(RefCell::new(user)));
+            room.players.push(PlayerAddr::Idle(addr));
+        }
+        room.game_name = "kof98".to_string();
+        let room = Rc::new(RefCell::new(room));
+        users.add_room(GameId(1), room.clone()).unwrap();
+        let nobody = std::net::SocketAddr::from(([10, 0, 0, 9], 27999));
+        let status = users.make_server_status(nobody, GradeDisplay::Off).unwrap();
+        let mut close = vec![0u8];
+        close.extend(bincode::serialize(&GameId(1)).unwrap());
+
+        let sent: Vec<(u8, Vec<u8>)> = vec![
+            (
+                USER_QUIT,
+                UserQuitPacket2Client::new(b"kim".to_vec(), UserId(1), b"bye".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+            (
+                USER_JOIN,
+                UserJoinPacket2Client::new(b"kim".to_vec(), UserId(1), 20, 1)
+                    .packetize()
+                    .unwrap(),
+            ),
+            (USER_SERVER_STATUS, status.data),
+            (S2C_ACK, bincode::serialize(&AckProtocol::new()).unwrap()),
+            (
+                GLOBAL_CHAT,
+                GlobalChat2Client::new(b"kim".to_vec(), b"hi".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+            (
+                GAME_CHAT,
+                GameChat2Client::new(b"kim".to_vec(), b"hi".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+            (
+                CREATE_GAME,
+                CreateGame2Client::new(
+                    b"kim".to_vec(),
+                    b"kof98".to_vec(),
+                    b"mame".to_vec(),
+                    GameId(1),
+                )
+                .packetize()
+                .unwrap(),
+            ),
+            (
+                QUIT_GAME,
+                QuitGame2Client::new(b"kim".to_vec(), UserId(1))
+                    .packetize()
+                    .unwrap(),
+            ),
+            (
+                JOIN_GAME,
+                JoinGame2Client::new(GameId(1), b"kim".to_vec(), 20, UserId(1), 1)
+                    .packetize()
+                    .unwrap(),
+            ),
+            (PLAYER_INFO, users.player_info(&room, nobody).unwrap()),
+            (
+                UPDATE_GAME_STATUS,
+                UpdateGameStatus2Client::new(GameId(1), 0, 2, 4)
+                    .packetize()
+                    .unwrap(),
+            ),
+            (CLOSE_GAME, close),
+            (
+                START_GAME,
+                StartGame2Client::new(1, 1, 2).packetize().unwrap(),
+            ),
+            (
+                GAME_DATA,
+                GameData2Client::new(2, b"ab".to_vec()).packetize().unwrap(),
+            ),
+            (GAME_CACHE, GameCache2Client::new(3).packetize().unwrap()),
+            (
+                DROP_GAME,
+                GameDrop2Client::new(b"kim".to_vec(), 1)
+                    .packetize()
+                    .unwrap(),
+            ),
+            (READY_TO_PLAY_SIGNAL, b"\x00".to_vec()),
+            (
+                CONNECTION_REJECT,
+                ConnectionReject2Client::new(b"kim".to_vec(), UserId(1), b"E1".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+            (SERVER_INFO, chat_bodies(b"Server", b"hello").remove(0)),
+            (
+                FAST_INPUT,
+                FastInput2Client::new(1, 7, b"ab".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+            (
+                GAME_PAUSE,
+                GamePause2Client::new(true, b"kim".to_vec())
+                    .packetize()
+                    .unwrap(),
+            ),
+        ];
+        for m in MESSAGES.iter().filter(|m| m.to_client.is_some()) {
+            let body = match sent.iter().find(|(ty, _)| *ty == m.message_type) {
+                Some((_, body)) => body,
+                None => panic!("no body sent as {} to check its layout against", m.name),
+            };
+            assert!(
+                parse_fields(m.to_client.unwrap(), body).is_some(),
+                "{} layout does not fit {:02x?}",
+                m.name,
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn client_message_fits() {
+        assert_eq!(fits_to_server(GAME_CACHE, &[0, 3]), Some(true));
+        assert_eq!(fits_to_server(GAME_CACHE, &[0]), Some(false));
+        assert_eq!(fits_to_server(GAME_CHAT, b"\x00hi\x00"), Some(true));
+        assert_eq!(fits_to_server(GAME_CHAT, b"\x00hi"), Some(false));
+        assert_eq!(fits_to_server(USER_JOIN, &[]), None);
+    }
+
+    #[test]
+    fn parse_exact_body() {
+        let fields = QuitGame2Client::FIELDS;
+        assert_eq!(
+            parse_fields(fields, b"kim\x00\x02\x01"),
+            Some(vec![Value::Bytes(b"kim".to_vec()), Value::Num(0x102)])
+        );
+        assert_eq!(parse_fields(fields, b"kim\x00\x02"), None);
+        assert_eq!(parse_fields(fields, b"kim\x00\x02\x01\x00"), None);
+        assert_eq!(parse_fields(fields, b"kim"), None);
+        let data = parse_fields(GameData2Client::FIELDS, b"\x00\x02\x00ab").unwrap();
+        assert_eq!(data[2], Value::Bytes(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn protocol_doc_up_to_date() {
+        let doc = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/docs/protocol.md"))
+            .unwrap();
+        assert!(
+            doc == markdown(),
+            "docs/protocol.md is stale, run `cargo run -- protocol-doc > docs/protocol.md`"
+        );
+    }
+}
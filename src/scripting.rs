@@ -0,0 +1,247 @@
+// operator policy scripts: a WebAssembly module (script_file) with hooks the
+// server calls on login, chat, game start and game end. build with
+// `--features scripting`; without it a configured script_file is an error.
+//
+// the module exports `memory` and `alloc(len) -> ptr`, and any of
+//   on_login(ptr, len) -> i32       {"name", "addr"}; non zero rejects the login
+//   on_chat(ptr, len) -> i32        {"name", "text", "game_id"}; non zero drops the chat
+//   on_game_start(ptr, len) -> i32  {"game_id", "game_name", "players"}
+//   on_game_end(ptr, len) -> i32    {"game_id", "game_name"}
+// where ptr/len is the event as json text. the only things a script can do
+// are the imports of module "direlera", both taking a (ptr, len) utf-8 string:
+//   reply      a server message to the user the event is about (or the room)
+//   announce   a server message to everyone in the lobby
+// each call runs on a fuel budget, so a looping script cannot stall the server.
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Login,
+    Chat,
+    GameStart,
+    GameEnd,
+}
+
+impl Hook {
+    pub fn export_name(self) -> &'static str {
+        match self {
+            Hook::Login => "on_login",
+            Hook::Chat => "on_chat",
+            Hook::GameStart => "on_game_start",
+            Hook::GameEnd => "on_game_end",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptAction {
+    Reply(String),
+    Announce(String),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HookResult {
+    // the hook returned non zero: reject the login or drop the chat
+    pub deny: bool,
+    pub actions: Vec<ScriptAction>,
+}
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use super::*;
+    use log::info;
+    use wasmi::{Caller, Config, Engine, Extern, Instance, Linker, Module, Store};
+
+    // instructions per hook call, roughly
+    const FUEL_PER_CALL: u64 = 10_000_000;
+
+    pub struct Scripts {
+        store: Store<Vec<ScriptAction>>,
+        instance: Instance,
+        // fuel added so far, to top up to FUEL_PER_CALL before a call
+        fuel_added: u64,
+    }
+
+    fn read_string(caller: &Caller<'_, Vec<ScriptAction>>, ptr: i32, len: i32) -> Option<String> {
+        let memory = match caller.get_export("memory") {
+            Some(Extern::Memory(memory)) => memory,
+            _ => return None,
+        };
+        let mut buf = vec![0u8; len.max(0) as usize];
+        memory.read(caller, ptr as u32 as usize, &mut buf).ok()?;
+        Some(String::from_utf8_lossy(&buf).to_string())
+    }
+
+    impl Scripts {
+        pub fn load(path: &Path) -> anyhow::Result<Scripts> {
+            let mut config = Config::default();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config);
+            let module = Module::new(&engine, std::fs::read(path)?.as_slice())
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            let mut store = Store::new(&engine, Vec::new());
+            let mut linker = <Linker<Vec<ScriptAction>>>::new(&engine);
+            linker
+                .func_wrap(
+                    "direlera",
+                    "reply",
+                    |mut caller: Caller<'_, Vec<ScriptAction>>, ptr: i32, len: i32| {
+                        if let Some(text) = read_string(&caller, ptr, len) {
+                            caller.data_mut().push(ScriptAction::Reply(text));
+                        }
+                    },
+                )
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            linker
+                .func_wrap(
+                    "direlera",
+                    "announce",
+                    |mut caller: Caller<'_, Vec<ScriptAction>>, ptr: i32, len: i32| {
+                        if let Some(text) = read_string(&caller, ptr, len) {
+                            caller.data_mut().push(ScriptAction::Announce(text));
+                        }
+                    },
+                )
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            store
+                .add_fuel(FUEL_PER_CALL)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            let instance = linker
+                .instantiate(&mut store, &module)
+                .and_then(|x| x.start(&mut store))
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            Ok(Scripts {
+                store,
+                instance,
+                fuel_added: FUEL_PER_CALL,
+            })
+        }
+
+        fn run(&mut self, hook: Hook, event: &str) -> anyhow::Result<i32> {
+            let consumed = self.store.fuel_consumed().unwrap_or(0);
+            let top_up = FUEL_PER_CALL.saturating_sub(self.fuel_added.saturating_sub(consumed));
+            self.store
+                .add_fuel(top_up)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            self.fuel_added += top_up;
+            let func = match self
+                .instance
+                .get_typed_func::<(i32, i32), i32>(&self.store, hook.export_name())
+            {
+                Ok(func) => func,
+                // the script does not handle this hook
+                Err(_) => return Ok(0),
+            };
+            let alloc = self
+                .instance
+                .get_typed_func::<i32, i32>(&self.store, "alloc")?;
+            let memory = self
+                .instance
+                .get_memory(&self.store, "memory")
+                .ok_or_else(|| anyhow::anyhow!("no memory export"))?;
+            let len = event.len() as i32;
+            let ptr = alloc.call(&mut self.store, len)?;
+            memory
+                .write(&mut self.store, ptr as u32 as usize, event.as_bytes())
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            Ok(func.call(&mut self.store, (ptr, len))?)
+        }
+
+        pub fn call(&mut self, hook: Hook, event: &serde_json::Value) -> HookResult {
+            self.store.data_mut().clear();
+            let deny = match self.run(hook, &event.to_string()) {
+                Ok(code) => code != 0,
+                Err(e) => {
+                    info!("script {} failed: {}", hook.export_name(), e);
+                    false
+                }
+            };
+            HookResult {
+                deny,
+                actions: std::mem::take(self.store.data_mut()),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod engine {
+    use super::*;
+
+    pub struct Scripts;
+
+    impl Scripts {
+        pub fn load(path: &Path) -> anyhow::Result<Scripts> {
+            anyhow::bail!(
+                "{}: built without the scripting feature (cargo build --features scripting)",
+                path.display()
+            )
+        }
+        pub fn call(&mut self, _hook: Hook, _event: &serde_json::Value) -> HookResult {
+            HookResult::default()
+        }
+    }
+}
+
+pub use engine::Scripts;
+
+// no script_file: every hook allows and does nothing
+#[derive(Default)]
+pub struct ScriptHooks {
+    pub scripts: Option<Scripts>,
+}
+
+impl ScriptHooks {
+    pub fn load(path: Option<&Path>) -> anyhow::Result<ScriptHooks> {
+        Ok(ScriptHooks {
+            scripts: path.map(Scripts::load).transpose()?,
+        })
+    }
+    pub fn call(&mut self, hook: Hook, event: serde_json::Value) -> HookResult {
+        match self.scripts.as_mut() {
+            Some(scripts) => scripts.call(hook, &event),
+            None => HookResult::default(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_hooks() {
+        // on_chat replies with the event and drops the chat
+        let wasm = wat::parse_str(
+            r#"(module
+                (import "direlera" "reply" (func $reply (param i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "on_chat") (param $ptr i32) (param $len i32) (result i32)
+                    (call $reply (local.get $ptr) (local.get $len))
+                    (i32.const 1))
+                (func (export "on_game_end") (param i32 i32) (result i32)
+                    (loop $forever (br $forever))
+                    (i32.const 0)))"#,
+        )
+        .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("direlera-script-{}.wasm", std::process::id()));
+        std::fs::write(&path, wasm).unwrap();
+        let mut hooks = ScriptHooks::load(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let event = serde_json::json!({"game_id": null, "text": "!rules", "name": "kim"});
+        let result = hooks.call(Hook::Chat, event.clone());
+        assert!(result.deny);
+        assert_eq!(result.actions, vec![ScriptAction::Reply(event.to_string())]);
+        // not exported
+        assert_eq!(
+            hooks.call(Hook::Login, serde_json::json!({})),
+            HookResult::default()
+        );
+        // runs out of fuel instead of hanging, and the next call still works
+        assert!(!hooks.call(Hook::GameEnd, serde_json::json!({})).deny);
+        assert_eq!(hooks.call(Hook::Chat, event).actions.len(), 1);
+    }
+}
@@ -0,0 +1,139 @@
+// minimal kaillera client: HELLO -> login -> ack -> server status -> quit.
+// `direlera-rs --selftest host:port` checks the handshake path of a running server.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::info;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+
+use crate::protocol::*;
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+// a datagram carrying a single message
+pub fn bundle(seq: u16, message_type: u8, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut p = Protocol::new(message_type, data);
+    p.header.seq = seq;
+    let mut v = vec![1u8];
+    v.append(&mut p.make_packet()?);
+    Ok(v)
+}
+
+pub(crate) struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+    send_seq: u16,
+    // highest seq received from the server
+    recv_seq: Option<u16>,
+}
+
+impl Client {
+    pub(crate) async fn send(&mut self, message_type: u8, data: Vec<u8>) -> anyhow::Result<()> {
+        let packet = bundle(self.send_seq, message_type, data)?;
+        self.send_seq += 1;
+        self.socket.send_to(&packet, self.server).await?;
+        Ok(())
+    }
+    // new messages from the next datagram, oldest first
+    pub(crate) async fn recv(&mut self) -> anyhow::Result<Vec<Protocol>> {
+        let mut buf = vec![0u8; 4096];
+        let (size, _) = timeout(RECV_TIMEOUT, self.socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("no reply from {}", self.server))??;
+        let mut messages: Vec<_> = get_protocol_from_bytes(&buf[..size].to_vec())?
+            .into_iter()
+            .filter(|p| match self.recv_seq {
+                Some(seq) => p.header.seq > seq,
+                None => true,
+            })
+            .collect();
+        messages.sort_by_key(|p| p.header.seq);
+        if let Some(p) = messages.last() {
+            self.recv_seq = Some(p.header.seq);
+        }
+        Ok(messages)
+    }
+}
+
+// HELLO on the main port, then login on the sub port until the server status arrives
+pub(crate) async fn login(target: &str, name: &str) -> anyhow::Result<Client> {
+    let main_addr = lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("cannot resolve {}", target))?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+
+    socket.send_to(b"HELLO0.83\x00", main_addr).await?;
+    let mut buf = vec![0u8; 1024];
+    let (size, _) = timeout(RECV_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("no reply to HELLO from {}", main_addr))??;
+    let reply = String::from_utf8_lossy(&buf[..size]).to_string();
+    let sub_port = reply
+        .strip_prefix("HELLOD00D")
+        .and_then(|x| x.split('\x00').next())
+        .and_then(|x| x.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("unexpected HELLO reply: {:?}", reply))?;
+    info!("{}: HELLO ok, sub port {}", name, sub_port);
+
+    let mut client = Client {
+        socket,
+        server: SocketAddr::new(main_addr.ip(), sub_port),
+        send_seq: 0,
+        recv_seq: None,
+    };
+    // name, emulator, connection type
+    let mut login = name.as_bytes().to_vec();
+    login.extend_from_slice(b"\x00direlera selftest\x00\x01");
+    client.send(USER_LOGIN_INFO, login).await?;
+    let mut acks = 0;
+    'status: loop {
+        for p in client.recv().await? {
+            match p.header.header.message_type {
+                S2C_ACK => {
+                    acks += 1;
+                    client
+                        .send(C2S_ACK, bincode::serialize(&AckProtocol::new())?)
+                        .await?;
+                }
+                USER_SERVER_STATUS => break 'status,
+                CONNECTION_REJECT => {
+                    anyhow::bail!(
+                        "login rejected: {}",
+                        String::from_utf8_lossy(&p.data).replace('\x00', " ")
+                    )
+                }
+                _ => {}
+            }
+        }
+    }
+    info!("{}: login ok after {} acks", name, acks);
+    Ok(client)
+}
+
+pub async fn run_selftest(target: &str) -> anyhow::Result<()> {
+    let mut client = login(target, "selftest").await?;
+
+    // empty name, user id 0xFFFF, message
+    client
+        .send(USER_QUIT, b"\x00\xff\xffselftest\x00".to_vec())
+        .await?;
+    info!("selftest: {} passed", target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_roundtrip() {
+        let packet = bundle(3, USER_QUIT, b"\x00\xff\xffbye\x00".to_vec()).unwrap();
+        let r = get_protocol_from_bytes(&packet).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].header.seq, 3);
+        assert_eq!(r[0].header.header.message_type, USER_QUIT);
+        assert_eq!(r[0].data, b"\x00\xff\xffbye\x00");
+    }
+}
@@ -0,0 +1,168 @@
+// outbound pacing per client (send_pacing_ms): lobby chatter (chats, joins,
+// quits, room list changes) goes out at most one message per gap, so a join
+// storm does not overflow the receive buffer of a client on a slow link.
+// messages have a priority, game data > game control > chat > status: game
+// data and in-game control never wait, and a backed up queue sends its chat
+// before its status updates. other control messages (JOIN_GAME, PLAYER_INFO,
+// ...) flush the queue first, since they can refer to rooms and users that
+// queued status messages announce.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::protocol::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // GAME_DATA, GAME_CACHE, FAST_INPUT
+    GameData,
+    // everything not listed elsewhere
+    GameControl,
+    Chat,
+    // user joined or quit, room created, closed or changed
+    Status,
+}
+
+pub fn priority(message_type: u8) -> Priority {
+    match message_type {
+        GAME_DATA | GAME_CACHE | FAST_INPUT => Priority::GameData,
+        GLOBAL_CHAT | GAME_CHAT => Priority::Chat,
+        USER_JOIN | USER_QUIT | CREATE_GAME | CLOSE_GAME | UPDATE_GAME_STATUS => Priority::Status,
+        _ => Priority::GameControl,
+    }
+}
+
+// messages that may wait
+pub fn deferrable(message_type: u8) -> bool {
+    priority(message_type) > Priority::GameControl
+}
+
+// control that only concerns the running game, so it need not wait for the queue
+fn in_game(message_type: u8) -> bool {
+    matches!(message_type, START_GAME | READY_TO_PLAY_SIGNAL | DROP_GAME)
+}
+
+#[derive(Debug, Default)]
+pub struct SendPacer {
+    // zero sends everything right away
+    pub gap: Duration,
+    // one queue per priority, oldest first
+    queues: [VecDeque<Protocol>; 4],
+    last: Option<Instant>,
+}
+
+impl SendPacer {
+    fn ready(&self, now: Instant) -> bool {
+        match self.last {
+            Some(last) => now >= last + self.gap,
+            None => true,
+        }
+    }
+    pub fn queued(&self) -> usize {
+        self.queues.iter().map(|x| x.len()).sum()
+    }
+    fn pop(&mut self) -> Option<Protocol> {
+        self.queues.iter_mut().find_map(|x| x.pop_front())
+    }
+    // what to send now, in order
+    pub fn admit(&mut self, p: Protocol, now: Instant) -> Vec<Protocol> {
+        let message_type = p.header.header.message_type;
+        if self.gap.is_zero() || priority(message_type) == Priority::GameData {
+            return vec![p];
+        }
+        if !deferrable(message_type) {
+            if in_game(message_type) {
+                return vec![p];
+            }
+            let mut out: Vec<_> = std::iter::from_fn(|| self.pop()).collect();
+            out.push(p);
+            return out;
+        }
+        if self.queued() == 0 && self.ready(now) {
+            self.last = Some(now);
+            return vec![p];
+        }
+        self.queues[priority(message_type) as usize].push_back(p);
+        Vec::new()
+    }
+    // the most urgent queued message once its gap has passed
+    pub fn due(&mut self, now: Instant) -> Option<Protocol> {
+        if self.queued() == 0 || !self.ready(now) {
+            return None;
+        }
+        self.last = Some(now);
+        self.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(ps: &[Protocol]) -> Vec<u8> {
+        ps.iter().map(|p| p.header.header.message_type).collect()
+    }
+
+    #[test]
+    fn paces_lobby_messages() {
+        let start = Instant::now();
+        let mut pacer = SendPacer {
+            gap: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let chat = || Protocol::new(GLOBAL_CHAT, vec![]);
+        assert_eq!(types(&pacer.admit(chat(), start)), [GLOBAL_CHAT]);
+        assert!(pacer.admit(chat(), start).is_empty());
+        assert!(pacer
+            .admit(Protocol::new(USER_JOIN, vec![]), start)
+            .is_empty());
+        assert_eq!(pacer.queued(), 2);
+        // input never waits
+        assert_eq!(
+            types(&pacer.admit(Protocol::new(GAME_DATA, vec![]), start)),
+            [GAME_DATA]
+        );
+        assert!(pacer.due(start + Duration::from_millis(10)).is_none());
+        let later = start + Duration::from_millis(20);
+        assert_eq!(
+            pacer.due(later).unwrap().header.header.message_type,
+            GLOBAL_CHAT
+        );
+        assert!(pacer.due(later).is_none());
+        // a join reply must not overtake what is queued
+        assert_eq!(
+            types(&pacer.admit(Protocol::new(JOIN_GAME, vec![]), later)),
+            [USER_JOIN, JOIN_GAME]
+        );
+        assert_eq!(pacer.queued(), 0);
+    }
+
+    #[test]
+    fn sends_by_priority() {
+        let start = Instant::now();
+        let mut pacer = SendPacer {
+            gap: Duration::from_millis(20),
+            ..Default::default()
+        };
+        assert_eq!(priority(GAME_CACHE), Priority::GameData);
+        assert_eq!(priority(JOIN_GAME), Priority::GameControl);
+        assert!(!deferrable(START_GAME));
+        for t in [CREATE_GAME, USER_QUIT, GAME_CHAT, USER_JOIN, GLOBAL_CHAT] {
+            pacer.admit(Protocol::new(t, vec![]), start);
+        }
+        // the first went out, chat overtakes the waiting status updates
+        assert_eq!(pacer.queued(), 4);
+        assert_eq!(
+            types(&pacer.admit(Protocol::new(DROP_GAME, vec![]), start)),
+            [DROP_GAME]
+        );
+        let later = start + Duration::from_millis(20);
+        assert_eq!(
+            pacer.due(later).unwrap().header.header.message_type,
+            GAME_CHAT
+        );
+        assert_eq!(
+            types(&pacer.admit(Protocol::new(PLAYER_INFO, vec![]), later)),
+            [GLOBAL_CHAT, USER_QUIT, USER_JOIN, PLAYER_INFO]
+        );
+    }
+}
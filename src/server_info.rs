@@ -0,0 +1,148 @@
+// how the server presents itself to server browsers and lists, separate from
+// the notice users get after login: server_name, server_description and the
+// max users it reports (listed_max_users, max_users when not set). with
+// server_name_load the name ends in the load level, "kof [load: low]". clients that
+// add INFO_TAG to their HELLO get them back as NAME=, DESC= and MAXUSERS= tags,
+// in EUC-KR like the rest of what clients are sent, and status_export carries
+// them as they are written in the config.
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::load::LoadLevel;
+use crate::settings;
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ServerInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+    #[serde(skip)]
+    pub show_load: bool,
+}
+
+impl ServerInfo {
+    pub fn from_config(config: &HashMap<String, String>) -> ServerInfo {
+        let text = |key: &str| {
+            config
+                .get(key)
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+        };
+        let max_users = |key: &str| {
+            config
+                .contains_key(key)
+                .then(|| settings::get_num(config, key, 0))
+        };
+        ServerInfo {
+            name: text("server_name"),
+            description: text("server_description"),
+            max_users: max_users("listed_max_users").or_else(|| max_users("max_users")),
+            show_load: settings::get_bool(config, "server_name_load", false),
+        }
+    }
+    // NUL terminated tags for the HELLO reply, nothing when none are set
+    pub fn hello_tags(&self, load: LoadLevel) -> Vec<u8> {
+        let mut tags = Vec::new();
+        let mut push = |key: &str, value: &str| {
+            tags.extend_from_slice(key.as_bytes());
+            // a NUL would end the tag early
+            tags.extend(
+                encoding_rs::EUC_KR
+                    .encode(value)
+                    .0
+                    .iter()
+                    .filter(|x| **x != 0),
+            );
+            tags.push(0);
+        };
+        match (&self.name, self.show_load) {
+            (Some(name), true) => push("NAME=", &format!("{} [load: {}]", name, load.name())),
+            (None, true) => push("NAME=", &format!("[load: {}]", load.name())),
+            (Some(name), false) => push("NAME=", name),
+            (None, false) => {}
+        }
+        if let Some(description) = &self.description {
+            push("DESC=", description);
+        }
+        if let Some(max_users) = self.max_users {
+            push("MAXUSERS=", &max_users.to_string());
+        }
+        tags
+    }
+}
+
+// hello_tags of the last load level. HELLO floods from server list scrapers
+// are answered without encoding the same tags again for each one
+#[derive(Debug, Default)]
+pub struct HelloTagsCache {
+    info: ServerInfo,
+    cached: Option<(LoadLevel, Vec<u8>)>,
+}
+
+impl HelloTagsCache {
+    pub fn new(info: ServerInfo) -> HelloTagsCache {
+        HelloTagsCache { info, cached: None }
+    }
+    pub fn tags(&mut self, load: LoadLevel) -> &[u8] {
+        if self.cached.as_ref().map(|(level, _)| *level) != Some(load) {
+            self.cached = Some((load, self.info.hello_tags(load)));
+        }
+        self.cached
+            .as_ref()
+            .map_or(&[], |(_, tags)| tags.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_tags() {
+        let mut config: HashMap<String, String> = [
+            ("server_name", "한글 서버"),
+            ("server_description", " kof and sf "),
+            ("max_users", "100"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let info = ServerInfo::from_config(&config);
+        let mut expected = b"NAME=".to_vec();
+        expected.extend_from_slice(&encoding_rs::EUC_KR.encode("한글 서버").0);
+        expected.extend_from_slice(b"\x00DESC=kof and sf\x00MAXUSERS=100\x00");
+        assert_eq!(info.hello_tags(LoadLevel::Low), expected);
+
+        config.insert("listed_max_users".to_string(), "32".to_string());
+        config.insert("server_description".to_string(), "".to_string());
+        let info = ServerInfo::from_config(&config);
+        assert_eq!(info.description, None);
+        assert_eq!(info.max_users, Some(32));
+        assert!(ServerInfo::default().hello_tags(LoadLevel::Low).is_empty());
+
+        config.insert("server_name_load".to_string(), "true".to_string());
+        let tags = ServerInfo::from_config(&config).hello_tags(LoadLevel::High);
+        assert!(tags.ends_with(b" [load: high]\x00MAXUSERS=32\x00"));
+    }
+    #[test]
+    fn hello_tags_cache() {
+        let config: HashMap<String, String> =
+            [("server_name", "kof"), ("server_name_load", "true")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        let info = ServerInfo::from_config(&config);
+        let mut cache = HelloTagsCache::new(info.clone());
+        assert_eq!(cache.tags(LoadLevel::Low), info.hello_tags(LoadLevel::Low));
+        assert_eq!(cache.tags(LoadLevel::Low), info.hello_tags(LoadLevel::Low));
+        // a new load level is a new name
+        assert_eq!(
+            cache.tags(LoadLevel::High),
+            info.hello_tags(LoadLevel::High)
+        );
+    }
+}
@@ -1,9 +1,41 @@
+use crate::acl::Acl;
+use crate::bot_api::*;
+use crate::control::ControlRequest;
+use crate::desync::*;
+use crate::emulators::Emulators;
+use crate::encryption::SessionCipher;
+use crate::federation::*;
+use crate::friends::Friends;
+use crate::game_names::GameNames;
+use crate::ids::*;
+use crate::input_record::InputRecorder;
+use crate::io_worker::{self, IoWorker};
+use crate::list_files::{self, ListFiles};
+use crate::load::LoadLimits;
+use crate::motd;
+use crate::packet_util::*;
+use crate::pending::PendingSessions;
+use crate::persistent_rooms::*;
 use crate::protocol::*;
+use crate::punishment::*;
+use crate::quality::GradeDisplay;
+use crate::reachability::*;
 use crate::room::*;
+use crate::saved_state::SavedState;
+use crate::schema;
+use crate::scripting::*;
+use crate::server_info::ServerInfo;
+use crate::settings;
+use crate::snapshot::*;
+use crate::stats::*;
+use crate::status_export::*;
+use crate::suspicion::Anomaly;
+use crate::templates::*;
+use crate::timeline::{self, Timeline};
 
 #[cfg(feature = "alloc")]
 use encoding_rs::*;
-use log::{info, trace};
+use log::{info, trace, warn};
 use rand::Rng;
 use serde::__private::from_utf8_lossy;
 use std::cell::RefCell;
@@ -16,19 +48,65 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::Duration;
 use std::time::Instant;
 
 use tokio::net::UdpSocket;
 
+// datagrams per loss check, see advise_on_loss
+const LOSS_WINDOW: u64 = 300;
+// one /desync report per room in this time, repeats describe the same desync
+const DESYNC_REPORT_GAP: Duration = Duration::from_secs(5);
+
 pub struct ServiceServer {
     pub config: HashMap<String, String>,
     pub socket: UdpSocket,
     pub buf: Vec<u8>,
     pub to_send: Option<(usize, SocketAddr)>,
     pub session_manager: UserRoom,
-    pub game_id: u32,
+    pub game_id: GameId,
+    pub acl: Acl,
+    pub stats: ServerStats,
+    pub punishments: Punishments,
+    pub friends: Friends,
+    // room settings saved with /savetemplate
+    pub templates: Templates,
+    // rooms made with /persist, opened again at startup
+    pub persistent_rooms: PersistentRooms,
+    // /redirect to everyone: where new logins are sent instead
+    pub redirect: Option<String>,
+    pub game_names: GameNames,
+    // room defaults by the creator's emulator
+    pub emulators: Emulators,
+    pub scripts: ScriptHooks,
+    // federated servers: sender addr -> (received time, status)
+    pub peers: HashMap<SocketAddr, (Instant, PeerStatus)>,
+    // addresses that negotiated encryption on the main port but have not logged in
+    // yet, with their session key
+    pub encryption_pending: HashMap<SocketAddr, (Instant, [u8; 32])>,
+    // addresses whose HELLO asked for GAME_PAUSE, until they log in
+    pub pause_pending: HashMap<SocketAddr, Instant>,
+    pub fast_input_pending: HashMap<SocketAddr, Instant>,
+    // addresses whose HELLO asked for the login challenge, until they log in
+    pub challenge_pending: HashMap<SocketAddr, Instant>,
+    // the running reachability check, see reachability.rs
+    pub probe: Option<Probe>,
+    // logins that have not finished the ack exchange, bounded by max_pending_sessions
+    pub pending: PendingSessions,
+    // challenged logins under duplicate_login = replace, by address, with the
+    // session each one replaces once it echoes the challenge and whether that
+    // session was ever announced
+    pub replacing: HashMap<SocketAddr, (Rc<RefCell<User>>, bool)>,
+    // log output and state files are written off the dispatcher
+    pub io: IoWorker,
+    // last time the public status was exported
+    pub status_exported: Option<Instant>,
+    // last write of state_file
+    pub state_saved: Option<Instant>,
+    // recent events for the control socket's timeline
+    pub timeline: Timeline,
     pub rx: Receiver<Event>,
     pub tx: Sender<Event>,
 }
@@ -36,17 +114,62 @@ pub struct ServiceServer {
 #[derive(Debug)]
 pub enum Event {
     KeepaliveTimer,
+    PeerStatus(SocketAddr, Vec<u8>),
+    Encrypt(SocketAddr, [u8; 32]),
+    // game_id, seconds left
+    StartCountdown(GameId, u8),
+    // game_id, session number: players that are not ready yet get dropped
+    NetsyncTimeout(GameId, SessionId),
+    // game_id, session number, seconds since START_GAME: tell the room who is still loading
+    NetsyncProgress(GameId, SessionId, u64),
+    // addr, user_id: disconnect if the rules are still not accepted
+    RulesTimeout(SocketAddr, UserId),
+    // addr, user_id: reject if the login acks are still not done
+    HandshakeTimeout(SocketAddr, UserId),
+    // a bot api request and where its answer goes
+    Bot(BotRequest, tokio::sync::oneshot::Sender<BotReply>),
+    // a control socket command and where its answer goes
+    Control(ControlRequest, tokio::sync::oneshot::Sender<String>),
+    // send what send_pacing_ms held back
+    PaceTimer,
+    // the HELLO from addr asked for GAME_PAUSE
+    PauseCapable(SocketAddr),
+    // the HELLO from addr asked for FAST_INPUT
+    FastInputCapable(SocketAddr),
+    // the HELLO from addr asked for the login challenge
+    ChallengeCapable(SocketAddr),
+    // a reachability probe arrived: port, token
+    ProbeReceived(u16, Vec<u8>),
+    // the reachability check with this token is over, or its request failed
+    ProbeDone(String, Option<String>),
+    // a list file changed: the key and its whole list, see list_files.rs
+    ListFile(&'static str, String),
 }
 impl ServiceServer {
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         info!("Service Run");
 
+        let pacing = Duration::from_millis(settings::get_num(&self.config, "send_pacing_ms", 0));
+        self.restore_state();
+        self.open_persistent_rooms();
+        if self.config.contains_key("reachability_url") {
+            info!("{}", self.start_probe(None)?);
+        }
+        let mut lists = ListFiles::from_config(&self.config);
+        for (key, list) in lists.changed() {
+            self.config.insert(key.to_string(), list);
+        }
+        let lists = RefCell::new(lists);
         loop {
             // let r = self.keepalive_timer;
             // let r2 = self.service;
             select! {
                 _ = ServiceServer::keepalive_timer(self.tx.clone()) => {
                 }
+                _ = ServiceServer::pace_timer(self.tx.clone(), pacing) => {
+                }
+                _ = ServiceServer::list_files_timer(self.tx.clone(), &lists) => {
+                }
                 _ = self.service() => {
                 }
             }
@@ -60,47 +183,326 @@ impl ServiceServer {
             tx.send(Event::KeepaliveTimer).await?;
         }
     }
+    // no ticks when pacing is off
+    pub async fn pace_timer(tx: Sender<Event>, gap: Duration) -> anyhow::Result<()> {
+        if gap.is_zero() {
+            return std::future::pending().await;
+        }
+        let mut interval = tokio::time::interval(gap);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            tx.send(Event::PaceTimer).await?;
+        }
+    }
+    // no ticks without list files
+    pub async fn list_files_timer(
+        tx: Sender<Event>,
+        lists: &RefCell<ListFiles>,
+    ) -> anyhow::Result<()> {
+        if lists.borrow().is_empty() {
+            return std::future::pending().await;
+        }
+        let mut interval = tokio::time::interval(list_files::CHECK_EVERY);
+        loop {
+            interval.tick().await;
+            let changed = lists.borrow_mut().changed();
+            for (key, list) in changed {
+                tx.send(Event::ListFile(key, list)).await?;
+            }
+        }
+    }
+    // a new bans list also disconnects the users on it
+    pub async fn list_file_event(&mut self, key: &'static str, list: String) -> anyhow::Result<()> {
+        if list_files::TEXT_KEYS.contains(&key) {
+            info!("{}_file changed", key);
+        } else {
+            info!("{}_file changed: {}", key, list);
+        }
+        self.config.insert(key.to_string(), list);
+        if key != "bans" {
+            return Ok(());
+        }
+        let banned: Vec<_> = self
+            .session_manager
+            .users
+            .values()
+            .filter(|u| settings::ip_in_list(&self.config, "bans", u.borrow().ip_addr.ip()))
+            .cloned()
+            .collect();
+        for user in banned {
+            info!("{} is on the new bans list", user.borrow().ip_addr);
+            self.disconnect_user(user, b"banned".to_vec()).await?;
+        }
+        Ok(())
+    }
+    pub async fn pace_event(&mut self) -> anyhow::Result<()> {
+        for u in self.session_manager.users.values() {
+            if u.borrow().send_pacer.queued() > 0 {
+                User::send_paced(u, &mut self.socket).await?;
+            }
+        }
+        Ok(())
+    }
     pub async fn keepalive_event(&mut self) -> anyhow::Result<()> {
         // check user timeout
         let now = Instant::now();
         let mut timeout_users = vec![];
         for (k, v) in self.session_manager.users.iter() {
-            if now.duration_since(v.borrow().keepalive_time) > Duration::from_secs(240) {
+            let timeout = settings::keepalive_timeout(&self.config, v.borrow().connect_type);
+            if now.duration_since(v.borrow().keepalive_time) > timeout {
                 info!("timeout!!!: {:#?}", k);
                 timeout_users.push(*k);
             }
         }
         for i in timeout_users.iter() {
             let user = self.session_manager.get_user(*i)?;
-            let _ = self.fun_quit_game(user.clone()).await;
-            // send quit message to all
+            self.disconnect_user(user, b"time out".to_vec()).await?;
+        }
+        Ok(())
+    }
+    // the only way a user goes away (quit, time out, kick, ban, replaced login,
+    // stalled handshake): leave the room, announce USER_QUIT to everyone and
+    // forget the session. message is the reason everyone sees: the client's own
+    // quit message, "time out", "kicked" and so on. users still logging in were
+    // never announced, so nobody hears about them leaving.
+    pub async fn disconnect_user(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        message: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let announced = !self.pending.contains(&user.borrow().ip_addr);
+        self.drop_session(user, message, announced).await
+    }
+    // false for a session that a new login from the same address pushed aside
+    fn owns_address(&self, user: &Rc<RefCell<User>>) -> bool {
+        self.session_manager
+            .users
+            .get(&user.borrow().ip_addr)
+            .is_none_or(|u| Rc::ptr_eq(u, user))
+    }
+    pub fn note_timeline(&mut self, kind: &'static str, detail: String) {
+        self.timeline.push(chrono::Local::now(), kind, detail);
+    }
+    async fn drop_session(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        message: Vec<u8>,
+        announced: bool,
+    ) -> anyhow::Result<()> {
+        let addr = user.borrow().ip_addr;
+        info!(
+            "quit {} ({}): {}",
+            display_name(&user.borrow().name),
+            addr,
+            display_name(&message)
+        );
+        let _ = self.leave_room(user.clone()).await;
+        if announced {
+            let detail = format!(
+                "{} ({}): {}",
+                display_name(&user.borrow().name),
+                addr,
+                display_name(&message)
+            );
+            self.note_timeline("quit", detail);
             let data = UserQuitPacket2Client::new(
                 user.borrow().name.clone(),
                 user.borrow().user_id,
-                b"time out".to_vec(),
+                message,
             )
             .packetize()?;
             for (_addr, u) in &self.session_manager.users {
-                u.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(USER_QUIT, data.clone()))
+                User::make_send_packet(u, &mut self.socket, Protocol::new(USER_QUIT, data.clone()))
                     .await?;
             }
-            self.session_manager.users.remove(i);
         }
+        self.replacing.retain(|_, (u, _)| !Rc::ptr_eq(u, &user));
+        if !self.owns_address(&user) {
+            return Ok(());
+        }
+        self.session_manager.users.remove(&addr);
+        self.pending.remove(&addr);
+        self.encryption_pending.remove(&addr);
+        self.pause_pending.remove(&addr);
+        self.fast_input_pending.remove(&addr);
+        self.challenge_pending.remove(&addr);
+        // a login that never finished gives the address back to the session it
+        // was going to replace
+        if let Some((old, _)) = self.replacing.remove(&addr) {
+            if old.borrow().ip_addr == addr {
+                self.session_manager.users.insert(addr, old);
+            }
+        }
+        Ok(())
+    }
+    // send our user/room summary to every configured peer's main port.
+    pub async fn peer_broadcast_event(&mut self) -> anyhow::Result<()> {
+        let (Some(peers), Some(key)) = (self.config.get("peers"), self.config.get("peer_key"))
+        else {
+            return Ok(());
+        };
+        let status = PeerStatus {
+            main_port: self
+                .config
+                .get("main_port")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0),
+            users: self.session_manager.users.len(),
+            rooms: self
+                .session_manager
+                .rooms
+                .values()
+                .map(|r| {
+                    let r = r.borrow();
+                    PeerRoom {
+                        game_name: r.game_name.clone(),
+                        emul_name: r.emul_name.clone(),
+                        game_status: r.game_status,
+                        players: r.player_some_count(),
+                        max_players: r.max_players as usize,
+                    }
+                })
+                .collect(),
+        };
+        let data = encode_status(key, chrono::Utc::now().timestamp() as u64, &status);
+        for peer in peers.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let addrs = match tokio::net::lookup_host(peer).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    info!("peer {} lookup failed: {}", peer, e);
+                    continue;
+                }
+            };
+            for addr in addrs {
+                self.socket.send_to(&data, addr).await?;
+            }
+        }
+        // forget peers that stopped reporting
+        self.peers
+            .retain(|_, (t, _)| t.elapsed() < Duration::from_secs(60));
         Ok(())
     }
+    pub fn peer_status_event(&mut self, addr: SocketAddr, data: Vec<u8>) {
+        let key = match self.config.get("peer_key") {
+            Some(key) => key,
+            None => return,
+        };
+        match decode_status(key, chrono::Utc::now().timestamp() as u64, &data) {
+            Ok(status) => {
+                self.peers.insert(addr, (Instant::now(), status));
+            }
+            Err(e) => info!("peer status from {} rejected: {}", addr, e),
+        }
+    }
+    // the dispatcher: datagrams and events are handled one at a time, in arrival
+    // order, which is what keeps each client's messages in order. it is not split
+    // into workers by session: a player's GAME_DATA fills the input queues of the
+    // others in the room and joins, kicks and chat go through rooms and the user
+    // list, so handlers of different sessions share that state and would wait on
+    // each other's locks. what can run elsewhere does (io_worker, the accept
+    // server, bot api and control socket tasks).
     pub async fn service(&mut self) -> anyhow::Result<()> {
         loop {
             select! {
-                _ = self.rx.recv() => {
-                    self.keepalive_event().await?;
+                ev = self.rx.recv() => {
+                    match ev {
+                        Some(Event::KeepaliveTimer) => {
+                            self.sample_load();
+                            self.keepalive_event().await?;
+                            self.peer_broadcast_event().await?;
+                            self.status_export_event();
+                            self.save_state_event();
+                            self.advertise_event().await?;
+                            self.encryption_pending
+                                .retain(|_, (t, _)| t.elapsed() < Duration::from_secs(60));
+                            self.pause_pending
+                                .retain(|_, t| t.elapsed() < Duration::from_secs(60));
+                            self.fast_input_pending
+                                .retain(|_, t| t.elapsed() < Duration::from_secs(60));
+                            self.challenge_pending
+                                .retain(|_, t| t.elapsed() < Duration::from_secs(60));
+                            self.punishments.expire(Instant::now());
+                        }
+                        Some(Event::PeerStatus(addr, data)) => self.peer_status_event(addr, data),
+                        Some(Event::Encrypt(addr, key)) => {
+                            let max = settings::get_num(&self.config, "max_pending_sessions", 256);
+                            let pending = &mut self.encryption_pending;
+                            if pending.len() >= max {
+                                let oldest = pending.iter().min_by_key(|x| x.1 .0).map(|x| *x.0);
+                                if let Some(oldest) = oldest {
+                                    pending.remove(&oldest);
+                                }
+                            }
+                            pending.insert(addr, (Instant::now(), key));
+                        }
+                        Some(Event::PauseCapable(addr)) => {
+                            let max = settings::get_num(&self.config, "max_pending_sessions", 256);
+                            note_hello(&mut self.pause_pending, addr, max);
+                        }
+                        Some(Event::FastInputCapable(addr)) => {
+                            let max = settings::get_num(&self.config, "max_pending_sessions", 256);
+                            note_hello(&mut self.fast_input_pending, addr, max);
+                        }
+                        Some(Event::ChallengeCapable(addr)) => {
+                            let max = settings::get_num(&self.config, "max_pending_sessions", 256);
+                            note_hello(&mut self.challenge_pending, addr, max);
+                        }
+                        Some(Event::StartCountdown(game_id, left)) => {
+                            self.countdown_event(game_id, left).await?;
+                        }
+                        Some(Event::NetsyncTimeout(game_id, session)) => {
+                            self.netsync_timeout_event(game_id, session).await?;
+                        }
+                        Some(Event::NetsyncProgress(game_id, session, elapsed)) => {
+                            self.netsync_progress_event(game_id, session, elapsed).await?;
+                        }
+                        Some(Event::RulesTimeout(addr, user_id)) => {
+                            self.rules_timeout_event(addr, user_id).await?;
+                        }
+                        Some(Event::HandshakeTimeout(addr, user_id)) => {
+                            self.handshake_timeout_event(addr, user_id).await?;
+                        }
+                        Some(Event::PaceTimer) => self.pace_event().await?,
+                        Some(Event::ProbeReceived(port, token)) => {
+                            self.probe_received_event(port, token).await?;
+                        }
+                        Some(Event::ProbeDone(token, error)) => {
+                            self.probe_done_event(token, error).await?;
+                        }
+                        Some(Event::ListFile(key, list)) => self.list_file_event(key, list).await?,
+                        Some(Event::Bot(request, reply)) => {
+                            let answer = match self.bot_event(request).await {
+                                Ok(answer) => answer,
+                                Err(e) => BotReply::error(500, &e.to_string()),
+                            };
+                            let _ = reply.send(answer);
+                        }
+                        Some(Event::Control(request, reply)) => {
+                            let answer = match self.control_event(request).await {
+                                Ok(answer) => answer,
+                                Err(e) => format!("error: {}", e),
+                            };
+                            let _ = reply.send(answer);
+                        }
+                        None => {}
+                    }
                 }
                 ts = self.socket.recv_from(&mut self.buf) => {
                     self.to_send = Some(ts?);
                     if let Some((size, peer)) = self.to_send {
+                        let started = Instant::now();
                         let result = self.service_proc(size, peer).await;
-                        if result.is_err() {
-                            info!("err content: {:#?}", result.err());
+                        self.stats.load.note_datagram(started.elapsed());
+                        match result {
+                            Ok(()) => {}
+                            Err(e) => match e.downcast_ref::<KailleraError>() {
+                                Some(KailleraError::InvalidInput { .. }) => {
+                                    self.stats.malformed_messages += 1;
+                                    info!("malformed message from {}: {}", peer, e);
+                                }
+                                _ => info!("err content: {:#?}", e),
+                            },
                         }
                     }
                 }
@@ -110,17 +512,50 @@ impl ServiceServer {
 
     pub async fn service_proc(&mut self, size: usize, peer: SocketAddr) -> anyhow::Result<()> {
         // info!("service size: {}, ", size);
-        let r = get_protocol_from_bytes(&self.buf[..size].to_vec())?;
+        if !self.acl.allows(peer.ip()) {
+            trace!("acl denied: {}", peer);
+            return Ok(());
+        }
+        if let Some(token) = probe_token(&self.buf[..size]) {
+            let (port, token) = (self.socket.local_addr()?.port(), token.to_vec());
+            return self.probe_received_event(port, token).await;
+        }
+        let mut datagram = self.buf[..size].to_vec();
+        let cipher = match self.session_manager.users.get(&peer) {
+            Some(u) => u.borrow().cipher.clone(),
+            None => self
+                .encryption_pending
+                .get(&peer)
+                .map(|(_, key)| SessionCipher::new(*key)),
+        };
+        if let Some(cipher) = &cipher {
+            datagram = match cipher.open(&datagram) {
+                Some(plain) => plain,
+                None => {
+                    trace!("datagram from {} not sealed with its session key", peer);
+                    return Ok(());
+                }
+            };
+        }
+        let r = get_protocol_from_bytes(&datagram)?;
         if r.len() == 0 {
             info!("protocol length: 0");
         }
+        // a client that restarted logs in again from the same address. the login
+        // gets a session of its own, the old one stays until the login gets in
+        let relogin = r.len() == 1
+            && r[0].header.seq == 0
+            && r[0].header.header.message_type == USER_LOGIN_INFO
+            && self.duplicate_login_policy() == "replace";
         let user = {
-            match self.session_manager.users.get(&peer) {
+            match self.session_manager.users.get(&peer).filter(|_| !relogin) {
                 Some(i) => i.clone(),
                 None => {
                     if r.len() == 1 && r[0].header.seq == 0 {
                         info!("new user: insert");
-                        Rc::new(RefCell::new(User::new(peer)))
+                        let mut u = User::new(peer);
+                        u.cipher = cipher;
+                        Rc::new(RefCell::new(u))
                     } else {
                         return Err(KailleraError::NotFoundUser {
                             message: format!("{:?}", r[0]),
@@ -130,13 +565,46 @@ impl ServiceServer {
                 }
             }
         };
+        if self.pending.contains(&peer) {
+            self.pending.touch(peer);
+        }
+        if user.borrow().in_packets.resync_pending {
+            if let Some(lowest) = r.iter().map(|p| p.header.seq).min() {
+                let mut u = user.borrow_mut();
+                info!(
+                    "resync {}: wanted seq {} -> {}",
+                    peer, u.in_packets.wanted_seq, lowest
+                );
+                u.in_packets.resync_to(lowest);
+            }
+        }
+        if let Some(newest) = r.first() {
+            user.borrow_mut()
+                .in_packets
+                .link
+                .note_datagram(newest.header.seq);
+            self.advise_on_loss(user.clone()).await?;
+        }
+        let mut fresh = 0;
         for i in r.iter() {
-            user.borrow_mut().in_packets.add(i.clone());
+            let mut u = user.borrow_mut();
+            if u.in_packets.mark_seen(i.header.seq) {
+                u.in_packets.add(i.clone());
+                fresh += 1;
+            }
+        }
+        self.stats.duplicate_messages += (r.len() - fresh) as u64;
+        // nothing new, but a message queued behind an earlier gap may be next
+        let duplicate = fresh == 0 && !r.is_empty();
+        if duplicate {
+            trace!("duplicate datagram from {}", peer);
+            self.stats.duplicate_datagrams += 1;
         }
-        let want_seq = user.borrow().cur_seq;
-        let message = user.borrow_mut().in_packets.fetch_protocol(want_seq);
+        let want_seq = user.borrow().in_packets.wanted_seq;
+        let message = user.borrow_mut().in_packets.fetch_next();
         let message = match message {
             Some(i) => i,
+            None if duplicate => return Ok(()),
             None => {
                 info!(
                     "user name: {}",
@@ -145,6 +613,13 @@ impl ServiceServer {
                 for i in &user.borrow().in_packets.packets {
                     info!("seq: {}", i.1.header.seq);
                 }
+                // a gap that never fills means the client moved on, follow it.
+                let resync_after = settings::get_num(&self.config, "resync_after", 30);
+                let mut u = user.borrow_mut();
+                u.in_packets.misses += 1;
+                if u.in_packets.misses >= resync_after {
+                    u.in_packets.resync_pending = true;
+                }
                 return Err(KailleraError::NotFoundSeq {
                     wanted_seq: want_seq,
                     cur_seq: 9999,
@@ -153,6 +628,7 @@ impl ServiceServer {
             }
         };
         user.borrow_mut().keepalive_time = Instant::now();
+        user.borrow_mut().in_packets.misses = 0;
         user.borrow().in_packets.show_seq_list();
         // let messages: Vec<_> = r
         //     .iter()
@@ -161,15 +637,121 @@ impl ServiceServer {
 
         // let message = messages.get(0).ok_or(KailleraError::NotFound)?;
         let user = user.clone();
-        user.borrow_mut().cur_seq += 1;
+        self.note_message(&user, &message);
+        let room = self.room_context(&user);
+        io_worker::in_room(room, self.dispatch(peer, user, message)).await
+    }
+    // hand a message to its svc_ handler
+    pub async fn dispatch(
+        &mut self,
+        peer: SocketAddr,
+        user: Rc<RefCell<User>>,
+        message: Protocol,
+    ) -> anyhow::Result<()> {
         if message.header.header.message_type == USER_QUIT {
             self.svc_user_quit(message.data.clone(), user).await?;
         } else if message.header.header.message_type == USER_LOGIN_INFO {
+            let user_name = message.data.split(|x| *x == 0).next().unwrap_or(&[]);
+            if let Some((reason, detail)) = self.login_reject_reason(peer, user_name) {
+                info!("reject login {}: {:?} {:?}", peer, reason, detail);
+                let language = self.config.get("language").map_or("en", |x| x.as_str());
+                let reason = reason.message(language, detail.as_deref());
+                let data = ConnectionReject2Client::new(user_name.to_vec(), UserId(0), reason)
+                    .packetize()?;
+                User::make_send_packet(
+                    &user,
+                    &mut self.socket,
+                    Protocol::new(CONNECTION_REJECT, data),
+                )
+                .await?;
+                return Ok(());
+            }
+            let event = serde_json::json!({
+                "name": display_name(user_name),
+                "addr": peer.to_string(),
+            });
+            let result = self.scripts.call(Hook::Login, event);
+            if result.deny {
+                info!("reject login {}: script", peer);
+                // the script's reply, if any, says why
+                let detail = result.actions.iter().find_map(|x| match x {
+                    ScriptAction::Reply(text) => Some(text.as_str()),
+                    _ => None,
+                });
+                let language = self.config.get("language").map_or("en", |x| x.as_str());
+                let reason = RejectReason::Policy.message(language, detail);
+                let data = ConnectionReject2Client::new(user_name.to_vec(), UserId(0), reason)
+                    .packetize()?;
+                User::make_send_packet(
+                    &user,
+                    &mut self.socket,
+                    Protocol::new(CONNECTION_REJECT, data),
+                )
+                .await?;
+                return Ok(());
+            }
+            self.script_actions(result.actions, Some(user.clone()), None)
+                .await?;
+            // the same name from the same ip, or whoever is logged in at this address
+            let replaced = match self.duplicate_login_policy() {
+                "replace" => self.replaceable_session(peer, user_name).or_else(|| {
+                    let old = self.session_manager.users.get(&peer)?;
+                    (!Rc::ptr_eq(old, &user)).then(|| old.clone())
+                }),
+                _ => None,
+            };
+            // checked before this login makes the address pending
+            let replaced = replaced.map(|old| {
+                let announced = !self.pending.contains(&old.borrow().ip_addr);
+                (old, announced)
+            });
+            let max_users = settings::get_num(&self.config, "max_users", usize::MAX);
+            if self.session_manager.users.len() >= max_users
+                && !self.session_manager.users.contains_key(&peer)
+            {
+                if let Some(bumped) = self.bump_candidate(peer) {
+                    info!(
+                        "server full, {} gives up the slot to {}",
+                        bumped.borrow().ip_addr,
+                        peer
+                    );
+                    self.disconnect_user(
+                        bumped,
+                        b"Your slot was given to a reserved user.".to_vec(),
+                    )
+                    .await?;
+                }
+            }
             self.session_manager.users.insert(peer, user.clone());
-            self.session_manager.next_user_id += 1;
+            self.encryption_pending.remove(&peer);
+            user.borrow_mut().pause_capable = self.pause_pending.remove(&peer).is_some();
+            user.borrow_mut().fast_input_capable = self.fast_input_pending.remove(&peer).is_some();
+            user.borrow_mut().challenged = self.challenge_pending.remove(&peer).is_some();
+            if let Some((old, announced)) = replaced {
+                if user.borrow().challenged {
+                    // replaced once the login echoes the challenge, see svc_ack
+                    self.replacing.insert(peer, (old, announced));
+                } else {
+                    info!("replace session of {} by {}", old.borrow().ip_addr, peer);
+                    self.drop_session(old, b"Replaced by a new login.".to_vec(), announced)
+                        .await?;
+                }
+            }
+            if let Some(evicted) = self.pending.touch(peer) {
+                info!("too many pending logins, forget {}", evicted);
+                // already out of pending, and never announced
+                if let Ok(evicted) = self.session_manager.get_user(evicted) {
+                    self.drop_session(evicted, b"too many pending logins".to_vec(), false)
+                        .await?;
+                }
+            }
+            self.session_manager.next_user_id = self.session_manager.next_user_id.next();
             user.borrow_mut().user_id = self.session_manager.next_user_id;
+            self.save_ids();
             user.borrow_mut().player_status = Idle;
+            self.note_timeline("login", format!("{} ({})", display_name(user_name), peer));
             self.svc_user_login(message.data.clone(), peer).await?;
+            self.start_handshake_timer(user);
         } else if message.header.header.message_type == USER_LOGIN_INFO {
         } else if message.header.header.message_type == USER_SERVER_STATUS {
         } else if message.header.header.message_type == S2C_ACK {
@@ -196,6 +778,13 @@ impl ServiceServer {
             self.svc_kick_user(message.data.clone(), user).await?;
         } else if message.header.header.message_type == START_GAME {
             self.svc_start_game(message.data.clone(), user).await?;
+        } else if (message.header.header.message_type == GAME_DATA
+            || message.header.header.message_type == GAME_CACHE)
+            && !user.borrow_mut().fresh_input(message.header.seq)
+        {
+            // a frame's input twice would shift every later frame
+            trace!("duplicate input seq {} from {}", message.header.seq, peer);
+            self.stats.duplicate_inputs += 1;
         } else if message.header.header.message_type == GAME_DATA {
             self.svc_game_data(message.data.clone(), user).await?;
         } else if message.header.header.message_type == GAME_CACHE {
@@ -210,29 +799,274 @@ impl ServiceServer {
 
         Ok(())
     }
+    // the room the log lines of handling a message from user are tagged with
+    pub fn room_context(&self, user: &Rc<RefCell<User>>) -> Option<(GameId, String)> {
+        let game_id = user.borrow().game_room_id?;
+        let room = self.session_manager.rooms.get(&game_id)?;
+        let game_name = room.borrow().game_name.clone();
+        Some((game_id, game_name))
+    }
+    // count the message and flag what a normal client would not send, see suspicion.rs
+    pub fn note_message(&self, user: &Rc<RefCell<User>>, message: &Protocol) {
+        let message_type = message.header.header.message_type;
+        user.borrow_mut().messages.count(message_type);
+        let room = user
+            .borrow()
+            .game_room_id
+            .and_then(|id| self.session_manager.rooms.get(&id).cloned());
+        let owner = match &room {
+            Some(room) => room.borrow().is_owner(&user.borrow()),
+            None => false,
+        };
+        let anomaly = match schema::fits_to_server(message_type, &message.data) {
+            None => Some(Anomaly::UnknownType),
+            Some(false) => Some(Anomaly::Malformed),
+            Some(true) => match message_type {
+                GAME_DATA | GAME_CACHE | READY_TO_PLAY_SIGNAL | DROP_GAME if room.is_none() => {
+                    Some(Anomaly::OutOfGame)
+                }
+                START_GAME | KICK_USER_FROM_GAME if room.is_some() && !owner => {
+                    Some(Anomaly::NotOwner)
+                }
+                _ => None,
+            },
+        };
+        if let Some(anomaly) = anomaly {
+            let mut u = user.borrow_mut();
+            u.messages.flag(anomaly);
+            info!(
+                "{} ({}): {} message 0x{:02x}, suspicion {}",
+                display_name(&u.name),
+                u.ip_addr,
+                anomaly.name(),
+                message_type,
+                u.messages.score()
+            );
+        }
+    }
+    // what to do when a name that is already online logs in again: keep, replace or reject.
+    pub fn duplicate_login_policy(&self) -> &str {
+        self.config
+            .get("duplicate_login")
+            .map_or("keep", |x| x.as_str())
+    }
+    // the session a login may replace: the same name from the same ip. names are
+    // not authenticated, so the name alone would let anyone kick its owner
+    pub fn replaceable_session(
+        &self,
+        peer: SocketAddr,
+        user_name: &[u8],
+    ) -> Option<Rc<RefCell<User>>> {
+        self.session_manager
+            .find_user_by_name(user_name)
+            .filter(|u| u.borrow().ip_addr.ip() == peer.ip())
+    }
+    pub fn login_reject_reason(
+        &self,
+        peer: SocketAddr,
+        user_name: &[u8],
+    ) -> Option<(RejectReason, Option<String>)> {
+        if settings::ip_in_list(&self.config, "bans", peer.ip()) {
+            return Some((RejectReason::Banned, None));
+        }
+        if self.config.get("login_challenge").map(|x| x.as_str()) == Some("require")
+            && !self.challenge_pending.contains_key(&peer)
+        {
+            return Some((
+                RejectReason::Challenge,
+                Some("CHALLENGE missing from HELLO".into()),
+            ));
+        }
+        // admins still get in to turn it off
+        if let Some(address) = &self.redirect {
+            if !self.is_admin(peer) {
+                return Some((RejectReason::Moved, Some(address.clone())));
+            }
+        }
+        if let Some(left) = self.punishments.banned_for(peer.ip(), Instant::now()) {
+            let detail = format!("{} minutes", left.as_secs() / 60 + 1);
+            return Some((RejectReason::Banned, Some(detail)));
+        }
+        // a replaced session frees its slot
+        let replaced = self.duplicate_login_policy() == "replace"
+            && self.replaceable_session(peer, user_name).is_some();
+        // replace falls back to reject for the name from another ip
+        if self.duplicate_login_policy() != "keep"
+            && !replaced
+            && self.session_manager.find_user_by_name(user_name).is_some()
+        {
+            return Some((RejectReason::DuplicateName, None));
+        }
+        // the last reserved_slots are kept for admins and vips
+        let max_users = settings::get_num(&self.config, "max_users", usize::MAX);
+        let vip = self.is_vip(peer);
+        let limit = if vip {
+            max_users
+        } else {
+            max_users.saturating_sub(settings::get_num(&self.config, "reserved_slots", 0))
+        };
+        if self.session_manager.users.len() >= limit && !replaced {
+            if vip && self.bump_candidate(peer).is_some() {
+                return None;
+            }
+            return Some((RejectReason::ServerFull, None));
+        }
+        None
+    }
+    // by ip only, anyone can log in with a vip's name
+    pub fn is_vip(&self, peer: SocketAddr) -> bool {
+        self.is_admin(peer) || settings::ip_in_list(&self.config, "vips", peer.ip())
+    }
+    // with full_server_policy = "bump", the lobby user idle the longest gives up
+    // their slot to an admin or vip on a full server. users in a game are never bumped.
+    pub fn bump_candidate(&self, peer: SocketAddr) -> Option<Rc<RefCell<User>>> {
+        if self
+            .config
+            .get("full_server_policy")
+            .map_or("reject", |x| x.as_str())
+            != "bump"
+        {
+            return None;
+        }
+        self.session_manager
+            .users
+            .values()
+            .filter(|u| {
+                let u = u.borrow();
+                u.ip_addr != peer
+                    && u.game_room_id.is_none()
+                    && !self.pending.contains(&u.ip_addr)
+                    && !self.is_vip(u.ip_addr)
+            })
+            .min_by_key(|u| u.borrow().keepalive_time)
+            .cloned()
+    }
+    pub fn save_ids(&self) {
+        let path = match self.config.get("id_state_file") {
+            Some(path) => path,
+            None => return,
+        };
+        let state = IdState {
+            user_id: self.session_manager.next_user_id,
+            game_id: self.game_id,
+        };
+        self.io
+            .write_file(path.into(), state.to_text().into_bytes());
+    }
+    pub fn is_admin(&self, addr: SocketAddr) -> bool {
+        settings::ip_in_list(&self.config, "admins", addr.ip())
+    }
+    // /redirect host:port [name ...] sends the named users, or everyone but the
+    // admin, to another server: a SERVER_INFO and a lobby line with the address,
+    // then a disconnect. redirecting everyone also rejects new logins with the
+    // address until /redirect off.
+    pub async fn svc_redirect(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let arg = display_name(arg.split(|x| *x == 0).next().unwrap_or(&[]));
+        let mut words = arg.split_whitespace();
+        let address = words.next().unwrap_or("").to_string();
+        let names: Vec<String> = words.map(|x| x.to_string()).collect();
+        if address == "off" {
+            self.redirect = None;
+            info!("redirect off");
+            return User::send_message(&user, &mut self.socket, b"redirect off".to_vec()).await;
+        }
+        let valid = match address.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+        if !valid {
+            return User::send_message(
+                &user,
+                &mut self.socket,
+                b"usage: /redirect host:port [name ...], /redirect off".to_vec(),
+            )
+            .await;
+        }
+        let admin = user.borrow().ip_addr;
+        let targets: Vec<_> = self
+            .session_manager
+            .users
+            .values()
+            .filter(|u| {
+                let name = display_name(&u.borrow().name);
+                if names.is_empty() {
+                    u.borrow().ip_addr != admin
+                } else {
+                    names.contains(&name)
+                }
+            })
+            .cloned()
+            .collect();
+        if names.is_empty() {
+            self.redirect = Some(address.clone());
+        }
+        info!("redirect {} users to {}", targets.len(), address);
+        let text = format!("This server is moving, please reconnect to {}", address);
+        let text = encoding_rs::EUC_KR.encode(&text).0.to_vec();
+        for u in &targets {
+            for data in chat_bodies(b"Server", &text) {
+                User::make_send_packet(u, &mut self.socket, Protocol::new(SERVER_INFO, data))
+                    .await?;
+            }
+            User::send_message(u, &mut self.socket, text.clone()).await?;
+        }
+        let reason = format!("moved to {}", address).into_bytes();
+        for u in &targets {
+            self.disconnect_user(u.clone(), reason.clone()).await?;
+        }
+        let line = format!("{} users sent to {}", targets.len(), address);
+        User::send_message(&user, &mut self.socket, line.into_bytes()).await
+    }
+    // mute and chat filter check for global and game chat.
+    // returns true when the message must not be relayed.
+    pub async fn moderate_chat(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        message: &[u8],
+    ) -> anyhow::Result<bool> {
+        let ip = user.borrow().ip_addr.ip();
+        let now = Instant::now();
+        if let Some(left) = self.punishments.muted_for(ip, now) {
+            let notice = format!("You are muted for {} seconds.", left.as_secs());
+            User::send_message(&user, &mut self.socket, notice.into_bytes()).await?;
+            return Ok(true);
+        }
+        let text = encoding_rs::EUC_KR.decode(message).0.to_lowercase();
+        let filtered = settings::get_list(&self.config, "chat_filter")
+            .iter()
+            .any(|w| text.contains(&w.to_lowercase()));
+        if !filtered {
+            return Ok(false);
+        }
+        let policy = EscalationPolicy::from_config(&self.config);
+        let action = self.punishments.record_filter_hit(ip, now, &policy);
+        info!("chat filter hit {}: {:?}", ip, action);
+        let notice = match action {
+            Action::Warn => "Your message was blocked by the chat filter.".to_string(),
+            Action::Mute(d) => format!("You are muted for {} minutes.", d.as_secs() / 60),
+            Action::Kick => "You are kicked for repeated chat filter violations.".to_string(),
+            Action::Ban(d) => format!("You are banned for {} minutes.", d.as_secs() / 60),
+        };
+        User::send_message(&user, &mut self.socket, notice.into_bytes()).await?;
+        match action {
+            Action::Kick => self.disconnect_user(user, b"kicked".to_vec()).await?,
+            Action::Ban(_) => self.disconnect_user(user, b"banned".to_vec()).await?,
+            _ => {}
+        }
+        Ok(true)
+    }
     pub async fn svc_user_quit(
         &mut self,
         buf: Vec<u8>,
         user: Rc<RefCell<User>>,
     ) -> anyhow::Result<()> {
         info!("== svc_user_quit ==");
-        let _ = self.fun_quit_game(user.clone()).await;
-
-        let client_message = &buf[3..];
-        // send quit message to all
-        let data = UserQuitPacket2Client::new(
-            user.borrow().name.clone(),
-            user.borrow().user_id,
-            client_message.to_vec(),
-        )
-        .packetize()?;
-        for (_addr, u) in &self.session_manager.users {
-            u.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(USER_QUIT, data.clone()))
-                .await?;
-        }
-        self.session_manager.users.remove(&user.borrow().ip_addr);
-        Ok(())
+        let reason = quit_reason(&buf);
+        self.disconnect_user(user, reason).await
     }
     pub async fn svc_user_login(
         &mut self,
@@ -246,168 +1080,1260 @@ impl ServiceServer {
         let un = iter.get(0).ok_or(KailleraError::NotFound)?.to_vec();
         let emul_name =
             String::from_utf8_lossy(iter.get(1).ok_or(KailleraError::NotFound)?).to_string();
-        let conn_type = iter.get(2).ok_or(KailleraError::NotFound)?[0];
+        // 1 (lan) to 6 (bad); frame sizes are divided by it. a 0 ends the body early
+        let conn_type = iter.get(2).and_then(|x| x.first()).copied().unwrap_or(0);
+        if !(1..=6).contains(&conn_type) {
+            info!("reject login {}: connection type {}", ip_addr, conn_type);
+            let language = self.config.get("language").map_or("en", |x| x.as_str());
+            let detail = format!("connection type {}", conn_type);
+            let reason = RejectReason::BadVersion.message(language, Some(&detail));
+            let data = ConnectionReject2Client::new(un, UserId(0), reason).packetize()?;
+            User::make_send_packet(
+                &user,
+                &mut self.socket,
+                Protocol::new(CONNECTION_REJECT, data),
+            )
+            .await?;
+            return self
+                .disconnect_user(user, b"bad connection type".to_vec())
+                .await;
+        }
         user.borrow_mut().name = un.clone();
         user.borrow_mut().emul_name = emul_name.clone();
         user.borrow_mut().connect_type = conn_type;
         info!("login info: {:?} {} {}", un.clone(), emul_name, conn_type);
 
-        let send_data = bincode::serialize::<AckProtocol>(&AckProtocol::new())?;
-        let protocol = Protocol::new(S2C_ACK, send_data);
-        user.borrow_mut().s2c_ack_time = Instant::now();
+        let protocol = Self::login_ack(&mut user.borrow_mut())?;
 
-        user.borrow_mut()
-            .make_send_packet(&mut self.socket, protocol)
-            .await?;
+        User::make_send_packet(&user, &mut self.socket, protocol).await?;
         // self.socket.send_to(&send_data, ip_addr).await?;
         Ok(())
     }
-    pub async fn svc_ack(&mut self, _buf: Vec<u8>, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+    // the next S2C_ACK of the login, with new values to echo for a challenged user
+    pub fn login_ack(user: &mut User) -> anyhow::Result<Protocol> {
+        let ack = if user.challenged {
+            let nonce = rand::thread_rng().gen();
+            user.ack_nonce = Some(nonce);
+            AckProtocol::challenge(nonce)
+        } else {
+            AckProtocol::new()
+        };
+        user.s2c_ack_time = Instant::now();
+        Ok(Protocol::new(S2C_ACK, bincode::serialize(&ack)?))
+    }
+    // a C2S_ACK that does not echo the challenge rejects the login
+    pub async fn check_ack_echo(
+        &mut self,
+        buf: &[u8],
+        user: Rc<RefCell<User>>,
+    ) -> anyhow::Result<bool> {
+        let nonce = match user.borrow().ack_nonce {
+            Some(nonce) => nonce,
+            None => return Ok(true),
+        };
+        if let Ok(ack) = bincode::deserialize::<AckProtocol>(buf) {
+            if ack.nonce() == nonce {
+                return Ok(true);
+            }
+        }
+        info!("{} did not echo the login challenge", user.borrow().ip_addr);
+        let language = self.config.get("language").map_or("en", |x| x.as_str());
+        let reason = RejectReason::Challenge.message(language, None);
+        let user_id = user.borrow().user_id;
+        let data = ConnectionReject2Client::new(user.borrow().name.clone(), user_id, reason)
+            .packetize()?;
+        User::make_send_packet(
+            &user,
+            &mut self.socket,
+            Protocol::new(CONNECTION_REJECT, data),
+        )
+        .await?;
+        self.disconnect_user(user, b"login challenge failed".to_vec())
+            .await?;
+        Ok(false)
+    }
+    pub async fn svc_ack(&mut self, buf: Vec<u8>, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
         info!("on svc_ack");
+        if !self.check_ack_echo(&buf, user.clone()).await? {
+            return Ok(());
+        }
         let elapsed = user.borrow().s2c_ack_time.elapsed().as_millis();
-        let user_room = &mut self.session_manager;
         user.borrow_mut().pings.push(elapsed as i32);
         if user.borrow().send_count <= 4 {
-            let send_data = bincode::serialize::<AckProtocol>(&AckProtocol::new())?;
-            user.borrow_mut().s2c_ack_time = Instant::now();
-            let protocol = Protocol::new(S2C_ACK, send_data);
-            user.borrow_mut()
-                .make_send_packet(&mut self.socket, protocol)
-                .await?;
+            let protocol = Self::login_ack(&mut user.borrow_mut())?;
+            User::make_send_packet(&user, &mut self.socket, protocol).await?;
         } else {
             let sum: i32 = user.borrow().pings.iter().sum();
             let len = user.borrow().pings.len() as f64;
 
             let average = sum as f64 / len;
-            let is_random = match self.config.get("random_ping") {
-                Some(x) => x.parse::<bool>().unwrap(),
-                None => false,
-            };
-            if is_random {
-                // set random ping [0, 100]
-                user.borrow_mut().ping = rand::thread_rng().gen_range(0..100);
-            } else {
-                user.borrow_mut().ping = average as u32;
+            self.pending.remove(&user.borrow().ip_addr);
+            // logged in, later acks need not echo anything
+            user.borrow_mut().ack_nonce = None;
+            let addr = user.borrow().ip_addr;
+            if let Some((old, announced)) = self.replacing.remove(&addr) {
+                info!("replace session of {} by {}", old.borrow().ip_addr, addr);
+                self.drop_session(old, b"Replaced by a new login.".to_vec(), announced)
+                    .await?;
+            }
+            let ping = average as u32;
+            let shown_ping = Self::debug_ping(&self.config, ping);
+            if shown_ping != ping {
+                info!(
+                    "debug ping for {}: measured {}ms, shown {}ms",
+                    user.borrow().ip_addr,
+                    ping,
+                    shown_ping
+                );
             }
+            user.borrow_mut().ping = ping;
+            user.borrow_mut().shown_ping = shown_ping;
+            if settings::get_bool(&self.config, "chat_timestamps", false) {
+                user.borrow_mut().chat_clock = Some(Self::server_clock(&self.config));
+            }
+            user.borrow_mut().send_pacer.gap =
+                Duration::from_millis(settings::get_num(&self.config, "send_pacing_ms", 0));
             {
-                let p = user_room.make_server_status(user.borrow().ip_addr)?;
-                user.borrow_mut()
-                    .make_send_packet(&mut self.socket, p)
-                    .await?;
+                let p = self.session_manager.make_server_status(
+                    user.borrow().ip_addr,
+                    GradeDisplay::from_config(&self.config),
+                )?;
+                User::make_send_packet(&user, &mut self.socket, p).await?;
             }
+            let (name, connect_type) = user
+                .borrow()
+                .listed_as(GradeDisplay::from_config(&self.config));
             for i in &self.session_manager.users {
                 let data = UserJoinPacket2Client::new(
-                    user.borrow().name.clone(),
+                    name.clone(),
                     user.borrow().user_id,
-                    user.borrow().ping,
-                    user.borrow().connect_type,
+                    user.borrow().shown_ping,
+                    connect_type,
                 )
                 .packetize()?;
-                i.1.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(USER_JOIN, data))
+                User::make_send_packet(i.1, &mut self.socket, Protocol::new(USER_JOIN, data))
                     .await?;
             }
             {
-                let mut data = Vec::new();
-                data.append(&mut b"Server\x00".to_vec());
-                let mut euc_kr = encoding_rs::EUC_KR
+                let mut text = encoding_rs::EUC_KR
                     .encode(&self.config.get("notice").unwrap_or(&"".to_string()).clone())
                     .0
                     .to_vec();
-                data.append(&mut euc_kr);
                 const VERSION: &str = env!("CARGO_PKG_VERSION");
-                data.append(&mut b"\ndirelera version: ".to_vec());
-                data.append(&mut VERSION.as_bytes().to_vec());
-                data.push(0);
-                user.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(SERVER_INFO, data))
+                text.append(&mut b"\ndirelera version: ".to_vec());
+                text.append(&mut VERSION.as_bytes().to_vec());
+                for data in chat_bodies(b"Server", &text) {
+                    User::make_send_packet(
+                        &user,
+                        &mut self.socket,
+                        Protocol::new(SERVER_INFO, data),
+                    )
                     .await?;
+                }
+            }
+            let pages = motd::pages(self.config.get("motd").map_or("", |x| x.as_str())).len();
+            if pages > 0 {
+                let text = format!("Type /motd to read the server board ({} pages).", pages);
+                User::send_message(&user, &mut self.socket, text.into_bytes()).await?;
+            }
+            self.send_rules(user.clone()).await?;
+            if settings::get_bool(&self.config, "suggest_connection_type", true) {
+                let (connect_type, ping) = (user.borrow().connect_type, user.borrow().ping);
+                let suggested = Self::suggest_connection_type(ping);
+                if suggested != connect_type {
+                    let text = format!(
+                        "Your ping ({}ms) suggests connection type {}, you are using {}.",
+                        ping, suggested, connect_type
+                    );
+                    User::send_message(&user, &mut self.socket, text.into_bytes()).await?;
+                }
             }
+            let name = display_name(&user.borrow().name);
+            self.notify_friends(&name, format!("Your friend {} logged in.", name))
+                .await?;
         }
 
         Ok(())
     }
-    pub async fn svc_global_chat(
-        &mut self,
-        buf: Vec<u8>,
-        ip_addr: SocketAddr,
-    ) -> anyhow::Result<()> {
-        let user_room = &mut self.session_manager;
-        let user = user_room.get_user(ip_addr)?;
-        let message = buf[1..].to_vec();
-        let data =
-            GlobalChat2Client::new(user.borrow().name.clone(), message.clone()).packetize()?;
-        for i in &self.session_manager.users {
-            i.1.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(GLOBAL_CHAT, data.clone()))
-                .await?;
+    // once per session, tell a logged in user whose datagrams keep getting lost to
+    // pick a slower connection type. checked every LOSS_WINDOW datagrams.
+    pub async fn advise_on_loss(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        let loss = match user.borrow_mut().in_packets.link.take_window(LOSS_WINDOW) {
+            Some(loss) => loss,
+            None => return Ok(()),
+        };
+        let threshold = settings::get_num(&self.config, "loss_advise_percent", 10);
+        let logged_in = self
+            .session_manager
+            .users
+            .contains_key(&user.borrow().ip_addr);
+        if threshold == 0 || loss < threshold || !logged_in || user.borrow().in_packets.link.advised
+        {
+            return Ok(());
         }
-        // cp949 to utf-8 for message
-
-        println!(
-            "chat message: {:?}",
-            encoding_rs::EUC_KR.decode(&message).0.to_string()
+        user.borrow_mut().in_packets.link.advised = true;
+        let connect_type = user.borrow().connect_type;
+        info!(
+            "{}: {}% datagram loss",
+            display_name(&user.borrow().name),
+            loss
         );
-
-        if String::from_utf8_lossy(&message.clone()) == "ts\x00" {
-            println!("admin mode");
-            let d = self.session_manager.to_string();
-            for i in &self.session_manager.users {
-                let d = d.clone();
-                let split_data: Vec<_> = d.split('\n').collect();
-                for each_data in split_data {
-                    if !each_data.is_empty() {
-                        let data = GlobalChat2Client::new(
-                            user.borrow().name.clone(),
-                            each_data.to_string().into_bytes(),
-                        )
-                        .packetize()?;
-                        i.1.borrow_mut()
-                            .make_send_packet(&mut self.socket, Protocol::new(GLOBAL_CHAT, data))
-                            .await?;
-                    }
-                }
-            }
-        }
-
-        Ok(())
+        let text = format!(
+            "About {}% of your packets are being lost. Try a slower connection type than {} (e.g. {}).",
+            loss,
+            connect_type,
+            connect_type.saturating_add(1).min(6)
+        );
+        User::send_message(&user, &mut self.socket, text.into_bytes()).await
     }
-    pub async fn svc_game_chat(&mut self, buf: Vec<u8>, ip_addr: SocketAddr) -> anyhow::Result<()> {
-        // let user_room = &self.user_room;
-        let user = self.session_manager.get_user(ip_addr)?;
+    // rules prompt: games stay closed until the user answers /agree within rules_agree_secs
+    pub async fn send_rules(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        let rules = match self.config.get("rules") {
+            Some(rules) if !rules.trim().is_empty() => rules.clone(),
+            _ => return Ok(()),
+        };
+        let secs = settings::get_num(&self.config, "rules_agree_secs", 60);
+        user.borrow_mut().rules_accepted = false;
+        for line in rules.trim().lines() {
+            User::send_message(
+                &user,
+                &mut self.socket,
+                encoding_rs::EUC_KR.encode(line).0.to_vec(),
+            )
+            .await?;
+        }
+        let text = format!(
+            "Type /agree within {} seconds to accept the rules and play.",
+            secs
+        );
+        User::send_message(&user, &mut self.socket, text.into_bytes()).await?;
+        let (addr, user_id) = (user.borrow().ip_addr, user.borrow().user_id);
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            let _ = tx.send(Event::RulesTimeout(addr, user_id)).await;
+        });
+        Ok(())
+    }
+    pub async fn rules_timeout_event(
+        &mut self,
+        addr: SocketAddr,
+        user_id: UserId,
+    ) -> anyhow::Result<()> {
+        let user = match self.session_manager.users.get(&addr) {
+            Some(u) if u.borrow().user_id == user_id && !u.borrow().rules_accepted => u.clone(),
+            _ => return Ok(()),
+        };
+        info!("{} did not accept the rules", addr);
+        self.disconnect_user(user, b"Rules not accepted.".to_vec())
+            .await
+    }
+    // a login has handshake_timeout_secs to finish the ack exchange
+    pub fn start_handshake_timer(&self, user: Rc<RefCell<User>>) {
+        let secs = settings::get_num(&self.config, "handshake_timeout_secs", 5);
+        let (addr, user_id) = (user.borrow().ip_addr, user.borrow().user_id);
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            let _ = tx.send(Event::HandshakeTimeout(addr, user_id)).await;
+        });
+    }
+    // the session is still pending: no USER_JOIN went out, so only the client is told
+    pub async fn handshake_timeout_event(
+        &mut self,
+        addr: SocketAddr,
+        user_id: UserId,
+    ) -> anyhow::Result<()> {
+        let user = match self.session_manager.users.get(&addr) {
+            Some(u) if u.borrow().user_id == user_id && self.pending.contains(&addr) => u.clone(),
+            _ => return Ok(()),
+        };
+        info!(
+            "{} stalled in the login handshake after {} acks",
+            addr,
+            user.borrow().pings.len()
+        );
+        let language = self.config.get("language").map_or("en", |x| x.as_str());
+        let reason = RejectReason::HandshakeTimeout.message(language, None);
+        let data = ConnectionReject2Client::new(user.borrow().name.clone(), user_id, reason)
+            .packetize()?;
+        User::make_send_packet(
+            &user,
+            &mut self.socket,
+            Protocol::new(CONNECTION_REJECT, data),
+        )
+        .await?;
+        self.disconnect_user(user, b"login timed out".to_vec())
+            .await
+    }
+    // false (and a reminder) while the rules are not accepted
+    pub async fn check_rules_accepted(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<bool> {
+        if user.borrow().rules_accepted {
+            return Ok(true);
+        }
+        User::send_message(
+            &user,
+            &mut self.socket,
+            b"Type /agree to accept the rules first.".to_vec(),
+        )
+        .await?;
+        Ok(false)
+    }
+    // tell the user why their request was ignored: in the room when they are in
+    // one, else in the lobby. refusal_feedback = "off" keeps quiet.
+    pub async fn refuse(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        refusal: Refusal,
+    ) -> anyhow::Result<()> {
+        info!("refuse {}: {:?}", user.borrow().ip_addr, refusal);
+        let verbose = match self
+            .config
+            .get("refusal_feedback")
+            .map_or("short", |x| x.as_str())
+        {
+            "off" => return Ok(()),
+            "verbose" => true,
+            _ => false,
+        };
+        let language = self.config.get("language").map_or("en", |x| x.as_str());
+        let text = refusal.message(language, verbose);
+        let in_room = user.borrow().game_room_id.is_some();
+        if in_room {
+            User::send_game_message(&user, &mut self.socket, text).await
+        } else {
+            User::send_message(&user, &mut self.socket, text).await
+        }
+    }
+    // tell everyone online who has name in their friend list
+    pub async fn notify_friends(&mut self, name: &str, text: String) -> anyhow::Result<()> {
+        for follower in self.friends.followers(name) {
+            let encoded = encoding_rs::EUC_KR.encode(&follower).0;
+            if let Some(u) = self.session_manager.find_user_by_name(&encoded) {
+                User::send_message(
+                    &u,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+    // /friends, /friend add name, /friend remove name
+    pub async fn svc_friend(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        message: &[u8],
+    ) -> anyhow::Result<()> {
+        let name = display_name(&user.borrow().name);
+        let text = display_name(message.split(|x| *x == 0).next().unwrap_or(&[]));
+        let mut changed = false;
+        let reply = if let Some(friend) = text.strip_prefix("/friend add ") {
+            match self.friends.add(&name, friend.trim()) {
+                Ok(true) => {
+                    changed = true;
+                    format!("{} added to your friends.", friend.trim())
+                }
+                Ok(false) => format!("{} is already your friend.", friend.trim()),
+                Err(e) => format!("{} not added: {}.", friend.trim(), e),
+            }
+        } else if let Some(friend) = text.strip_prefix("/friend remove ") {
+            if self.friends.remove(&name, friend.trim()) {
+                changed = true;
+                format!("{} removed from your friends.", friend.trim())
+            } else {
+                format!("{} is not your friend.", friend.trim())
+            }
+        } else {
+            let list = self.friends.list(&name);
+            if list.is_empty() {
+                "No friends yet, add one with /friend add name".to_string()
+            } else {
+                format!("friends: {}", list.join(", "))
+            }
+        };
+        if let (true, Some(path)) = (changed, &self.friends.path) {
+            self.io
+                .write_file(path.clone(), self.friends.to_text().into_bytes());
+        }
+        User::send_message(
+            &user,
+            &mut self.socket,
+            encoding_rs::EUC_KR.encode(&reply).0.to_vec(),
+        )
+        .await
+    }
+    // /find name: which room a user is in. find_privacy says who may ask:
+    // everyone ("open"), users on the target's friend list ("friends") or
+    // admins only ("admins"); admins always may.
+    pub async fn svc_find(&mut self, user: Rc<RefCell<User>>, arg: &[u8]) -> anyhow::Result<()> {
+        let shown = display_name(arg.split(|x| *x == 0).next().unwrap_or(&[]));
+        let shown = shown.trim();
+        let searcher = display_name(&user.borrow().name);
+        let allowed = self.is_admin(user.borrow().ip_addr)
+            || match self
+                .config
+                .get("find_privacy")
+                .map_or("open", |x| x.as_str())
+            {
+                "friends" => self.friends.list(shown).contains(&searcher),
+                "admins" => false,
+                _ => true,
+            };
+        let encoded = encoding_rs::EUC_KR.encode(shown).0;
+        let reply = match self.session_manager.find_user_by_name(&encoded) {
+            None => format!("{} is not online.", shown),
+            Some(_) if !allowed => format!("{} does not share where they are.", shown),
+            Some(u) => match u.borrow().game_room_id {
+                None => format!("{} is in the lobby.", shown),
+                Some(room_id) => {
+                    let room = self.session_manager.get_room(room_id)?;
+                    let room = room.borrow();
+                    format!(
+                        "{} is in game {} \"{}\" ({}, {}/{} players).",
+                        shown,
+                        room.game_id,
+                        room.game_name,
+                        if room.game_status == GAME_STATUS_PLAYING {
+                            "playing"
+                        } else {
+                            "waiting"
+                        },
+                        room.player_some_count(),
+                        room.max_players
+                    )
+                }
+            },
+        };
+        User::send_message(
+            &user,
+            &mut self.socket,
+            encoding_rs::EUC_KR.encode(&reply).0.to_vec(),
+        )
+        .await
+    }
+    pub async fn svc_global_chat(
+        &mut self,
+        buf: Vec<u8>,
+        ip_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let user_room = &mut self.session_manager;
+        let user = user_room.get_user(ip_addr)?;
+        let message = try_get_rest(&buf, 1)?.to_vec();
+        if self.chat_hook(user.clone(), None, &message).await? {
+            return Ok(());
+        }
+        if message == b"/info\x00" {
+            return self.svc_info(user).await;
+        } else if message == b"/agree\x00" {
+            if !user.borrow().rules_accepted {
+                user.borrow_mut().rules_accepted = true;
+                return User::send_message(
+                    &user,
+                    &mut self.socket,
+                    b"Thanks, you can create and join games now.".to_vec(),
+                )
+                .await;
+            }
+            return Ok(());
+        } else if message.starts_with(b"/loadtemplate ") {
+            return self.load_template(None, user, &message[14..]).await;
+        } else if message == b"/templates\x00" {
+            let owner = from_utf8_lossy(user.borrow().name.as_slice()).to_string();
+            let names = self.templates.names(&owner);
+            let line = if names.is_empty() {
+                "no templates, save one in a room with /savetemplate name".to_string()
+            } else {
+                format!("templates: {}", names.join(", "))
+            };
+            return User::send_message(&user, &mut self.socket, line.into_bytes()).await;
+        } else if message == b"/motd\x00" || message.starts_with(b"/motd ") {
+            let page = String::from_utf8_lossy(&message[5..]);
+            let page = page.trim_matches(|x: char| x == '\x00' || x.is_whitespace());
+            let text = self.config.get("motd").map_or("", |x| x.as_str());
+            for line in motd::page_lines(text, page.parse().unwrap_or(1)) {
+                User::send_message(
+                    &user,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&line).0.to_vec(),
+                )
+                .await?;
+            }
+            return Ok(());
+        } else if message.starts_with(b"/timestamps ") {
+            return self.svc_timestamps(user, &message[12..]).await;
+        } else if message == b"/peers\x00" {
+            return self.svc_peers(user).await;
+        } else if message.starts_with(b"/find ") {
+            return self.svc_find(user, &message[6..]).await;
+        } else if message == b"/friends\x00" || message.starts_with(b"/friend ") {
+            return self.svc_friend(user, &message).await;
+        } else if message == b"/punishments\x00" && self.is_admin(ip_addr) {
+            let mut lines = self.punishments.describe(Instant::now());
+            if lines.is_empty() {
+                lines.push("no punishments".to_string());
+            }
+            for line in lines {
+                User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+            }
+            return Ok(());
+        } else if message.starts_with(b"/redirect ") && self.is_admin(ip_addr) {
+            return self.svc_redirect(user, &message[10..]).await;
+        } else if message == b"/reachability\x00" && self.is_admin(ip_addr) {
+            let line = self.start_probe(Some(ip_addr))?;
+            return User::send_message(&user, &mut self.socket, line.into_bytes()).await;
+        } else if message == b"/dump\x00" && self.is_admin(ip_addr) {
+            let line = match self.dump_state() {
+                Ok(path) => format!("state written to {}", path.display()),
+                Err(e) => format!("dump failed: {}", e),
+            };
+            info!("{}", line);
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+            return Ok(());
+        } else if message == b"/loss\x00" && self.is_admin(ip_addr) {
+            let mut users: Vec<_> = self
+                .session_manager
+                .users
+                .values()
+                .map(|u| {
+                    let u = u.borrow();
+                    (
+                        u.in_packets.link.loss_percent(),
+                        u.in_packets.link.clone(),
+                        display_name(&u.name),
+                    )
+                })
+                .collect();
+            users.sort_by_key(|x| std::cmp::Reverse(x.0));
+            let mut lines: Vec<_> = users
+                .iter()
+                .take(10)
+                .map(|(loss, link, name)| {
+                    format!(
+                        "{}: {}% lost ({} of {}), {} stale",
+                        name,
+                        loss,
+                        link.lost,
+                        link.datagrams + link.lost,
+                        link.stale
+                    )
+                })
+                .collect();
+            if lines.is_empty() {
+                lines.push("no users".to_string());
+            }
+            for line in lines {
+                User::send_message(
+                    &user,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&line).0.to_vec(),
+                )
+                .await?;
+            }
+            return Ok(());
+        } else if message == b"/suspicion\x00" && self.is_admin(ip_addr) {
+            let mut users: Vec<_> = self
+                .session_manager
+                .users
+                .values()
+                .map(|u| {
+                    let u = u.borrow();
+                    (
+                        u.messages.score(),
+                        u.messages.clone(),
+                        display_name(&u.name),
+                    )
+                })
+                .filter(|(score, _, _)| *score > 0)
+                .collect();
+            users.sort_by_key(|x| std::cmp::Reverse(x.0));
+            let mut lines: Vec<_> = users
+                .iter()
+                .take(10)
+                .map(|(score, messages, name)| {
+                    format!(
+                        "{}: suspicion {} ({}) in {} messages",
+                        name,
+                        score,
+                        messages.describe(),
+                        messages.total()
+                    )
+                })
+                .collect();
+            if lines.is_empty() {
+                lines.push("no anomalies".to_string());
+            }
+            for line in lines {
+                User::send_message(
+                    &user,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&line).0.to_vec(),
+                )
+                .await?;
+            }
+            return Ok(());
+        } else if message.starts_with(b"/seq ") && self.is_admin(ip_addr) {
+            let name = message[5..].split(|x| *x == 0).next().unwrap_or(&[]);
+            let line = match self.session_manager.find_user_by_name(name) {
+                Some(u) => u.borrow().in_packets.state(),
+                None => "no such user".to_string(),
+            };
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+            return Ok(());
+        } else if message.starts_with(b"/resync ") && self.is_admin(ip_addr) {
+            let name = message[8..].split(|x| *x == 0).next().unwrap_or(&[]);
+            let line = match self.session_manager.find_user_by_name(name) {
+                Some(u) => {
+                    u.borrow_mut().in_packets.resync_pending = true;
+                    "resync on next datagram".to_string()
+                }
+                None => "no such user".to_string(),
+            };
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+            return Ok(());
+        }
+        if self.moderate_chat(user.clone(), &message).await? {
+            return Ok(());
+        }
+        let name = user.borrow().name.clone();
+        let now = chrono::Utc::now();
+        for i in &self.session_manager.users {
+            let text = i.1.borrow().chat_text(now, &message);
+            for data in GlobalChat2Client::new(name.clone(), text).packetize_split() {
+                User::make_send_packet(i.1, &mut self.socket, Protocol::new(GLOBAL_CHAT, data))
+                    .await?;
+            }
+        }
+        // cp949 to utf-8 for message
+
+        println!(
+            "chat message: {:?}",
+            encoding_rs::EUC_KR.decode(&message).0.to_string()
+        );
+
+        if String::from_utf8_lossy(&message.clone()) == "ts\x00" {
+            println!("admin mode");
+            let d = self.session_manager.to_string();
+            let name = user.borrow().name.clone();
+            for i in &self.session_manager.users {
+                let d = d.clone();
+                let split_data: Vec<_> = d.split('\n').collect();
+                for each_data in split_data {
+                    if !each_data.is_empty() {
+                        for data in
+                            GlobalChat2Client::new(name.clone(), each_data.to_string().into_bytes())
+                                .packetize_split()
+                        {
+                            User::make_send_packet(
+                                i.1,
+                                &mut self.socket,
+                                Protocol::new(GLOBAL_CHAT, data),
+                            )
+                            .await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+    // reply to /info with server version, uptime and population.
+    pub async fn svc_info(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        const VERSION: &str = env!("CARGO_PKG_VERSION");
+        let lines = vec![
+            format!("direlera version: {}", VERSION),
+            format!("uptime: {}", format_uptime(self.stats.uptime())),
+            format!("users: {}", self.session_manager.users.len()),
+            format!("games: {}", self.session_manager.rooms.len()),
+            format!("games played: {}", self.stats.games_played),
+            format!(
+                "duplicates dropped: {} messages, {} datagrams, {} game inputs",
+                self.stats.duplicate_messages,
+                self.stats.duplicate_datagrams,
+                self.stats.duplicate_inputs
+            ),
+            format!("malformed messages: {}", self.stats.malformed_messages),
+            format!("load: {}", self.stats.load.current.line()),
+        ];
+        for line in lines {
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+        }
+        Ok(())
+    }
+    // list rooms of federated servers with the address to connect to.
+    pub async fn svc_peers(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        for (addr, (_, status)) in &self.peers {
+            lines.push(format!(
+                "{}:{} users: {}, games: {}",
+                addr.ip(),
+                status.main_port,
+                status.users,
+                status.rooms.len()
+            ));
+            for r in &status.rooms {
+                lines.push(format!(
+                    "  {} ({}) {}/{} {}",
+                    r.game_name,
+                    r.emul_name,
+                    r.players,
+                    r.max_players,
+                    game_status_name(r.game_status)
+                ));
+            }
+        }
+        if lines.is_empty() {
+            lines.push("no peer servers".to_string());
+        }
+        for line in lines {
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
+        }
+        Ok(())
+    }
+    // /timestamps on|off|+9: [HH:MM] on chat this user sees, in server time or the given utc offset
+    pub async fn svc_timestamps(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let arg = String::from_utf8_lossy(arg.split(|x| *x == 0).next().unwrap_or(&[])).to_string();
+        let line = match arg.trim() {
+            "off" => {
+                user.borrow_mut().chat_clock = None;
+                "chat timestamps off".to_string()
+            }
+            "on" => {
+                user.borrow_mut().chat_clock = Some(Self::server_clock(&self.config));
+                "chat timestamps on".to_string()
+            }
+            offset => match parse_utc_offset(offset) {
+                Some(zone) => {
+                    user.borrow_mut().chat_clock = Some(zone);
+                    format!("chat timestamps on, utc{}", zone)
+                }
+                None => "usage: /timestamps on|off|+9|-3:30".to_string(),
+            },
+        };
+        User::send_message(&user, &mut self.socket, line.into_bytes()).await
+    }
+    // chat_timezone, or the zone the server runs in
+    pub fn server_clock(config: &HashMap<String, String>) -> chrono::FixedOffset {
+        config
+            .get("chat_timezone")
+            .and_then(|x| parse_utc_offset(x))
+            .unwrap_or_else(|| *chrono::Local::now().offset())
+    }
+    pub async fn svc_game_chat(&mut self, buf: Vec<u8>, ip_addr: SocketAddr) -> anyhow::Result<()> {
+        // let user_room = &self.user_room;
+        let user = self.session_manager.get_user(ip_addr)?;
         let room_id = match user.borrow().game_room_id {
             Some(i) => i,
             None => {
                 return Err(KailleraError::NotFound.into());
             }
         };
+        let chat_content = try_get_rest(&buf, 1)?.to_vec();
+        if self.moderate_chat(user.clone(), &chat_content).await? {
+            return Ok(());
+        }
         let room = self.session_manager.get_room(room_id)?;
+        if self
+            .chat_hook(user.clone(), Some(room_id), &chat_content)
+            .await?
+        {
+            return Ok(());
+        }
         let mut ips = Vec::new();
         for i in &room.borrow().players {
             ips.push(*i);
         }
 
-        let data = GameChat2Client::new(user.borrow().name.clone(), buf.clone()[1..].to_vec())
-            .packetize()?;
-        let chat_content = buf.clone()[1..].to_vec();
-        if chat_content == b"/samedelay true\x00" {
+        // room settings only the owner changes
+        const OWNER_COMMANDS: [&[u8]; 9] = [
+            b"/samedelay ",
+            b"/fastinput ",
+            b"/pingorder ",
+            b"/maxping ",
+            b"/minplayers ",
+            b"/relay ",
+            b"/advertise ",
+            b"/handoff ",
+            b"/readycheck ",
+        ];
+        let setting = OWNER_COMMANDS.iter().any(|x| chat_content.starts_with(x));
+        if setting && !room.borrow().is_owner(&user.borrow()) {
+            self.refuse(user.clone(), Refusal::NotOwner).await?;
+        } else if chat_content == b"/samedelay true\x00" {
             info!("delay true");
             room.borrow_mut().same_delay = true;
         } else if chat_content == b"/samedelay false\x00" {
             info!("delay false");
             room.borrow_mut().same_delay = false;
+        } else if chat_content == b"/fastinput true\x00" {
+            self.set_fast_input(room.clone(), user.clone(), true)
+                .await?;
+        } else if chat_content == b"/fastinput false\x00" {
+            self.set_fast_input(room.clone(), user.clone(), false)
+                .await?;
+        } else if chat_content == b"/pingorder true\x00" {
+            room.borrow_mut().ping_order = true;
+        } else if chat_content == b"/pingorder false\x00" {
+            room.borrow_mut().ping_order = false;
+        } else if chat_content.starts_with(b"/maxping ") {
+            let arg = String::from_utf8_lossy(&chat_content[9..]).to_string();
+            if let Ok(max_ping) = arg.trim_end_matches('\x00').trim().parse() {
+                room.borrow_mut().max_ping = max_ping;
+            }
+        } else if chat_content.starts_with(b"/minplayers ") {
+            let arg = String::from_utf8_lossy(&chat_content[12..]).to_string();
+            if let Ok(min_players) = arg.trim_end_matches('\x00').trim().parse::<u8>() {
+                let max_players = room.borrow().max_players;
+                room.borrow_mut().min_players = min_players.min(max_players);
+            }
+        } else if chat_content == b"/relay on\x00" {
+            room.borrow_mut().relay = true;
+        } else if chat_content == b"/relay off\x00" {
+            room.borrow_mut().relay = false;
+        } else if chat_content == b"/advertise on\x00" {
+            room.borrow_mut().advertise = true;
+        } else if chat_content == b"/advertise off\x00" {
+            room.borrow_mut().advertise = false;
+        } else if chat_content == b"/handoff on\x00" {
+            room.borrow_mut().handoff = true;
+        } else if chat_content == b"/handoff off\x00" {
+            room.borrow_mut().handoff = false;
+        } else if chat_content == b"/readycheck true\x00" {
+            room.borrow_mut().ready_check = true;
+        } else if chat_content == b"/readycheck false\x00" {
+            room.borrow_mut().ready_check = false;
+            room.borrow_mut().ready_check_running = false;
         }
         info!("game chat: {:?}", chat_content);
+        let name = user.borrow().name.clone();
+        let now = chrono::Utc::now();
         info!("cmp chat: {:?}", b"/samedelay true");
         for i in ips {
             match i {
                 PlayerAddr::None => {}
                 PlayerAddr::Playing(s) | PlayerAddr::Idle(s) => {
                     let u = self.session_manager.get_user(s)?;
-                    u.borrow_mut()
-                        .make_send_packet(&mut self.socket, Protocol::new(GAME_CHAT, data.clone()))
+                    let text = u.borrow().chat_text(now, &chat_content);
+                    for data in GameChat2Client::new(name.clone(), text).packetize_split() {
+                        User::make_send_packet(
+                            &u,
+                            &mut self.socket,
+                            Protocol::new(GAME_CHAT, data),
+                        )
                         .await?;
+                    }
+                }
+            }
+        }
+        if room.borrow().relay && !chat_content.starts_with(b"/") {
+            self.relay_game_chat(room.clone(), user.clone(), &chat_content)
+                .await?;
+        }
+        if chat_content == b"/forceend\x00" {
+            let is_owner = room.borrow().is_owner(&user.borrow());
+            if is_owner || self.is_admin(ip_addr) {
+                self.force_end_game(room).await?;
+            }
+        } else if chat_content == b"/pause\x00" || chat_content == b"/resume\x00" {
+            self.pause_event(room, user, chat_content == b"/pause\x00")
+                .await?;
+        } else if chat_content == b"/persist\x00" || chat_content == b"/unpersist\x00" {
+            if self.is_admin(ip_addr) {
+                self.persist_event(room, user, chat_content == b"/persist\x00")
+                    .await?;
+            }
+        } else if chat_content == b"/desync\x00" {
+            self.desync_event(room, user).await?;
+        } else if chat_content == b"/ready\x00" {
+            self.ready_event(room, ip_addr).await?;
+        } else if chat_content.starts_with(b"/team ") {
+            self.team_event(room, user, &chat_content[6..]).await?;
+        } else if chat_content == b"/pacing\x00" {
+            for line in self.pacing_lines(&room)? {
+                User::send_game_message(&user, &mut self.socket, line.into_bytes()).await?;
+            }
+        } else if chat_content == b"/teams\x00" {
+            for line in self.team_lines(&room) {
+                User::send_game_message(&user, &mut self.socket, line).await?;
+            }
+        } else if chat_content.starts_with(b"/savetemplate ") {
+            self.save_template(room, user, &chat_content[14..]).await?;
+        } else if chat_content.starts_with(b"/loadtemplate ") {
+            self.load_template(Some(room), user, &chat_content[14..])
+                .await?;
+        } else if chat_content.starts_with(b"/swap ") {
+            self.swap_event(room, user, &chat_content[6..]).await?;
+        } else if chat_content.starts_with(b"/rename ") {
+            self.rename_event(room, user, &chat_content[8..]).await?;
+        } else if chat_content == b"/history\x00" {
+            let mut lines = room.borrow().history_lines();
+            if lines.is_empty() {
+                lines.push("no games played in this room yet".to_string());
+            }
+            for line in lines.into_iter().rev().take(10).rev() {
+                User::send_game_message(&user, &mut self.socket, line.into_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+    // team_lines of the room with player names, EUC-KR encoded
+    pub fn team_lines(&self, room: &Rc<RefCell<Room>>) -> Vec<Vec<u8>> {
+        let users = &self.session_manager.users;
+        let lines = room.borrow().team_lines(|addr| {
+            users
+                .get(&addr)
+                .map(|u| display_name(&u.borrow().name))
+                .unwrap_or_default()
+        });
+        if lines.is_empty() {
+            return vec![b"no teams, the owner sets them with /team A name".to_vec()];
+        }
+        lines
+            .iter()
+            .map(|x| encoding_rs::EUC_KR.encode(x).0.to_vec())
+            .collect()
+    }
+    // /team A name puts a seated player on team A, /team - name takes them off. owner only
+    pub async fn team_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let is_owner = room.borrow().is_owner(&user.borrow());
+        let arg = arg.split(|x| *x == 0).next().unwrap_or(&[]);
+        let mut parts = arg.splitn(2, |x| *x == b' ');
+        let (label, name) = (parts.next().unwrap_or(&[]), parts.next().unwrap_or(&[]));
+        let label = String::from_utf8_lossy(label).to_string();
+        let target = self
+            .session_manager
+            .find_user_by_name(name)
+            .filter(|u| u.borrow().game_room_id == Some(room.borrow().game_id));
+        // Ok goes to the whole room, Err only to the owner
+        let result = match target {
+            _ if !is_owner => Err("only the owner can set teams".to_string()),
+            _ if label.is_empty() || label.len() > 8 => {
+                Err("usage: /team A name, /team - name".to_string())
+            }
+            None => Err("no such player in this room".to_string()),
+            Some(target) => {
+                let addr = target.borrow().ip_addr;
+                let who = display_name(&target.borrow().name);
+                if label == "-" {
+                    room.borrow_mut().set_team(addr, None);
+                    Ok(format!("{} has no team", who))
+                } else {
+                    room.borrow_mut().set_team(addr, Some(label.clone()));
+                    Ok(format!("{} is on team {}", who, label))
+                }
+            }
+        };
+        match result {
+            Ok(line) => {
+                self.session_manager
+                    .send_game_chat_to_players(
+                        &mut self.socket,
+                        room,
+                        "SERVER".to_string(),
+                        encoding_rs::EUC_KR.encode(&line).0.to_vec(),
+                    )
+                    .await
+            }
+            Err(line) => User::send_game_message(&user, &mut self.socket, line.into_bytes()).await,
+        }
+    }
+    // /savetemplate name: the owner keeps the room's settings for later
+    pub async fn save_template(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let owner = from_utf8_lossy(user.borrow().name.as_slice()).to_string();
+        let name = String::from_utf8_lossy(arg.split(|x| *x == 0).next().unwrap_or(&[]))
+            .trim()
+            .to_string();
+        let line = if !room.borrow().is_owner(&user.borrow()) {
+            "only the owner can save the room as a template".to_string()
+        } else if name.is_empty() {
+            "usage: /savetemplate name".to_string()
+        } else {
+            let template = RoomTemplate::from_room(&room.borrow());
+            match self.templates.save(&owner, &name, template) {
+                Ok(changed) => {
+                    if let (true, Some(path)) = (changed, &self.templates.path) {
+                        self.io
+                            .write_file(path.clone(), self.templates.to_text()?.into_bytes());
+                    }
+                    format!(
+                        "saved as template {}, /loadtemplate {} brings it back",
+                        name, name
+                    )
+                }
+                Err(e) => format!("not saved: {}", e),
+            }
+        };
+        User::send_game_message(&user, &mut self.socket, line.into_bytes()).await
+    }
+    // /loadtemplate name: in the lobby a new room is created from the template,
+    // in a room the owner gets its settings (the game name stays)
+    pub async fn load_template(
+        &mut self,
+        room: Option<Rc<RefCell<Room>>>,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let owner = from_utf8_lossy(user.borrow().name.as_slice()).to_string();
+        let name = String::from_utf8_lossy(arg.split(|x| *x == 0).next().unwrap_or(&[]))
+            .trim()
+            .to_string();
+        let template = match self.templates.get(&owner, &name) {
+            Some(template) => template.clone(),
+            None => {
+                let line = format!("no template {}, see /templates", name).into_bytes();
+                return match room {
+                    Some(_) => User::send_game_message(&user, &mut self.socket, line).await,
+                    None => User::send_message(&user, &mut self.socket, line).await,
+                };
+            }
+        };
+        let room = match room {
+            Some(room) => {
+                if !room.borrow().is_owner(&user.borrow()) {
+                    return User::send_game_message(
+                        &user,
+                        &mut self.socket,
+                        b"only the owner can load a template".to_vec(),
+                    )
+                    .await;
                 }
+                room
+            }
+            None => {
+                // the same request a client sends for a new game
+                let mut buf = vec![0u8];
+                buf.extend_from_slice(template.game_name.as_bytes());
+                buf.extend_from_slice(b"\x00\x00\xff\xff\xff\xff");
+                self.svc_create_game(buf, user.clone()).await?;
+                match user.borrow().game_room_id {
+                    Some(id) => self.session_manager.get_room(id)?,
+                    None => return Ok(()),
+                }
+            }
+        };
+        template.apply(&mut room.borrow_mut());
+        if room.borrow().fast_input && self.fast_input_blocker(&room)?.is_some() {
+            room.borrow_mut().fast_input = false;
+        }
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                format!("settings of template {} loaded", name).into_bytes(),
+            )
+            .await
+    }
+    // /rename name: the owner corrects the game name before anyone joins, with the
+    // checks of a new room. the lobby gets the room again under the new name
+    // (CLOSE_GAME, CREATE_GAME); a guest could not be told, a CLOSE_GAME for
+    // their own room would take them out of it. persistent rooms are saved by
+    // name and keep it.
+    pub async fn rename_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        if !room.borrow().is_owner(&user.borrow()) {
+            return self.refuse(user, Refusal::NotOwner).await;
+        }
+        if room.borrow().game_status != GAME_STATUS_WAITING {
+            return self.refuse(user, Refusal::GameStarted).await;
+        }
+        let refusal = if room.borrow().persistent {
+            Some("A persistent room keeps its name.")
+        } else if room.borrow().player_some_count() > 1 {
+            Some("Rename the room while no one else is in it, players in it would keep the old name.")
+        } else {
+            None
+        };
+        if let Some(text) = refusal {
+            return User::send_game_message(&user, &mut self.socket, text.as_bytes().to_vec())
+                .await;
+        }
+        let mut game_name = arg.split(|x| *x == 0).next().unwrap_or(&[]).to_vec();
+        if game_name.is_empty() {
+            return User::send_game_message(
+                &user,
+                &mut self.socket,
+                b"usage: /rename name".to_vec(),
+            )
+            .await;
+        }
+        let allowed_games = self.config.get("allowed_games").map_or("", |x| x.as_str());
+        if !self
+            .game_names
+            .allowed(allowed_games, &String::from_utf8_lossy(&game_name))
+        {
+            return self.refuse(user, Refusal::GameNotAllowed).await;
+        }
+        let game_id = room.borrow().game_id;
+        if self
+            .session_manager
+            .game_name_taken(&game_name, Some(game_id))
+        {
+            match self
+                .config
+                .get("duplicate_room_name")
+                .map_or("allow", |x| x.as_str())
+            {
+                "reject" => return self.refuse(user, Refusal::GameNameTaken).await,
+                "suffix" => {
+                    game_name = self
+                        .session_manager
+                        .free_game_name(&game_name, Some(game_id))
+                }
+                _ => {}
+            }
+        }
+        let old_name = std::mem::replace(
+            &mut room.borrow_mut().game_name,
+            String::from_utf8_lossy(&game_name).to_string(),
+        );
+        info!(
+            "game {} renamed from {} to {}",
+            game_id,
+            old_name,
+            room.borrow().game_name
+        );
+
+        let mut close = vec![0u8];
+        close.extend(bincode::serialize(&game_id)?);
+        let create = CreateGame2Client::new(
+            user.borrow().name.clone(),
+            game_name.clone(),
+            room.borrow().emul_name.clone().into(),
+            game_id,
+        )
+        .packetize()?;
+        let status = UpdateGameStatus2Client::new(
+            game_id,
+            room.borrow().game_status,
+            room.borrow().player_some_count() as u8,
+            room.borrow().max_players,
+        )
+        .packetize()?;
+        for u in self.session_manager.users.values() {
+            if u.borrow().game_room_id == Some(game_id) {
+                continue;
+            }
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(CLOSE_GAME, close.clone()),
+            )
+            .await?;
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(CREATE_GAME, create.clone()),
+            )
+            .await?;
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, status.clone()),
+            )
+            .await?;
+        }
+        let text = format!("The room is now called {}.", display_name(&game_name));
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+            )
+            .await
+    }
+    // /swap a b: the owner exchanges two seats; everyone gets the new PLAYER_INFO
+    pub async fn swap_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        arg: &[u8],
+    ) -> anyhow::Result<()> {
+        let is_owner = room.borrow().is_owner(&user.borrow());
+        if !is_owner {
+            return User::send_game_message(
+                &user,
+                &mut self.socket,
+                b"only the owner can swap players".to_vec(),
+            )
+            .await;
+        }
+        let arg = String::from_utf8_lossy(arg.split(|x| *x == 0).next().unwrap_or(&[])).to_string();
+        let numbers: Vec<usize> = arg
+            .split_whitespace()
+            .filter_map(|x| x.parse().ok())
+            .collect();
+        let result = match numbers[..] {
+            [a, b] => room.borrow_mut().swap_players(a, b).map(|_| (a, b)),
+            _ => Err("usage: /swap 1 2".to_string()),
+        };
+        let (a, b) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                return User::send_game_message(&user, &mut self.socket, e.into_bytes()).await;
+            }
+        };
+        for u in self.session_manager.seated_users(&room)? {
+            let data = self
+                .session_manager
+                .player_info(&room, u.borrow().ip_addr)?;
+            User::make_send_packet(&u, &mut self.socket, Protocol::new(PLAYER_INFO, data)).await?;
+        }
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                format!("players {} and {} swapped seats", a, b).into_bytes(),
+            )
+            .await
+    }
+    // mirror a game chat line to the lobby as "[room] message"
+    pub async fn relay_game_chat(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        chat_content: &[u8],
+    ) -> anyhow::Result<()> {
+        let per_minute = settings::get_num(&self.config, "relay_per_minute", 20);
+        if !room.borrow_mut().relay_allowed(Instant::now(), per_minute) {
+            return Ok(());
+        }
+        let mut message = format!("[{}] ", room.borrow().game_name).into_bytes();
+        message.extend(chat_content.split(|x| *x == 0).next().unwrap_or(&[]));
+        let name = user.borrow().name.clone();
+        let now = chrono::Utc::now();
+        // players of the room already saw it
+        let game_id = Some(room.borrow().game_id);
+        for u in self.session_manager.users.values() {
+            if u.borrow().game_room_id == game_id {
+                continue;
+            }
+            let text = u.borrow().chat_text(now, &message);
+            for data in GlobalChat2Client::new(name.clone(), text).packetize_split() {
+                User::make_send_packet(u, &mut self.socket, Protocol::new(GLOBAL_CHAT, data))
+                    .await?;
             }
         }
         Ok(())
@@ -417,24 +2343,49 @@ impl ServiceServer {
         buf: Vec<u8>,
         user: Rc<RefCell<User>>,
     ) -> anyhow::Result<()> {
-        let has_room = user.borrow().game_room_id.is_some();
-        if has_room {
-            user.borrow_mut()
-                .send_message(
-                    &mut self.socket,
-                    b"You are already joining the room.".to_vec(),
-                )
-                .await?;
-            return Err(KailleraError::AlreadyError {
-                message: "already has room.".to_string(),
-            }
-            .into());
+        if !self.check_rules_accepted(user.clone()).await? {
+            return Ok(());
+        }
+        if user.borrow().game_room_id.is_some() {
+            return self.refuse(user, Refusal::AlreadyInRoom).await;
         }
         let iter = buf.split(|num| num == &0).collect::<Vec<_>>();
-        // let game_name = String::from_utf8(iter.get(1).ok_or(KailleraError::NotFound)?.to_vec())?;
+        let mut game_name = iter.get(1).ok_or(KailleraError::NotFound)?.to_vec();
+        let allowed_games = self.config.get("allowed_games").map_or("", |x| x.as_str());
+        if !self
+            .game_names
+            .allowed(allowed_games, &String::from_utf8_lossy(&game_name))
+        {
+            return self.refuse(user, Refusal::GameNotAllowed).await;
+        }
+        // another room with the same name: allow, suffix (" #2") or reject
+        if self.session_manager.game_name_taken(&game_name, None) {
+            match self
+                .config
+                .get("duplicate_room_name")
+                .map_or("allow", |x| x.as_str())
+            {
+                "reject" => return self.refuse(user, Refusal::GameNameTaken).await,
+                "suffix" => {
+                    let renamed = self.session_manager.free_game_name(&game_name, None);
+                    let text = format!(
+                        "A room named {} already exists, yours is called {}.",
+                        display_name(&game_name),
+                        display_name(&renamed)
+                    );
+                    User::send_message(
+                        &user,
+                        &mut self.socket,
+                        encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+                    )
+                    .await?;
+                    game_name = renamed;
+                }
+                _ => {}
+            }
+        }
         // create game packet
         {
-            let game_name = iter.get(1).ok_or(KailleraError::NotFound)?.to_vec();
             let data = CreateGame2Client::new(
                 user.borrow().name.clone(),
                 game_name.clone(),
@@ -444,19 +2395,35 @@ impl ServiceServer {
             .packetize()?;
             info!("S->C: CREATE_GAME id: {}", self.game_id);
             for (_, user) in &self.session_manager.users {
-                user.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(CREATE_GAME, data.clone()))
-                    .await?;
+                User::make_send_packet(
+                    user,
+                    &mut self.socket,
+                    Protocol::new(CREATE_GAME, data.clone()),
+                )
+                .await?;
             }
         }
         let mut new_room = Room::new();
         new_room.creator_id = from_utf8_lossy(user.borrow().name.clone().as_slice()).to_string();
         new_room.emul_name = user.borrow().emul_name.clone();
+        new_room.ping_order = settings::get_bool(&self.config, "ping_order", false);
+        new_room.max_ping = settings::get_num(&self.config, "max_ping", 0);
+        new_room.max_players =
+            settings::get_num(&self.config, "room_max_players", DEFAULT_MAX_PLAYERS);
+        if let Some(emulator) = self.emulators.find(&new_room.emul_name) {
+            info!("room defaults for {}: {:?}", new_room.emul_name, emulator);
+            new_room.max_players = emulator.max_players.unwrap_or(new_room.max_players);
+            new_room.same_delay = emulator.same_delay;
+        }
+        new_room.max_players = new_room.max_players.clamp(2, MAX_PLAYERS_LIMIT);
         new_room.game_id = self.game_id;
         user.borrow_mut().game_room_id = Some(new_room.game_id);
-        self.game_id += 1;
-        new_room.game_name =
-            String::from_utf8_lossy(iter.get(1).ok_or(KailleraError::NotFound)?).to_string();
+        self.game_id = self.game_id.next();
+        self.save_ids();
+        new_room.game_name = String::from_utf8_lossy(&game_name).to_string();
+        new_room.min_players = settings::min_players_for(&self.config, &new_room.game_name)
+            .unwrap_or(0)
+            .min(new_room.max_players);
         new_room.game_status = GAME_STATUS_WAITING;
         new_room
             .players
@@ -467,18 +2434,23 @@ impl ServiceServer {
                 new_room.game_id,
                 new_room.game_status,
                 new_room.player_some_count() as u8,
-                4,
+                new_room.max_players,
             )
             .packetize()?;
             for (_, user) in &self.session_manager.users {
-                user.borrow_mut()
-                    .make_send_packet(
-                        &mut self.socket,
-                        Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                    )
-                    .await?;
+                User::make_send_packet(
+                    user,
+                    &mut self.socket,
+                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+                )
+                .await?;
             }
         }
+        {
+            let name = display_name(&user.borrow().name);
+            let text = format!("Your friend {} created {}.", name, display_name(&game_name));
+            self.notify_friends(&name, text).await?;
+        }
         // join game
         let new_room = Rc::new(RefCell::new(new_room));
         {
@@ -486,7 +2458,7 @@ impl ServiceServer {
             let data = JoinGame2Client::new(
                 new_room.borrow().game_id,
                 user.borrow().name.clone(),
-                user.borrow().ping,
+                user.borrow().shown_ping,
                 user.borrow().user_id,
                 user.borrow().connect_type,
             )
@@ -496,9 +2468,7 @@ impl ServiceServer {
                 new_room.borrow().game_id,
                 user.borrow().user_id
             );
-            user.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(JOIN_GAME, data))
-                .await?;
+            User::make_send_packet(&user, &mut self.socket, Protocol::new(JOIN_GAME, data)).await?;
 
             self.session_manager
                 .send_game_chat_to_players(
@@ -516,21 +2486,36 @@ impl ServiceServer {
                     "/samedealy true|false\x00".as_bytes().into(),
                 )
                 .await?;
+            self.session_manager
+                .send_game_chat_to_players(
+                    &mut self.socket,
+                    new_room.clone(),
+                    "SERVER".to_string(),
+                    "/readycheck true|false, /pingorder true|false, /relay on|off, /maxping ms, /advertise on|off, /swap 1 2, /handoff on|off, /rename name, /team A name, /savetemplate name, /loadtemplate name\x00"
+                        .as_bytes()
+                        .into(),
+                )
+                .await?;
             // for (_, u) in &self.session_manager.users {
         }
         // server info
         {
-            let mut data = Vec::new();
-            data.append(&mut b"Server\x00".to_vec());
             let game_name_str =
                 String::from_utf8_lossy(iter.get(1).ok_or(KailleraError::NotFound)?).to_string();
-            let s = format!("Creates Room: {}\x00", game_name_str);
-            data.append(&mut s.as_bytes().to_vec());
-            user.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(SERVER_INFO, data))
-                .await?;
+            let s = format!("Creates Room: {}", game_name_str);
+            for data in chat_bodies(b"Server", s.as_bytes()) {
+                User::make_send_packet(&user, &mut self.socket, Protocol::new(SERVER_INFO, data))
+                    .await?;
+            }
         }
         let gi = new_room.borrow().game_id;
+        let detail = format!(
+            "game {} {} by {}",
+            gi,
+            new_room.borrow().game_name,
+            new_room.borrow().creator_id
+        );
+        self.note_timeline("room", detail);
         self.session_manager.add_room(gi, new_room)?;
 
         Ok(())
@@ -540,73 +2525,57 @@ impl ServiceServer {
         buf: Vec<u8>,
         user: Rc<RefCell<User>>,
     ) -> anyhow::Result<()> {
+        if !self.check_rules_accepted(user.clone()).await? {
+            return Ok(());
+        }
         info!("on svc_join_game");
-        let has_room = user.borrow().game_room_id.is_some();
-        if has_room {
-            user.borrow_mut()
-                .send_message(
-                    &mut self.socket,
-                    b"You are already joining the room.".to_vec(),
-                )
-                .await?;
-            return Err(KailleraError::AlreadyError {
-                message: "already has room.".to_string(),
-            }
-            .into());
+        if user.borrow().game_room_id.is_some() {
+            return self.refuse(user, Refusal::AlreadyInRoom).await;
         }
-        let game_id = bincode::deserialize::<u32>(&buf[1..5])?;
+        let game_id = GameId(try_get_u32_le(&buf, 1)?);
         let _conn_type = buf.get(12).ok_or(KailleraError::NotFound);
-        let join_room = self.session_manager.get_room(game_id)?;
-        if join_room.borrow().game_status != GAME_STATUS_WAITING {
-            return Err(KailleraError::GameStatusError {
-                message: "game is playing".to_string(),
-            }
-            .into());
+        let join_room = match self.session_manager.get_room(game_id) {
+            Ok(room) => room,
+            Err(_) => return self.refuse(user, Refusal::NoSuchGame).await,
+        };
+        let seat = join_room.borrow().handoff_seat();
+        if join_room.borrow().game_status != GAME_STATUS_WAITING && seat.is_none() {
+            return self.refuse(user, Refusal::GameStarted).await;
+        }
+        if join_room.borrow().player_some_count() >= join_room.borrow().max_players as usize {
+            return self.refuse(user, Refusal::RoomFull).await;
         }
         info!("[svc_join_game] game id: {}", game_id);
 
-        join_room
-            .borrow_mut()
-            .players
-            .push(PlayerAddr::Idle(user.borrow().ip_addr));
+        let joined = PlayerAddr::Idle(user.borrow().ip_addr);
+        match seat {
+            Some(seat) => join_room.borrow_mut().players[seat] = joined,
+            None => join_room.borrow_mut().players.push(joined),
+        }
         user.borrow_mut().game_room_id = Some(game_id);
+        let stop_fast_input = join_room.borrow().fast_input && !user.borrow().fast_input_capable;
 
         // send join message to all users.
         let data = UpdateGameStatus2Client::new(
             game_id,
             join_room.borrow().game_status,
             join_room.borrow().player_some_count() as u8,
-            4,
+            join_room.borrow().max_players,
         )
         .packetize()?;
         for (_addr, user) in &self.session_manager.users {
-            user.borrow_mut()
-                .make_send_packet(
-                    &mut self.socket,
-                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                )
-                .await?;
+            User::make_send_packet(
+                user,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+            )
+            .await?;
         }
-        // response game join message
+        // response game join message: everyone else in the room
         {
-            let mut data = Vec::new();
-            data.push(0u8);
-            data.append(&mut bincode::serialize::<u32>(
-                &(join_room.borrow().players.len() as u32 - 1),
-            )?);
-            for i in &join_room.borrow().players {
-                if let PlayerAddr::Idle(addr) | PlayerAddr::Playing(addr) = *i {
-                    let room_user = self.session_manager.get_user(addr)?;
-                    let room_user = room_user.borrow();
-                    data.append(&mut room_user.name.clone());
-                    data.push(0u8);
-                    data.append(&mut bincode::serialize::<u32>(&room_user.ping)?);
-                    data.append(&mut bincode::serialize::<u16>(&room_user.user_id)?);
-                    data.push(room_user.connect_type);
-                }
-            }
-            user.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(PLAYER_INFO, data))
+            let ip_addr = user.borrow().ip_addr;
+            let data = self.session_manager.player_info(&join_room, ip_addr)?;
+            User::make_send_packet(&user, &mut self.socket, Protocol::new(PLAYER_INFO, data))
                 .await?;
         }
         // send joingame to all users
@@ -614,25 +2583,61 @@ impl ServiceServer {
             let data = JoinGame2Client::new(
                 game_id,
                 user.borrow().name.clone(),
-                user.borrow().ping,
+                user.borrow().shown_ping,
                 user.borrow().user_id,
                 user.borrow().connect_type,
             )
             .packetize()?;
             // send data to room's player
-            for i in &join_room.borrow().players {
-                if let PlayerAddr::Idle(addr) | PlayerAddr::Playing(addr) = *i {
-                    let room_user = self.session_manager.get_user(addr)?;
-                    room_user
-                        .borrow_mut()
-                        .make_send_packet(&mut self.socket, Protocol::new(JOIN_GAME, data.clone()))
-                        .await?;
-                }
+            for room_user in self.session_manager.seated_users(&join_room)? {
+                User::make_send_packet(
+                    &room_user,
+                    &mut self.socket,
+                    Protocol::new(JOIN_GAME, data.clone()),
+                )
+                .await?;
             }
         }
+        if stop_fast_input {
+            join_room.borrow_mut().fast_input = false;
+            let mut text = user.borrow().name.clone();
+            text.extend(b"'s client cannot take fast input, it is off\x00");
+            self.session_manager
+                .send_game_chat_to_players(
+                    &mut self.socket,
+                    join_room.clone(),
+                    "SERVER".to_string(),
+                    text,
+                )
+                .await?;
+        }
+        if let Some(seat) = seat {
+            return self.hand_off_seat(join_room, user, seat).await;
+        }
 
         Ok(())
     }
+    // a player joined the running game into a seat someone left. the game goes on
+    // without them, START_GAME deals them in as that player number next time
+    pub async fn hand_off_seat(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        seat: usize,
+    ) -> anyhow::Result<()> {
+        {
+            let mut u = user.borrow_mut();
+            u.player_index = seat as u8;
+            u.room_order = seat as u8;
+        }
+        let mut text = user.borrow().name.clone();
+        text.extend(
+            format!(" takes over as player {} from the next game\x00", seat + 1).into_bytes(),
+        );
+        self.session_manager
+            .send_game_chat_to_players(&mut self.socket, room, "SERVER".to_string(), text)
+            .await
+    }
     pub async fn fun_quit_game(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
         if user.borrow().game_room_id.is_none() {
             anyhow::bail!("not exist in room")
@@ -657,6 +2662,10 @@ impl ServiceServer {
                     .get_mut(index)
                     .map(|player| *player = PlayerAddr::None);
             }
+            let name = String::from_utf8_lossy(&user.borrow().name).to_string();
+            if user_room.borrow_mut().record_drop(name) {
+                self.game_ended(user_room.clone()).await?;
+            }
         } else {
             user_room.borrow_mut().players.retain(|&x| {
                 let delete = {
@@ -668,21 +2677,33 @@ impl ServiceServer {
                 !delete
             });
         }
+        user_room.borrow_mut().set_team(user.borrow().ip_addr, None);
         let mut close_game = false;
         if user_room.borrow().player_some_count() == 0 {
-            self.session_manager.delete_room(room_id)?;
-            close_game = true;
+            if user_room.borrow().persistent {
+                let mut room = user_room.borrow_mut();
+                room.force_end();
+                room.players.clear();
+            } else {
+                self.session_manager.delete_room(room_id)?;
+                close_game = true;
+            }
         }
         if close_game {
             info!("close game");
+            let game_id = user_room.borrow().game_id;
+            self.note_timeline("close", format!("game {}", game_id));
             // game close noti
             let mut data = Vec::new();
             data.push(0u8);
             data.append(&mut bincode::serialize(&user_room.borrow().game_id)?);
             for (_addr, u) in &self.session_manager.users {
-                u.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(CLOSE_GAME, data.clone()))
-                    .await?;
+                User::make_send_packet(
+                    u,
+                    &mut self.socket,
+                    Protocol::new(CLOSE_GAME, data.clone()),
+                )
+                .await?;
             }
         } else {
             info!("keep game room");
@@ -690,28 +2711,24 @@ impl ServiceServer {
             let data = UpdateGameStatus2Client::new(
                 user_room.borrow().game_id,
                 user_room.borrow().game_status,
-                user_room.borrow().players.len() as u8,
-                4,
+                user_room.borrow().player_some_count() as u8,
+                user_room.borrow().max_players,
             )
             .packetize()?;
             for (_addr, u) in &self.session_manager.users {
-                u.borrow_mut()
-                    .make_send_packet(
-                        &mut self.socket,
-                        Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                    )
-                    .await?;
+                User::make_send_packet(
+                    u,
+                    &mut self.socket,
+                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+                )
+                .await?;
             }
         }
         let data =
             QuitGame2Client::new(user.borrow().name.clone(), user.borrow().user_id).packetize()?;
-        for i in &user_room.borrow().players {
-            if let PlayerAddr::Idle(addr) | PlayerAddr::Playing(addr) = *i {
-                let u = self.session_manager.get_user(addr)?;
-                u.borrow_mut()
-                    .make_send_packet(&mut self.socket, Protocol::new(QUIT_GAME, data.clone()))
-                    .await?;
-            }
+        for u in self.session_manager.seated_users(&user_room)? {
+            User::make_send_packet(&u, &mut self.socket, Protocol::new(QUIT_GAME, data.clone()))
+                .await?;
         }
         user.borrow_mut().game_room_id = None;
         Ok(())
@@ -721,6 +2738,22 @@ impl ServiceServer {
         _buf: Vec<u8>,
         user: Rc<RefCell<User>>,
     ) -> anyhow::Result<()> {
+        self.leave_room(user).await
+    }
+    // every way out of a room: QUIT_GAME, a disconnect or time out, a kick, the
+    // room being closed. a player still in the running game is dropped first, as
+    // their client would, so the others are not left waiting for their input.
+    pub async fn leave_room(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        let mut in_game = false;
+        if let Some(id) = user.borrow().game_room_id {
+            if let Ok(room) = self.session_manager.get_room(id) {
+                in_game = room.borrow().game_status != GAME_STATUS_WAITING
+                    && user.borrow().player_status == Playing;
+            }
+        }
+        if in_game {
+            self.svc_drop_game(Vec::new(), user.clone()).await?;
+        }
         self.fun_quit_game(user).await
     }
 
@@ -736,22 +2769,337 @@ impl ServiceServer {
             }
         };
         let user_room = self.session_manager.get_room(room_id)?;
+        if !user_room.borrow().is_owner(&user.borrow()) {
+            return self.refuse(user, Refusal::NotOwner).await;
+        }
+        if user_room.borrow().game_status != GAME_STATUS_WAITING {
+            return self.refuse(user, Refusal::GameStarted).await;
+        }
+        // pressing start again neither resets the check nor starts a second countdown
+        if user_room.borrow().ready_check_running || user_room.borrow().counting_down {
+            info!("start game {}: ready check or countdown running", room_id);
+            return Ok(());
+        }
+        let (seated, min_players) = {
+            let room = user_room.borrow();
+            (room.player_some_count(), room.min_players as usize)
+        };
+        if seated < min_players {
+            let text = format!(
+                "This game needs at least {} players to start, {} seated.",
+                min_players, seated
+            );
+            return User::send_game_message(&user, &mut self.socket, text.into_bytes()).await;
+        }
+        if !self.check_max_ping(user_room.clone()).await? {
+            return Ok(());
+        }
+        if user_room.borrow().ready_check {
+            return self.begin_ready_check(user_room).await;
+        }
+        self.start_game(user_room).await
+    }
+    // tell the room who is over its max ping. false when the game must not start.
+    pub async fn check_max_ping(&mut self, room: Rc<RefCell<Room>>) -> anyhow::Result<bool> {
+        let max_ping = room.borrow().max_ping;
+        if max_ping == 0 {
+            return Ok(true);
+        }
+        let mut over = Vec::new();
+        for i in &room.borrow().players {
+            if let PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) = i {
+                let u = self.session_manager.get_user(*addr)?;
+                let u = u.borrow();
+                if u.ping > max_ping {
+                    over.push(format!("{} ({}ms)", display_name(&u.name), u.ping));
+                }
+            }
+        }
+        if over.is_empty() {
+            return Ok(true);
+        }
+        let refuse = self
+            .config
+            .get("max_ping_action")
+            .map_or("refuse", |x| x.as_str())
+            != "warn";
+        let text = format!(
+            "{} over the {}ms ping limit{}\x00",
+            over.join(", "),
+            max_ping,
+            if refuse { ", game not started." } else { "." }
+        );
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+            )
+            .await?;
+        Ok(!refuse)
+    }
+    pub async fn begin_ready_check(&mut self, room: Rc<RefCell<Room>>) -> anyhow::Result<()> {
+        if room.borrow().game_status != GAME_STATUS_WAITING {
+            return Ok(());
+        }
+        room.borrow_mut().ready_check_running = true;
+        room.borrow_mut().ready_players.clear();
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                b"Ready check: type /ready to start the game\x00".to_vec(),
+            )
+            .await
+    }
+    pub async fn ready_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        if !room.borrow().ready_check_running {
+            return Ok(());
+        }
+        room.borrow_mut().ready_players.insert(addr);
+        if !room.borrow().all_ready() {
+            return Ok(());
+        }
+        room.borrow_mut().ready_check_running = false;
+        room.borrow_mut().counting_down = true;
+        let game_id = room.borrow().game_id;
+        self.countdown_event(game_id, 3).await
+    }
+    // 3, 2, 1 in game chat, one second apart, then START_GAME. the room may have
+    // changed meanwhile, so min_players and max_ping are checked once more.
+    pub async fn countdown_event(&mut self, game_id: GameId, left: u8) -> anyhow::Result<()> {
+        let room = match self.session_manager.get_room(game_id) {
+            Ok(room) => room,
+            Err(_) => return Ok(()),
+        };
+        if room.borrow().game_status != GAME_STATUS_WAITING {
+            return Ok(());
+        }
+        if left == 0 {
+            room.borrow_mut().counting_down = false;
+            let (seated, min_players) = {
+                let room = room.borrow();
+                (room.player_some_count(), room.min_players as usize)
+            };
+            if seated < min_players {
+                let text = format!(
+                    "This game needs at least {} players to start, {} seated, game not started.\x00",
+                    min_players, seated
+                );
+                return self
+                    .session_manager
+                    .send_game_chat_to_players(
+                        &mut self.socket,
+                        room,
+                        "SERVER".to_string(),
+                        text.into_bytes(),
+                    )
+                    .await;
+            }
+            if !self.check_max_ping(room.clone()).await? {
+                return Ok(());
+            }
+            return self.start_game(room).await;
+        }
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                format!("{}\x00", left).into_bytes(),
+            )
+            .await?;
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let _ = tx.send(Event::StartCountdown(game_id, left - 1)).await;
+        });
+        Ok(())
+    }
+    // players given START_GAME that have not sent READY_TO_PLAY yet, which marks
+    // the slot as Playing
+    pub fn netsync_laggards(
+        &self,
+        room: &Rc<RefCell<Room>>,
+    ) -> anyhow::Result<Vec<Rc<RefCell<User>>>> {
+        let mut laggards = Vec::new();
+        for i in &room.borrow().players {
+            if let PlayerAddr::Idle(addr) = i {
+                let u = self
+                    .session_manager
+                    .users
+                    .get(addr)
+                    .ok_or(KailleraError::NotFound)?;
+                if u.borrow().player_status == Playing {
+                    laggards.push(u.clone());
+                }
+            }
+        }
+        Ok(laggards)
+    }
+    // every netsync_notice_secs until netsync_timeout, while someone is still loading
+    pub fn schedule_netsync_progress(&self, game_id: GameId, session: SessionId, elapsed: u64) {
+        let notice = settings::get_num(&self.config, "netsync_notice_secs", 10u64);
+        let timeout = settings::get_num(&self.config, "netsync_timeout", 30u64);
+        if notice == 0 || elapsed + notice >= timeout {
+            return;
+        }
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(notice)).await;
+            let _ = tx
+                .send(Event::NetsyncProgress(game_id, session, elapsed + notice))
+                .await;
+        });
+    }
+    pub async fn netsync_progress_event(
+        &mut self,
+        game_id: GameId,
+        session: SessionId,
+        elapsed: u64,
+    ) -> anyhow::Result<()> {
+        let room = match self.session_manager.get_room(game_id) {
+            Ok(room) => room,
+            Err(_) => return Ok(()),
+        };
+        if SessionId(room.borrow().history.len()) != session
+            || room.borrow().game_status == GAME_STATUS_WAITING
+        {
+            return Ok(());
+        }
+        let laggards = self.netsync_laggards(&room)?;
+        if laggards.is_empty() {
+            return Ok(());
+        }
+        let names: Vec<String> = laggards
+            .iter()
+            .map(|u| display_name(&u.borrow().name))
+            .collect();
+        let timeout = settings::get_num(&self.config, "netsync_timeout", 30u64);
+        let text = format!(
+            "Waiting for {} to load the game, {}s left.\x00",
+            names.join(", "),
+            timeout.saturating_sub(elapsed)
+        );
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+            )
+            .await?;
+        self.schedule_netsync_progress(game_id, session, elapsed);
+        Ok(())
+    }
+    pub async fn netsync_timeout_event(
+        &mut self,
+        game_id: GameId,
+        session: SessionId,
+    ) -> anyhow::Result<()> {
+        let room = match self.session_manager.get_room(game_id) {
+            Ok(room) => room,
+            Err(_) => return Ok(()),
+        };
+        if SessionId(room.borrow().history.len()) != session
+            || room.borrow().game_status == GAME_STATUS_WAITING
+        {
+            return Ok(());
+        }
+        let laggards = self.netsync_laggards(&room)?;
+        if laggards.is_empty() {
+            return Ok(());
+        }
+        let names: Vec<String> = laggards
+            .iter()
+            .map(|u| display_name(&u.borrow().name))
+            .collect();
+        info!("netsync timeout in game {}: {:?}", game_id, names);
+        // the players who loaded stay in the room, which goes back to WAITING
+        let text = format!(
+            "{} did not finish netsync in time and left the game.\x00",
+            names.join(", ")
+        );
+        self.end_game(room, laggards, encoding_rs::EUC_KR.encode(&text).0.to_vec())
+            .await
+    }
+    pub async fn start_game(&mut self, user_room: Rc<RefCell<Room>>) -> anyhow::Result<()> {
         user_room.borrow_mut().game_status = GAME_STATUS_NET_SYNC;
+        user_room.borrow_mut().begin_session();
+        {
+            let game_id = user_room.borrow().game_id;
+            let session = SessionId(user_room.borrow().history.len());
+            let timeout = settings::get_num(&self.config, "netsync_timeout", 30);
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(timeout)).await;
+                let _ = tx.send(Event::NetsyncTimeout(game_id, session)).await;
+            });
+            self.schedule_netsync_progress(game_id, session, 0);
+        }
+        // seats are final from here: the recorder, hooks and START_GAME follow them
+        if user_room.borrow().ping_order {
+            let users = &self.session_manager.users;
+            user_room.borrow_mut().order_players_by_ping(|addr| {
+                users
+                    .get(&addr)
+                    .map(|u| u.borrow().ping)
+                    .unwrap_or(u32::MAX)
+            });
+        }
+        if self.config.contains_key("input_record_dir") {
+            let mut names = Vec::new();
+            for i in &user_room.borrow().players {
+                if let PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) = i {
+                    let u = self.session_manager.get_user(*addr)?;
+                    names.push(String::from_utf8_lossy(&u.borrow().name).to_string());
+                }
+            }
+            user_room.borrow_mut().input_recorder = Some(InputRecorder::new(names));
+        }
+        self.stats.games_played += 1;
+        {
+            let mut players = Vec::new();
+            for u in self.session_manager.seated_users(&user_room)? {
+                players.push(display_name(&u.borrow().name));
+            }
+            let detail = format!(
+                "game {} {}: {}",
+                user_room.borrow().game_id,
+                user_room.borrow().game_name,
+                players.join(", ")
+            );
+            self.note_timeline("start", detail);
+            let event = serde_json::json!({
+                "game_id": user_room.borrow().game_id,
+                "game_name": user_room.borrow().game_name,
+                "players": players,
+            });
+            let result = self.scripts.call(Hook::GameStart, event);
+            self.script_actions(result.actions, None, Some(user_room.clone()))
+                .await?;
+        }
         // send UPDATE_GAME_STATUS to all
         let data = UpdateGameStatus2Client::new(
             user_room.borrow().game_id,
             user_room.borrow().game_status,
-            user_room.borrow().players.len() as u8,
-            4,
+            user_room.borrow().player_some_count() as u8,
+            user_room.borrow().max_players,
         )
         .packetize()?;
         for (_addr, u) in &self.session_manager.users {
-            u.borrow_mut()
-                .make_send_packet(
-                    &mut self.socket,
-                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                )
-                .await?;
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+            )
+            .await?;
         }
         // send GAME_START to room players
         let mut order = 0u8;
@@ -763,70 +3111,618 @@ impl ServiceServer {
                 PlayerAddr::None => continue,
             }?;
             let u = u.borrow();
-            let frame_delay = Self::cal_frame_delay(u.connect_type, u.ping);
+            let frame_delay = Self::cal_frame_delay(u.connect_type, u.shown_ping);
             if max_frame_delay < frame_delay {
                 max_frame_delay = frame_delay;
             }
         }
-        // show frame delay to all
-        let mut delay_messages: Vec<Vec<u8>> = Vec::new();
-        for i in &user_room.borrow().players {
-            let u = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => self.session_manager.get_user(*i),
-                PlayerAddr::None => continue,
-            }?;
-            let mut u = u.borrow_mut();
-            u.player_index = order;
-            u.room_order = order;
-            u.player_status = Playing;
-
-            let real_frame_delay = Self::cal_frame_delay(u.connect_type, u.ping);
-            let frame_delay = if user_room.borrow().same_delay {
-                max_frame_delay
-            } else {
-                real_frame_delay
+        // show frame delay to all
+        let mut delay_messages: Vec<Vec<u8>> = Vec::new();
+        for player in self.session_manager.seated_users(&user_room)? {
+            let data = {
+                let mut u = player.borrow_mut();
+                u.player_index = order;
+                u.room_order = order;
+                u.player_status = Playing;
+
+                let real_frame_delay = Self::cal_frame_delay(u.connect_type, u.shown_ping);
+                let frame_delay = if user_room.borrow().same_delay {
+                    max_frame_delay
+                } else {
+                    real_frame_delay
+                };
+                info!("frame_delay: {}", frame_delay);
+                let mut notice_message = u.name.clone();
+                if user_room.borrow().same_delay {
+                    notice_message.append(
+                        &mut format!(
+                            ", [samedelay mode] index {} -> {}",
+                            real_frame_delay, max_frame_delay
+                        )
+                        .into_bytes(),
+                    );
+                } else {
+                    notice_message.append(&mut ", frame delay(index): ".to_string().into_bytes());
+                    notice_message.append(&mut real_frame_delay.to_string().into_bytes());
+                }
+                notice_message.push(0u8);
+                delay_messages.push(notice_message.clone());
+                let data = StartGame2Client::new(
+                    frame_delay,
+                    order + 1,
+                    user_room.borrow().players.len() as u8,
+                )
+                .packetize()?;
+                u.reset_outcoming();
+                u.players_input
+                    .resize(user_room.borrow().players.len(), Vec::new());
+                data
+            };
+            User::make_send_packet(&player, &mut self.socket, Protocol::new(START_GAME, data))
+                .await?;
+            order += 1;
+        }
+        if user_room.borrow().ping_order {
+            let mut notice = b"player order by ping:".to_vec();
+            for i in &user_room.borrow().players {
+                if let PlayerAddr::Playing(addr) | PlayerAddr::Idle(addr) = i {
+                    let u = self.session_manager.get_user(*addr)?;
+                    let u = u.borrow();
+                    notice.append(&mut format!(" P{} ", u.player_index + 1).into_bytes());
+                    notice.append(&mut u.name.clone());
+                    notice.append(&mut format!("({}ms)", u.ping).into_bytes());
+                }
+            }
+            notice.push(0u8);
+            delay_messages.push(notice);
+        }
+        if !user_room.borrow().teams.is_empty() {
+            delay_messages.extend(self.team_lines(&user_room));
+        }
+        for i in delay_messages {
+            self.session_manager
+                .send_game_chat_to_players(
+                    &mut self.socket,
+                    user_room.clone(),
+                    "SERVER".to_string(),
+                    i.clone(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+    // a session of the room is over: every player dropped or it was force ended
+    pub async fn game_ended(&mut self, room: Rc<RefCell<Room>>) -> anyhow::Result<()> {
+        self.export_inputs(room.clone());
+        let detail = format!("game {} {}", room.borrow().game_id, room.borrow().game_name);
+        self.note_timeline("end", detail);
+        let event = serde_json::json!({
+            "game_id": room.borrow().game_id,
+            "game_name": room.borrow().game_name,
+        });
+        let result = self.scripts.call(Hook::GameEnd, event);
+        self.script_actions(result.actions, None, Some(room)).await
+    }
+    // the chat hook of script_file; true when the script drops the message
+    pub async fn chat_hook(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        game_id: Option<GameId>,
+        message: &[u8],
+    ) -> anyhow::Result<bool> {
+        let event = serde_json::json!({
+            "name": display_name(&user.borrow().name),
+            "text": display_name(message.split(|x| *x == 0).next().unwrap_or(&[])),
+            "game_id": game_id,
+        });
+        let result = self.scripts.call(Hook::Chat, event);
+        let room = match game_id {
+            Some(id) => Some(self.session_manager.get_room(id)?),
+            None => None,
+        };
+        self.script_actions(result.actions, Some(user), room)
+            .await?;
+        Ok(result.deny)
+    }
+    // carry out what a script hook asked for. replies go to the room when there
+    // is one, else to the user
+    pub async fn script_actions(
+        &mut self,
+        actions: Vec<ScriptAction>,
+        user: Option<Rc<RefCell<User>>>,
+        room: Option<Rc<RefCell<Room>>>,
+    ) -> anyhow::Result<()> {
+        for action in actions {
+            match action {
+                ScriptAction::Reply(text) => {
+                    let text = encoding_rs::EUC_KR.encode(&text).0.to_vec();
+                    if let Some(room) = &room {
+                        self.session_manager
+                            .send_game_chat_to_players(
+                                &mut self.socket,
+                                room.clone(),
+                                "SERVER".to_string(),
+                                text,
+                            )
+                            .await?;
+                    } else if let Some(user) = &user {
+                        User::send_message(user, &mut self.socket, text).await?;
+                    }
+                }
+                ScriptAction::Announce(text) => {
+                    let text = encoding_rs::EUC_KR.encode(&text).0.to_vec();
+                    let users: Vec<_> = self
+                        .session_manager
+                        .users
+                        .values()
+                        .filter(|u| !self.pending.contains(&u.borrow().ip_addr))
+                        .cloned()
+                        .collect();
+                    for u in users {
+                        User::send_message(&u, &mut self.socket, text.clone()).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    // write the recorded inputs of a finished game, see input_record_dir.
+    pub fn export_inputs(&self, room: Rc<RefCell<Room>>) {
+        let recorder = match room.borrow_mut().input_recorder.take() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let dir = match self.config.get("input_record_dir") {
+            Some(dir) => dir,
+            None => return,
+        };
+        let format = self
+            .config
+            .get("input_record_format")
+            .map(|x| x.as_str())
+            .unwrap_or("csv");
+        let room = room.borrow();
+        match recorder.render(Path::new(dir), format, room.game_id, &room.game_name) {
+            Ok((path, body)) => {
+                info!(
+                    "inputs of game {} written to {}",
+                    room.game_id,
+                    path.display()
+                );
+                self.io.write_file(path, body.into_bytes());
+            }
+            Err(e) => info!("input export of game {} failed: {}", room.game_id, e),
+        }
+    }
+    pub fn snapshot(&self) -> ServerSnapshot {
+        let mut users: Vec<_> = self
+            .session_manager
+            .users
+            .values()
+            .map(|u| UserSnapshot::from_user(&u.borrow()))
+            .collect();
+        users.sort_by_key(|u| u.user_id);
+        let mut rooms: Vec<_> = self
+            .session_manager
+            .rooms
+            .values()
+            .map(|r| RoomSnapshot::from_room(&r.borrow()))
+            .collect();
+        rooms.sort_by_key(|r| r.game_id);
+        ServerSnapshot {
+            taken_at: chrono::Local::now().to_rfc3339(),
+            uptime_secs: self.stats.uptime().as_secs(),
+            games_played: self.stats.games_played,
+            duplicate_messages: self.stats.duplicate_messages,
+            duplicate_datagrams: self.stats.duplicate_datagrams,
+            duplicate_inputs: self.stats.duplicate_inputs,
+            malformed_messages: self.stats.malformed_messages,
+            load: self.stats.load.current,
+            next_game_id: self.game_id,
+            peers: self.peers.len(),
+            users,
+            rooms,
+        }
+    }
+    // lobby users hear about rooms still waiting for players, every advertise_minutes
+    pub async fn advertise_event(&mut self) -> anyhow::Result<()> {
+        let minutes = settings::get_num(&self.config, "advertise_minutes", 0u64);
+        if minutes == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let every = Duration::from_secs(minutes * 60);
+        let lines: Vec<String> = self
+            .session_manager
+            .rooms
+            .values()
+            .filter_map(|r| r.borrow_mut().advertisement(now, every))
+            .collect();
+        for line in lines {
+            for u in self.session_manager.users.values() {
+                if u.borrow().game_room_id.is_some() {
+                    continue;
+                }
+                User::send_message(u, &mut self.socket, line.clone().into_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+    // a request from the bot api, see bot_api.rs
+    pub async fn bot_event(&mut self, request: BotRequest) -> anyhow::Result<BotReply> {
+        let find = |name: &str| {
+            self.session_manager
+                .find_user_by_name(&encoding_rs::EUC_KR.encode(name).0)
+                .filter(|u| !self.pending.contains(&u.borrow().ip_addr))
+        };
+        match request {
+            BotRequest::Status => {
+                let status = PublicStatus::from_snapshot(
+                    &self.snapshot(),
+                    ServerInfo::from_config(&self.config),
+                );
+                Ok(BotReply::ok(serde_json::to_string(&status)?))
+            }
+            // generated from the message layouts in schema.rs, like docs/protocol.md
+            BotRequest::Protocol => Ok(BotReply::markdown(crate::schema::markdown())),
+            BotRequest::CreateRoom(x) => {
+                let owner = match find(&x.owner) {
+                    Some(u) => u,
+                    None => return Ok(BotReply::error(404, "owner is not online")),
+                };
+                if owner.borrow().game_room_id.is_some() {
+                    return Ok(BotReply::error(409, "owner is already in a room"));
+                }
+                info!("bot api: room {} for {}", x.name, x.owner);
+                // the same request a client sends for a new game
+                let mut buf = vec![0u8];
+                buf.extend_from_slice(&encoding_rs::EUC_KR.encode(&x.name).0);
+                buf.extend_from_slice(b"\x00\x00\xff\xff\xff\xff");
+                self.svc_create_game(buf, owner.clone()).await?;
+                let game_id = match owner.borrow().game_room_id {
+                    Some(id) => id,
+                    None => return Ok(BotReply::error(409, "the server refused the room")),
+                };
+                let room = self.session_manager.get_room(game_id)?;
+                let body = serde_json::json!({
+                    "game_id": game_id,
+                    "name": room.borrow().game_name,
+                });
+                Ok(BotReply::ok(body.to_string()))
+            }
+            BotRequest::Invite(x) => {
+                let user = match find(&x.user) {
+                    Some(u) => u,
+                    None => return Ok(BotReply::error(404, "user is not online")),
+                };
+                let room = match self.session_manager.rooms.get(&x.game_id) {
+                    Some(room) => room.clone(),
+                    None => return Ok(BotReply::error(404, "no such game")),
+                };
+                let text = format!(
+                    "You are invited to {} (game {}, owner {}), join it from the game list.",
+                    room.borrow().game_name,
+                    x.game_id,
+                    room.borrow().creator_id
+                );
+                User::send_message(
+                    &user,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+                )
+                .await?;
+                Ok(BotReply::ok("{}".to_string()))
+            }
+            BotRequest::Message(x) => {
+                let user = match find(&x.user) {
+                    Some(u) => u,
+                    None => return Ok(BotReply::error(404, "user is not online")),
+                };
+                User::send_message(
+                    &user,
+                    &mut self.socket,
+                    encoding_rs::EUC_KR.encode(&x.text).0.to_vec(),
+                )
+                .await?;
+                Ok(BotReply::ok("{}".to_string()))
+            }
+        }
+    }
+    // a command from the control socket, see control.rs
+    pub async fn control_event(&mut self, request: ControlRequest) -> anyhow::Result<String> {
+        let find = |name: &str| {
+            self.session_manager
+                .find_user_by_name(&encoding_rs::EUC_KR.encode(name).0)
+                .filter(|u| !self.pending.contains(&u.borrow().ip_addr))
+        };
+        match request {
+            ControlRequest::Users => {
+                let mut users: Vec<_> = self
+                    .session_manager
+                    .users
+                    .values()
+                    .filter(|u| !self.pending.contains(&u.borrow().ip_addr))
+                    .map(|u| {
+                        let u = u.borrow();
+                        let room = match u.game_room_id {
+                            Some(id) => format!("game {}", id),
+                            None => "lobby".to_string(),
+                        };
+                        format!(
+                            "{} {} {} ping {} {}",
+                            u.user_id,
+                            display_name(&u.name),
+                            u.ip_addr,
+                            u.ping,
+                            room
+                        )
+                    })
+                    .collect();
+                users.sort();
+                Ok(format!("{} online\n{}", users.len(), users.join("\n")))
+            }
+            ControlRequest::Kick(name) => {
+                let user = match find(&name) {
+                    Some(u) => u,
+                    None => return Ok(format!("error: {} is not online", name)),
+                };
+                info!("control socket: kick {}", name);
+                self.disconnect_user(user, b"kicked".to_vec()).await?;
+                Ok(format!("kicked {}", name))
+            }
+            ControlRequest::Ban(name, duration) => {
+                let user = match find(&name) {
+                    Some(u) => u,
+                    None => return Ok(format!("error: {} is not online", name)),
+                };
+                let ip = user.borrow().ip_addr.ip();
+                info!("control socket: ban {} ({}) for {:?}", name, ip, duration);
+                self.punishments.ban(ip, Instant::now(), duration);
+                let notice = format!("You are banned for {} minutes.", duration.as_secs() / 60);
+                User::send_message(&user, &mut self.socket, notice.into_bytes()).await?;
+                self.disconnect_user(user, b"banned".to_vec()).await?;
+                Ok(format!("banned {} ({})", name, ip))
+            }
+            ControlRequest::Announce(text) => {
+                info!("control socket: announce {}", text);
+                let text = encoding_rs::EUC_KR.encode(&text).0.to_vec();
+                let mut told = 0;
+                for u in self.session_manager.users.values() {
+                    if self.pending.contains(&u.borrow().ip_addr) {
+                        continue;
+                    }
+                    let in_room = u.borrow().game_room_id.is_some();
+                    User::send_message(u, &mut self.socket, text.clone()).await?;
+                    if in_room {
+                        User::send_game_message(u, &mut self.socket, text.clone()).await?;
+                    }
+                    told += 1;
+                }
+                Ok(format!("told {} users", told))
+            }
+            ControlRequest::Close(game_id) => {
+                let room = match self.session_manager.rooms.get(&game_id) {
+                    Some(room) => room.clone(),
+                    None => return Ok(format!("error: no game {}", game_id)),
+                };
+                info!("control socket: close game {}", game_id);
+                // a persistent room would only reopen
+                if room.borrow().persistent {
+                    room.borrow_mut().persistent = false;
+                    self.persistent_rooms.remove(&room.borrow().game_name);
+                    if let Some(path) = &self.persistent_rooms.path {
+                        self.io.write_file(
+                            path.clone(),
+                            self.persistent_rooms.to_text()?.into_bytes(),
+                        );
+                    }
+                }
+                let players = self.session_manager.seated_users(&room)?;
+                for u in &players {
+                    let data = QuitGame2Client::new(u.borrow().name.clone(), u.borrow().user_id)
+                        .packetize()?;
+                    User::send_game_message(
+                        u,
+                        &mut self.socket,
+                        b"The server closed this room.".to_vec(),
+                    )
+                    .await?;
+                    User::make_send_packet(u, &mut self.socket, Protocol::new(QUIT_GAME, data))
+                        .await?;
+                    self.leave_room(u.clone()).await?;
+                }
+                Ok(format!(
+                    "closed game {}, {} players sent out",
+                    game_id,
+                    players.len()
+                ))
+            }
+            ControlRequest::Dump => Ok(serde_json::to_string(&self.snapshot())?),
+            ControlRequest::Timeline(None) => {
+                let lines: Vec<_> = self.timeline.latest(50).iter().map(|x| x.line()).collect();
+                Ok(format!("{} events\n{}", lines.len(), lines.join("\n")))
+            }
+            ControlRequest::Timeline(Some(time)) => {
+                let at = timeline::last_at(time, chrono::Local::now());
+                let lines: Vec<_> = self
+                    .timeline
+                    .around(at, chrono::Duration::minutes(timeline::AROUND_MINUTES))
+                    .iter()
+                    .map(|x| x.line())
+                    .collect();
+                Ok(format!(
+                    "{} events around {}\n{}",
+                    lines.len(),
+                    at.format("%Y-%m-%d %H:%M"),
+                    lines.join("\n")
+                ))
+            }
+        }
+    }
+    // on the keepalive tick, see load.rs
+    pub fn sample_load(&mut self) {
+        let active_games = self
+            .session_manager
+            .rooms
+            .values()
+            .filter(|x| x.borrow().game_status != GAME_STATUS_WAITING)
+            .count();
+        let limits = LoadLimits::from_config(&self.config);
+        let load = self.stats.load.sample(Instant::now(), active_games, limits);
+        trace!("load: {}", load.line());
+    }
+    // public status for community pages, on the keepalive tick every status_export_interval
+    pub fn status_export_event(&mut self) {
+        let file = self.config.get("status_export_file").cloned();
+        let url = self.config.get("status_export_url").cloned();
+        if file.is_none() && url.is_none() {
+            return;
+        }
+        let interval = settings::get_num(&self.config, "status_export_interval", 30);
+        if let Some(t) = self.status_exported {
+            if t.elapsed() < Duration::from_secs(interval) {
+                return;
+            }
+        }
+        self.status_exported = Some(Instant::now());
+        let mut status =
+            PublicStatus::from_snapshot(&self.snapshot(), ServerInfo::from_config(&self.config));
+        // pages show one name per game, clients get the raw one
+        for game in status.games.iter_mut() {
+            game.name = self.game_names.normalize(&game.name);
+        }
+        if let Some(file) = file {
+            match status.render(is_yaml_path(&file)) {
+                Ok((body, _)) => self.io.write_file(file.into(), body.into_bytes()),
+                Err(e) => info!("status export failed: {}", e),
+            }
+        }
+        if let Some(url) = url {
+            match status.render(false) {
+                Ok((body, content_type)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = push(&url, content_type, &body).await {
+                            info!("status push to {} failed: {}", url, e);
+                        }
+                    });
+                }
+                Err(e) => info!("status export failed: {}", e),
+            }
+        }
+    }
+    // ask reachability_url to probe main_port and sub_port; the answer is a
+    // line for whoever asked
+    pub fn start_probe(&mut self, requested_by: Option<SocketAddr>) -> anyhow::Result<String> {
+        let url = match self.config.get("reachability_url") {
+            Some(url) => url.clone(),
+            None => return Ok("reachability_url is not set".to_string()),
+        };
+        if self.probe.is_some() {
+            return Ok("a reachability check is already running".to_string());
+        }
+        let ports = vec![
+            settings::get_num(&self.config, "main_port", 27888),
+            self.socket.local_addr()?.port(),
+        ];
+        let mut probe = Probe::new(ports);
+        probe.requested_by = requested_by;
+        let (token, body) = (probe.token.clone(), probe.body());
+        self.probe = Some(probe);
+        let secs = settings::get_num(&self.config, "reachability_timeout_secs", 10);
+        let line = format!("checking reachability with {}", url);
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let error = match push(&url, "application/x-www-form-urlencoded", &body).await {
+                Ok(()) => {
+                    tokio::time::sleep(Duration::from_secs(secs)).await;
+                    None
+                }
+                Err(e) => Some(e.to_string()),
             };
-            info!("frame_delay: {}", frame_delay);
-            let mut notice_message = u.name.clone();
-            if user_room.borrow().same_delay {
-                notice_message.append(
-                    &mut format!(
-                        ", [samedelay mode] index {} -> {}",
-                        real_frame_delay, max_frame_delay
-                    )
-                    .into_bytes(),
-                );
+            let _ = tx.send(Event::ProbeDone(token, error)).await;
+        });
+        Ok(line)
+    }
+    pub async fn probe_received_event(&mut self, port: u16, token: Vec<u8>) -> anyhow::Result<()> {
+        let probe = match &mut self.probe {
+            Some(probe) => probe,
+            None => return Ok(()),
+        };
+        if probe.note(port, &token) && probe.all_seen() {
+            let token = probe.token.clone();
+            self.probe_done_event(token, None).await?;
+        }
+        Ok(())
+    }
+    pub async fn probe_done_event(
+        &mut self,
+        token: String,
+        error: Option<String>,
+    ) -> anyhow::Result<()> {
+        let probe = match self.probe.take() {
+            Some(probe) if probe.token == token => probe,
+            other => {
+                self.probe = other;
+                return Ok(());
+            }
+        };
+        let lines = match error {
+            Some(e) => vec![(
+                false,
+                format!("reachability check failed, echo service: {}", e),
+            )],
+            None => probe.report(),
+        };
+        for (reachable, line) in &lines {
+            if *reachable {
+                info!("{}", line);
             } else {
-                notice_message.append(&mut ", frame delay(index): ".to_string().into_bytes());
-                notice_message.append(&mut real_frame_delay.to_string().into_bytes());
-            }
-            notice_message.push(0u8);
-            delay_messages.push(notice_message.clone());
-            let data = StartGame2Client::new(
-                frame_delay,
-                order + 1,
-                user_room.borrow().players.len() as u8,
-            )
-            .packetize()?;
-            u.reset_outcoming();
-            u.players_input
-                .resize(user_room.borrow().players.len(), Vec::new());
-            u.make_send_packet(&mut self.socket, Protocol::new(START_GAME, data))
-                .await?;
-            order += 1;
+                warn!("{}", line);
+            }
         }
-        for i in delay_messages {
-            self.session_manager
-                .send_game_chat_to_players(
-                    &mut self.socket,
-                    user_room.clone(),
-                    "SERVER".to_string(),
-                    i.clone(),
-                )
-                .await?;
+        let user = match probe
+            .requested_by
+            .and_then(|x| self.session_manager.users.get(&x))
+        {
+            Some(u) => u.clone(),
+            None => return Ok(()),
+        };
+        for (_, line) in lines {
+            User::send_message(&user, &mut self.socket, line.into_bytes()).await?;
         }
         Ok(())
     }
+    // write the snapshot as json into dump_dir (default: working directory).
+    pub fn dump_state(&self) -> anyhow::Result<std::path::PathBuf> {
+        let dir = self.config.get("dump_dir").map_or(".", |x| x.as_str());
+        let path = Path::new(dir).join(format!(
+            "state-{}.json",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ));
+        self.io
+            .write_file(path.clone(), serde_json::to_vec_pretty(&self.snapshot())?);
+        Ok(path)
+    }
+    // the ping reported to clients for a measured one: anything in
+    // 0..debug_random_ping_max with debug_random_ping, or within debug_ping_jitter ms of it
+    pub fn debug_ping(config: &HashMap<String, String>, ping: u32) -> u32 {
+        if settings::get_bool(config, "debug_random_ping", false) {
+            let max = settings::get_num(config, "debug_random_ping_max", 100u32).max(1);
+            return rand::thread_rng().gen_range(0..max);
+        }
+        let jitter = settings::get_num(config, "debug_ping_jitter", 0u32);
+        if jitter == 0 {
+            return ping;
+        }
+        let low = ping.saturating_sub(jitter);
+        rand::thread_rng().gen_range(low..=ping.saturating_add(jitter))
+    }
+    // the fastest connection type that still gets every input there within one packet
+    pub fn suggest_connection_type(ping: u32) -> u8 {
+        (1..=6)
+            .find(|&x| Self::cal_frame_delay(x, ping) == 1)
+            .unwrap_or(6)
+    }
     pub fn cal_frame_delay(connection_type: u8, ping: u32) -> u16 {
         match connection_type {
             1 => match ping {
@@ -902,6 +3798,73 @@ impl ServiceServer {
         }
     }
 
+    // frame rate and input jitter of everyone seated, for /pacing
+    pub fn pacing_lines(&self, room: &Rc<RefCell<Room>>) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        for u in self.session_manager.seated_users(room)? {
+            let u = u.borrow();
+            let line = match (u.pacing.fps(), u.pacing.jitter_ms()) {
+                (Some(fps), Some(jitter)) => format!(
+                    "P{} {}: {:.1} fps, jitter {:.1}ms",
+                    u.player_index + 1,
+                    display_name(&u.name),
+                    fps,
+                    jitter
+                ),
+                _ => format!(
+                    "P{} {}: no input yet",
+                    u.player_index + 1,
+                    display_name(&u.name)
+                ),
+            };
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+    // record when a player's input arrived; once per game, tell a player whose
+    // emulator runs more than pacing_warn_percent off pacing_fps.
+    pub async fn note_pacing(&mut self, user: Rc<RefCell<User>>) -> anyhow::Result<()> {
+        let frames = user.borrow().connect_type.max(1) as u32;
+        user.borrow_mut().pacing.note(Instant::now(), frames);
+        let expected = settings::get_num(&self.config, "pacing_fps", 60).max(1) as f64;
+        let threshold = settings::get_num(&self.config, "pacing_warn_percent", 5) as f64;
+        let fps = match user.borrow().pacing.fps() {
+            Some(fps) if user.borrow().pacing.settled() && !user.borrow().pacing.warned => fps,
+            _ => return Ok(()),
+        };
+        let off = (fps - expected) / expected * 100.0;
+        if threshold == 0.0 || off.abs() < threshold {
+            return Ok(());
+        }
+        user.borrow_mut().pacing.warned = true;
+        info!(
+            "{}: running at {:.1} fps, expected {}",
+            display_name(&user.borrow().name),
+            fps,
+            expected
+        );
+        // the whole room sees who makes the game stutter
+        let room = match user.borrow().game_room_id {
+            Some(id) => self.session_manager.get_room(id)?,
+            None => return Ok(()),
+        };
+        let text = format!(
+            "{}'s emulator runs {} ({:.1} fps instead of {}), which makes the game stutter for everyone. Check its frame rate and vsync settings.\x00",
+            display_name(&user.borrow().name),
+            if off > 0.0 { "fast" } else { "slow" },
+            fps,
+            expected
+        );
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+            )
+            .await
+    }
+
     pub async fn svc_game_data(
         &mut self,
         buf: Vec<u8>,
@@ -913,29 +3876,91 @@ impl ServiceServer {
                 return Err(KailleraError::NotFound.into());
             }
         };
-        let game_data_length = (bincode::deserialize::<u16>(&buf[1..3])?) as usize;
-        if buf.len() < (3 + game_data_length) {
-            anyhow::bail!("..");
+        if self.input_over_rate(user.clone(), buf.len()).await? {
+            return Ok(());
         }
-        let game_data = &buf[3..3 + game_data_length];
+        let game_data_length = try_get_u16_le(&buf, 1)? as usize;
+        let game_data = try_get_bytes(&buf, 3, game_data_length)?;
         let conntype = user.borrow().connect_type as u8;
-        user.borrow_mut().atomic_input_size = game_data.len() as u8 / conntype;
+        let configured =
+            settings::input_size_for(&self.config, &user.borrow().emul_name).or_else(|| {
+                self.emulators
+                    .find(&user.borrow().emul_name)
+                    .and_then(|x| x.input_size)
+            });
+        match configured {
+            Some(size) if game_data.len() != size as usize * conntype as usize => {
+                // chunking it by the wrong size would scramble every player's input.
+                // cached so the client's GAME_CACHE positions still line up
+                info!(
+                    "{}: {} bytes of input, {} expects {} per frame x connection type {}, dropped from the game",
+                    display_name(&user.borrow().name),
+                    game_data.len(),
+                    user.borrow().emul_name,
+                    size,
+                    conntype
+                );
+                user.borrow_mut().cache_system.put_data(game_data.to_vec());
+                let reason = format!(
+                    "Dropped: {} bytes of input, {} per frame x connection type {} expected.",
+                    game_data.len(),
+                    size,
+                    conntype
+                );
+                User::send_game_message(&user, &mut self.socket, reason.into_bytes()).await?;
+                return self.svc_drop_game(Vec::new(), user).await;
+            }
+            Some(size) => user.borrow_mut().atomic_input_size = size,
+            None => {
+                let size = (game_data.len() as u8).checked_div(conntype).ok_or(
+                    KailleraError::InvalidInput {
+                        message: "connection type 0".to_string(),
+                        pos: 0,
+                    },
+                )?;
+                user.borrow_mut().atomic_input_size = size;
+            }
+        }
         info!("atomic_input_size: {}", user.borrow().atomic_input_size);
         info!("game_data: {:?}", game_data);
 
         let user_room = self.session_manager.get_room(room_id)?;
         let target_user_index = user.borrow().player_index as usize;
+        // cached even when dropped, the client's GAME_CACHE positions count it
         user.borrow_mut().cache_system.put_data(game_data.to_vec());
+        self.note_pacing(user.clone()).await?;
+        if let Some(recorder) = user_room.borrow_mut().input_recorder.as_mut() {
+            recorder.push(
+                target_user_index,
+                user.borrow().atomic_input_size,
+                game_data,
+            );
+        }
 
+        // paused: merged input waits in players_input, fast input here
+        if user_room.borrow().paused {
+            let fast = user_room.borrow().fast_input;
+            let held = fast.then(|| game_data.to_vec());
+            user_room
+                .borrow_mut()
+                .held_inputs
+                .push((user.borrow().ip_addr, held));
+            if fast {
+                return Ok(());
+            }
+        }
+        if user_room.borrow().fast_input {
+            return self
+                .forward_fast_input(user_room, user, game_data.to_vec())
+                .await;
+        }
         // user 입력 game_data 을 방에 모든 인원의 메모리에 넣어야 함.
-        for pi in &user_room.borrow().players {
-            let u = match pi {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => {
-                    self.session_manager.get_user(*i)?
-                }
-                PlayerAddr::None => continue,
-            };
-            u.borrow_mut().players_input[target_user_index].append(&mut game_data.to_vec().clone());
+        for u in self.session_manager.seated_users(&user_room)? {
+            // queues come with START_GAME
+            let mut u = u.borrow_mut();
+            if let Some(queue) = u.players_input.get_mut(target_user_index) {
+                queue.extend_from_slice(game_data);
+            }
         }
         // InputProcess
         self.input_process(buf.clone(), user.clone()).await?;
@@ -952,13 +3977,54 @@ impl ServiceServer {
                 return Err(KailleraError::NotFound.into());
             }
         };
-        if buf.len() != 2 {
-            anyhow::bail!("!= 2");
+        if self.input_over_rate(user.clone(), buf.len()).await? {
+            return Ok(());
+        }
+        let cache_position = try_get_u8(&buf, 1)?;
+        // a position past what the client filled is not a lost packet, the
+        // client is broken or lying; its inputs cannot be trusted for the game
+        let filled = user.borrow().cache_system.len();
+        if cache_position as usize >= filled {
+            info!(
+                "{}: cache position {} of {}, dropped from the game",
+                display_name(&user.borrow().name),
+                cache_position,
+                filled
+            );
+            user.borrow_mut().messages.flag(Anomaly::BadCache);
+            let reason = format!(
+                "Dropped: input cache position {} was never sent ({} cached).",
+                cache_position, filled
+            );
+            User::send_game_message(&user, &mut self.socket, reason.into_bytes()).await?;
+            return self.svc_drop_game(Vec::new(), user).await;
         }
-        let cache_position = buf[1];
         let input_data = user.borrow().cache_system.get_data(cache_position)?;
         let user_room = self.session_manager.get_room(room_id)?;
+        self.note_pacing(user.clone()).await?;
         let target_user_index = user.borrow().player_index as usize;
+        if let Some(recorder) = user_room.borrow_mut().input_recorder.as_mut() {
+            recorder.push(
+                target_user_index,
+                user.borrow().atomic_input_size,
+                &input_data,
+            );
+        }
+        // paused: merged input waits in players_input, fast input here
+        if user_room.borrow().paused {
+            let fast = user_room.borrow().fast_input;
+            let held = fast.then(|| input_data.clone());
+            user_room
+                .borrow_mut()
+                .held_inputs
+                .push((user.borrow().ip_addr, held));
+            if fast {
+                return Ok(());
+            }
+        }
+        if user_room.borrow().fast_input {
+            return self.forward_fast_input(user_room, user, input_data).await;
+        }
 
         for pi in &user_room.borrow().players {
             let u = match pi {
@@ -967,12 +4033,369 @@ impl ServiceServer {
                 }
                 PlayerAddr::None => continue,
             };
-            u.borrow_mut().players_input[target_user_index].append(&mut input_data.clone());
+            let mut u = u.borrow_mut();
+            if let Some(queue) = u.players_input.get_mut(target_user_index) {
+                queue.extend_from_slice(&input_data);
+            }
         }
         self.input_process(buf.clone(), user.clone()).await?;
 
         Ok(())
     }
+    // a seated player whose client did not ask for FAST_INPUT in its HELLO
+    pub fn fast_input_blocker(&self, room: &Rc<RefCell<Room>>) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .session_manager
+            .seated_users(room)?
+            .iter()
+            .find(|u| !u.borrow().fast_input_capable)
+            .map(|u| u.borrow().name.clone()))
+    }
+    // /fastinput true|false. it stays off while a seated client cannot take
+    // FAST_INPUT, that client would never see the others' inputs
+    pub async fn set_fast_input(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        if on {
+            if let Some(name) = self.fast_input_blocker(&room)? {
+                let mut text = b"fast input needs every client to support it, ".to_vec();
+                text.extend(name);
+                text.extend(b"'s does not".to_vec());
+                return User::send_game_message(&user, &mut self.socket, text).await;
+            }
+        }
+        room.borrow_mut().fast_input = on;
+        Ok(())
+    }
+    // fast input mode: send the input to the other players right away, tagged with
+    // the sender's player number and frame number.
+    pub async fn forward_fast_input(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        input: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let player_index = user.borrow().player_index as usize;
+        let input_size = (user.borrow().atomic_input_size as u32).max(1);
+        let frame = {
+            let mut room = room.borrow_mut();
+            if room.input_frames.len() <= player_index {
+                room.input_frames.resize(player_index + 1, 0);
+            }
+            let frame = room.input_frames[player_index];
+            room.input_frames[player_index] += input.len() as u32 / input_size;
+            frame
+        };
+        let data = FastInput2Client::new(player_index as u8 + 1, frame, input).packetize()?;
+        let sender = user.borrow().ip_addr;
+        for u in self.session_manager.seated_users(&room)? {
+            // fast input is only turned on when every seat can take it
+            if u.borrow().ip_addr == sender || !u.borrow().fast_input_capable {
+                continue;
+            }
+            User::make_send_packet(
+                &u,
+                &mut self.socket,
+                Protocol::new(FAST_INPUT, data.clone()),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+    // counts a GAME_DATA or GAME_CACHE of user. over input_max_packets_per_sec or
+    // input_max_bytes_per_sec the user is dropped from the game and the room told
+    // why; true while the message is to be ignored.
+    pub async fn input_over_rate(
+        &mut self,
+        user: Rc<RefCell<User>>,
+        bytes: usize,
+    ) -> anyhow::Result<bool> {
+        let max_packets = settings::get_num(&self.config, "input_max_packets_per_sec", 0);
+        let max_bytes = settings::get_num(&self.config, "input_max_bytes_per_sec", 0);
+        if !user
+            .borrow_mut()
+            .input_rate
+            .note(Instant::now(), bytes, max_packets, max_bytes)
+        {
+            return Ok(false);
+        }
+        if user.borrow().player_status != Playing {
+            return Ok(true);
+        }
+        let room = match user.borrow().game_room_id {
+            Some(id) => self.session_manager.get_room(id)?,
+            None => return Ok(true),
+        };
+        let name = display_name(&user.borrow().name);
+        let (packets, bytes) = {
+            let u = user.borrow();
+            (u.input_rate.packets, u.input_rate.bytes)
+        };
+        warn!(
+            "{} over the input rate cap ({} messages, {} bytes this second), dropped from game {}",
+            name,
+            packets,
+            bytes,
+            room.borrow().game_id
+        );
+        let text = format!(
+            "{} sent more game data than the server allows and was dropped from the game.\x00",
+            name
+        );
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                encoding_rs::EUC_KR.encode(&text).0.to_vec(),
+            )
+            .await?;
+        self.svc_drop_game(Vec::new(), user).await?;
+        Ok(true)
+    }
+    // /pause and /resume from the room owner while the game runs. paused, the
+    // merged input is held back (the emulators wait for it); resume replays
+    // the held inputs in arrival order. GAME_PAUSE goes to clients that asked
+    // for it, everyone gets a chat line.
+    pub async fn pause_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        pause: bool,
+    ) -> anyhow::Result<()> {
+        if !room.borrow().is_owner(&user.borrow()) {
+            return self.refuse(user, Refusal::NotOwner).await;
+        }
+        if room.borrow().game_status != GAME_STATUS_PLAYING || room.borrow().paused == pause {
+            return Ok(());
+        }
+        room.borrow_mut().paused = pause;
+        info!(
+            "game {} {} by {}",
+            room.borrow().game_id,
+            if pause { "paused" } else { "resumed" },
+            display_name(&user.borrow().name)
+        );
+        let data = GamePause2Client::new(pause, user.borrow().name.clone()).packetize()?;
+        for u in self.session_manager.seated_users(&room)? {
+            if u.borrow().pause_capable {
+                User::make_send_packet(
+                    &u,
+                    &mut self.socket,
+                    Protocol::new(GAME_PAUSE, data.clone()),
+                )
+                .await?;
+            }
+        }
+        let text = if pause {
+            "Game paused by the owner, /resume to continue."
+        } else {
+            "Game resumed."
+        };
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room.clone(),
+                "SERVER".to_string(),
+                text.as_bytes().to_vec(),
+            )
+            .await?;
+        if pause {
+            return Ok(());
+        }
+        let held = std::mem::take(&mut room.borrow_mut().held_inputs);
+        for (addr, input) in held {
+            let sender = match self.session_manager.users.get(&addr) {
+                Some(u) if u.borrow().game_room_id == Some(room.borrow().game_id) => u.clone(),
+                _ => continue,
+            };
+            match input {
+                Some(input) => self.forward_fast_input(room.clone(), sender, input).await?,
+                None => self.input_process(Vec::new(), sender).await?,
+            }
+        }
+        Ok(())
+    }
+    // state_file every state_save_secs, see saved_state.rs
+    pub fn save_state_event(&mut self) {
+        let path = match self.config.get("state_file") {
+            Some(path) => PathBuf::from(path),
+            None => return,
+        };
+        let every = Duration::from_secs(settings::get_num(&self.config, "state_save_secs", 60));
+        if let Some(t) = self.state_saved {
+            if t.elapsed() < every {
+                return;
+            }
+        }
+        self.state_saved = Some(Instant::now());
+        let mut state = SavedState::new(&self.punishments, Instant::now());
+        state.redirect = self.redirect.clone();
+        let mut rooms: Vec<_> = self.session_manager.rooms.values().collect();
+        rooms.sort_by_key(|x| x.borrow().game_id);
+        state.persistent_rooms = rooms
+            .into_iter()
+            .filter(|x| x.borrow().persistent)
+            .map(|x| PersistentRoom::from_room(&x.borrow()))
+            .collect();
+        match state.to_text() {
+            Ok(text) => self.io.write_file(path, text.into_bytes()),
+            Err(e) => warn!("state not saved: {}", e),
+        }
+    }
+    // what state_file kept from before the restart, before persistent rooms open
+    pub fn restore_state(&mut self) {
+        let path = match self.config.get("state_file") {
+            Some(path) => PathBuf::from(path),
+            None => return,
+        };
+        let state = match SavedState::load(&path) {
+            Ok(Some(state)) => state,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("state not restored: {}", e);
+                return;
+            }
+        };
+        state.restore_punishments(&mut self.punishments, Instant::now());
+        self.redirect = state.redirect.clone();
+        // only rooms still in persistent_rooms_file, an /unpersist after the
+        // last save stays undone
+        for room in &mut self.persistent_rooms.rooms {
+            if let Some(saved) = state
+                .persistent_rooms
+                .iter()
+                .find(|x| x.settings.game_name == room.settings.game_name)
+            {
+                *room = saved.clone();
+            }
+        }
+        info!(
+            "restored state of {}: {} bans and mutes, redirect {:?}",
+            state.saved_at,
+            state.punishments.len(),
+            state.redirect
+        );
+    }
+    // open the rooms of persistent_rooms_file, before anyone is logged in
+    pub fn open_persistent_rooms(&mut self) {
+        for saved in self.persistent_rooms.rooms.clone() {
+            let mut room = saved.open();
+            room.game_id = self.game_id;
+            self.game_id = self.game_id.next();
+            info!("persistent room {}: {}", room.game_id, room.game_name);
+            let _ = self
+                .session_manager
+                .add_room(room.game_id, Rc::new(RefCell::new(room)));
+        }
+        self.save_ids();
+    }
+    // admin /persist: the room becomes the server's and stays open when empty,
+    // also after a restart. /unpersist gives it to the longest seated player.
+    pub async fn persist_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+        persist: bool,
+    ) -> anyhow::Result<()> {
+        let game_name = room.borrow().game_name.clone();
+        if persist {
+            let mut r = room.borrow_mut();
+            r.persistent = true;
+            r.creator_id = SERVER_OWNER.to_string();
+            self.persistent_rooms.add(PersistentRoom::from_room(&r));
+        } else {
+            let first = self.session_manager.seated_users(&room)?.first().cloned();
+            let mut r = room.borrow_mut();
+            r.persistent = false;
+            if let Some(first) = first {
+                r.creator_id = from_utf8_lossy(&first.borrow().name).to_string();
+            }
+            self.persistent_rooms.remove(&game_name);
+        }
+        info!(
+            "{} {} persistent room {}",
+            display_name(&user.borrow().name),
+            if persist { "made" } else { "ended" },
+            game_name
+        );
+        if let Some(path) = &self.persistent_rooms.path {
+            self.io
+                .write_file(path.clone(), self.persistent_rooms.to_text()?.into_bytes());
+        }
+        let text = if persist {
+            "This room is now kept open by the server."
+        } else {
+            "This room is no longer kept open by the server."
+        };
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                text.as_bytes().to_vec(),
+            )
+            .await
+    }
+    // /desync from anyone in a running game: the sync state of every seated
+    // player goes into audit_log as one json line and the room is told.
+    pub async fn desync_event(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        user: Rc<RefCell<User>>,
+    ) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if room.borrow().game_status != GAME_STATUS_PLAYING {
+            return Ok(());
+        }
+        if let Some(last) = room.borrow().desync_reported {
+            if now < last + DESYNC_REPORT_GAP {
+                return Ok(());
+            }
+        }
+        room.borrow_mut().desync_reported = Some(now);
+        let players = self
+            .session_manager
+            .seated_users(&room)?
+            .iter()
+            .map(|u| PlayerSync::new(&u.borrow()))
+            .collect();
+        let time = chrono::Utc::now();
+        let report = DesyncReport {
+            time: time.to_rfc3339(),
+            event: "desync",
+            game_id: room.borrow().game_id,
+            game_name: room.borrow().game_name.clone(),
+            reporter: display_name(&user.borrow().name),
+            players,
+        };
+        let path = match self.config.get("audit_log") {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(self.config.get("dump_dir").map_or(".", |x| x.as_str()))
+                .join("audit.jsonl"),
+        };
+        info!(
+            "game {}: desync reported by {}",
+            report.game_id, report.reporter
+        );
+        self.io.append_file(path, report.line()?);
+        let text = format!(
+            "Desync reported by {} at {} UTC, saved for the server admin.",
+            report.reporter,
+            time.format("%H:%M:%S")
+        );
+        self.session_manager
+            .send_game_chat_to_players(
+                &mut self.socket,
+                room,
+                "SERVER".to_string(),
+                text.into_bytes(),
+            )
+            .await
+    }
     pub async fn input_process(
         &mut self,
         _buf: Vec<u8>,
@@ -985,45 +4408,39 @@ impl ServiceServer {
             }
         };
         let user_room = self.session_manager.get_room(room_id)?;
-        // create packet each player
-        for i in user_room.borrow().players.iter() {
-            // select user to send data
-            let u = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => {
-                    self.session_manager.get_user(*i)?
-                }
-                PlayerAddr::None => continue,
-            };
+        if user_room.borrow().paused {
+            return Ok(());
+        }
+        // create packet each player. the room is not borrowed while sending
+        for u in self.session_manager.seated_users(&user_room)? {
             let data_to_send_to_user = UserRoom::gen_input(u.clone(), user_room.clone());
             if let Ok(data_to_send_to_user) = data_to_send_to_user {
                 if !data_to_send_to_user.is_empty() {
-                    let t = u
-                        .borrow()
-                        .put_cache
-                        .get_cache_position(data_to_send_to_user.clone());
+                    let t = u.borrow().put_cache.position(&data_to_send_to_user);
+                    // message bodies come from the user's pool and go back to it
+                    // once they fall out of the resend window
+                    let mut data = u.borrow_mut().pool.take();
                     match t {
-                        Ok(cache_position) => {
-                            let data = GameCache2Client::new(cache_position).packetize()?;
-                            u.borrow_mut()
-                                .make_send_packet(&mut self.socket, Protocol::new(GAME_CACHE, data))
-                                .await?;
+                        Some(cache_position) => {
+                            GameCache2Client::new(cache_position).write(&mut data);
+                            u.borrow_mut().pool.give(data_to_send_to_user);
+                            User::make_send_packet(
+                                &u,
+                                &mut self.socket,
+                                Protocol::new(GAME_CACHE, data),
+                            )
+                            .await?;
                         }
-                        Err(_e) => {
-                            u.borrow_mut()
-                                .put_cache
-                                .put_data(data_to_send_to_user.clone());
-                            trace!(
-                                "cache len : {}",
-                                u.borrow().put_cache.incoming_data_vec.len()
-                            );
-                            let data = GameData2Client::new(
-                                data_to_send_to_user.len() as u16,
-                                data_to_send_to_user,
+                        None => {
+                            GameData2Client::write(&data_to_send_to_user, &mut data);
+                            u.borrow_mut().put_cache.put_data(data_to_send_to_user);
+                            trace!("cache len : {}", u.borrow().put_cache.len());
+                            User::make_send_packet(
+                                &u,
+                                &mut self.socket,
+                                Protocol::new(GAME_DATA, data),
                             )
-                            .packetize()?;
-                            u.borrow_mut()
-                                .make_send_packet(&mut self.socket, Protocol::new(GAME_DATA, data))
-                                .await?;
+                            .await?;
                         }
                     }
                 }
@@ -1047,45 +4464,116 @@ impl ServiceServer {
         let data = UpdateGameStatus2Client::new(
             room_id,
             room.borrow().game_status,
-            room.borrow().players.len() as u8,
-            4,
+            room.borrow().player_some_count() as u8,
+            room.borrow().max_players,
         )
         .packetize()?;
         for (_addr, u) in &self.session_manager.users {
-            u.borrow_mut()
-                .make_send_packet(
-                    &mut self.socket,
-                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                )
-                .await?;
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+            )
+            .await?;
         }
 
         // send DROP_GAME to room's users
         let data = GameDrop2Client::new(user.borrow().name.clone(), user.borrow().player_index + 1)
             .packetize()?;
-        for i in room.borrow().players.iter() {
-            let u = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => {
-                    self.session_manager.get_user(*i)?
-                }
-                PlayerAddr::None => continue,
-            };
-            u.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(DROP_GAME, data.clone()))
+        for u in self.session_manager.seated_users(&room)? {
+            User::make_send_packet(&u, &mut self.socket, Protocol::new(DROP_GAME, data.clone()))
                 .await?;
         }
 
         user.borrow_mut().player_status = Idle;
+        let detail = format!(
+            "{} from game {}",
+            display_name(&user.borrow().name),
+            room_id
+        );
+        self.note_timeline("drop", detail);
 
-        let players = &mut room.borrow_mut().players;
-        if let Some(PlayerAddr::Playing(u)) | Some(PlayerAddr::Idle(u)) =
-            players.get_mut(user.borrow().player_index as usize)
         {
-            *players
-                .get_mut(user.borrow().player_index as usize)
-                .unwrap() = PlayerAddr::Idle(*u);
+            let players = &mut room.borrow_mut().players;
+            if let Some(PlayerAddr::Playing(u)) | Some(PlayerAddr::Idle(u)) =
+                players.get_mut(user.borrow().player_index as usize)
+            {
+                *players
+                    .get_mut(user.borrow().player_index as usize)
+                    .unwrap() = PlayerAddr::Idle(*u);
+            }
+        }
+        let name = String::from_utf8_lossy(&user.borrow().name).to_string();
+        if room.borrow_mut().record_drop(name) {
+            self.game_ended(room).await?;
+        }
+        Ok(())
+    }
+    // unstick a room: drop every player, reset their sync state and go back to WAITING.
+    pub async fn force_end_game(&mut self, room: Rc<RefCell<Room>>) -> anyhow::Result<()> {
+        info!("force end game: {}", room.borrow().game_id);
+        let mut dropped = Vec::new();
+        for i in room.borrow().players.iter() {
+            if let PlayerAddr::Playing(i) | PlayerAddr::Idle(i) = i {
+                let u = self.session_manager.get_user(*i)?;
+                if u.borrow().player_status == Playing {
+                    dropped.push(u);
+                }
+            }
+        }
+        self.end_game(room, dropped, b"The game was ended.\x00".to_vec())
+            .await
+    }
+    // DROP_GAME for `dropped` to the room, then everyone goes back to WAITING
+    async fn end_game(
+        &mut self,
+        room: Rc<RefCell<Room>>,
+        dropped: Vec<Rc<RefCell<User>>>,
+        notice: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut users = Vec::new();
+        for i in room.borrow().players.iter() {
+            if let PlayerAddr::Playing(i) | PlayerAddr::Idle(i) = i {
+                users.push(self.session_manager.get_user(*i)?);
+            }
+        }
+        for dropped in &dropped {
+            let data = GameDrop2Client::new(
+                dropped.borrow().name.clone(),
+                dropped.borrow().player_index + 1,
+            )
+            .packetize()?;
+            for u in &users {
+                User::make_send_packet(u, &mut self.socket, Protocol::new(DROP_GAME, data.clone()))
+                    .await?;
+            }
+        }
+        for u in &users {
+            let mut u = u.borrow_mut();
+            u.player_status = Idle;
+            u.reset_outcoming();
+        }
+        if room.borrow_mut().force_end() {
+            self.game_ended(room.clone()).await?;
+        }
+        let data = UpdateGameStatus2Client::new(
+            room.borrow().game_id,
+            room.borrow().game_status,
+            room.borrow().player_some_count() as u8,
+            room.borrow().max_players,
+        )
+        .packetize()?;
+        for u in self.session_manager.users.values() {
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+            )
+            .await?;
         }
-        Ok(())
+        self.session_manager
+            .send_game_chat_to_players(&mut self.socket, room, "SERVER".to_string(), notice)
+            .await
     }
     pub async fn svc_kick_user(
         &mut self,
@@ -1099,7 +4587,10 @@ impl ServiceServer {
             }
         };
         let room = self.session_manager.get_room(room_id)?;
-        let target_user_id = bincode::deserialize::<u16>(&buf[1..3])?;
+        if !room.borrow().is_owner(&user.borrow()) {
+            return self.refuse(user, Refusal::NotOwner).await;
+        }
+        let target_user_id = UserId(try_get_u16_le(&buf, 1)?);
 
         // get user in room using target_user_id == User's user_id
         let target_user = {
@@ -1118,52 +4609,20 @@ impl ServiceServer {
             }
             match target_user {
                 Some(i) => i,
-                None => anyhow::bail!("target user not found"),
+                None => return self.refuse(user, Refusal::NoSuchPlayer).await,
             }
         };
 
-        target_user.borrow_mut().game_room_id = None;
+        // the kicked user's client leaves the room on its own QUIT_GAME
         let data =
             QuitGame2Client::new(target_user.borrow().name.clone(), target_user_id).packetize()?;
-        // send QUIT_GAME data to room's users
-        for i in room.borrow().players.iter() {
-            let u = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => {
-                    self.session_manager.get_user(*i)?
-                }
-                PlayerAddr::None => continue,
-            };
-            u.borrow_mut()
-                .make_send_packet(&mut self.socket, Protocol::new(QUIT_GAME, data.clone()))
-                .await?;
-        }
-
-        // remove ip_addr target_user.borrow().ip_addr in room's players
-        room.borrow_mut().players.retain(|i| {
-            let delete = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => *i == target_user.borrow().ip_addr,
-                PlayerAddr::None => false,
-            };
-            !delete
-        });
-
-        // send UPDATE_GAME_STATUS to session_manager.users all
-        let data = UpdateGameStatus2Client::new(
-            room_id,
-            room.borrow().game_status,
-            room.borrow().players.len() as u8,
-            4,
+        User::make_send_packet(
+            &target_user,
+            &mut self.socket,
+            Protocol::new(QUIT_GAME, data),
         )
-        .packetize()?;
-        for (_addr, u) in &self.session_manager.users {
-            u.borrow_mut()
-                .make_send_packet(
-                    &mut self.socket,
-                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                )
-                .await?;
-        }
-        Ok(())
+        .await?;
+        self.leave_room(target_user).await
     }
     pub async fn svc_ready_to_playsignal(
         &mut self,
@@ -1183,25 +4642,21 @@ impl ServiceServer {
         let data = UpdateGameStatus2Client::new(
             room_id,
             user_room.borrow().game_status,
-            user_room.borrow().players.len() as u8,
-            4,
+            user_room.borrow().player_some_count() as u8,
+            user_room.borrow().max_players,
         )
         .packetize()?;
         for (_addr, u) in &self.session_manager.users {
-            u.borrow_mut()
-                .make_send_packet(
-                    &mut self.socket,
-                    Protocol::new(UPDATE_GAME_STATUS, data.clone()),
-                )
-                .await?;
+            User::make_send_packet(
+                u,
+                &mut self.socket,
+                Protocol::new(UPDATE_GAME_STATUS, data.clone()),
+            )
+            .await?;
         }
-        for i in &user_room.borrow().players {
-            let u = match i {
-                PlayerAddr::Playing(i) | PlayerAddr::Idle(i) => self.session_manager.get_user(*i),
-                PlayerAddr::None => continue,
-            }?;
-            let mut u = u.borrow_mut();
-            u.make_send_packet(
+        for u in self.session_manager.seated_users(&user_room)? {
+            User::make_send_packet(
+                &u,
                 &mut self.socket,
                 Protocol::new(READY_TO_PLAY_SIGNAL, b"\x00".to_vec()),
             )
@@ -1210,3 +4665,1538 @@ impl ServiceServer {
         Ok(())
     }
 }
+
+// remember the HELLO of addr for its login, forgetting the oldest past max
+fn note_hello(hellos: &mut HashMap<SocketAddr, Instant>, addr: SocketAddr, max: usize) {
+    if hellos.len() >= max {
+        let oldest = hellos.iter().min_by_key(|x| *x.1).map(|x| *x.0);
+        if let Some(oldest) = oldest {
+            hellos.remove(&oldest);
+        }
+    }
+    hellos.insert(addr, Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn join_game() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest, third) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("third"),
+        );
+        let room = t.add_room(&owner, "kof98");
+        room.borrow_mut().max_players = 2;
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        assert_eq!(guest.borrow().game_room_id, Some(game_id));
+        assert_eq!(room.borrow().player_some_count(), 2);
+        let sent = t.received(&guest);
+        expect_message(&sent, PLAYER_INFO);
+        expect_message(&sent, JOIN_GAME);
+        expect_message(&t.received(&owner), JOIN_GAME);
+        let status = t.received(&third);
+        let status = expect_message(&status, UPDATE_GAME_STATUS);
+        // game id, status, players, max players
+        assert_eq!(status.data[5..8], [GAME_STATUS_WAITING, 2, 2]);
+
+        // full now
+        t.server
+            .svc_join_game(join_request(game_id), third.clone())
+            .await
+            .unwrap();
+        assert_eq!(third.borrow().game_room_id, None);
+        expect_no_message(&t.received(&third), PLAYER_INFO);
+        expect_no_message(&t.received(&owner), JOIN_GAME);
+    }
+
+    #[tokio::test]
+    async fn join_lists_others_around_vacated_seat() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, left, other, guest) = (
+            t.add_user("owner"),
+            t.add_user("left"),
+            t.add_user("other"),
+            t.add_user("guest"),
+        );
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        for u in [&left, &other] {
+            t.server
+                .svc_join_game(join_request(game_id), u.clone())
+                .await
+                .unwrap();
+        }
+        room.borrow_mut().players[1] = PlayerAddr::None;
+        left.borrow_mut().game_room_id = None;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        let sent = t.received(&guest);
+        let info = PlayerInfo2Client::parse(&expect_message(&sent, PLAYER_INFO).data).unwrap();
+        let names: Vec<_> = info.players.iter().map(|p| p.name.as_slice()).collect();
+        assert_eq!(names, [b"owner".as_slice(), b"other"]);
+    }
+
+    #[tokio::test]
+    async fn create_game_emulator_defaults() {
+        let mut t = TestServer::new(&[("room_max_players", "8")]).await;
+        let (n64, mame) = (t.add_user("n64"), t.add_user("mame"));
+        n64.borrow_mut().emul_name = "Project64k 0.13 (01 Aug 2003)".to_string();
+        mame.borrow_mut().emul_name = "MAME32k 0.64".to_string();
+        let create = b"\x00Mario Kart 64\x00\x00\xff\xff\xff\xff".to_vec();
+        t.server.svc_create_game(create, n64.clone()).await.unwrap();
+        let room = n64.borrow().game_room_id.unwrap();
+        let room = t.server.session_manager.get_room(room).unwrap();
+        assert_eq!(room.borrow().max_players, 4);
+        let create = b"\x00kof98\x00\x00\xff\xff\xff\xff".to_vec();
+        t.server
+            .svc_create_game(create, mame.clone())
+            .await
+            .unwrap();
+        let room = mame.borrow().game_room_id.unwrap();
+        let room = t.server.session_manager.get_room(room).unwrap();
+        assert_eq!(room.borrow().max_players, 8);
+        assert!(!room.borrow().same_delay);
+    }
+
+    #[tokio::test]
+    async fn cache_position_never_filled() {
+        let mut t = TestServer::new(&[]).await;
+        let player = t.add_user("player");
+        t.add_room(&player, "kof98");
+        player.borrow_mut().player_status = Playing;
+        player.borrow_mut().cache_system.put_data(vec![1, 2]);
+        t.server
+            .svc_game_cache(vec![0, 1], player.clone())
+            .await
+            .unwrap();
+        let received = t.received(&player);
+        expect_message(&received, GAME_CHAT);
+        expect_message(&received, DROP_GAME);
+        assert_eq!(player.borrow().player_status, Idle);
+        assert_eq!(player.borrow().messages.anomalies[&Anomaly::BadCache], 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_input_size() {
+        let mut t = TestServer::new(&[("input_sizes", "mame:2")]).await;
+        let player = t.add_user("player");
+        player.borrow_mut().emul_name = "mame".to_string();
+        player.borrow_mut().connect_type = 1;
+        t.add_room(&player, "kof98");
+        player.borrow_mut().player_status = Playing;
+        t.server
+            .svc_game_data(vec![0, 3, 0, 1, 2, 3], player.clone())
+            .await
+            .unwrap();
+        assert_eq!(player.borrow().cache_system.len(), 1);
+        let received = t.received(&player);
+        expect_message(&received, GAME_CHAT);
+        expect_message(&received, DROP_GAME);
+        assert_eq!(player.borrow().player_status, Idle);
+    }
+
+    #[tokio::test]
+    async fn pacing_warning_names_player() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, slow) = (t.add_user("owner"), t.add_user("slow"));
+        let game_id = t.add_room(&owner, "kof98").borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), slow.clone())
+            .await
+            .unwrap();
+        // 10 fps for the window but the newest input
+        let start = Instant::now() - Duration::from_secs(30);
+        for i in 0..239 {
+            slow.borrow_mut()
+                .pacing
+                .note(start + Duration::from_millis(100 * i), 1);
+        }
+        t.received(&owner);
+        t.server.note_pacing(slow.clone()).await.unwrap();
+        let received = t.received(&owner);
+        let warning = expect_message(&received, GAME_CHAT);
+        assert!(String::from_utf8_lossy(&warning.data).contains("slow's emulator runs slow"));
+    }
+
+    #[tokio::test]
+    async fn input_rate_drops_only_the_flooder() {
+        let mut t = TestServer::new(&[("input_max_packets_per_sec", "70")]).await;
+        let players: Vec<_> = ["owner", "p2", "p3", "p4"]
+            .iter()
+            .map(|x| t.add_user(x))
+            .collect();
+        let room = t.add_room(&players[0], "kof98");
+        let game_id = room.borrow().game_id;
+        for p in &players[1..] {
+            t.server
+                .svc_join_game(join_request(game_id), p.clone())
+                .await
+                .unwrap();
+        }
+        t.server
+            .svc_start_game(vec![0], players[0].clone())
+            .await
+            .unwrap();
+        for p in &players {
+            t.server
+                .svc_ready_to_playsignal(vec![0], p.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(room.borrow().game_status, GAME_STATUS_PLAYING);
+        for p in &players {
+            t.received(p);
+        }
+        // a second of a 4 player game at 60 fps
+        for frame in 0..60u8 {
+            for p in &players {
+                t.server
+                    .svc_game_data(vec![0, 2, 0, frame, 0], p.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+        for p in &players {
+            assert_eq!(p.borrow().player_status, Playing);
+            let sent = t.received(p);
+            expect_no_message(&sent, DROP_GAME);
+            expect_message(&sent, GAME_DATA);
+        }
+        // p4 floods
+        for _ in 0..20 {
+            t.server
+                .svc_game_data(vec![0, 2, 0, 1, 0], players[3].clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(players[3].borrow().player_status, Idle);
+        let sent = t.received(&players[0]);
+        expect_message(&sent, DROP_GAME);
+        let notice = expect_message(&sent, GAME_CHAT);
+        assert!(String::from_utf8_lossy(&notice.data).contains("p4 sent more game data"));
+        for p in &players[..3] {
+            assert_eq!(p.borrow().player_status, Playing);
+        }
+    }
+
+    #[tokio::test]
+    async fn rename_room() {
+        let mut t = TestServer::new(&[("duplicate_room_name", "reject")]).await;
+        let (owner, guest, lobby) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("lobby"),
+        );
+        let room = t.add_room(&owner, "kof97");
+        let other = t.add_user("other");
+        t.add_room(&other, "sf2");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        for u in [&owner, &guest, &lobby] {
+            t.received(u);
+        }
+
+        let (owner_addr, guest_addr) = (owner.borrow().ip_addr, guest.borrow().ip_addr);
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/rename kof98\x00".to_vec(), guest_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof97");
+        // the guest's client would keep the old name
+        s.svc_game_chat(b"\x00/rename kof98\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof97");
+        room.borrow_mut().players.pop();
+        guest.borrow_mut().game_room_id = None;
+
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/rename sf2\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof97");
+        // its own name is not taken
+        s.svc_game_chat(b"\x00/rename kof97\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        t.received(&lobby);
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/rename kof98\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof98");
+        let received = t.received(&lobby);
+        expect_message(&received, CLOSE_GAME);
+        let create = expect_message(&received, CREATE_GAME);
+        assert!(create.data.windows(5).any(|x| x == b"kof98"));
+        expect_no_message(&t.received(&owner), CLOSE_GAME);
+
+        // persistent rooms are saved under their name
+        room.borrow_mut().persistent = true;
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/rename kof2002\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof98");
+        room.borrow_mut().persistent = false;
+
+        room.borrow_mut().game_status = GAME_STATUS_PLAYING;
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/rename kof2002\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_name, "kof98");
+    }
+
+    #[tokio::test]
+    async fn handoff_seat() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, left, late) = (t.add_user("owner"), t.add_user("left"), t.add_user("late"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        {
+            let mut room = room.borrow_mut();
+            room.players.push(PlayerAddr::None);
+            room.players
+                .push(PlayerAddr::Playing(left.borrow().ip_addr));
+            room.game_status = GAME_STATUS_PLAYING;
+        }
+        left.borrow_mut().game_room_id = Some(game_id);
+        t.server
+            .svc_join_game(join_request(game_id), late.clone())
+            .await
+            .unwrap();
+        assert_eq!(late.borrow().game_room_id, None);
+
+        room.borrow_mut().handoff = true;
+        t.received(&owner);
+        t.server
+            .svc_join_game(join_request(game_id), late.clone())
+            .await
+            .unwrap();
+        assert_eq!(late.borrow().game_room_id, Some(game_id));
+        assert!(
+            matches!(room.borrow().players[1], PlayerAddr::Idle(a) if a == late.borrow().ip_addr)
+        );
+        assert_eq!(late.borrow().player_index, 1);
+        // the running game goes on without them
+        expect_no_message(&t.received(&late), START_GAME);
+        expect_message(&t.received(&owner), GAME_CHAT);
+        // no seat left
+        assert_eq!(room.borrow().handoff_seat(), None);
+
+        // the next game deals them in as player 2
+        room.borrow_mut().force_end();
+        t.server.start_game(room.clone()).await.unwrap();
+        let sent = t.received(&late);
+        let start = expect_message(&sent, START_GAME);
+        assert_eq!(StartGame2Client::parse(&start.data).unwrap().player_num, 2);
+    }
+
+    // arbitrary bodies for the handlers that parse their message, from users in
+    // and out of rooms: errors are fine, panics are not
+    #[tokio::test]
+    async fn malformed_bodies() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(4945);
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        // the owner's game is running, so input reaches the sync code
+        {
+            let mut room = room.borrow_mut();
+            room.players[0] = PlayerAddr::Playing(owner.borrow().ip_addr);
+            room.game_status = GAME_STATUS_PLAYING;
+        }
+        {
+            let mut owner = owner.borrow_mut();
+            owner.player_status = Playing;
+            owner.reset_outcoming();
+            owner.players_input.resize(1, Vec::new());
+        }
+        for round in 0..1000 {
+            let len = rng.gen_range(0..24);
+            let body: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let user = if round % 2 == 0 { &owner } else { &guest };
+            let addr = user.borrow().ip_addr;
+            let s = &mut t.server;
+            let _ = s.svc_create_game(body.clone(), user.clone()).await;
+            let _ = s.svc_join_game(body.clone(), user.clone()).await;
+            let _ = s.svc_kick_user(body.clone(), user.clone()).await;
+            let _ = s.svc_game_data(body.clone(), user.clone()).await;
+            let _ = s.svc_game_cache(body.clone(), user.clone()).await;
+            let _ = s.svc_game_chat(body.clone(), addr).await;
+            let _ = s.svc_global_chat(body.clone(), addr).await;
+            let _ = s.svc_user_login(body.clone(), addr).await;
+            let _ = s.svc_user_quit(body.clone(), user.clone()).await;
+            // quitting forgot them, keep fuzzing the same users
+            for u in [&owner, &guest] {
+                let addr = u.borrow().ip_addr;
+                s.session_manager.users.insert(addr, u.clone());
+            }
+        }
+
+        // connection type 0 never gets past login, and input sized by it is refused
+        let (addr, s) = (owner.borrow().ip_addr, &mut t.server);
+        owner.borrow_mut().game_room_id = Some(room.borrow().game_id);
+        s.svc_user_login(b"owner\x00mame\x00\x00".to_vec(), addr)
+            .await
+            .unwrap();
+        assert!(!s.session_manager.users.contains_key(&addr));
+        s.session_manager.users.insert(addr, owner.clone());
+        owner.borrow_mut().game_room_id = Some(room.borrow().game_id);
+        owner.borrow_mut().connect_type = 0;
+        owner.borrow_mut().emul_name = "unknown".to_string();
+        assert!(s
+            .svc_game_data(b"\x00\x02\x00\x01\x02".to_vec(), owner.clone())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn list_file_changes() {
+        let mut t = TestServer::new(&[("bans", "10.0.0.1")]).await;
+        let owner = t.add_user("owner");
+        let addr = owner.borrow().ip_addr;
+        assert!(!t.server.is_admin(addr));
+        t.server
+            .list_file_event("admins", "127.0.0.1".to_string())
+            .await
+            .unwrap();
+        assert!(t.server.is_admin(addr));
+        assert_eq!(t.server.session_manager.users.len(), 1);
+
+        t.server
+            .list_file_event("bans", "10.0.0.1,127.0.0.*".to_string())
+            .await
+            .unwrap();
+        assert!(t.server.session_manager.users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn login_challenge() {
+        let mut t = TestServer::new(&[("login_challenge", "require")]).await;
+        let spoofed: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(
+            t.server.login_reject_reason(spoofed, b"guest").unwrap().0,
+            RejectReason::Challenge
+        );
+
+        let guest = t.add_user("guest");
+        let addr = guest.borrow().ip_addr;
+        t.server.pending.touch(addr);
+        guest.borrow_mut().challenged = true;
+        let s = &mut t.server;
+        s.svc_user_login(b"guest\x00mame\x00\x01".to_vec(), addr)
+            .await
+            .unwrap();
+        let ack = t.received(&guest).pop().unwrap();
+        let ack: AckProtocol = bincode::deserialize(&ack.data).unwrap();
+        assert_eq!(Some(ack.nonce()), guest.borrow().ack_nonce);
+        t.server
+            .svc_ack(bincode::serialize(&ack).unwrap(), guest.clone())
+            .await
+            .unwrap();
+        assert!(t.server.session_manager.users.contains_key(&addr));
+
+        // the stock ack does not echo the new values
+        expect_message(&t.received(&guest), S2C_ACK);
+        let stock = bincode::serialize(&AckProtocol::new()).unwrap();
+        t.server.svc_ack(stock, guest.clone()).await.unwrap();
+        expect_message(&t.received(&guest), CONNECTION_REJECT);
+        assert!(!t.server.session_manager.users.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn login_suggests_connection_type() {
+        let mut t = TestServer::new(&[]).await;
+        let (lan, good) = (t.add_user("lan"), t.add_user("good"));
+        // a loopback ping suits LAN
+        t.log_in(&lan, 6).await;
+        let lines: Vec<_> = t
+            .received(&lan)
+            .iter()
+            .filter(|p| p.header.header.message_type == GLOBAL_CHAT)
+            .map(|p| String::from_utf8_lossy(&p.data).to_string())
+            .collect();
+        assert!(
+            lines
+                .iter()
+                .any(|x| x.contains("suggests connection type 1, you are using 6")),
+            "{:?}",
+            lines
+        );
+        t.log_in(&good, 1).await;
+        let told = t.received(&good).iter().any(|p| {
+            p.header.header.message_type == GLOBAL_CHAT
+                && String::from_utf8_lossy(&p.data).contains("suggests")
+        });
+        assert!(!told);
+    }
+
+    #[tokio::test]
+    async fn rules_must_be_accepted() {
+        let mut t = TestServer::new(&[("rules", "Be nice.")]).await;
+        let (polite, silent) = (t.add_user("polite"), t.add_user("silent"));
+        for u in [&polite, &silent] {
+            t.log_in(u, 1).await;
+        }
+        let create = b"\x00kof98\x00\x00\xff\xff\xff\xff".to_vec();
+        t.server
+            .svc_create_game(create.clone(), polite.clone())
+            .await
+            .unwrap();
+        assert_eq!(polite.borrow().game_room_id, None);
+
+        let addr = polite.borrow().ip_addr;
+        t.server
+            .svc_global_chat(b"\x00/agree\x00".to_vec(), addr)
+            .await
+            .unwrap();
+        t.server
+            .svc_create_game(create, polite.clone())
+            .await
+            .unwrap();
+        assert!(polite.borrow().game_room_id.is_some());
+
+        // the timer only disconnects who never agreed
+        for u in [&polite, &silent] {
+            let (addr, user_id) = (u.borrow().ip_addr, u.borrow().user_id);
+            t.server.rules_timeout_event(addr, user_id).await.unwrap();
+        }
+        let users = &t.server.session_manager.users;
+        assert!(users.contains_key(&polite.borrow().ip_addr));
+        assert!(!users.contains_key(&silent.borrow().ip_addr));
+    }
+
+    #[tokio::test]
+    async fn debug_ping_shown_not_kept() {
+        let mut t = TestServer::new(&[
+            ("debug_random_ping", "true"),
+            ("debug_random_ping_max", "100"),
+        ])
+        .await;
+        let (slow, other) = (t.add_user("slow"), t.add_user("other"));
+        // as if the earlier acks took 300ms
+        slow.borrow_mut().pings = vec![300; 100];
+        t.log_in(&slow, 1).await;
+        let (ping, shown_ping) = (slow.borrow().ping, slow.borrow().shown_ping);
+        assert!(ping > 200, "{}", ping);
+        assert!(shown_ping < 100, "{}", shown_ping);
+
+        // everyone else sees the shown ping
+        let sent = t.received(&other);
+        let join = UserJoinPacket2Client::parse(&expect_message(&sent, USER_JOIN).data).unwrap();
+        assert_eq!(join.ping, shown_ping);
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_is_rejected() {
+        let mut t = TestServer::new(&[]).await;
+        let (stalled, done, lobby) = (
+            t.add_user("stalled"),
+            t.add_user("done"),
+            t.add_user("lobby"),
+        );
+        t.log_in(&done, 1).await;
+        let addr = stalled.borrow().ip_addr;
+        t.server.pending.touch(addr);
+        t.server
+            .svc_user_login(b"stalled\x00mame\x00\x01".to_vec(), addr)
+            .await
+            .unwrap();
+        t.received(&lobby);
+
+        for u in [&stalled, &done] {
+            let (addr, user_id) = (u.borrow().ip_addr, u.borrow().user_id);
+            t.server
+                .handshake_timeout_event(addr, user_id)
+                .await
+                .unwrap();
+        }
+        expect_message(&t.received(&stalled), CONNECTION_REJECT);
+        let users = &t.server.session_manager.users;
+        assert!(!users.contains_key(&addr));
+        assert!(!t.server.pending.contains(&addr));
+        assert!(users.contains_key(&done.borrow().ip_addr));
+        // it was never announced, so nobody hears it leave
+        expect_no_message(&t.received(&lobby), USER_QUIT);
+    }
+
+    #[tokio::test]
+    async fn control_commands() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        let users = t.server.control_event(ControlRequest::Users).await.unwrap();
+        assert!(users.starts_with("2 online\n"));
+        assert!(users.contains(&format!(
+            "owner 127.0.0.1:{} ping 0 game {}",
+            owner.borrow().ip_addr.port(),
+            game_id
+        )));
+
+        let closed = t
+            .server
+            .control_event(ControlRequest::Close(game_id))
+            .await
+            .unwrap();
+        assert_eq!(
+            closed,
+            format!("closed game {}, 1 players sent out", game_id)
+        );
+        assert_eq!(owner.borrow().game_room_id, None);
+        expect_message(&t.received(&owner), QUIT_GAME);
+        expect_message(&t.received(&guest), CLOSE_GAME);
+
+        let banned = t
+            .server
+            .control_event(ControlRequest::Ban(
+                "guest".to_string(),
+                Duration::from_secs(60),
+            ))
+            .await
+            .unwrap();
+        assert!(banned.starts_with("banned guest"));
+        assert!(t
+            .server
+            .session_manager
+            .find_user_by_name(b"guest")
+            .is_none());
+        let ip = guest.borrow().ip_addr.ip();
+        assert!(t
+            .server
+            .punishments
+            .banned_for(ip, Instant::now())
+            .is_some());
+        let kicked = t
+            .server
+            .control_event(ControlRequest::Kick("nobody".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(kicked, "error: nobody is not online");
+
+        let timeline = t
+            .server
+            .control_event(ControlRequest::Timeline(None))
+            .await
+            .unwrap();
+        assert!(timeline.starts_with("2 events\n"));
+        assert!(timeline.contains(&format!("close game {}", game_id)));
+        assert!(timeline.contains("quit guest"));
+        assert!(timeline.ends_with(": banned"));
+    }
+
+    #[tokio::test]
+    async fn kick_ends_whole_session() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_game(&owner, &[&guest], "kof98").await;
+        let addr = guest.borrow().ip_addr;
+        let kicked = t
+            .server
+            .control_event(ControlRequest::Kick("guest".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(kicked, "kicked guest");
+
+        // out of the game, the room and the user list
+        assert!(!t.server.session_manager.users.contains_key(&addr));
+        assert_eq!(guest.borrow().game_room_id, None);
+        assert_eq!(room.borrow().active_count(), 1);
+        assert_eq!(room.borrow().player_some_count(), 1);
+        let sent = t.received(&owner);
+        expect_message(&sent, DROP_GAME);
+        expect_message(&sent, QUIT_GAME);
+        expect_message(&sent, USER_QUIT);
+    }
+
+    #[tokio::test]
+    async fn redirect_users() {
+        let mut t = TestServer::new(&[("admins", "127.0.0.1")]).await;
+        let (admin, named, rest) = (t.add_user("admin"), t.add_user("named"), t.add_user("rest"));
+        let admin_addr = admin.borrow().ip_addr;
+        t.server
+            .svc_global_chat(
+                b"\x00/redirect new.example:27888 named\x00".to_vec(),
+                admin_addr,
+            )
+            .await
+            .unwrap();
+        let sent = t.received(&named);
+        let info = expect_message(&sent, SERVER_INFO);
+        assert!(String::from_utf8_lossy(&info.data).contains("new.example:27888"));
+        let users = &t.server.session_manager.users;
+        assert!(!users.contains_key(&named.borrow().ip_addr));
+        assert!(users.contains_key(&rest.borrow().ip_addr));
+        assert!(t.server.redirect.is_none());
+
+        // everyone but the admin, and new logins are told where to go
+        t.server
+            .svc_global_chat(b"\x00/redirect new.example:27888\x00".to_vec(), admin_addr)
+            .await
+            .unwrap();
+        expect_message(&t.received(&rest), SERVER_INFO);
+        assert_eq!(t.server.session_manager.users.len(), 1);
+        let elsewhere: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(
+            t.server.login_reject_reason(elsewhere, b"late"),
+            Some((RejectReason::Moved, Some("new.example:27888".to_string())))
+        );
+        t.server
+            .svc_global_chat(b"\x00/redirect off\x00".to_vec(), admin_addr)
+            .await
+            .unwrap();
+        assert!(t.server.login_reject_reason(elsewhere, b"late").is_none());
+    }
+
+    #[tokio::test]
+    async fn quit_game_closes_empty_room() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest, lobby) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("lobby"),
+        );
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        t.received(&owner);
+        t.received(&lobby);
+
+        t.server.fun_quit_game(guest.clone()).await.unwrap();
+        assert_eq!(guest.borrow().game_room_id, None);
+        let quit = t.received(&owner);
+        let quit = expect_message(&quit, QUIT_GAME);
+        assert!(quit.data.starts_with(b"guest\x00"));
+        expect_message(&t.received(&lobby), UPDATE_GAME_STATUS);
+
+        t.server.fun_quit_game(owner.clone()).await.unwrap();
+        assert!(t.server.session_manager.get_room(game_id).is_err());
+        expect_message(&t.received(&lobby), CLOSE_GAME);
+        // not in a room anymore
+        assert!(t.server.fun_quit_game(owner).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn kick_user() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        t.received(&owner);
+        t.received(&guest);
+        let kick = |target: &Rc<RefCell<User>>| {
+            let mut buf = vec![0u8];
+            buf.extend_from_slice(&target.borrow().user_id.to_le_bytes());
+            buf
+        };
+
+        // only the owner kicks
+        t.server
+            .svc_kick_user(kick(&owner), guest.clone())
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().player_some_count(), 2);
+        expect_no_message(&t.received(&owner), QUIT_GAME);
+
+        t.server
+            .svc_kick_user(kick(&guest), owner.clone())
+            .await
+            .unwrap();
+        assert_eq!(guest.borrow().game_room_id, None);
+        assert_eq!(room.borrow().player_some_count(), 1);
+        expect_message(&t.received(&guest), QUIT_GAME);
+        let sent = t.received(&owner);
+        expect_message(&sent, QUIT_GAME);
+        expect_message(&sent, UPDATE_GAME_STATUS);
+    }
+
+    #[tokio::test]
+    async fn timed_out_player_is_dropped() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, gone) = (t.add_user("owner"), t.add_user("gone"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        {
+            let mut room = room.borrow_mut();
+            room.players
+                .push(PlayerAddr::Playing(gone.borrow().ip_addr));
+            room.game_status = GAME_STATUS_PLAYING;
+        }
+        {
+            let mut gone = gone.borrow_mut();
+            gone.game_room_id = Some(game_id);
+            gone.player_index = 1;
+            gone.player_status = Playing;
+        }
+        t.server
+            .disconnect_user(gone.clone(), b"time out".to_vec())
+            .await
+            .unwrap();
+        let sent = t.received(&owner);
+        expect_message(&sent, DROP_GAME);
+        expect_message(&sent, QUIT_GAME);
+        expect_message(&sent, USER_QUIT);
+        // the seat stays, so player numbers do not shift mid-game
+        assert!(matches!(room.borrow().players[1], PlayerAddr::None));
+    }
+
+    #[tokio::test]
+    async fn history_names_dropper() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        t.add_game(&owner, &[&guest], "kof98").await;
+        t.server
+            .svc_drop_game(Vec::new(), guest.clone())
+            .await
+            .unwrap();
+        t.received(&owner);
+        let owner_addr = owner.borrow().ip_addr;
+        t.server
+            .svc_game_chat(b"\x00/history\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        let lines: Vec<_> = t
+            .received(&owner)
+            .iter()
+            .filter(|p| p.header.header.message_type == GAME_CHAT)
+            .map(|p| String::from_utf8_lossy(&p.data).to_string())
+            .collect();
+        assert!(
+            lines
+                .iter()
+                .any(|x| x.contains("#1 ") && x.contains("dropped: guest")),
+            "{:?}",
+            lines
+        );
+    }
+
+    #[tokio::test]
+    async fn ready_to_play() {
+        let mut t = TestServer::new(&[("netsync_timeout", "0")]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        t.received(&owner);
+        t.received(&guest);
+
+        t.server
+            .svc_start_game(vec![0], guest.clone())
+            .await
+            .unwrap();
+        expect_no_message(&t.received(&guest), START_GAME);
+        t.server
+            .svc_start_game(vec![0], owner.clone())
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_NET_SYNC);
+        for u in [&owner, &guest] {
+            expect_message(&t.received(u), START_GAME);
+        }
+
+        t.server
+            .svc_ready_to_playsignal(vec![0], owner.clone())
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_PLAYING);
+        assert!(matches!(room.borrow().players[0], PlayerAddr::Playing(_)));
+        expect_message(&t.received(&guest), READY_TO_PLAY_SIGNAL);
+
+        // the guest never finished netsync
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let session = match t.events().as_slice() {
+            [Event::NetsyncTimeout(id, session)] if *id == game_id => *session,
+            events => panic!("unexpected events {:?}", events),
+        };
+        t.received(&owner);
+        t.server
+            .netsync_progress_event(game_id, session, 10)
+            .await
+            .unwrap();
+        let notice = t.received(&owner);
+        let notice = expect_message(&notice, GAME_CHAT);
+        assert!(String::from_utf8_lossy(&notice.data).contains("Waiting for guest"));
+        t.server
+            .netsync_timeout_event(game_id, session)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        let sent = t.received(&owner);
+        expect_message(&sent, GAME_CHAT);
+        expect_message(&sent, DROP_GAME);
+    }
+
+    #[tokio::test]
+    async fn forceend_unsticks_netsync() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest, lobby) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("lobby"),
+        );
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        t.server
+            .svc_start_game(vec![0], owner.clone())
+            .await
+            .unwrap();
+        for u in [&owner, &guest, &lobby] {
+            t.received(u);
+        }
+        let (owner_addr, guest_addr) = (owner.borrow().ip_addr, guest.borrow().ip_addr);
+
+        // only the owner or an admin may end it
+        t.server
+            .svc_game_chat(b"\x00/forceend\x00".to_vec(), guest_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_NET_SYNC);
+        t.server
+            .svc_game_chat(b"\x00/forceend\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        for u in [&owner, &guest] {
+            assert_eq!(u.borrow().player_status, Idle);
+        }
+        expect_message(&t.received(&guest), DROP_GAME);
+        let status = t.received(&lobby);
+        let status = expect_message(&status, UPDATE_GAME_STATUS);
+        assert_eq!(status.data[5], GAME_STATUS_WAITING);
+    }
+
+    #[tokio::test]
+    async fn counts_during_game() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest, third, lobby) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("third"),
+            t.add_user("lobby"),
+        );
+        t.add_game(&owner, &[&guest, &third], "kof98").await;
+        let counts = |t: &TestServer| {
+            let room = &t.server.snapshot().rooms[0];
+            (room.seated, room.active)
+        };
+        assert_eq!(counts(&t), (3, 3));
+        t.server
+            .svc_drop_game(Vec::new(), guest.clone())
+            .await
+            .unwrap();
+        assert_eq!(counts(&t), (3, 2));
+
+        // the seat left behind by quitting the running game is not counted
+        t.received(&lobby);
+        t.server
+            .svc_quit_game(Vec::new(), guest.clone())
+            .await
+            .unwrap();
+        assert_eq!(counts(&t), (2, 2));
+        let status = t.received(&lobby);
+        let status = expect_message(&status, UPDATE_GAME_STATUS);
+        // status, players
+        assert_eq!(status.data[5..7], [GAME_STATUS_PLAYING, 2]);
+    }
+
+    #[tokio::test]
+    async fn netsync_timeout_only_for_its_session() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_game(&owner, &[&guest], "kof98").await;
+        let game_id = room.borrow().game_id;
+        let first = SessionId(room.borrow().history.len());
+
+        // everyone loaded in time
+        t.server
+            .netsync_timeout_event(game_id, first)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_PLAYING);
+        expect_no_message(&t.received(&guest), DROP_GAME);
+
+        // the next game stalls, the first one's timer does not end it
+        t.server.force_end_game(room.clone()).await.unwrap();
+        t.server
+            .svc_start_game(vec![0], owner.clone())
+            .await
+            .unwrap();
+        t.server
+            .netsync_timeout_event(game_id, first)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_NET_SYNC);
+        let second = SessionId(room.borrow().history.len());
+        t.server
+            .netsync_timeout_event(game_id, second)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        assert_eq!(room.borrow().player_some_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn netsync_timeout_drops_laggard_only() {
+        let mut t = TestServer::new(&[("netsync_timeout", "0")]).await;
+        let (owner, guest, slow) = (t.add_user("owner"), t.add_user("guest"), t.add_user("slow"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        for u in [&guest, &slow] {
+            t.server
+                .svc_join_game(join_request(game_id), u.clone())
+                .await
+                .unwrap();
+        }
+        t.server
+            .svc_start_game(vec![0], owner.clone())
+            .await
+            .unwrap();
+        for u in [&owner, &guest] {
+            t.server
+                .svc_ready_to_playsignal(vec![0], u.clone())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let session = match t.events().as_slice() {
+            [Event::NetsyncTimeout(id, session)] if *id == game_id => *session,
+            events => panic!("unexpected events {:?}", events),
+        };
+        for u in [&owner, &guest, &slow] {
+            t.received(u);
+        }
+        t.server
+            .netsync_timeout_event(game_id, session)
+            .await
+            .unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        let sent = t.received(&guest);
+        let drops: Vec<_> = sent
+            .iter()
+            .filter(|m| m.header.header.message_type == DROP_GAME)
+            .collect();
+        assert_eq!(drops.len(), 1);
+        assert!(drops[0].data.starts_with(b"slow\x00"));
+        let notice = expect_message(&sent, GAME_CHAT);
+        assert!(String::from_utf8_lossy(&notice.data).contains("slow did not finish"));
+        // everyone is still in the room, ready for another start
+        assert_eq!(room.borrow().player_some_count(), 3);
+        for u in [&owner, &guest, &slow] {
+            assert_eq!(u.borrow().player_status, Idle);
+        }
+    }
+
+    #[tokio::test]
+    async fn room_settings_are_owner_only() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        let (owner_addr, guest_addr) = (owner.borrow().ip_addr, guest.borrow().ip_addr);
+        let commands: [&[u8]; 8] = [
+            b"\x00/samedelay true\x00",
+            b"\x00/maxping 50\x00",
+            b"\x00/minplayers 2\x00",
+            b"\x00/relay on\x00",
+            b"\x00/advertise on\x00",
+            b"\x00/pingorder true\x00",
+            b"\x00/readycheck true\x00",
+            b"\x00/handoff on\x00",
+        ];
+        let settings = |room: &Rc<RefCell<Room>>| {
+            let r = room.borrow();
+            (
+                r.same_delay,
+                r.max_ping,
+                r.min_players,
+                r.relay,
+                r.advertise,
+                r.ping_order,
+                r.ready_check,
+                r.handoff,
+            )
+        };
+        let before = settings(&room);
+        for command in commands {
+            t.server
+                .svc_game_chat(command.to_vec(), guest_addr)
+                .await
+                .unwrap();
+        }
+        assert_eq!(settings(&room), before);
+        for command in commands {
+            t.server
+                .svc_game_chat(command.to_vec(), owner_addr)
+                .await
+                .unwrap();
+        }
+        assert_eq!(settings(&room), (true, 50, 2, true, true, true, true, true));
+    }
+
+    #[tokio::test]
+    async fn start_game_waits_for_ready_check() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        room.borrow_mut().ready_check = true;
+        let (owner_addr, guest_addr) = (owner.borrow().ip_addr, guest.borrow().ip_addr);
+        t.server
+            .svc_start_game(Vec::new(), owner.clone())
+            .await
+            .unwrap();
+        assert!(room.borrow().ready_check_running);
+        t.server
+            .svc_game_chat(b"\x00/ready\x00".to_vec(), guest_addr)
+            .await
+            .unwrap();
+
+        // start again: the guest stays ready
+        t.server
+            .svc_start_game(Vec::new(), owner.clone())
+            .await
+            .unwrap();
+        assert!(room.borrow().ready_players.contains(&guest_addr));
+        t.server
+            .svc_game_chat(b"\x00/ready\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert!(!room.borrow().ready_check_running);
+        assert!(room.borrow().counting_down);
+
+        // and during the countdown it neither restarts the check nor starts the game
+        t.server
+            .svc_start_game(Vec::new(), owner.clone())
+            .await
+            .unwrap();
+        assert!(!room.borrow().ready_check_running);
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        t.server.countdown_event(game_id, 0).await.unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_NET_SYNC);
+    }
+
+    #[tokio::test]
+    async fn countdown_checks_start_again() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        room.borrow_mut().min_players = 2;
+        room.borrow_mut().counting_down = true;
+        t.server
+            .svc_quit_game(Vec::new(), guest.clone())
+            .await
+            .unwrap();
+        t.received(&owner);
+
+        t.server.countdown_event(game_id, 0).await.unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+        assert!(!room.borrow().counting_down);
+        expect_message(&t.received(&owner), GAME_CHAT);
+
+        // a player over max_ping joined during the countdown
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        guest.borrow_mut().ping = 300;
+        room.borrow_mut().max_ping = 100;
+        t.server.countdown_event(game_id, 0).await.unwrap();
+        assert_eq!(room.borrow().game_status, GAME_STATUS_WAITING);
+    }
+
+    #[tokio::test]
+    async fn fast_input_needs_capable_clients() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest, stock) = (
+            t.add_user("owner"),
+            t.add_user("guest"),
+            t.add_user("stock"),
+        );
+        owner.borrow_mut().fast_input_capable = true;
+        guest.borrow_mut().fast_input_capable = true;
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        let (owner_addr, guest_addr) = (owner.borrow().ip_addr, guest.borrow().ip_addr);
+        let s = &mut t.server;
+        s.svc_game_chat(b"\x00/fastinput true\x00".to_vec(), guest_addr)
+            .await
+            .unwrap();
+        assert!(!room.borrow().fast_input);
+        s.svc_game_chat(b"\x00/fastinput true\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert!(room.borrow().fast_input);
+
+        // a stock client joining turns it off
+        s.svc_join_game(join_request(game_id), stock.clone())
+            .await
+            .unwrap();
+        assert!(!room.borrow().fast_input);
+        s.svc_game_chat(b"\x00/fastinput true\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert!(!room.borrow().fast_input);
+    }
+
+    #[tokio::test]
+    async fn fast_input_forwards_each_input() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        for u in [&owner, &guest] {
+            u.borrow_mut().fast_input_capable = true;
+        }
+        let room = t.add_game(&owner, &[&guest], "kof98").await;
+        room.borrow_mut().fast_input = true;
+        for input in [[1u8, 2], [3, 4]] {
+            let mut body = vec![0, 2, 0];
+            body.extend_from_slice(&input);
+            t.server.svc_game_data(body, owner.clone()).await.unwrap();
+        }
+
+        // to the others at once, numbered by frame, instead of merged
+        let sent = t.received(&guest);
+        expect_no_message(&sent, GAME_DATA);
+        let forwarded: Vec<_> = sent
+            .iter()
+            .filter(|p| p.header.header.message_type == FAST_INPUT)
+            .map(|p| FastInput2Client::parse(&p.data).unwrap())
+            .collect();
+        assert_eq!(
+            forwarded,
+            [
+                FastInput2Client::new(1, 0, vec![1, 2]),
+                FastInput2Client::new(1, 1, vec![3, 4])
+            ]
+        );
+        expect_no_message(&t.received(&owner), FAST_INPUT);
+    }
+
+    #[tokio::test]
+    async fn pause_holds_inputs_until_resume() {
+        let mut t = TestServer::new(&[]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        guest.borrow_mut().pause_capable = true;
+        let room = t.add_game(&owner, &[&guest], "kof98").await;
+        let owner_addr = owner.borrow().ip_addr;
+        t.server
+            .svc_game_chat(b"\x00/pause\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert!(room.borrow().paused);
+        // GAME_PAUSE only to clients that asked for it, the notice to everyone
+        let sent = t.received(&guest);
+        let pause = expect_message(&sent, GAME_PAUSE);
+        assert_eq!(
+            GamePause2Client::parse(&pause.data).unwrap(),
+            GamePause2Client::new(true, b"owner".to_vec())
+        );
+        expect_message(&sent, GAME_CHAT);
+        let sent = t.received(&owner);
+        expect_no_message(&sent, GAME_PAUSE);
+        expect_message(&sent, GAME_CHAT);
+
+        for u in [&owner, &guest] {
+            t.server
+                .svc_game_data(vec![0, 2, 0, 1, 2], u.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(room.borrow().held_inputs.len(), 2);
+        for u in [&owner, &guest] {
+            expect_no_message(&t.received(u), GAME_DATA);
+        }
+
+        t.server
+            .svc_game_chat(b"\x00/resume\x00".to_vec(), owner_addr)
+            .await
+            .unwrap();
+        assert!(!room.borrow().paused);
+        assert!(room.borrow().held_inputs.is_empty());
+        for u in [&owner, &guest] {
+            expect_message(&t.received(u), GAME_DATA);
+        }
+    }
+
+    #[tokio::test]
+    async fn find_respects_privacy() {
+        let mut t = TestServer::new(&[("find_privacy", "friends")]).await;
+        let (seeker, target) = (t.add_user("seeker"), t.add_user("target"));
+        let room = t.add_room(&target, "kof98");
+        let (seeker_addr, target_addr) = (seeker.borrow().ip_addr, target.borrow().ip_addr);
+        let shared = format!(
+            "target is in game {} \"kof98\" (waiting, 1/",
+            room.borrow().game_id
+        );
+        // hidden until the target puts the seeker on its friend list
+        for expected in ["target does not share where they are.", shared.as_str()] {
+            t.received(&seeker);
+            t.server
+                .svc_global_chat(b"\x00/find target\x00".to_vec(), seeker_addr)
+                .await
+                .unwrap();
+            let sent = t.received(&seeker);
+            let reply = expect_message(&sent, GLOBAL_CHAT);
+            assert!(String::from_utf8_lossy(&reply.data).contains(expected));
+            t.server
+                .svc_global_chat(b"\x00/friend add seeker\x00".to_vec(), target_addr)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_datagram_drains_queue() {
+        let mut t = TestServer::new(&[]).await;
+        let user = t.add_user("user");
+        let peer = user.borrow().ip_addr;
+        // seq 0 and 1 in one datagram, newest first
+        let mut datagram = vec![2u8];
+        for seq in [1, 0] {
+            let mut p = Protocol::new(KEEPALIVE, vec![0]);
+            p.header.seq = seq;
+            datagram.append(&mut p.make_packet().unwrap());
+        }
+        let size = datagram.len();
+        t.server.buf[..size].copy_from_slice(&datagram);
+        t.server.service_proc(size, peer).await.unwrap();
+        assert_eq!(user.borrow().in_packets.wanted_seq, 1);
+
+        // the client resends it; seq 1 still waits and is handled now
+        t.server.service_proc(size, peer).await.unwrap();
+        assert_eq!(user.borrow().in_packets.wanted_seq, 2);
+        assert_eq!(t.server.stats.duplicate_datagrams, 1);
+        t.server.service_proc(size, peer).await.unwrap();
+        assert_eq!(user.borrow().in_packets.wanted_seq, 2);
+    }
+
+    #[tokio::test]
+    async fn encrypted_session() {
+        let mut t = TestServer::new(&[]).await;
+        let user = t.add_user("user");
+        let peer = user.borrow().ip_addr;
+        let key = [7u8; 32];
+        user.borrow_mut().cipher = Some(SessionCipher::new(key));
+        let mut datagram = vec![1u8];
+        datagram.append(&mut Protocol::new(KEEPALIVE, vec![0]).make_packet().unwrap());
+
+        // a plain datagram, e.g. forged from the user's address, is dropped
+        let size = datagram.len();
+        t.server.buf[..size].copy_from_slice(&datagram);
+        t.server.service_proc(size, peer).await.unwrap();
+        assert_eq!(user.borrow().in_packets.wanted_seq, 0);
+
+        let sealed = crate::encryption::seal_as_client(key, 0, &datagram);
+        t.server.buf[..sealed.len()].copy_from_slice(&sealed);
+        t.server.service_proc(sealed.len(), peer).await.unwrap();
+        assert_eq!(user.borrow().in_packets.wanted_seq, 1);
+    }
+
+    #[tokio::test]
+    async fn ping_order_names_recording() {
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let mut t = TestServer::new(&[("input_record_dir", &dir)]).await;
+        let (owner, guest) = (t.add_user("owner"), t.add_user("guest"));
+        owner.borrow_mut().ping = 200;
+        guest.borrow_mut().ping = 20;
+        let room = t.add_room(&owner, "kof98");
+        let game_id = room.borrow().game_id;
+        t.server
+            .svc_join_game(join_request(game_id), guest.clone())
+            .await
+            .unwrap();
+        room.borrow_mut().ping_order = true;
+        t.server.start_game(room.clone()).await.unwrap();
+
+        // the lower ping is player 1, in the recording too
+        assert_eq!(guest.borrow().player_index, 0);
+        let room = room.borrow();
+        let names: Vec<_> = room
+            .input_recorder
+            .as_ref()
+            .unwrap()
+            .players
+            .iter()
+            .map(|x| (x.player, x.name.as_str()))
+            .collect();
+        assert_eq!(names, [(1, "guest"), (2, "owner")]);
+    }
+
+    #[tokio::test]
+    async fn replace_login_needs_same_ip() {
+        let mut t = TestServer::new(&[("duplicate_login", "replace")]).await;
+        t.add_user("someone");
+        let other_ip: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(
+            t.server
+                .login_reject_reason(other_ip, b"someone")
+                .unwrap()
+                .0,
+            RejectReason::DuplicateName
+        );
+        // a restarted client gets a new port
+        let restarted: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert!(t
+            .server
+            .login_reject_reason(restarted, b"someone")
+            .is_none());
+        assert!(t
+            .server
+            .replaceable_session(restarted, b"someone")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn rejected_relogin_keeps_session() {
+        let mut t = TestServer::new(&[
+            ("duplicate_login", "replace"),
+            ("login_challenge", "require"),
+        ])
+        .await;
+        let (old, watcher) = (t.add_user("someone"), t.add_user("watcher"));
+        let peer = old.borrow().ip_addr;
+        let mut datagram = vec![1u8];
+        let login = Protocol::new(USER_LOGIN_INFO, b"someone\x00mame\x00\x01".to_vec());
+        datagram.append(&mut login.make_packet().unwrap());
+        let size = datagram.len();
+
+        // no CHALLENGE in the HELLO: the login is refused, the session stays
+        t.server.buf[..size].copy_from_slice(&datagram);
+        t.server.service_proc(size, peer).await.unwrap();
+        expect_message(&t.received(&old), CONNECTION_REJECT);
+        let current = t.server.session_manager.users[&peer].clone();
+        assert!(Rc::ptr_eq(&current, &old));
+
+        // a challenged login only pushes it aside until it echoes the challenge
+        t.server.challenge_pending.insert(peer, Instant::now());
+        t.server.buf[..size].copy_from_slice(&datagram);
+        t.server.service_proc(size, peer).await.unwrap();
+        let new = t.server.session_manager.users[&peer].clone();
+        assert!(!Rc::ptr_eq(&new, &old));
+        assert!(t.server.replacing.contains_key(&peer));
+        expect_no_message(&t.received(&watcher), USER_QUIT);
+
+        // and never does: the old session is back, nobody saw it quit
+        let user_id = new.borrow().user_id;
+        t.server
+            .handshake_timeout_event(peer, user_id)
+            .await
+            .unwrap();
+        let current = t.server.session_manager.users[&peer].clone();
+        assert!(Rc::ptr_eq(&current, &old));
+        assert!(t.server.replacing.is_empty());
+        expect_no_message(&t.received(&watcher), USER_QUIT);
+    }
+
+    #[tokio::test]
+    async fn vip_login_bumps_idle_user() {
+        let mut t = TestServer::new(&[
+            ("max_users", "3"),
+            ("reserved_slots", "1"),
+            ("vips", "127.0.0.2"),
+            ("full_server_policy", "bump"),
+        ])
+        .await;
+        let (idle, busy) = (t.add_user("idle"), t.add_user("busy"));
+        t.add_room(&busy, "kof98");
+        let login = |name: &[u8]| {
+            let mut body = name.to_vec();
+            body.extend_from_slice(b"\x00mame\x00\x01");
+            let mut datagram = vec![1u8];
+            datagram.append(&mut Protocol::new(USER_LOGIN_INFO, body).make_packet().unwrap());
+            datagram
+        };
+
+        // the last slot is reserved
+        let guest: SocketAddr = "127.0.0.3:5000".parse().unwrap();
+        let datagram = login(b"guest");
+        t.server.buf[..datagram.len()].copy_from_slice(&datagram);
+        t.server.service_proc(datagram.len(), guest).await.unwrap();
+        assert!(!t.server.session_manager.users.contains_key(&guest));
+        let vip: SocketAddr = "127.0.0.2:5000".parse().unwrap();
+        let datagram = login(b"vip");
+        t.server.buf[..datagram.len()].copy_from_slice(&datagram);
+        t.server.service_proc(datagram.len(), vip).await.unwrap();
+        assert!(t.server.session_manager.users.contains_key(&vip));
+
+        // full now: the next vip takes the idle lobby user's slot, never a player's
+        let other_vip: SocketAddr = "127.0.0.2:5001".parse().unwrap();
+        let datagram = login(b"vip2");
+        t.server.buf[..datagram.len()].copy_from_slice(&datagram);
+        t.server
+            .service_proc(datagram.len(), other_vip)
+            .await
+            .unwrap();
+        let users = &t.server.session_manager.users;
+        assert!(users.contains_key(&other_vip));
+        assert!(!users.contains_key(&idle.borrow().ip_addr));
+        assert!(users.contains_key(&busy.borrow().ip_addr));
+    }
+
+    #[tokio::test]
+    async fn vips_are_matched_by_ip() {
+        let mut t = TestServer::new(&[
+            ("max_users", "2"),
+            ("reserved_slots", "1"),
+            ("vips", "kim, 10.0.0.*"),
+        ])
+        .await;
+        t.add_user("someone");
+        let elsewhere: SocketAddr = "192.168.0.1:5000".parse().unwrap();
+        assert_eq!(
+            t.server.login_reject_reason(elsewhere, b"kim").unwrap().0,
+            RejectReason::ServerFull
+        );
+        let vip: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert!(t.server.login_reject_reason(vip, b"lee").is_none());
+    }
+}
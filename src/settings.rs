@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+// helpers for reading the flat key/value server config.
+
+pub fn get_bool(config: &HashMap<String, String>, key: &str, default: bool) -> bool {
+    get_num(config, key, default)
+}
+
+pub fn get_num<T: FromStr>(config: &HashMap<String, String>, key: &str, default: T) -> T {
+    match config.get(key) {
+        Some(x) => x.trim().parse::<T>().unwrap_or(default),
+        None => default,
+    }
+}
+
+// comma separated list, empty items skipped.
+pub fn get_list(config: &HashMap<String, String>, key: &str) -> Vec<String> {
+    match config.get(key) {
+        Some(x) => x
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// how often clients are asked for a KEEPALIVE, see KEEPALIVE_TAG
+pub fn keepalive_interval(config: &HashMap<String, String>) -> Duration {
+    Duration::from_secs(get_num(config, "keepalive_interval_secs", 30))
+}
+
+// silence after which a user times out: keepalive_timeout_by_type
+// ("6:600,5:400") for the user's connection type, else keepalive_timeout_secs
+pub fn keepalive_timeout(config: &HashMap<String, String>, connect_type: u8) -> Duration {
+    let by_type = get_list(config, "keepalive_timeout_by_type")
+        .iter()
+        .find_map(|x| {
+            let (t, secs) = x.split_once(':')?;
+            if t.trim().parse::<u8>().ok()? == connect_type {
+                secs.trim().parse().ok()
+            } else {
+                None
+            }
+        });
+    Duration::from_secs(by_type.unwrap_or_else(|| get_num(config, "keepalive_timeout_secs", 240)))
+}
+
+// "*" matches everything, "192.168.*" matches by prefix, anything else must be equal.
+pub fn ip_matches(pattern: &str, ip: IpAddr) -> bool {
+    let ip = ip.to_string();
+    match pattern.strip_suffix('*') {
+        Some(prefix) => ip.starts_with(prefix),
+        None => pattern == ip,
+    }
+}
+
+pub fn ip_in_list(config: &HashMap<String, String>, key: &str, ip: IpAddr) -> bool {
+    get_list(config, key).iter().any(|p| ip_matches(p, ip))
+}
+
+// keys older configs used, renamed on startup: (old, new)
+pub const RENAMED: &[(&str, &str)] = &[("random_ping", "debug_random_ping")];
+// keys older configs carry that nothing reads anymore
+pub const UNUSED: &[&str] = &["key", "priority"];
+
+// bring a config written for an older release up to date. returns what was changed,
+// for the startup log. a renamed key never overrides the new one.
+pub fn migrate_legacy(config: &mut HashMap<String, String>) -> Vec<String> {
+    let mut notes = Vec::new();
+    for (old, new) in RENAMED {
+        if let Some(value) = config.remove(*old) {
+            if config.contains_key(*new) {
+                notes.push(format!("{} is ignored, {} is set", old, new));
+            } else {
+                notes.push(format!("{} is now {}", old, new));
+                config.insert(new.to_string(), value);
+            }
+        }
+    }
+    for key in UNUSED {
+        if config.contains_key(*key) {
+            notes.push(format!("{} is no longer used", key));
+        }
+    }
+    notes
+}
+
+// input bytes per frame per player from input_sizes ("mame:2,snes9x:4"), matched
+// case-insensitively against the start of the emulator name.
+pub fn input_size_for(config: &HashMap<String, String>, emulator: &str) -> Option<u8> {
+    let emulator = emulator.to_lowercase();
+    get_list(config, "input_sizes").iter().find_map(|x| {
+        let (name, size) = x.split_once(':')?;
+        if emulator.starts_with(&name.trim().to_lowercase()) {
+            size.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+// seated players a game needs before it starts, from min_players_by_game
+// ("gauntlet:3"), matched case-insensitively against the start of the game name.
+pub fn min_players_for(config: &HashMap<String, String>, game: &str) -> Option<u8> {
+    let game = game.to_lowercase();
+    get_list(config, "min_players_by_game")
+        .iter()
+        .find_map(|x| {
+            let (name, count) = x.rsplit_once(':')?;
+            if game.starts_with(&name.trim().to_lowercase()) {
+                count.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+}
+
+// the config for the startup log: keys and tokens show as "<redacted>".
+pub fn redacted(config: &HashMap<String, String>) -> BTreeMap<&str, &str> {
+    config
+        .iter()
+        .map(|(key, value)| {
+            let secret = key == "key" || key.ends_with("_key") || key.ends_with("_token");
+            (
+                key.as_str(),
+                if secret { "<redacted>" } else { value.as_str() },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_renamed_keys() {
+        let mut config = HashMap::from([
+            ("random_ping".to_string(), "true".to_string()),
+            ("priority".to_string(), "32".to_string()),
+        ]);
+        let notes = migrate_legacy(&mut config);
+        assert_eq!(
+            notes,
+            vec![
+                "random_ping is now debug_random_ping",
+                "priority is no longer used"
+            ]
+        );
+        assert!(get_bool(&config, "debug_random_ping", false));
+        assert!(!config.contains_key("random_ping"));
+
+        config.insert("random_ping".to_string(), "false".to_string());
+        migrate_legacy(&mut config);
+        assert!(get_bool(&config, "debug_random_ping", false));
+    }
+
+    #[test]
+    fn secrets_redacted() {
+        let config = HashMap::from([
+            ("peer_key".to_string(), "hunter2".to_string()),
+            ("control_query_token".to_string(), "abc".to_string()),
+            ("main_port".to_string(), "27888".to_string()),
+        ]);
+        let shown = format!("{:?}", redacted(&config));
+        assert!(!shown.contains("hunter2") && !shown.contains("abc"));
+        assert!(shown.contains("27888"));
+    }
+
+    #[test]
+    fn input_size_by_emulator() {
+        let config =
+            HashMap::from([("input_sizes".to_string(), "snes9x:4, MAME32k:2".to_string())]);
+        assert_eq!(input_size_for(&config, "mame32k 0.64 (Feb 2003)"), Some(2));
+        assert_eq!(input_size_for(&config, "Snes9x 1.60"), Some(4));
+        assert_eq!(input_size_for(&config, "Project64k"), None);
+    }
+
+    #[test]
+    fn min_players_by_game() {
+        let config = HashMap::from([(
+            "min_players_by_game".to_string(),
+            "Gauntlet:3, Dungeons & Dragons: Shadow over Mystara:2".to_string(),
+        )]);
+        assert_eq!(min_players_for(&config, "gauntlet (rev 14)"), Some(3));
+        assert_eq!(
+            min_players_for(
+                &config,
+                "Dungeons & Dragons: Shadow over Mystara (Euro 960619)"
+            ),
+            Some(2)
+        );
+        assert_eq!(min_players_for(&config, "KOF98"), None);
+    }
+
+    #[test]
+    fn keepalive_timeout_by_type() {
+        let mut config = HashMap::from([(
+            "keepalive_timeout_by_type".to_string(),
+            "6:600, 5:400".to_string(),
+        )]);
+        assert_eq!(keepalive_timeout(&config, 1), Duration::from_secs(240));
+        assert_eq!(keepalive_timeout(&config, 6), Duration::from_secs(600));
+        config.insert("keepalive_timeout_secs".to_string(), "120".to_string());
+        assert_eq!(keepalive_timeout(&config, 3), Duration::from_secs(120));
+        assert_eq!(keepalive_timeout(&config, 5), Duration::from_secs(400));
+    }
+}
@@ -0,0 +1,247 @@
+// offline replay of a game's input: `direlera-rs simulate script.json` feeds
+// timestamped GAME_DATA, GAME_CACHE and drops through the same merge and cache
+// code the server runs, and prints every message each player would get. turns
+// a desync report into a case that reproduces the same way every time.
+//
+// {"input_size": 2,
+//  "players": [{"name": "a", "connection_type": 1}, {"name": "b", "connection_type": 1}],
+//  "events": [{"at_ms": 0, "player": 1, "data": "0102"},
+//             {"at_ms": 3, "player": 2, "cache": 0},
+//             {"at_ms": 40, "player": 2, "drop": true}]}
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use crate::input_record::hex;
+use crate::protocol::*;
+use crate::room::*;
+
+#[derive(Debug, Deserialize)]
+pub struct SimPlayer {
+    pub name: String,
+    #[serde(default = "default_connection_type")]
+    pub connection_type: u8,
+}
+
+fn default_connection_type() -> u8 {
+    1
+}
+
+// one of data (hex), cache or drop
+#[derive(Debug, Deserialize)]
+pub struct SimEvent {
+    pub at_ms: u64,
+    // 1 based, like the protocol's player numbers
+    pub player: usize,
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub cache: Option<u8>,
+    #[serde(default)]
+    pub drop: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimScript {
+    // input bytes per frame per player
+    pub input_size: u8,
+    pub players: Vec<SimPlayer>,
+    pub events: Vec<SimEvent>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SimMessage {
+    pub at_ms: u64,
+    // 1 based receiver
+    pub player: usize,
+    pub message_type: u8,
+    pub body: Vec<u8>,
+}
+
+fn unhex(text: &str) -> anyhow::Result<Vec<u8>> {
+    let text: String = text.chars().filter(|x| !x.is_whitespace()).collect();
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?),
+            _ => anyhow::bail!("odd number of hex digits: {}", text),
+        })
+        .collect()
+}
+
+fn addr(i: usize) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 10000 + i as u16))
+}
+
+// every message sent to the players, in order. events run in at_ms order,
+// ties in script order.
+pub fn simulate(script: &SimScript) -> anyhow::Result<Vec<SimMessage>> {
+    let mut room = Room::new();
+    let users: Vec<_> = script
+        .players
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            room.players.push(PlayerAddr::Playing(addr(i)));
+            let mut user = User::new(addr(i));
+            user.name = p.name.clone().into_bytes();
+            user.connect_type = p.connection_type;
+            user.atomic_input_size = script.input_size;
+            user.player_index = i as u8;
+            user.reset_outcoming();
+            Rc::new(RefCell::new(user))
+        })
+        .collect();
+    let room = Rc::new(RefCell::new(room));
+    let mut events: Vec<_> = script.events.iter().enumerate().collect();
+    events.sort_by_key(|(i, e)| (e.at_ms, *i));
+
+    let mut out = Vec::new();
+    for (n, event) in events {
+        let index = event
+            .player
+            .checked_sub(1)
+            .filter(|x| *x < users.len())
+            .ok_or_else(|| anyhow::anyhow!("event {}: no player {}", n, event.player))?;
+        let sender = users[index].clone();
+        let seated: Vec<_> = room
+            .borrow()
+            .players
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| !matches!(p, PlayerAddr::None))
+            .map(|(i, _)| i)
+            .collect();
+        if event.drop {
+            room.borrow_mut().players[index] = PlayerAddr::Idle(addr(index));
+            let body =
+                GameDrop2Client::new(sender.borrow().name.clone(), index as u8 + 1).packetize()?;
+            for i in &seated {
+                out.push(SimMessage {
+                    at_ms: event.at_ms,
+                    player: i + 1,
+                    message_type: DROP_GAME,
+                    body: body.clone(),
+                });
+            }
+            continue;
+        }
+        let input = match (&event.data, event.cache) {
+            (Some(data), None) => {
+                let input = unhex(data)?;
+                sender.borrow_mut().cache_system.put_data(input.clone());
+                input
+            }
+            (None, Some(position)) => sender
+                .borrow()
+                .cache_system
+                .get_data(position)
+                .map_err(|_| anyhow::anyhow!("event {}: empty cache position {}", n, position))?,
+            _ => anyhow::bail!("event {}: needs exactly one of data, cache or drop", n),
+        };
+        for i in &seated {
+            users[*i].borrow_mut().players_input[index].extend_from_slice(&input);
+        }
+        for i in &seated {
+            let u = users[*i].clone();
+            let merged = match UserRoom::gen_input(u.clone(), room.clone()) {
+                Ok(merged) if !merged.is_empty() => merged,
+                _ => continue,
+            };
+            let mut body = Vec::new();
+            let position = u.borrow().put_cache.position(&merged);
+            let message_type = match position {
+                Some(position) => {
+                    GameCache2Client::new(position).write(&mut body);
+                    GAME_CACHE
+                }
+                None => {
+                    GameData2Client::write(&merged, &mut body);
+                    u.borrow_mut().put_cache.put_data(merged);
+                    GAME_DATA
+                }
+            };
+            out.push(SimMessage {
+                at_ms: event.at_ms,
+                player: i + 1,
+                message_type,
+                body,
+            });
+        }
+    }
+    Ok(out)
+}
+
+// one line per message: time, receiver, merged frame number, type and body
+pub fn render(messages: &[SimMessage]) -> String {
+    let mut frames = std::collections::HashMap::new();
+    let mut ret = String::new();
+    for m in messages {
+        let name = match m.message_type {
+            GAME_DATA => "GAME_DATA",
+            GAME_CACHE => "GAME_CACHE",
+            DROP_GAME => "DROP_GAME",
+            _ => "?",
+        };
+        let frame = frames.entry(m.player).or_insert(0usize);
+        let tag = if m.message_type == DROP_GAME {
+            "-".to_string()
+        } else {
+            *frame += 1;
+            format!("#{}", *frame - 1)
+        };
+        ret += &format!(
+            "{:>8}ms P{} {:>5} {:<10} {}\n",
+            m.at_ms,
+            m.player,
+            tag,
+            name,
+            hex(&m.body)
+        );
+    }
+    ret
+}
+
+pub fn run_script(path: &str) -> anyhow::Result<String> {
+    let script: SimScript = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(render(&simulate(&script)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_script() {
+        let script: SimScript = serde_json::from_str(
+            r#"{"input_size": 2,
+                "players": [{"name": "a"}, {"name": "b"}],
+                "events": [{"at_ms": 5, "player": 2, "data": "0304"},
+                           {"at_ms": 0, "player": 1, "data": "0102"},
+                           {"at_ms": 20, "player": 1, "cache": 0},
+                           {"at_ms": 20, "player": 2, "cache": 0},
+                           {"at_ms": 30, "player": 2, "drop": true},
+                           {"at_ms": 40, "player": 1, "data": "0506"}]}"#,
+        )
+        .unwrap();
+        let messages = simulate(&script).unwrap();
+        let text = render(&messages);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "       5ms P1    #0 GAME_DATA  00040001020304");
+        // the same frame again is a cache hit
+        assert_eq!(lines[3], "      20ms P2    #1 GAME_CACHE 0000");
+        assert_eq!(messages[4].message_type, DROP_GAME);
+        // the dropped player's input is zero filled
+        assert_eq!(lines[6], "      40ms P1    #2 GAME_DATA  00040005060000");
+
+        let bad: SimScript = serde_json::from_str(
+            r#"{"input_size": 2, "players": [{"name": "a"}],
+                "events": [{"at_ms": 0, "player": 1, "cache": 9}]}"#,
+        )
+        .unwrap();
+        assert!(simulate(&bad).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// serializable copies of the server state for the admin /dump command.
+// socket and packet buffers are left out, only their sizes are kept.
+use serde::Serialize;
+
+use crate::ids::*;
+use crate::load::Load;
+use crate::protocol::*;
+use crate::room::*;
+
+#[derive(Serialize, Debug)]
+pub struct UserSnapshot {
+    pub addr: String,
+    pub user_id: UserId,
+    pub name: String,
+    pub emul_name: String,
+    pub ping: u32,
+    pub connect_type: u8,
+    pub playing: bool,
+    pub player_index: u8,
+    pub game_room_id: Option<GameId>,
+    pub send_count: u16,
+    pub wanted_seq: u16,
+    pub pending_in: usize,
+    pub sent_packets: usize,
+    // buffered input bytes by player index, only non empty ones
+    pub players_input: Vec<(usize, usize)>,
+    pub cache_size: usize,
+    pub put_cache_size: usize,
+    pub keepalive_secs: u64,
+    pub datagrams: u64,
+    pub lost_datagrams: u64,
+    pub stale_datagrams: u64,
+    pub loss_percent: u64,
+    // emulator frame rate and input jitter in the current game
+    pub fps: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    // messages received by type, and the score of the anomalies among them
+    pub messages: Vec<(String, u64)>,
+    pub suspicion: u64,
+    pub anomalies: String,
+}
+
+impl UserSnapshot {
+    pub fn from_user(u: &User) -> UserSnapshot {
+        UserSnapshot {
+            addr: u.ip_addr.to_string(),
+            user_id: u.user_id,
+            name: display_name(&u.name),
+            emul_name: u.emul_name.clone(),
+            ping: u.ping,
+            connect_type: u.connect_type,
+            playing: u.player_status == Playing,
+            player_index: u.player_index,
+            game_room_id: u.game_room_id,
+            send_count: u.send_count,
+            wanted_seq: u.in_packets.wanted_seq,
+            pending_in: u.in_packets.len(),
+            sent_packets: u.out_packets.len(),
+            players_input: u
+                .players_input
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| !x.is_empty())
+                .map(|(i, x)| (i, x.len()))
+                .collect(),
+            cache_size: u.cache_system.len(),
+            put_cache_size: u.put_cache.len(),
+            keepalive_secs: u.keepalive_time.elapsed().as_secs(),
+            datagrams: u.in_packets.link.datagrams,
+            lost_datagrams: u.in_packets.link.lost,
+            stale_datagrams: u.in_packets.link.stale,
+            loss_percent: u.in_packets.link.loss_percent(),
+            fps: u.pacing.fps(),
+            jitter_ms: u.pacing.jitter_ms(),
+            messages: u.messages.named_counts(),
+            suspicion: u.messages.score(),
+            anomalies: u.messages.describe(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomSnapshot {
+    pub game_id: GameId,
+    pub game_name: String,
+    pub emul_name: String,
+    pub creator: String,
+    pub persistent: bool,
+    pub status: &'static str,
+    // "playing addr", "idle addr" or "none"
+    pub players: Vec<String>,
+    pub seated: usize,
+    pub active: usize,
+    pub max_players: u8,
+    pub min_players: u8,
+    pub same_delay: bool,
+    pub fast_input: bool,
+    pub ping_order: bool,
+    pub ready_check: bool,
+    pub ready_check_running: bool,
+    pub input_frames: Vec<u32>,
+    pub sessions: usize,
+    pub recording: bool,
+    // "label addr"
+    pub teams: Vec<String>,
+}
+
+impl RoomSnapshot {
+    pub fn from_room(r: &Room) -> RoomSnapshot {
+        RoomSnapshot {
+            game_id: r.game_id,
+            game_name: r.game_name.clone(),
+            emul_name: r.emul_name.clone(),
+            creator: r.creator_id.clone(),
+            persistent: r.persistent,
+            status: game_status_name(r.game_status),
+            players: r
+                .players
+                .iter()
+                .map(|p| match p {
+                    PlayerAddr::Playing(addr) => format!("playing {}", addr),
+                    PlayerAddr::Idle(addr) => format!("idle {}", addr),
+                    PlayerAddr::None => "none".to_string(),
+                })
+                .collect(),
+            seated: r.player_some_count(),
+            active: r.active_count(),
+            max_players: r.max_players,
+            min_players: r.min_players,
+            same_delay: r.same_delay,
+            fast_input: r.fast_input,
+            ping_order: r.ping_order,
+            ready_check: r.ready_check,
+            ready_check_running: r.ready_check_running,
+            input_frames: r.input_frames.clone(),
+            sessions: r.history.len(),
+            recording: r.input_recorder.is_some(),
+            teams: r
+                .teams
+                .iter()
+                .map(|(addr, label)| format!("{} {}", label, addr))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerSnapshot {
+    pub taken_at: String,
+    pub uptime_secs: u64,
+    pub games_played: u64,
+    pub duplicate_messages: u64,
+    pub duplicate_datagrams: u64,
+    pub duplicate_inputs: u64,
+    pub malformed_messages: u64,
+    pub load: Load,
+    pub next_game_id: GameId,
+    pub peers: usize,
+    pub users: Vec<UserSnapshot>,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn room_to_json() {
+        let addr: SocketAddr = "10.0.0.1:27999".parse().unwrap();
+        let mut room = Room::new();
+        room.game_id = GameId(3);
+        room.game_name = "kof98".to_string();
+        room.players.push(PlayerAddr::Idle(addr));
+        room.players.push(PlayerAddr::None);
+        let json = serde_json::to_value(RoomSnapshot::from_room(&room)).unwrap();
+        assert_eq!(json["game_id"], 3);
+        assert_eq!(json["status"], "waiting");
+        assert_eq!(json["players"][0], "idle 10.0.0.1:27999");
+        assert_eq!(json["players"][1], "none");
+        assert_eq!(json["seated"], 1);
+        assert_eq!(json["active"], 0);
+
+        let user = UserSnapshot::from_user(&User::new(addr));
+        let json = serde_json::to_value(user).unwrap();
+        assert_eq!(json["addr"], "10.0.0.1:27999");
+        assert_eq!(json["game_room_id"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use crate::load::LoadMeter;
+
+pub struct ServerStats {
+    pub start_time: Instant,
+    pub games_played: u64,
+    // retransmitted messages dropped before parsing
+    pub duplicate_messages: u64,
+    pub duplicate_datagrams: u64,
+    // game inputs seen again after a resync, see User::fresh_input
+    pub duplicate_inputs: u64,
+    // bodies too short for what their handler reads, see packet_util
+    pub malformed_messages: u64,
+    // datagrams and handling time for the load indicator, see load.rs
+    pub load: LoadMeter,
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats {
+            start_time: Instant::now(),
+            games_played: 0,
+            duplicate_messages: 0,
+            duplicate_datagrams: 0,
+            duplicate_inputs: 0,
+            malformed_messages: 0,
+            load: LoadMeter::default(),
+        }
+    }
+    pub fn uptime(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 1d 02:03:04
+pub fn format_uptime(d: Duration) -> String {
+    let secs = d.as_secs();
+    let days = secs / 86400;
+    let (h, m, s) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}:{:02}:{:02}", days, h, m, s)
+    } else {
+        format!("{:02}:{:02}:{:02}", h, m, s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime_format() {
+        assert_eq!(format_uptime(Duration::from_secs(0)), "00:00:00");
+        assert_eq!(format_uptime(Duration::from_secs(3725)), "01:02:05");
+        assert_eq!(format_uptime(Duration::from_secs(90061)), "1d 01:01:01");
+    }
+}
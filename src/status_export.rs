@@ -0,0 +1,139 @@
+// public server status for community web pages: who is online and which games
+// are open. written to status_export_file (.json, or .yaml/.yml) and/or POSTed
+// as json to status_export_url (http or https) every status_export_interval
+// seconds. addresses are left out, unlike the admin snapshot it is made from.
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::ids::GameId;
+use crate::load::Load;
+use crate::server_info::ServerInfo;
+use crate::snapshot::ServerSnapshot;
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct PublicUser {
+    pub name: String,
+    pub ping: u32,
+    pub status: &'static str,
+    pub connection_type: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicGame {
+    pub game_id: GameId,
+    pub name: String,
+    pub emulator: String,
+    pub owner: String,
+    pub status: &'static str,
+    pub players: usize,
+    pub max_players: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicStatus {
+    pub server: ServerInfo,
+    pub updated_at: String,
+    pub uptime_secs: u64,
+    pub users_online: usize,
+    pub load: Load,
+    pub users: Vec<PublicUser>,
+    pub games: Vec<PublicGame>,
+}
+
+impl PublicStatus {
+    pub fn from_snapshot(s: &ServerSnapshot, server: ServerInfo) -> PublicStatus {
+        PublicStatus {
+            server,
+            updated_at: s.taken_at.clone(),
+            uptime_secs: s.uptime_secs,
+            users_online: s.users.len(),
+            load: s.load,
+            users: s
+                .users
+                .iter()
+                .map(|u| PublicUser {
+                    name: u.name.clone(),
+                    ping: u.ping,
+                    status: if u.playing { "playing" } else { "idle" },
+                    connection_type: u.connect_type,
+                })
+                .collect(),
+            games: s
+                .rooms
+                .iter()
+                .map(|r| PublicGame {
+                    game_id: r.game_id,
+                    name: r.game_name.clone(),
+                    emulator: r.emul_name.clone(),
+                    owner: r.creator.clone(),
+                    status: r.status,
+                    players: r.seated,
+                    max_players: r.max_players,
+                })
+                .collect(),
+        }
+    }
+    // yaml for .yaml/.yml paths, json otherwise; (body, content type)
+    pub fn render(&self, yaml: bool) -> anyhow::Result<(String, &'static str)> {
+        if yaml {
+            Ok((serde_yaml::to_string(self)?, "application/yaml"))
+        } else {
+            Ok((serde_json::to_string_pretty(self)?, "application/json"))
+        }
+    }
+}
+
+pub fn is_yaml_path(path: &str) -> bool {
+    path.ends_with(".yaml") || path.ends_with(".yml")
+}
+
+// POST body to an http or https url, failing on anything but a 2xx answer
+pub async fn push(url: &str, content_type: &str, body: &str) -> anyhow::Result<()> {
+    let (url, content_type, body) = (url.to_string(), content_type.to_string(), body.to_string());
+    tokio::task::spawn_blocking(move || {
+        ureq::post(&url)
+            .timeout(PUSH_TIMEOUT)
+            .set("Content-Type", &content_type)
+            .send_string(&body)?;
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_round_trips() {
+        let status = PublicStatus {
+            server: ServerInfo {
+                name: Some("kof".to_string()),
+                description: None,
+                max_users: Some(50),
+                show_load: false,
+            },
+            updated_at: "2024-01-01T00:00:00+09:00".to_string(),
+            uptime_secs: 60,
+            users_online: 1,
+            load: Load::default(),
+            users: vec![PublicUser {
+                name: "a \"b\"".to_string(),
+                ping: 20,
+                status: "idle",
+                connection_type: 1,
+            }],
+            games: vec![],
+        };
+        let (yaml, content_type) = status.render(true).unwrap();
+        assert_eq!(content_type, "application/yaml");
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(value["server"]["name"].as_str(), Some("kof"));
+        assert_eq!(value["users"][0]["name"].as_str(), Some("a \"b\""));
+        assert_eq!(value["users"][0]["ping"].as_u64(), Some(20));
+        assert!(value["games"].as_sequence().unwrap().is_empty());
+    }
+}
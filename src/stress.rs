@@ -0,0 +1,328 @@
+// load generator: `direlera-rs stress host:port` logs in simulated clients in
+// pairs; each pair creates a game, joins, starts it, plays frames of GAME_DATA
+// and quits, over and over until time runs out. the seed fixes names, inputs
+// and frame timing jitter so runs are comparable.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::ids::GameId;
+use crate::protocol::*;
+use crate::selftest::{login, Client};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StressOptions {
+    pub clients: usize,
+    pub seconds: u64,
+    // GAME_DATA per second per client
+    pub fps: u32,
+    // frames played before the pair quits and makes a new game
+    pub frames: u32,
+    pub seed: u64,
+}
+
+impl Default for StressOptions {
+    fn default() -> StressOptions {
+        StressOptions {
+            clients: 8,
+            seconds: 30,
+            fps: 60,
+            frames: 600,
+            seed: 1,
+        }
+    }
+}
+
+impl StressOptions {
+    // --clients M --seconds S --fps F --frames N --seed X, any order
+    pub fn parse(args: &[String]) -> anyhow::Result<StressOptions> {
+        let mut options = StressOptions::default();
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            let value = iter
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--clients" => options.clients = value.parse()?,
+                "--seconds" => options.seconds = value.parse()?,
+                "--fps" => options.fps = value.parse()?,
+                "--frames" => options.frames = value.parse()?,
+                "--seed" => options.seed = value.parse()?,
+                _ => anyhow::bail!("unknown option {}", flag),
+            }
+        }
+        if options.clients < 2 || options.fps == 0 || options.frames == 0 {
+            anyhow::bail!("need at least 2 clients, 1 fps and 1 frame");
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StressReport {
+    pub logins: Vec<Duration>,
+    // GAME_DATA sent until the merged frame came back
+    pub frames: Vec<Duration>,
+    pub games: usize,
+    pub errors: Vec<String>,
+}
+
+impl StressReport {
+    fn merge(&mut self, other: StressReport) {
+        self.logins.extend(other.logins);
+        self.frames.extend(other.frames);
+        self.games += other.games;
+        self.errors.extend(other.errors);
+    }
+    pub fn summary(&mut self, elapsed: Duration) -> String {
+        self.logins.sort();
+        self.frames.sort();
+        let mut out = format!(
+            "{} logins, {} games, {} frames in {:.1}s ({:.0} frames/s), {} errors\n",
+            self.logins.len(),
+            self.games,
+            self.frames.len(),
+            elapsed.as_secs_f64(),
+            self.frames.len() as f64 / elapsed.as_secs_f64().max(0.001),
+            self.errors.len()
+        );
+        for (name, samples) in [("login", &self.logins), ("frame", &self.frames)] {
+            out += &format!(
+                "{:5} p50 {:?} p90 {:?} p99 {:?} max {:?}\n",
+                name,
+                percentile(samples, 50.0),
+                percentile(samples, 90.0),
+                percentile(samples, 99.0),
+                samples.last().copied().unwrap_or_default()
+            );
+        }
+        for e in self.errors.iter().take(10) {
+            out += &format!("error: {}\n", e);
+        }
+        out
+    }
+}
+
+// nearest rank percentile of sorted samples
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// a client with messages that arrived but were not asked for yet
+struct SimClient {
+    client: Client,
+    inbox: VecDeque<Protocol>,
+}
+
+impl SimClient {
+    async fn send(&mut self, message_type: u8, data: Vec<u8>) -> anyhow::Result<()> {
+        self.client.send(message_type, data).await
+    }
+    // the next message of one of the types, everything before it is dropped
+    async fn expect(&mut self, types: &[u8]) -> anyhow::Result<Protocol> {
+        loop {
+            while let Some(p) = self.inbox.pop_front() {
+                if types.contains(&p.header.header.message_type) {
+                    return Ok(p);
+                }
+            }
+            self.inbox.extend(self.client.recv().await?);
+        }
+    }
+}
+
+// game id from a CREATE_GAME announcement, when it is for game_name
+fn created_game_id(data: &[u8], game_name: &[u8]) -> Option<GameId> {
+    let mut parts = data.splitn(4, |x| *x == 0);
+    let (_, name, _, id) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if name != game_name || id.len() < 4 {
+        return None;
+    }
+    Some(GameId(u32::from_le_bytes([id[0], id[1], id[2], id[3]])))
+}
+
+async fn timed_login(
+    target: &str,
+    name: &str,
+    report: &mut StressReport,
+) -> anyhow::Result<SimClient> {
+    let start = Instant::now();
+    let client = login(target, name).await?;
+    report.logins.push(start.elapsed());
+    Ok(SimClient {
+        client,
+        inbox: VecDeque::new(),
+    })
+}
+
+async fn play_game(
+    game_name: Vec<u8>,
+    owner: &mut SimClient,
+    guest: &mut SimClient,
+    options: &StressOptions,
+    deadline: Instant,
+    rng: &mut StdRng,
+    report: &mut StressReport,
+) -> anyhow::Result<()> {
+    let mut create = vec![0u8];
+    create.extend_from_slice(&game_name);
+    create.extend_from_slice(b"\x00stress\x00\xff\xff\xff\xff");
+    owner.send(CREATE_GAME, create).await?;
+    let game_id = loop {
+        let p = owner.expect(&[CREATE_GAME]).await?;
+        if let Some(id) = created_game_id(&p.data, &game_name) {
+            break id;
+        }
+    };
+    // unused, game id, unused name, unused ping, unused user id, connection type
+    let mut join = vec![0u8];
+    join.extend_from_slice(&game_id.to_le_bytes());
+    join.extend_from_slice(b"\x00\x00\x00\x00\x00\xff\xff\x01");
+    guest.send(JOIN_GAME, join).await?;
+    guest.expect(&[PLAYER_INFO]).await?;
+    owner
+        .send(START_GAME, b"\x00\xff\xff\xff\xff".to_vec())
+        .await?;
+    for c in [&mut *owner, &mut *guest] {
+        c.expect(&[START_GAME]).await?;
+        c.send(READY_TO_PLAY_SIGNAL, b"\x00".to_vec()).await?;
+    }
+    for c in [&mut *owner, &mut *guest] {
+        c.expect(&[READY_TO_PLAY_SIGNAL]).await?;
+    }
+    report.games += 1;
+
+    let frame = Duration::from_secs(1) / options.fps;
+    let mut next = Instant::now();
+    let mut keepalive = Instant::now();
+    for _ in 0..options.frames {
+        if Instant::now() >= deadline {
+            break;
+        }
+        // 2 bytes of input at connection type 1
+        let mut sent = Vec::new();
+        for c in [&mut *owner, &mut *guest] {
+            let input: [u8; 2] = rng.gen();
+            c.send(GAME_DATA, vec![0, 2, 0, input[0], input[1]]).await?;
+            sent.push(Instant::now());
+        }
+        for (c, t) in [&mut *owner, &mut *guest].into_iter().zip(sent) {
+            c.expect(&[GAME_DATA, GAME_CACHE]).await?;
+            report.frames.push(t.elapsed());
+        }
+        if keepalive.elapsed() > Duration::from_secs(30) {
+            keepalive = Instant::now();
+            for c in [&mut *owner, &mut *guest] {
+                c.send(KEEPALIVE, Vec::new()).await?;
+            }
+        }
+        // up to a quarter frame early or late
+        let jitter = rng.gen_range(0..=frame.as_micros() as u64 / 2);
+        next += frame;
+        let at = next + Duration::from_micros(jitter) - frame / 4;
+        tokio::time::sleep_until(at.into()).await;
+    }
+    for c in [owner, guest] {
+        c.send(QUIT_GAME, b"\x00\xff\xff".to_vec()).await?;
+    }
+    Ok(())
+}
+
+async fn run_pair(
+    target: String,
+    pair: usize,
+    options: StressOptions,
+    deadline: Instant,
+) -> StressReport {
+    let mut report = StressReport::default();
+    let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(pair as u64));
+    let result = async {
+        let mut owner = timed_login(&target, &format!("stress{}a", pair), &mut report).await?;
+        let mut guest = timed_login(&target, &format!("stress{}b", pair), &mut report).await?;
+        let mut round = 0;
+        while Instant::now() < deadline {
+            play_game(
+                format!("stress-{}-{}-{}", options.seed, pair, round).into_bytes(),
+                &mut owner,
+                &mut guest,
+                &options,
+                deadline,
+                &mut rng,
+                &mut report,
+            )
+            .await?;
+            round += 1;
+        }
+        for c in [&mut owner, &mut guest] {
+            c.send(USER_QUIT, b"\x00\xff\xffstress\x00".to_vec())
+                .await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        report.errors.push(format!("pair {}: {:#}", pair, e));
+    }
+    report
+}
+
+pub async fn run_stress(target: &str, options: &StressOptions) -> anyhow::Result<String> {
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(options.seconds);
+    let pairs = options.clients / 2;
+    info!(
+        "stress: {} clients against {} for {}s",
+        pairs * 2,
+        target,
+        options.seconds
+    );
+    let tasks: Vec<_> = (0..pairs)
+        .map(|pair| {
+            tokio::spawn(run_pair(
+                target.to_string(),
+                pair,
+                options.clone(),
+                deadline,
+            ))
+        })
+        .collect();
+    let mut report = StressReport::default();
+    for task in tasks {
+        report.merge(task.await?);
+    }
+    Ok(report.summary(start.elapsed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_and_percentiles() {
+        let args: Vec<String> = ["--clients", "16", "--seed", "7"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let options = StressOptions::parse(&args).unwrap();
+        assert_eq!((options.clients, options.seed, options.fps), (16, 7, 60));
+        assert!(StressOptions::parse(&["--clients".to_string()]).is_err());
+
+        let samples: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+
+        let data = CreateGame2Client::new(b"a".to_vec(), b"g".to_vec(), b"e".to_vec(), GameId(9))
+            .packetize()
+            .unwrap();
+        assert_eq!(created_game_id(&data, b"g"), Some(GameId(9)));
+        assert_eq!(created_game_id(&data, b"h"), None);
+    }
+}
@@ -0,0 +1,105 @@
+// per client message counts and the protocol anomalies a normal client does
+// not produce: game input outside a game, owner actions from other players,
+// bodies missing fields and types only the server sends. each anomaly adds its
+// weight to a suspicion score admins see with /suspicion and in the dump.
+use std::collections::BTreeMap;
+
+use crate::schema::find_message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Anomaly {
+    // GAME_DATA, GAME_CACHE, READY_TO_PLAY_SIGNAL or DROP_GAME without a game
+    OutOfGame,
+    // START_GAME or KICK_USER_FROM_GAME from someone who does not own the room
+    NotOwner,
+    // body shorter than its layout in schema::MESSAGES
+    Malformed,
+    // a type clients never send
+    UnknownType,
+    // GAME_CACHE for a position the client never filled
+    BadCache,
+}
+
+impl Anomaly {
+    pub fn weight(self) -> u64 {
+        match self {
+            Anomaly::OutOfGame => 1,
+            Anomaly::NotOwner => 3,
+            Anomaly::Malformed | Anomaly::UnknownType | Anomaly::BadCache => 5,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Anomaly::OutOfGame => "out of game",
+            Anomaly::NotOwner => "not owner",
+            Anomaly::Malformed => "malformed",
+            Anomaly::UnknownType => "unknown type",
+            Anomaly::BadCache => "bad cache position",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MessageStats {
+    // message type -> messages received
+    pub counts: BTreeMap<u8, u64>,
+    pub anomalies: BTreeMap<Anomaly, u64>,
+}
+
+impl MessageStats {
+    pub fn count(&mut self, message_type: u8) {
+        *self.counts.entry(message_type).or_default() += 1;
+    }
+    pub fn flag(&mut self, anomaly: Anomaly) {
+        *self.anomalies.entry(anomaly).or_default() += 1;
+    }
+    pub fn score(&self) -> u64 {
+        self.anomalies.iter().map(|(a, n)| a.weight() * n).sum()
+    }
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+    // "GAME_DATA" or "0x7f" for types the schema does not know
+    pub fn named_counts(&self) -> Vec<(String, u64)> {
+        self.counts
+            .iter()
+            .map(|(t, n)| match find_message(*t) {
+                Some(m) => (m.name.to_string(), *n),
+                None => (format!("0x{:02x}", t), *n),
+            })
+            .collect()
+    }
+    // "out of game x3, malformed x1"
+    pub fn describe(&self) -> String {
+        self.anomalies
+            .iter()
+            .map(|(a, n)| format!("{} x{}", a.name(), n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::*;
+
+    #[test]
+    fn scores_anomalies() {
+        let mut stats = MessageStats::default();
+        stats.count(GAME_DATA);
+        stats.count(GAME_DATA);
+        stats.count(0x7f);
+        assert_eq!(stats.score(), 0);
+        stats.flag(Anomaly::OutOfGame);
+        stats.flag(Anomaly::OutOfGame);
+        stats.flag(Anomaly::UnknownType);
+        assert_eq!(stats.score(), 7);
+        assert_eq!(stats.total(), 3);
+        assert_eq!(stats.describe(), "out of game x2, unknown type x1");
+        assert_eq!(
+            stats.named_counts(),
+            vec![("GAME_DATA".to_string(), 2), ("0x7f".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,165 @@
+// room settings owners saved with /savetemplate, by owner name and template
+// name, kept in templates_file as json. /loadtemplate brings them back. owner
+// names are not authenticated, so templates are capped per owner and in all.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::room::Room;
+
+pub const MAX_TEMPLATES: usize = 20;
+pub const MAX_OWNERS: usize = 10000;
+pub const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomTemplate {
+    pub game_name: String,
+    pub max_players: u8,
+    pub same_delay: bool,
+    pub fast_input: bool,
+    pub ping_order: bool,
+    pub ready_check: bool,
+    pub max_ping: u32,
+    pub relay: bool,
+    #[serde(default)]
+    pub min_players: u8,
+}
+
+impl RoomTemplate {
+    pub fn from_room(room: &Room) -> RoomTemplate {
+        RoomTemplate {
+            game_name: room.game_name.clone(),
+            max_players: room.max_players,
+            same_delay: room.same_delay,
+            fast_input: room.fast_input,
+            ping_order: room.ping_order,
+            ready_check: room.ready_check,
+            max_ping: room.max_ping,
+            relay: room.relay,
+            min_players: room.min_players,
+        }
+    }
+    // everything but the game name, which is fixed once the room exists
+    pub fn apply(&self, room: &mut Room) {
+        room.max_players = self.max_players.max(room.player_some_count() as u8);
+        room.same_delay = self.same_delay;
+        room.fast_input = self.fast_input;
+        room.ping_order = self.ping_order;
+        room.ready_check = self.ready_check;
+        room.max_ping = self.max_ping;
+        room.relay = self.relay;
+        room.min_players = self.min_players.min(room.max_players);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Templates {
+    pub path: Option<PathBuf>,
+    // owner -> template name -> template
+    pub owners: BTreeMap<String, BTreeMap<String, RoomTemplate>>,
+}
+
+impl Templates {
+    // without a path the templates only live until restart
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Templates> {
+        let mut templates = Templates {
+            path: path.map(|x| x.to_path_buf()),
+            owners: BTreeMap::new(),
+        };
+        match path.map(fs::read_to_string) {
+            Some(Ok(text)) => templates.owners = serde_json::from_str(&text)?,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(templates)
+    }
+    pub fn to_text(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&self.owners)?)
+    }
+    // Ok(false) when the same template is already saved under name
+    pub fn save(
+        &mut self,
+        owner: &str,
+        name: &str,
+        template: RoomTemplate,
+    ) -> Result<bool, String> {
+        if name.chars().count() > MAX_NAME_LEN {
+            return Err(format!(
+                "template names are at most {} characters",
+                MAX_NAME_LEN
+            ));
+        }
+        if !self.owners.contains_key(owner) && self.owners.len() >= MAX_OWNERS {
+            return Err("the server keeps no more templates".to_string());
+        }
+        let templates = self.owners.entry(owner.to_string()).or_default();
+        if !templates.contains_key(name) && templates.len() >= MAX_TEMPLATES {
+            return Err(format!(
+                "at most {} templates, overwrite one",
+                MAX_TEMPLATES
+            ));
+        }
+        Ok(templates.insert(name.to_string(), template.clone()) != Some(template))
+    }
+    pub fn get(&self, owner: &str, name: &str) -> Option<&RoomTemplate> {
+        self.owners.get(owner)?.get(name)
+    }
+    pub fn names(&self, owner: &str) -> Vec<String> {
+        self.owners
+            .get(owner)
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_reload() {
+        let mut room = Room::new();
+        room.game_name = "KOF98".to_string();
+        room.max_players = 2;
+        room.same_delay = true;
+        room.max_ping = 120;
+        room.min_players = 2;
+        let mut templates = Templates::default();
+        let template = RoomTemplate::from_room(&room);
+        assert_eq!(templates.save("kim", "weekly", template.clone()), Ok(true));
+        assert_eq!(templates.save("kim", "weekly", template.clone()), Ok(false));
+        for i in 1..MAX_TEMPLATES {
+            assert_eq!(
+                templates.save("kim", &i.to_string(), template.clone()),
+                Ok(true)
+            );
+        }
+        assert!(templates.save("kim", "one more", template.clone()).is_err());
+        for i in 1..MAX_TEMPLATES {
+            templates
+                .owners
+                .get_mut("kim")
+                .unwrap()
+                .remove(&i.to_string());
+        }
+        assert_eq!(templates.names("kim"), vec!["weekly"]);
+        assert!(templates.get("lee", "weekly").is_none());
+
+        let path = std::env::temp_dir().join(format!("direlera-templates-{}", std::process::id()));
+        fs::write(&path, templates.to_text().unwrap()).unwrap();
+        let loaded = Templates::load(Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let template = loaded.get("kim", "weekly").unwrap();
+        assert_eq!(template.game_name, "KOF98");
+
+        let mut fresh = Room::new();
+        template.apply(&mut fresh);
+        assert!(fresh.same_delay);
+        assert_eq!(
+            (fresh.max_players, fresh.min_players, fresh.max_ping),
+            (2, 2, 120)
+        );
+    }
+}
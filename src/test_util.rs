@@ -0,0 +1,231 @@
+// handler tests: a ServiceServer on a loopback socket with fake users, each
+// behind its own udp socket so everything the server sends them can be read
+// back and checked. events the handlers queue (timers and the like) stay in
+// the server's channel for the test to look at.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::acl::Acl;
+use crate::emulators::Emulators;
+use crate::friends::Friends;
+use crate::game_names::GameNames;
+use crate::ids::*;
+use crate::io_worker::{IoWorker, LogFormat};
+use crate::pending::PendingSessions;
+use crate::persistent_rooms::PersistentRooms;
+use crate::protocol::*;
+use crate::punishment::Punishments;
+use crate::room::*;
+use crate::scripting::ScriptHooks;
+use crate::service_server::{Event, ServiceServer};
+use crate::stats::ServerStats;
+use crate::templates::Templates;
+use crate::timeline::{self, Timeline};
+
+struct Client {
+    // std, non blocking: reads what already arrived without a reactor turn
+    socket: std::net::UdpSocket,
+    // newest seq read so far, datagrams repeat the last few messages
+    seen: Option<u16>,
+}
+
+pub struct TestServer {
+    pub server: ServiceServer,
+    clients: HashMap<SocketAddr, Client>,
+}
+
+impl TestServer {
+    pub async fn new(config: &[(&str, &str)]) -> TestServer {
+        let config: HashMap<String, String> = config
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let (tx, rx) = mpsc::channel(64);
+        let server = ServiceServer {
+            acl: Acl::from_config(&config).unwrap(),
+            config,
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            buf: vec![0; 1024],
+            to_send: None,
+            session_manager: UserRoom::new(),
+            game_id: GameId(1),
+            stats: ServerStats::new(),
+            punishments: Punishments::new(),
+            friends: Friends::default(),
+            templates: Templates::default(),
+            persistent_rooms: PersistentRooms::default(),
+            redirect: None,
+            game_names: GameNames::default(),
+            emulators: Emulators::default(),
+            scripts: ScriptHooks::default(),
+            peers: HashMap::new(),
+            encryption_pending: HashMap::new(),
+            pause_pending: HashMap::new(),
+            fast_input_pending: HashMap::new(),
+            challenge_pending: HashMap::new(),
+            probe: None,
+            pending: PendingSessions::new(16),
+            replacing: HashMap::new(),
+            io: IoWorker::start(64, LogFormat::Text, None),
+            status_exported: None,
+            state_saved: None,
+            timeline: Timeline::new(timeline::DEFAULT_SIZE),
+            rx,
+            tx,
+        };
+        TestServer {
+            server,
+            clients: HashMap::new(),
+        }
+    }
+    // a logged in user in the lobby
+    pub fn add_user(&mut self, name: &str) -> Rc<RefCell<User>> {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let users = &mut self.server.session_manager;
+        users.next_user_id = users.next_user_id.next();
+        let mut user = User::new(addr);
+        user.name = name.as_bytes().to_vec();
+        user.user_id = users.next_user_id;
+        user.connect_type = 1;
+        let user = Rc::new(RefCell::new(user));
+        users.users.insert(addr, user.clone());
+        self.clients.insert(addr, Client { socket, seen: None });
+        user
+    }
+    // a waiting room owned by owner, who sits in it
+    pub fn add_room(&mut self, owner: &Rc<RefCell<User>>, game_name: &str) -> Rc<RefCell<Room>> {
+        let mut room = Room::new();
+        room.game_id = self.server.game_id;
+        self.server.game_id = self.server.game_id.next();
+        room.game_name = game_name.to_string();
+        room.creator_id = display_name(&owner.borrow().name);
+        room.players.push(PlayerAddr::Idle(owner.borrow().ip_addr));
+        owner.borrow_mut().game_room_id = Some(room.game_id);
+        let game_id = room.game_id;
+        let room = Rc::new(RefCell::new(room));
+        self.server
+            .session_manager
+            .add_room(game_id, room.clone())
+            .unwrap();
+        room
+    }
+    // a room of owner and guests with its game started and past netsync. what
+    // getting there sent is read away.
+    pub async fn add_game(
+        &mut self,
+        owner: &Rc<RefCell<User>>,
+        guests: &[&Rc<RefCell<User>>],
+        game_name: &str,
+    ) -> Rc<RefCell<Room>> {
+        let room = self.add_room(owner, game_name);
+        let game_id = room.borrow().game_id;
+        for u in guests {
+            self.server
+                .svc_join_game(join_request(game_id), (*u).clone())
+                .await
+                .unwrap();
+        }
+        self.server
+            .svc_start_game(vec![0], owner.clone())
+            .await
+            .unwrap();
+        for u in std::iter::once(owner).chain(guests.iter().copied()) {
+            self.server
+                .svc_ready_to_playsignal(vec![0], u.clone())
+                .await
+                .unwrap();
+            self.received(u);
+        }
+        assert_eq!(room.borrow().game_status, GAME_STATUS_PLAYING);
+        room
+    }
+    // user sends USER_LOGIN_INFO and acks until logged in, as a client would
+    pub async fn log_in(&mut self, user: &Rc<RefCell<User>>, connect_type: u8) {
+        let addr = user.borrow().ip_addr;
+        self.server.pending.touch(addr);
+        let mut login = user.borrow().name.clone();
+        login.extend_from_slice(b"\x00mame\x00");
+        login.push(connect_type);
+        self.server.svc_user_login(login, addr).await.unwrap();
+        for _ in 0..10 {
+            if !self.server.pending.contains(&addr) {
+                return;
+            }
+            let ack = bincode::serialize(&AckProtocol::new()).unwrap();
+            self.server.svc_ack(ack, user.clone()).await.unwrap();
+        }
+        panic!("{} did not get logged in", addr);
+    }
+    // what the server sent user since the last call, oldest first
+    pub fn received(&mut self, user: &Rc<RefCell<User>>) -> Vec<Protocol> {
+        let client = self.clients.get_mut(&user.borrow().ip_addr).unwrap();
+        let mut messages = Vec::new();
+        let mut buf = vec![0; 4096];
+        while let Ok((size, _)) = client.socket.recv_from(&mut buf) {
+            let mut datagram = get_protocol_from_bytes(&buf[..size].to_vec()).unwrap();
+            // newest first in the datagram
+            datagram.reverse();
+            for p in datagram {
+                let newer = match client.seen {
+                    Some(seen) => p.header.seq.wrapping_sub(seen) as i16 > 0,
+                    None => true,
+                };
+                if newer {
+                    client.seen = Some(p.header.seq);
+                    messages.push(p);
+                }
+            }
+        }
+        messages
+    }
+    // events handlers queued, in order
+    pub fn events(&mut self) -> Vec<Event> {
+        std::iter::from_fn(|| self.server.rx.try_recv().ok()).collect()
+    }
+}
+
+pub fn types(messages: &[Protocol]) -> Vec<u8> {
+    messages
+        .iter()
+        .map(|p| p.header.header.message_type)
+        .collect()
+}
+
+// the first message of that type, failing the test with what was sent instead
+pub fn expect_message(messages: &[Protocol], message_type: u8) -> &Protocol {
+    match messages
+        .iter()
+        .find(|p| p.header.header.message_type == message_type)
+    {
+        Some(p) => p,
+        None => panic!(
+            "no message 0x{:02x} among {:02x?}",
+            message_type,
+            types(messages)
+        ),
+    }
+}
+
+pub fn expect_no_message(messages: &[Protocol], message_type: u8) {
+    assert!(
+        !types(messages).contains(&message_type),
+        "unexpected message 0x{:02x} among {:02x?}",
+        message_type,
+        types(messages)
+    );
+}
+
+// JOIN_GAME from a client: unused, game id, unused fields, connection type
+pub fn join_request(game_id: GameId) -> Vec<u8> {
+    let mut join = vec![0u8];
+    join.extend_from_slice(&game_id.to_le_bytes());
+    join.extend_from_slice(b"\x00\x00\x00\x00\x00\xff\xff\x01");
+    join
+}
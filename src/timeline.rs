@@ -0,0 +1,116 @@
+// the last timeline_size things that happened on the server, with the time:
+// logins, quits and why, rooms opening and closing, games starting and ending,
+// drops. the control socket answers "what happened around 21:05 when everyone
+// disconnected?" from it (timeline 21:05) without going through the log.
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Local, NaiveTime};
+
+pub const DEFAULT_SIZE: usize = 1000;
+// timeline hh:mm shows this many minutes either side
+pub const AROUND_MINUTES: i64 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEvent {
+    pub at: DateTime<Local>,
+    // login, quit, room, close, start, end, drop
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+impl TimelineEvent {
+    // "21:05:13 quit kim (1.2.3.4:5000): time out"
+    pub fn line(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.at.format("%H:%M:%S"),
+            self.kind,
+            self.detail
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Timeline {
+    events: VecDeque<TimelineEvent>,
+    size: usize,
+}
+
+impl Timeline {
+    pub fn new(size: usize) -> Timeline {
+        Timeline {
+            events: VecDeque::new(),
+            size,
+        }
+    }
+    pub fn push(&mut self, at: DateTime<Local>, kind: &'static str, detail: String) {
+        if self.size == 0 {
+            return;
+        }
+        if self.events.len() >= self.size {
+            self.events.pop_front();
+        }
+        self.events.push_back(TimelineEvent { at, kind, detail });
+    }
+    // the newest n, oldest first
+    pub fn latest(&self, n: usize) -> Vec<&TimelineEvent> {
+        self.events
+            .iter()
+            .skip(self.events.len().saturating_sub(n))
+            .collect()
+    }
+    pub fn around(&self, at: DateTime<Local>, window: Duration) -> Vec<&TimelineEvent> {
+        self.events
+            .iter()
+            .filter(|x| x.at >= at - window && x.at <= at + window)
+            .collect()
+    }
+}
+
+// the last time the clock showed time: today, or yesterday when that is still to come
+pub fn last_at(time: NaiveTime, now: DateTime<Local>) -> DateTime<Local> {
+    let today = now
+        .date_naive()
+        .and_time(time)
+        .and_local_timezone(Local)
+        .earliest()
+        .unwrap_or(now);
+    if today > now {
+        today - Duration::days(1)
+    } else {
+        today
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_events() {
+        let start = Local::now();
+        let mut timeline = Timeline::new(3);
+        for i in 0..5 {
+            timeline.push(
+                start + Duration::minutes(i * 4),
+                "login",
+                format!("user{}", i),
+            );
+        }
+        let names: Vec<_> = timeline.latest(10).iter().map(|x| &x.detail).collect();
+        assert_eq!(names, ["user2", "user3", "user4"]);
+        assert_eq!(timeline.latest(1)[0].detail, "user4");
+        // user2 at +8, user3 at +12, user4 at +16
+        let near = timeline.around(
+            start + Duration::minutes(10),
+            Duration::minutes(AROUND_MINUTES),
+        );
+        assert_eq!(near.len(), 2);
+
+        let now = Local::now();
+        let later = (now + Duration::minutes(1)).time();
+        if later > now.time() {
+            assert!(last_at(later, now) < now);
+        }
+    }
+}